@@ -4,6 +4,60 @@
 #include <ostream>
 #include <new>
 
+/// Values returned by `verdant_service_get_state`.
+enum class ConnectionStateTag {
+  Idle = 0,
+  Discovering = 1,
+  Authenticating = 2,
+  Connected = 3,
+  Error = 4,
+};
+
+enum class LoginResultTag {
+  Success,
+  PasswordReset,
+  Unauthorized,
+  UnknownServer,
+};
+
+/// Reason carried by an `Unauthorized` login result, the `LoginResult` event payload
+/// encodes it as `{"Unauthorized":"<variant name>"}`.
+enum class UnauthorizedReasonTag {
+  InvalidCredentials,
+  AccountLocked,
+  AccountNotFound,
+  SessionExpired,
+  RateLimited,
+  LoggedOut,
+  ServerUnreachable,
+};
+
+/// Tag values for the C-visible event type
+enum class VerdantEventTag {
+  None = 0,
+  LoginResult = 1,
+  ServerDiscovered = 2,
+  LkToken = 3,
+  DirectConnectionOffer = 4,
+  Disconnected = 5,
+  UserProfile = 6,
+  RoomUpdate = 7,
+  ParticipantList = 8,
+  ServerFailover = 9,
+  HealthStatus = 10,
+  ServerUnreachable = 11,
+  ServerLost = 12,
+  /// a `verdant_service_register` succeeded, see `VerdantUiCmd::Registered`.
+  RegistrationResult = 13,
+  Pong = 14,
+  ServerAdded = 15,
+  Error = 65535,
+};
+
+struct Runtime;
+
+struct VerdantService;
+
 /// Opaque C handle
 struct VerdantServiceHandle {
   VerdantService *inner;
@@ -11,6 +65,9 @@ struct VerdantServiceHandle {
 
 /// A simple FFI-safe event result. `payload` is a JSON string whose ownership is transferred
 /// to the caller. The caller must call `verdant_free_cstring(payload)` when done.
+/// `payload` holds the event's data without the event's name, which `tag` already gives:
+/// an event carrying one value sends just that value (e.g. the `LoginResult` or `Discovery`),
+/// others an object of their fields, e.g. `{"url":"...","username":"..."}` for `RegistrationResult`.
 struct VerdantEventFFI {
   uint32_t tag;
   char *payload;
@@ -20,8 +77,32 @@ struct RuntimeHandle {
   void *ptr;
 };
 
+/// Called after every background token refresh. `url` is the server that was refreshed and
+/// `token` the new access token, or NULL if the refresh failed. Both strings are only valid
+/// for the duration of the call. Invoked from a tokio worker thread.
+using VerdantRefreshCallback = void(*)(void *user_data, const char *url, const char *token);
+
+/// Called on the `verdant-events` thread with each event, see `verdant_service_set_callback`.
+/// `payload` is only valid during the call.
+using VerdantEventCallback = void(*)(uint32_t tag, const char *payload, void *userdata);
+
 extern "C" {
 
+/// The library version, e.g. "0.1.0". The string is static and must not be freed.
+const char *verdant_get_version();
+
+/// Version of the wire format spoken with servers, bumped whenever it changes incompatibly.
+uint32_t verdant_get_protocol_version();
+
+/// Describe the last failed call made on this thread, like `errno`. Returns NULL if no call
+/// failed since the last `verdant_error_clear`.
+/// The string is UTF-8, owned by the library and must not be freed. It stays valid until the
+/// next failing call or `verdant_error_clear` on the same thread.
+const char *verdant_error_get_last();
+
+/// Forget the last error of this thread, `verdant_error_get_last` returns NULL afterwards.
+void verdant_error_clear();
+
 /// Create a new VerdantService.
 /// - `start_discovery`: if non-zero, discovery is enabled
 /// - `rt_ptr`: optional pointer to a tokio::runtime::Runtime (if you have one).
@@ -38,11 +119,109 @@ int verdant_service_login(VerdantServiceHandle *h,
                           const char *username,
                           const char *password);
 
+/// Request the participants of `room_id` (a UUID string) on the server at `url`, answered
+/// with a `ParticipantList` event. Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_list_participants(VerdantServiceHandle *h,
+                                      const char *url,
+                                      const char *room_id);
+
+/// Refresh the session with the server at `url` now, answered with a `LoginResult` event
+/// (or `Error` if the refresh failed). Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_refresh_token(VerdantServiceHandle *h, const char *url);
+
+/// Create the account `username` on the server at `url`, for servers only asking for a
+/// username and email. The server doesn't need to be discovered or added first.
+/// Answered with a `RegistrationResult` event, or an `Error` event if the registration failed.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_register(VerdantServiceHandle *h,
+                             const char *url,
+                             const char *username,
+                             const char *password,
+                             const char *email);
+
+/// Like `verdant_service_register` with every field of the account, `request` is a JSON
+/// encoded `RegistrationRequest`.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_register_request(VerdantServiceHandle *h,
+                                     const char *url,
+                                     const char *request,
+                                     const char *password);
+
+/// End the session with the server at `url`, failures are reported as an `Error` event.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_logout(VerdantServiceHandle *h, const char *url);
+
+/// Check the round trip time to the server at `url`, answered with a `Pong` event (or `Error`).
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_ping(VerdantServiceHandle *h, const char *url);
+
+/// Add the server at `url` without waiting for it to be discovered, e.g. when mDNS is blocked.
+/// Answered with a `ServerAdded` event (or `Error`). Returns 0 on success, -1 on bad args.
+int verdant_service_add_server(VerdantServiceHandle *h, const char *url);
+
+/// Check whether the server at `url` is up, answered with a `HealthStatus` event (or `Error`).
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+int verdant_service_health_check(VerdantServiceHandle *h, const char *url);
+
 /// Try to receive an UI event without blocking. Returns a VerdantEventFFIby value.
 /// If no event is available, returns an event with tag = None and payload = NULL.
 /// Caller is responsible for freeing `payload` if non-null by calling `verdant_free_cstring`.
 VerdantEventFFI verdant_service_try_recv(VerdantServiceHandle *h);
 
+/// Like `verdant_service_try_recv`, waiting up to `timeout_ms` milliseconds for an event.
+/// Returns an event with tag = None and payload = NULL if none arrived in time.
+/// Blocks the calling thread, so it must not be called from a thread running the Tokio
+/// runtime (e.g. from a refresh callback), that fails with tag = None and sets the last error.
+VerdantEventFFI verdant_service_recv_timeout(VerdantServiceHandle *h, uint64_t timeout_ms);
+
+/// Deliver events to `cb` instead of `verdant_service_try_recv`.
+/// `cb` is always called on a single thread owned by the service ("verdant-events"), one
+/// event at a time and never on the caller's thread: post events to your UI thread from `cb`.
+/// A NULL `cb` drops events until another callback is set. Once `verdant_service_free`
+/// returns `cb` is never called again; don't call `verdant_service_free` from within `cb`.
+/// Events delivered to `cb` don't update the state mirrored by the service: once a callback
+/// is set `verdant_service_get_state`, `verdant_service_get_display_name`,
+/// `verdant_service_get_server_urls` and `verdant_service_get_discoveries` keep returning
+/// what they did before, track it from the events instead.
+/// Returns 0 on success, -1 on bad args, -2 if the event thread couldn't be started.
+int verdant_service_set_callback(VerdantServiceHandle *h, VerdantEventCallback cb, void *userdata);
+
+/// Get the state of the service: 0 = idle, 1 = discovering, 2 = authenticating,
+/// 3 = connected, 4 = error (the last login failed). Returns -1 on bad args.
+int verdant_service_get_state(VerdantServiceHandle *h);
+
+/// Get the display name of the user logged into `url`, as received through a
+/// `UserProfile` event. Returns NULL if unknown.
+/// Caller is responsible for freeing the result by calling `verdant_free_cstring`.
+char *verdant_service_get_display_name(VerdantServiceHandle *h, const char *url);
+
+/// Get the urls of the discovered servers, most recently seen first.
+/// Like every string array of the library the result holds `*out_count` strings followed
+/// by a NULL entry, it is empty (only the NULL) if nothing was discovered yet.
+/// Returns NULL on bad args. Caller is responsible for freeing each string with
+/// `verdant_free_cstring` and then the array with `verdant_free_string_array`.
+char **verdant_service_get_server_urls(VerdantServiceHandle *h, uintptr_t *out_count);
+
+/// Get the discovered servers as JSON encoded `Discovery` strings, most recently seen first.
+/// The array holds `*out_count` strings followed by a NULL entry, like
+/// `verdant_service_get_server_urls` it is empty (only the NULL) if nothing was discovered yet. Servers added with `verdant_service_add_server` aren't
+/// discoveries, they advertised no key, and are only listed once discovered on the LAN.
+/// Returns NULL on bad args. Caller is responsible for freeing each string with
+/// `verdant_free_cstring` and then the array with `verdant_free_string_array`.
+char **verdant_service_get_discoveries(VerdantServiceHandle *h, uintptr_t *out_count);
+
+/// Free an array returned by `verdant_service_get_server_urls` or
+/// `verdant_service_get_discoveries`, `count` must be the count it reported.
+/// The strings in it are not freed, free them with `verdant_free_cstring` first.
+/// Safe to call with NULL.
+void verdant_free_string_array(char **arr, uintptr_t count);
+
+/// Install (or clear, by passing NULL) the token refresh callback.
+/// Returns 0 on success, -1 if the handle is null.
+int verdant_service_set_refresh_callback(VerdantServiceHandle *h,
+                                         VerdantRefreshCallback callback,
+                                         void *user_data);
+
 /// Free a C string returned by the above APIs (or any CString you create via `into_raw()`).
 void verdant_free_cstring(char *s);
 
@@ -0,0 +1,28 @@
+package org.qrespite.verdant;
+
+/**
+ * Receives events pushed by the native service, install with
+ * {@code VerdantService.setEventCallback}.
+ *
+ * {@code onEvent} is called on a native thread owned by the service, one event at a time.
+ * Post to the UI thread (e.g. with a {@code Handler} on the main looper) before touching views.
+ * Exceptions thrown by {@code onEvent} are logged and cleared, the next event is still delivered.
+ *
+ * The tag constants are the values of the C {@code VerdantEventTag}. They replace the
+ * native {@code VERDANT_*} constants of earlier releases, which used different numbers:
+ * {@code VERDANT_SERVER_DISCOVERED} was 1 and is now 2, {@code VERDANT_LOGIN_RESULT} was 2
+ * and is now 1, and {@code VERDANT_REGISTRATION_RESULT} is 13.
+ */
+public interface VerdantEventListener {
+    long LOGIN_RESULT = 1;
+    long SERVER_DISCOVERED = 2;
+    long LK_RESPONSE = 3;
+    long REGISTRATION_RESULT = 13;
+    long ERROR = 0xFFFF;
+
+    /**
+     * @param tag     the kind of event, the values of the C {@code VerdantEventTag}
+     * @param payload the JSON encoded event payload, or {@code null} if it has none
+     */
+    void onEvent(long tag, String payload);
+}
@@ -0,0 +1,17 @@
+package org.qrespite.verdant;
+
+/**
+ * Thrown by the native {@code VerdantService} methods when a request can't be sent,
+ * e.g. because of malformed arguments or because the service has shut down.
+ *
+ * Failures of the request itself (a rejected login, an unreachable server) aren't thrown,
+ * they arrive later as events from {@code TryRecv}.
+ *
+ * The class must keep this name and a {@code (String)} constructor, the native library
+ * throws it with {@code JNIEnv::ThrowNew}.
+ */
+public class VerdantException extends RuntimeException {
+    public VerdantException(String message) {
+        super(message);
+    }
+}
@@ -1,28 +1,511 @@
 use crate::client::auth as client_auth;
 
 use crate::auth::LoginResult;
-use crate::auth::challenge::LoginUpload;
+use crate::auth::challenge::{LoginUpload, Transcript};
+use crate::auth::registration::{
+    RegistrationChallenge, RegistrationFinish, RegistrationRequest, RegistrationStart,
+    RegistrationStartResponse,
+};
+use crate::discovery::ServerIdentity;
 use crate::errors::Error;
+use crate::p2p::{DirectConnectionAnswer, DirectConnectionOffer, DirectConnectionOfferResponse};
 use crate::server::auth::LoginResponse;
-use aes_gcm::aead::KeyInit;
 use reqwest;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::auth::challenge::LoginCompletion;
+use hmac::{Hmac, Mac};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder};
 use sha2::Sha256;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "tracing")]
+use tracing::{debug, warn};
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use der::Decode;
 use keycast::discovery::Discovery;
 use sha2::Digest;
 
+pub const REQUEST_SIGNATURE_HEADER: &str = "X-Request-Signature";
+pub const REQUEST_TIMESTAMP_HEADER: &str = "X-Request-Timestamp";
+/// carries [`crate::VERDANT_CLIENT_VERSION`] on every request.
+pub const CLIENT_VERSION_HEADER: &str = "X-Verdant-Client-Version";
+
 /// Simple API client for auth-related endpoints.
 pub struct APIClient {
     pub url: String,
     pub decoder: DecodingKey,
     pub validation: Validation,
     pub access_token: Option<String>,
+    /// claims of `access_token`, parsed when it is set by login or refresh.
+    claims: Option<VerdantClaims>,
+    /// OPAQUE session key shared with the server, set after a successful login.
+    session_key: Option<Zeroizing<Vec<u8>>>,
+    request_signing: bool,
+    /// where the encrypted credentials are cached after each login, see
+    /// [`APIClient::enable_credential_cache`].
+    credential_cache: Option<PathBuf>,
+    fallback_urls: Vec<String>,
+    /// limit applied to every HTTP request, see [`APIClientBuilder::timeout`].
+    timeout: Option<Duration>,
+    skip_compatibility_check: bool,
+    /// set once the server accepted this client's version, see [`APIClient::check_compatibility`].
+    compatibility_checked: bool,
+    /// last token returned by [`APIClient::get_livekit_token_cached`].
+    livekit_token: Option<crate::livekit::TokenResponse>,
+    /// shared by every request so connections are pooled, see [`APIClientBuilder::with_client`].
+    client: Client,
+    /// how idempotent requests are retried, see [`APIClientBuilder::retry_policy`].
+    retry_policy: Option<RetryPolicy>,
+    /// fetched by [`APIClient::from_discovery`], see [`APIClient::server_info`].
+    server_info: Option<ServerInfo>,
+}
+
+/// How idempotent requests (GETs and the start of a login) are retried after
+/// transient failures: timeouts, refused connections and 502, 503 or 504 responses.
+///
+/// The n-th retry waits a random duration between half of and the full
+/// `base_delay * 2^n`, capped at `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u8,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (starting at 0), with jitter.
+    pub fn delay(&self, retry: u8) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.into()))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// `true` for responses worth retrying.
+    fn retries_status(&self, status: reqwest::StatusCode) -> bool {
+        matches!(status.as_u16(), 502..=504)
+    }
+}
+
+/// Answer of `/auth/api/compatibility`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompatibilityResponse {
+    /// oldest client version the server supports, e.g. "0.2.0".
+    pub min_client_version: String,
+}
+
+/// Connects to `addr` while presenting `hostname` for TLS SNI and certificate validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SniOverride {
+    hostname: String,
+    addr: SocketAddr,
+}
+
+impl SniOverride {
+    /// If `url` is https to a bare IP, rewrites it to use `hostname` and returns
+    /// the rewritten url along with the override pinning `hostname` to that IP.
+    ///
+    /// Plain http has no SNI, its urls are left as they are.
+    fn for_url(url: &str, hostname: &str) -> Option<(String, Self)> {
+        let mut parsed = reqwest::Url::parse(url).ok()?;
+        if parsed.scheme() != "https" {
+            return None;
+        }
+        let ip: IpAddr = parsed
+            .host_str()?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()?;
+        let port = parsed.port_or_known_default()?;
+        let hostname = hostname.trim_end_matches('.');
+        parsed.set_host(Some(hostname)).ok()?;
+        Some((
+            parsed.as_str().trim_end_matches('/').to_string(),
+            Self {
+                hostname: hostname.to_string(),
+                addr: SocketAddr::new(ip, port),
+            },
+        ))
+    }
+}
+
+fn build_http_client(sni: Option<&SniOverride>, timeout: Option<Duration>) -> Client {
+    http_client_builder(sni, timeout)
+        .build()
+        .unwrap_or_else(|_| Client::new())
+}
+
+fn http_client_builder(
+    sni: Option<&SniOverride>,
+    timeout: Option<Duration>,
+) -> reqwest::ClientBuilder {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        CLIENT_VERSION_HEADER,
+        reqwest::header::HeaderValue::from_static(crate::VERDANT_CLIENT_VERSION),
+    );
+    let mut builder = Client::builder()
+        .default_headers(headers)
+        .dns_resolver(std::sync::Arc::new(SystemResolver));
+    if let Some(sni) = sni {
+        builder = builder.resolve(&sni.hostname, sni.addr);
+    }
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder
+}
+
+/// Resolves hostnames with the system resolver like reqwest's default one, failed lookups
+/// are reported as [`DnsError`](crate::errors::DnsError).
+struct SystemResolver;
+
+impl reqwest::dns::Resolve for SystemResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        use std::net::ToSocketAddrs;
+
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs())
+                .await?
+                .map_err(crate::errors::DnsError)?;
+            Ok(Box::new(addrs) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Builder for [`APIClient`] when the defaults of [`APIClient::new`] aren't enough.
+///
+/// Start from [`APIClientBuilder::new`] with a ready [`DecodingKey`], or from
+/// [`APIClientBuilder::default`] and set the url and key with the fluent methods:
+///
+/// ```ignore
+/// let client = APIClientBuilder::default()
+///     .url("https://verdant.local")
+///     .rsa_pem(pem)
+///     .validate_exp(true)
+///     .build()?;
+/// ```
+pub struct APIClientBuilder {
+    url: String,
+    decoder: Option<DecodingKey>,
+    /// algorithms used when none were configured, matching the key's type.
+    key_algorithms: Vec<Algorithm>,
+    /// first error from parsing a key, reported by [`APIClientBuilder::build`].
+    key_error: Option<Error>,
+    validation: Validation,
+    request_signing: bool,
+    sni_hostname: Option<String>,
+    fallback_urls: Vec<String>,
+    timeout: Option<Duration>,
+    skip_compatibility_check: bool,
+    client: Option<Client>,
+    retry_policy: Option<RetryPolicy>,
+}
+
+impl APIClientBuilder {
+    pub fn new(url: impl Into<String>, decoder: DecodingKey, validation: Validation) -> Self {
+        Self {
+            url: url.into(),
+            decoder: Some(decoder),
+            key_algorithms: Vec::new(),
+            key_error: None,
+            validation,
+            request_signing: false,
+            sni_hostname: None,
+            fallback_urls: Vec::new(),
+            timeout: None,
+            skip_compatibility_check: false,
+            client: None,
+            retry_policy: None,
+        }
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// verify tokens with a PEM encoded RSA public key, using RS256/384/512 unless
+    /// other algorithms are added.
+    pub fn rsa_pem(self, pem: &str) -> Self {
+        let key = DecodingKey::from_rsa_pem(pem.as_bytes());
+        self.key(key, KeyType::Rsa.algorithms())
+    }
+
+    /// verify tokens with a PEM encoded EC public key, using ES256/384 unless
+    /// other algorithms are added.
+    pub fn ec_pem(self, pem: &str) -> Self {
+        let key = DecodingKey::from_ec_pem(pem.as_bytes());
+        self.key(key, KeyType::Ec.algorithms())
+    }
+
+    /// verify tokens with a DER encoded Ed25519 public key, using EdDSA unless
+    /// other algorithms are added.
+    pub fn ed25519_der(self, der: &[u8]) -> Self {
+        self.key(
+            Ok(DecodingKey::from_ed_der(der)),
+            KeyType::Ed25519.algorithms(),
+        )
+    }
+
+    fn key(
+        mut self,
+        key: Result<DecodingKey, jsonwebtoken::errors::Error>,
+        algorithms: Vec<Algorithm>,
+    ) -> Self {
+        match key {
+            Ok(key) => {
+                self.decoder = Some(key);
+                self.key_algorithms = algorithms;
+            }
+            Err(e) => {
+                self.key_error.get_or_insert(e.into());
+            }
+        }
+        self
+    }
+
+    /// accept tokens signed with `algorithm`. Once an algorithm is added, only
+    /// added algorithms are accepted.
+    pub fn add_algorithm(mut self, algorithm: Algorithm) -> Self {
+        if !self.validation.algorithms.contains(&algorithm) {
+            self.validation.algorithms.push(algorithm);
+        }
+        self
+    }
+
+    /// reject expired tokens, enabled by default.
+    pub fn validate_exp(mut self, validate: bool) -> Self {
+        self.validation.validate_exp = validate;
+        self
+    }
+
+    /// hostname to use for TLS SNI when `url` points at a bare IP address,
+    /// e.g. an mDNS discovered server whose certificate is issued for its hostname.
+    pub fn sni_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.sni_hostname = Some(hostname.into());
+        self
+    }
+
+    /// another address of the same server, tried in order when logging in to `url`
+    /// fails with a 5xx error.
+    pub fn fallback_url(mut self, url: impl Into<String>) -> Self {
+        self.fallback_urls.push(url.into());
+        self
+    }
+
+    /// give up on requests that take longer than `timeout`, failing them with [`Error::Timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// don't ask the server for its minimum client version before logging in,
+    /// see [`APIClient::check_compatibility`].
+    pub fn skip_compatibility_check(mut self, skip: bool) -> Self {
+        self.skip_compatibility_check = skip;
+        self
+    }
+
+    /// retry idempotent requests after transient failures, off by default.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// send requests with `client` instead of building one, e.g. to share a connection
+    /// pool. The client is used as is, so [`APIClientBuilder::timeout`] and
+    /// [`APIClientBuilder::sni_hostname`] have to be configured on it by the caller.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// sign every authenticated request with the session key, see [`APIClient::sign_request`].
+    pub fn with_request_signing(mut self, enabled: bool) -> Self {
+        self.request_signing = enabled;
+        self
+    }
+
+    /// Fails if a key couldn't be parsed or no key was set.
+    pub fn build(mut self) -> Result<APIClient, Error> {
+        if let Some(e) = self.key_error {
+            return Err(e);
+        }
+        let decoder = self
+            .decoder
+            .ok_or_else(|| Error::Internal("no decoding key set".to_string()))?;
+        if self.validation.algorithms.is_empty() {
+            self.validation.algorithms = self.key_algorithms;
+        }
+        let (url, sni) = match self
+            .sni_hostname
+            .as_deref()
+            .and_then(|hostname| SniOverride::for_url(&self.url, hostname))
+        {
+            Some((url, sni)) => (url, Some(sni)),
+            None => (self.url, None),
+        };
+        let client = self
+            .client
+            .unwrap_or_else(|| build_http_client(sni.as_ref(), self.timeout));
+        Ok(APIClient {
+            url,
+            decoder,
+            validation: self.validation,
+            access_token: None,
+            claims: None,
+            session_key: None,
+            request_signing: self.request_signing,
+            credential_cache: None,
+            fallback_urls: self.fallback_urls,
+            timeout: self.timeout,
+            skip_compatibility_check: self.skip_compatibility_check,
+            compatibility_checked: false,
+            livekit_token: None,
+            client,
+            retry_policy: self.retry_policy,
+            server_info: None,
+        })
+    }
+}
+
+impl Default for APIClientBuilder {
+    /// no url or key, and no algorithms until a key or algorithm is set.
+    fn default() -> Self {
+        let mut validation = Validation::default();
+        validation.algorithms.clear();
+        Self {
+            url: String::new(),
+            decoder: None,
+            key_algorithms: Vec::new(),
+            key_error: None,
+            validation,
+            request_signing: false,
+            sni_hostname: None,
+            fallback_urls: Vec::new(),
+            timeout: None,
+            skip_compatibility_check: false,
+            client: None,
+            retry_policy: None,
+        }
+    }
+}
+
+/// Computes the request signature sent in [`REQUEST_SIGNATURE_HEADER`].
+///
+/// The signature is the hex encoded
+/// `HMAC-SHA256(session_key, method || url || sha256(body) || timestamp)`
+/// where `timestamp` is the decimal unix timestamp sent in [`REQUEST_TIMESTAMP_HEADER`].
+/// Servers can recompute this to validate requests beyond the JWT.
+pub fn request_signature(
+    session_key: &[u8],
+    method: &str,
+    url: &str,
+    body: &[u8],
+    timestamp: u64,
+) -> String {
+    let body_hash = Sha256::digest(body);
+    let mut mac = Hmac::<Sha256>::new_from_slice(session_key).expect("hmac key");
+    mac.update(method.as_bytes());
+    mac.update(url.as_bytes());
+    mac.update(&body_hash);
+    mac.update(timestamp.to_string().as_bytes());
+    crate::crypto::hex_encode(&mac.finalize().into_bytes())
+}
+
+/// Writes `data` to `path`, readable and writable by the owner only.
+fn write_private_file(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        // the mode only applies to new files
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(data)
+    }
+    #[cfg(not(unix))]
+    options.open(path)?.write_all(data)
+}
+
+/// Largest body [`decode_json_body`] inflates a gzip response to.
+const MAX_BODY: u64 = 4 * 1024 * 1024;
+
+/// Parses a JSON response body, transparently inflating it if it is still gzip
+/// compressed (e.g. served without a `Content-Encoding` header).
+fn decode_json_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut inflated = Vec::new();
+        // one byte past the limit tells a body of exactly MAX_BODY from a larger one
+        flate2::read::GzDecoder::new(body)
+            .take(MAX_BODY + 1)
+            .read_to_end(&mut inflated)?;
+        if inflated.len() as u64 > MAX_BODY {
+            return Err(Error::Internal(format!(
+                "inflated response body exceeds {MAX_BODY} bytes"
+            )));
+        }
+        return Ok(serde_json::from_slice(&inflated)?);
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Body of a `/auth/api/refresh` response.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RefreshResponse {
+    Result(LoginResult),
+    Token { token: String },
+}
+
+/// Claims carried in access tokens issued by verdant servers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct VerdantClaims {
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub exp: Option<u64>,
+    #[serde(default)]
+    pub iat: Option<u64>,
+    /// name to greet the user with, e.g. "Alice".
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+}
+
+/// Reads the claims of a JWT without verifying its signature.
+pub(crate) fn unverified_claims(token: &str) -> Option<VerdantClaims> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()?;
+    serde_json::from_slice(&bytes).ok()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -34,22 +517,115 @@ pub enum KeyType {
     Ed448,
 }
 
+impl KeyType {
+    /// rsaEncryption
+    pub const RSA_OID: &'static str = "1.2.840.113549.1.1.1";
+    pub const ED25519_OID: &'static str = "1.3.101.112";
+    pub const ED448_OID: &'static str = "1.3.101.113";
+    /// generic ecPublicKey (secp256r1, secp384r1, secp521r1, etc.)
+    pub const EC_OID: &'static str = "1.2.840.10045.2.1";
+
+    pub fn from_oid(oid: &str) -> Self {
+        match oid {
+            Self::RSA_OID => KeyType::Rsa,
+            Self::ED25519_OID => KeyType::Ed25519,
+            Self::ED448_OID => KeyType::Ed448,
+            Self::EC_OID => KeyType::Ec,
+            _ => KeyType::Unknown(oid.to_string()),
+        }
+    }
+
+    /// OID of the key types a [`DecodingKey`] can be built from, `None` for the others.
+    pub fn to_oid(&self) -> Option<&'static str> {
+        match self {
+            KeyType::Rsa => Some(Self::RSA_OID),
+            KeyType::Ec => Some(Self::EC_OID),
+            KeyType::Ed25519 => Some(Self::ED25519_OID),
+            KeyType::Unknown(_) | KeyType::Ed448 => None,
+        }
+    }
+
+    /// JWT algorithms tokens verified with a key of this type may be signed with.
+    pub fn algorithms(&self) -> Vec<Algorithm> {
+        match self {
+            KeyType::Rsa => vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512],
+            KeyType::Ec => vec![Algorithm::ES256, Algorithm::ES384],
+            KeyType::Ed25519 => vec![Algorithm::EdDSA],
+            KeyType::Unknown(_) | KeyType::Ed448 => Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyType::Rsa => write!(f, "RSA"),
+            KeyType::Ec => write!(f, "EC"),
+            KeyType::Ed25519 => write!(f, "Ed25519"),
+            KeyType::Ed448 => write!(f, "Ed448"),
+            KeyType::Unknown(oid) => write!(f, "unknown key type ({})", oid),
+        }
+    }
+}
+
+/// Compares [`crate::VERDANT_CLIENT_VERSION`] against the server's minimum.
+fn check_client_version(min_client_version: &str) -> Result<(), Error> {
+    let min = semver::Version::parse(min_client_version).map_err(|e| {
+        Error::Internal(format!(
+            "invalid min_client_version {}: {}",
+            min_client_version, e
+        ))
+    })?;
+    let current =
+        semver::Version::parse(crate::VERDANT_CLIENT_VERSION).expect("valid crate version");
+    if current < min {
+        return Err(Error::Internal(format!(
+            "client too old: {} is older than the required {}",
+            current, min
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the key type from the algorithm of a DER encoded SubjectPublicKeyInfo.
 fn detect_key_type(der: &[u8]) -> Result<KeyType, Error> {
-    let id: spki::AlgorithmIdentifier<()> = spki::AlgorithmIdentifier::from_der(der)?;
-    Ok(match id.oid.to_string().as_str() {
-        // RSA (rsaEncryption)
-        "1.2.840.113549.1.1.1" => KeyType::Rsa,
+    let info = spki::SubjectPublicKeyInfoRef::from_der(der)?;
+    Ok(KeyType::from_oid(&info.algorithm.oid.to_string()))
+}
 
-        // Ed25519 / Ed448
-        "1.3.101.112" => KeyType::Ed25519,
-        "1.3.101.113" => KeyType::Ed448,
+/// Capabilities a server advertises at `/.well-known/verdant`, see [`APIClient::get_server_info`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct ServerInfo {
+    /// version of the server software.
+    pub version: String,
+    /// JWT algorithms access tokens may be signed with, e.g. "RS256".
+    #[serde(default)]
+    pub supported_algorithms: Vec<String>,
+    /// registering needs an invite from an existing user.
+    #[serde(default)]
+    pub require_registration_invite: bool,
+    /// optional features the server supports, e.g. "p2p".
+    #[serde(default)]
+    pub features: Vec<String>,
+}
 
-        // EC public keys (secp256r1, secp384r1, secp521r1, etc.)
-        "1.2.840.10045.2.1" => KeyType::Ec, // generic ecPublicKey
+impl ServerInfo {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
 
-        // Fallback
-        _ => KeyType::Unknown(id.oid.to_string()),
-    })
+/// Answer of a server's `/health` endpoint, see [`APIClient::health_check`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// e.g. "ok".
+    pub status: String,
+    /// version of the server software.
+    pub version: String,
+    pub uptime_secs: u64,
+    /// number of logged in users, if the server shares it.
+    #[serde(default)]
+    pub authenticated_users: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,75 +637,402 @@ pub struct PubKeyResponse {
 
 impl PubKeyResponse {
     pub fn decode_pubkey(&self) -> Result<DecodingKey, crate::errors::Error> {
-        let resp = base64::decode(&self.pubkey)?;
+        let resp = STANDARD.decode(&self.pubkey)?;
+        // servers may send the bare key (PKCS#1, SEC1 point, raw Ed25519 bytes) or wrap it
+        // in a SubjectPublicKeyInfo, jsonwebtoken only verifies with the bare key.
+        let info = spki::SubjectPublicKeyInfoRef::from_der(&resp);
+        let key = match &info {
+            Ok(info) => info.subject_public_key.raw_bytes(),
+            Err(_) => &resp,
+        };
         Ok(match &self.key_type {
-            KeyType::Rsa => DecodingKey::from_rsa_der(&resp),
-            KeyType::Ec => DecodingKey::from_ec_der(&resp),
-            KeyType::Ed25519 => DecodingKey::from_ed_der(&resp),
+            KeyType::Rsa => DecodingKey::from_rsa_der(key),
+            KeyType::Ec => DecodingKey::from_ec_der(key),
+            KeyType::Ed25519 => DecodingKey::from_ed_der(key),
             KeyType::Unknown(u) => return Err(Error::UnknownKeyType(u.to_string())),
             KeyType::Ed448 => return Err(Error::UnknownKeyType("Ed448".to_string())),
         })
     }
 
     pub fn encode_pubkey(key_type: KeyType, der: &[u8]) -> Self {
-        let pubkey = base64::encode(der);
+        let pubkey = STANDARD.encode(der);
         Self { key_type, pubkey }
     }
+
+    /// Like [`PubKeyResponse::encode_pubkey`], reading the key type from the
+    /// DER encoded SubjectPublicKeyInfo.
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        Ok(Self::encode_pubkey(detect_key_type(der)?, der))
+    }
+
+    /// Like [`PubKeyResponse::from_der`] for a PEM encoded (`-----BEGIN PUBLIC KEY-----`) key.
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let body: String = pem
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        Self::from_der(&STANDARD.decode(body)?)
+    }
+
+    /// The DER encoded public key.
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(STANDARD.decode(&self.pubkey)?)
+    }
+
+    /// Base64 encoded SHA-256 of the DER encoded public key, as advertised in a
+    /// discovery's `pubkey_hash`.
+    pub fn key_hash(&self) -> Result<String, Error> {
+        Ok(STANDARD.encode(Sha256::digest(self.to_der()?)))
+    }
+
+    /// Fails with [`Error::KeyHashMismatch`] unless the key hashes to `expected`.
+    ///
+    /// `expected` may be base64 or, for servers that advertise it that way, hex.
+    pub fn verify_hash(&self, expected: &str) -> Result<(), Error> {
+        let is_hex = expected.len() == 64 && expected.bytes().all(|b| b.is_ascii_hexdigit());
+        let (actual, matches) = if is_hex {
+            let actual = crate::crypto::sha256_hex(&self.to_der()?);
+            let matches = actual.eq_ignore_ascii_case(expected);
+            (actual, matches)
+        } else {
+            let actual = self.key_hash()?;
+            let matches = actual == expected;
+            (actual, matches)
+        };
+        if !matches {
+            return Err(Error::KeyHashMismatch(actual, expected.to_string()));
+        }
+        Ok(())
+    }
 }
 
 impl APIClient {
     pub async fn from_discovery(discovery: Discovery) -> Result<Self, crate::errors::Error> {
         // steps: create request client to grab the decoding key
         // verify the hash of the decoding key matches the public key hash in the discovery.
-        let url = match discovery.urls().get(0) {
-            Some(addr) => addr.to_string(),
-            None => return Err(Error::MissingIpAddr),
-        };
+        let url = discovery.primary_url()?;
 
-        Self::from_url(url).await
+        // the beacon's host is what the server's certificate is issued for
+        let sni_hostname = Some(discovery.host.clone()).filter(|host| !host.is_empty());
+        let mut client =
+            Self::from_url_checked(url, sni_hostname, Some(&discovery.pubkey_hash.hash)).await?;
+        // the remaining advertised addresses serve as fallbacks
+        client.fallback_urls = discovery
+            .urls()
+            .iter()
+            .skip(1)
+            .map(|addr| addr.to_string())
+            .collect();
+        // servers predating the manifest don't serve it
+        match client.get_server_info().await {
+            Ok(info) => client.server_info = Some(info),
+            Err(e) => debug!(url = %client.url, error = %e, "no server info"),
+        }
+        Ok(client)
     }
     pub async fn from_url(url: impl Into<String>) -> Result<Self, crate::errors::Error> {
+        Self::from_url_with_sni(url, None).await
+    }
+
+    /// Like [`APIClient::from_url`], presenting `sni_hostname` for TLS when `url` is an IP address.
+    pub async fn from_url_with_sni(
+        url: impl Into<String>,
+        sni_hostname: Option<String>,
+    ) -> Result<Self, crate::errors::Error> {
+        Self::from_url_checked(url, sni_hostname, None).await
+    }
+
+    /// Fetches the server's key, rejecting it unless it hashes to `expected_key_hash` when given.
+    async fn from_url_checked(
+        url: impl Into<String>,
+        sni_hostname: Option<String>,
+        expected_key_hash: Option<&str>,
+    ) -> Result<Self, crate::errors::Error> {
         let url = url.into();
-        let client = Client::new();
-        let key_url = format!("{}/pubkey", url);
+        let (base_url, sni) = match sni_hostname
+            .as_deref()
+            .and_then(|hostname| SniOverride::for_url(&url, hostname))
+        {
+            Some((base_url, sni)) => (base_url, Some(sni)),
+            None => (url.clone(), None),
+        };
+        let client = build_http_client(sni.as_ref(), None);
+        let key_url = format!("{}/pubkey", base_url.trim_end_matches('/'));
         let jsonresp = client.get(&key_url).send().await?.bytes().await?;
-        let response: PubKeyResponse = serde_json::from_slice(&jsonresp)?;
-        // Compute hash of the key
-        let hasher = Sha256::new();
-        //hasher.update(&resp);
-        let result = hasher.finalize();
-        let key_hash_base64 = base64::encode(result);
-
-        // Compare with expected hash
-        // not enabling for now, but will re-enable
-        /*if key_hash_base64 != discovery.pubkey_hash.hash {
-            return Err(Error::KeyHashMismatch(
-                key_hash_base64,
-                discovery.pubkey_hash.hash,
-            ));
-        }*/
+        let response: PubKeyResponse = decode_json_body(&jsonresp)?;
+        if let Some(expected) = expected_key_hash {
+            response.verify_hash(expected)?;
+        }
 
         let key = response.decode_pubkey()?;
-        let mut validation = Validation::default();
-        validation.algorithms = vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512];
 
-        Ok(Self {
-            url,
-            decoder: key,
-            access_token: None,
-            validation,
-        })
+        // keep the connection used to fetch the key
+        let mut builder = APIClientBuilder::default()
+            .url(url)
+            .key(Ok(key), response.key_type.algorithms())
+            .with_client(client);
+        if let Some(hostname) = sni_hostname {
+            builder = builder.sni_hostname(hostname);
+        }
+        builder.build()
     }
     /// Create a new API client pointing at `url`.
     pub fn new(url: impl Into<String>, decoder: DecodingKey, validation: Validation) -> Self {
-        Self {
-            url: url.into(),
-            decoder,
+        APIClientBuilder::new(url, decoder, validation)
+            .build()
+            .expect("APIClientBuilder::new always sets a key")
+    }
+
+    pub fn builder(
+        url: impl Into<String>,
+        decoder: DecodingKey,
+        validation: Validation,
+    ) -> APIClientBuilder {
+        APIClientBuilder::new(url, decoder, validation)
+    }
+
+    /// Unix timestamp at which the current access token expires, read from its `exp` claim.
+    ///
+    /// The token isn't verified here, this is only used to schedule refreshes.
+    pub fn token_expires_at(&self) -> Option<u64> {
+        self.access_token
+            .as_deref()
+            .and_then(unverified_claims)
+            .and_then(|claims| claims.exp)
+    }
+
+    /// Claims of the current access token.
+    pub fn claims(&self) -> Option<&VerdantClaims> {
+        self.claims.as_ref()
+    }
+
+    /// Display name from the current access token, if the server included one.
+    pub fn display_name(&self) -> Option<&str> {
+        self.claims
+            .as_ref()
+            .and_then(|claims| claims.display_name.as_deref())
+    }
+
+    /// Replaces the access token along with the claims parsed from it.
+    pub fn set_access_token(&mut self, token: Option<String>) {
+        self.claims = token.as_deref().and_then(unverified_claims);
+        self.access_token = token;
+    }
+
+    /// Exchanges the current access token for a fresh one at `/auth/api/refresh`.
+    ///
+    /// The server answers with a [`LoginResult`] or a plain `{ "token": "..." }` body.
+    /// On success the new token replaces `access_token` and is returned.
+    pub async fn refresh_token(&mut self) -> Result<String, crate::errors::Error> {
+        let token = self
+            .access_token
+            .as_ref()
+            .ok_or_else(|| crate::errors::Error::Unauthorized)?;
+
+        let url = format!("{}/auth/api/refresh", self.url.trim_end_matches('/'));
+        let client = self.http_client();
+        let body = self
+            .sign_request(client.post(&url).bearer_auth(token))
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let result = match decode_json_body(&body)? {
+            RefreshResponse::Result(result) => result,
+            RefreshResponse::Token { token } => LoginResult::Success(token),
+        };
+        match result {
+            LoginResult::Success(token) => {
+                let (newtoken, _) = self.validate_token(&token, &self.decoder)?;
+                self.set_access_token(Some(newtoken.clone()));
+                Ok(newtoken)
+            }
+            LoginResult::Unauthorized(reason) => {
+                Err(crate::errors::Error::LoginUnauthorized(reason))
+            }
+            _ => Err(crate::errors::Error::Unauthorized),
+        }
+    }
+
+    /// Ends the session on the server via `/auth/api/logout` and clears local credentials.
+    ///
+    /// Does nothing if not logged in. Local credentials are cleared even if the
+    /// server can't be reached, in which case the request error is returned.
+    pub async fn logout(&mut self) -> Result<(), crate::errors::Error> {
+        let token = match &self.access_token {
+            Some(token) => token.clone(),
+            None => return Ok(()),
+        };
+
+        let url = format!("{}/auth/api/logout", self.url.trim_end_matches('/'));
+        let client = self.http_client();
+        let result = self
+            .sign_request(client.post(&url).bearer_auth(token))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        self.logout_local();
+        result.map_err(|e| self.http_error(e))?;
+        Ok(())
+    }
+
+    /// Clears the access token and session key without contacting the server,
+    /// e.g. when it can't be reached at shutdown.
+    pub fn logout_local(&mut self) {
+        self.set_access_token(None);
+        self.session_key = None;
+        self.livekit_token = None;
+    }
+
+    /// Sends an idempotent `request`, retrying transient failures according to the
+    /// [`RetryPolicy`]. Requests with a streaming body can't be retried and are sent once.
+    async fn send_idempotent(&self, request: RequestBuilder) -> Result<reqwest::Response, Error> {
+        let policy = match &self.retry_policy {
+            Some(policy) => policy,
+            None => return request.send().await.map_err(|e| self.http_error(e)),
+        };
+        let mut retry = 0;
+        loop {
+            let attempt = match request.try_clone() {
+                Some(attempt) if retry < policy.max_retries => attempt,
+                _ => return request.send().await.map_err(|e| self.http_error(e)),
+            };
+            match attempt.send().await {
+                Ok(resp) if !policy.retries_status(resp.status()) => return Ok(resp),
+                Ok(resp) => {
+                    debug!(status = %resp.status(), retry, "transient server error, retrying");
+                }
+                Err(e) => {
+                    let e = self.http_error(e);
+                    if !e.is_retryable() {
+                        return Err(e);
+                    }
+                    debug!(error = %e, retry, "transient request error, retrying");
+                }
+            }
+            tokio::time::sleep(policy.delay(retry)).await;
+            retry += 1;
+        }
+    }
+
+    /// reqwest client honouring the configured SNI override, clones share its connection pool.
+    fn http_client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Converts a failed request into [`Error::Timeout`] when it ran into the configured timeout.
+    fn http_error(&self, e: reqwest::Error) -> Error {
+        match self.timeout {
+            Some(timeout) if e.is_timeout() => Error::Timeout(timeout),
+            _ => Error::from_reqwest_error(e),
+        }
+    }
+
+    /// other addresses of this server, see [`APIClientBuilder::fallback_url`].
+    pub fn fallback_urls(&self) -> &[String] {
+        &self.fallback_urls
+    }
+
+    /// Caches the password-encrypted credentials at `path` after every successful login,
+    /// so [`APIClient::cached_credentials`] can check the password while offline.
+    pub fn enable_credential_cache(mut self, path: PathBuf) -> Self {
+        self.credential_cache = Some(path);
+        self
+    }
+
+    /// Loads the credentials cached by an earlier login, e.g. after a restart without
+    /// connectivity. `Ok(None)` if caching is disabled or nothing was cached yet,
+    /// [`crate::errors::Error::AesGcmError`] if `password` is wrong.
+    pub fn cached_credentials(
+        &self,
+        password: &str,
+    ) -> Result<Option<client_auth::ClientCredentialCache>, Error> {
+        let Some(path) = &self.credential_cache else {
+            return Ok(None);
+        };
+        match std::fs::read(path) {
+            Ok(data) => client_auth::Client::load_credentials(password, &data).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Moves the login of `previous`, a client of the same server at its old address,
+    /// over to this client.
+    pub(crate) fn take_session(&mut self, previous: APIClient) {
+        self.access_token = previous.access_token;
+        self.claims = previous.claims;
+        self.session_key = previous.session_key;
+        self.request_signing = previous.request_signing;
+        self.credential_cache = previous.credential_cache;
+    }
+
+    /// A client for the same server sharing the connection pool, timeout and retry policy
+    /// but none of the session, for requests that don't need a login, e.g. health checks
+    /// running on their own task.
+    pub(crate) fn without_session(&self) -> APIClient {
+        APIClient {
+            url: self.url.clone(),
+            decoder: self.decoder.clone(),
+            validation: self.validation.clone(),
             access_token: None,
-            validation,
+            claims: None,
+            session_key: None,
+            request_signing: false,
+            credential_cache: None,
+            fallback_urls: self.fallback_urls.clone(),
+            timeout: self.timeout,
+            skip_compatibility_check: self.skip_compatibility_check,
+            compatibility_checked: self.compatibility_checked,
+            livekit_token: None,
+            client: self.client.clone(),
+            retry_policy: self.retry_policy,
+            server_info: self.server_info.clone(),
         }
     }
 
+    /// The session key derived during the last successful login, if any.
+    pub fn session_key(&self) -> Option<&[u8]> {
+        self.session_key.as_ref().map(|key| key.as_slice())
+    }
+
+    /// Adds [`REQUEST_SIGNATURE_HEADER`] and [`REQUEST_TIMESTAMP_HEADER`] to `builder`.
+    ///
+    /// The request is returned unchanged if signing is disabled, no session key
+    /// has been established yet, or the request body can't be inspected (e.g. streams).
+    /// See [`request_signature`] for how the signature is computed.
+    pub fn sign_request(&self, builder: RequestBuilder) -> RequestBuilder {
+        let session_key = match (&self.session_key, self.request_signing) {
+            (Some(key), true) => key,
+            _ => return builder,
+        };
+        let request = match builder.try_clone().map(|b| b.build()) {
+            Some(Ok(request)) => request,
+            _ => return builder,
+        };
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let signature = request_signature(
+            session_key,
+            request.method().as_str(),
+            request.url().as_str(),
+            body,
+            timestamp,
+        );
+        builder
+            .header(REQUEST_SIGNATURE_HEADER, signature)
+            .header(REQUEST_TIMESTAMP_HEADER, timestamp.to_string())
+    }
+
     /// Send a login request using a username and password.
     ///
     /// This function:
@@ -151,6 +1054,11 @@ impl APIClient {
         let username = username.into();
         let password = password.into();
 
+        if !self.skip_compatibility_check && !self.compatibility_checked {
+            self.check_compatibility().await?;
+            self.compatibility_checked = true;
+        }
+
         // Build the OPAQUE client helper from the plaintext password.
         let opaque_client = client_auth::Client::new(password);
 
@@ -165,14 +1073,12 @@ impl APIClient {
         // (assumes client_auth::LoginRequest has fields `username` and `credential_request`).
         let login_request = client_auth::LoginRequest::new(&username, credential_request);
 
-        let client = reqwest::Client::new();
+        let client = self.http_client();
         let endpoint = format!("{}/auth/api/login/", self.url.trim_end_matches('/'));
 
         // Send initial login request
-        let initial_resp: LoginResponse = client
-            .post(&endpoint)
-            .json(&login_request)
-            .send()
+        let initial_resp: LoginResponse = self
+            .send_idempotent(client.post(&endpoint).json(&login_request))
             .await?
             .error_for_status()?
             .json::<LoginResponse>()
@@ -195,15 +1101,18 @@ impl APIClient {
         match &initial_resp {
             LoginResponse::OTP(_) => Ok(LoginResult::PasswordReset),
             LoginResponse::PAKE((id, cred_response)) => {
-                match opaque_client.finish_login(client_login, cred_response.clone()) {
-                    Ok((key, finalize)) => {
+                match opaque_client.finish_login_with_keys(client_login, cred_response.clone()) {
+                    Ok((keys, finalize)) => {
+                        let key = keys.session_key;
                         let upload = LoginUpload::new(
-                            id.clone(),
+                            *id,
                             finalize,
                             &key,
                             &login_request,
                             &initial_resp,
-                        );
+                            Transcript::MAX_SIZE,
+                            None,
+                        )?;
                         let finalize_endpoint =
                             format!("{}/auth/api/login/finalize", self.url.trim_end_matches('/'));
 
@@ -211,18 +1120,37 @@ impl APIClient {
                             .post(&finalize_endpoint)
                             .json(&upload)
                             .send()
-                            .await?
+                            .await
+                            .map_err(|e| self.http_error(e))?
                             .error_for_status()?
                             .json::<LoginCompletion>()
                             .await?;
-                        if !final_resp.verify(&key, &login_request, &initial_resp) {
-                            panic!("failed to verify server authenticity");
+                        // rejections are unsigned (see `LoginCompletion::unauthorized`),
+                        // trusting one can't log anybody in
+                        if let LoginResult::Unauthorized(reason) = final_resp.result {
+                            return Ok(LoginResult::Unauthorized(reason));
+                        }
+                        // a completion for another transcript nonce is a replay
+                        if final_resp.nonce() != upload.nonce()
+                            || !final_resp.verify(&key, &login_request, &initial_resp)
+                        {
+                            return Err(crate::errors::Error::ServerAuthentication);
                         }
                         match final_resp.result {
                             LoginResult::Success(token) => {
-                                // token validation must be failing hmm
-                                let newtoken = self.validate_token(&token, &self.decoder)?;
-                                self.access_token = Some(newtoken.clone());
+                                let (newtoken, _) = self.validate_token(&token, &self.decoder)?;
+                                self.set_access_token(Some(newtoken.clone()));
+                                self.session_key = Some(key);
+                                if let Some(path) = &self.credential_cache {
+                                    let exported = opaque_client
+                                        .with_export_key(keys.export_key.to_vec())
+                                        .export_credentials();
+                                    if let Err(e) = exported
+                                        .and_then(|data| Ok(write_private_file(path, &data)?))
+                                    {
+                                        warn!(path = %path.display(), error = %e, "failed to cache credentials");
+                                    }
+                                }
                                 Ok(LoginResult::Success(newtoken))
                             }
                             _ => Ok(final_resp.result),
@@ -231,68 +1159,1535 @@ impl APIClient {
                     Err(e) => Err(crate::errors::Error::Opaque(e)),
                 }
             }
-            _ => Ok(LoginResult::Unauthorized),
+            _ => Ok(LoginResult::Unauthorized(
+                crate::auth::UnauthorizedReason::InvalidCredentials,
+            )),
         }
     }
 
+    /// Verifies `token`'s signature with `decoder` and its claims against the client's
+    /// [`Validation`] (by default requiring an `exp` in the future), returning the token
+    /// along with its claims.
     pub fn validate_token(
         &self,
         token: &str,
         decoder: &DecodingKey,
-    ) -> Result<String, crate::errors::Error> {
-        /*// local imports to avoid changing top-level use list
-        // base64 crate for portable encoding/decoding
-        // expects token_enc to be base64(nonce || ciphertext || tag)
-        let raw = base64::decode(token_enc)?;
-
-        if session_key.len() != 32 {
-            return Err(crate::errors::Error::IOError(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "session key must be 32 bytes for AES-256-GCM",
-            )));
-        }
-
-        if raw.len() < 12 {
-            return Err(crate::errors::Error::IOError(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "encrypted token too short (expect nonce + ciphertext)",
-            )));
-        }
-
-        let key = GenericArray::from_slice(session_key);
-        let cipher = Aes256Gcm::new(key);
-
-        let (nonce_bytes, ciphertext) = raw.split_at(12);
-        let nonce = GenericArray::from_slice(nonce_bytes);
-
-        let plaintext = cipher.decrypt(nonce, ciphertext.as_ref())?;
-        */
-        let jwt_str = token;
-
-        //jsonwebtoken::decode::<Value>(&jwt_str, decoder, &self.validation)?;
-
-        Ok(jwt_str.to_string())
+    ) -> Result<(String, VerdantClaims), crate::errors::Error> {
+        let data = jsonwebtoken::decode::<VerdantClaims>(token, decoder, &self.validation)?;
+        Ok((token.to_string(), data.claims))
     }
 
-    /// Fetches a LiveKit token from the server's `/rpc/token` endpoint.
+    /// Offers a direct peer-to-peer connection to `peer_username` via `/rpc/p2p/offer`.
     ///
-    /// Requires that the `APIClient` has a valid `access_token` already set.
-    /// Uses the token as a Bearer auth header in the request.
-    pub async fn get_livekit_token(
+    /// Returns the answer token to poll with [`APIClient::poll_direct_connection_answer`].
+    pub async fn offer_direct_connection(
         &self,
-    ) -> Result<crate::livekit::TokenResponse, crate::errors::Error> {
+        peer_username: impl Into<String>,
+        offer_sdp: impl Into<String>,
+    ) -> Result<String, crate::errors::Error> {
         let token = self
             .access_token
             .as_ref()
             .ok_or_else(|| crate::errors::Error::Unauthorized)?;
 
-        let url = format!("{}/rpc/token", self.url.trim_end_matches('/'));
+        let url = format!("{}/rpc/p2p/offer", self.url.trim_end_matches('/'));
+        let offer = DirectConnectionOffer {
+            peer_username: peer_username.into(),
+            offer_sdp: offer_sdp.into(),
+        };
+
+        let client = self.http_client();
+        let resp: DirectConnectionOfferResponse = self
+            .sign_request(client.post(&url).bearer_auth(token).json(&offer))
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.answer_token)
+    }
+
+    /// Checks `/rpc/p2p/answer/{answer_token}` once.
+    ///
+    /// Returns `None` while the peer hasn't answered yet (`204 No Content`).
+    pub async fn poll_direct_connection_answer(
+        &self,
+        answer_token: &str,
+    ) -> Result<Option<DirectConnectionAnswer>, crate::errors::Error> {
+        let token = self
+            .access_token
+            .as_ref()
+            .ok_or_else(|| crate::errors::Error::Unauthorized)?;
+
+        let url = format!(
+            "{}/rpc/p2p/answer/{}",
+            self.url.trim_end_matches('/'),
+            answer_token
+        );
+
+        let client = self.http_client();
+        let resp = self
+            .send_idempotent(self.sign_request(client.get(&url).bearer_auth(token)))
+            .await?
+            .error_for_status()?;
+        if resp.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(resp.json().await?))
+    }
+
+    /// Polls for the peer's answer every `interval`, giving up after `max_attempts`.
+    pub async fn wait_for_direct_connection_answer(
+        &self,
+        answer_token: &str,
+        interval: Duration,
+        max_attempts: u32,
+    ) -> Result<Option<DirectConnectionAnswer>, crate::errors::Error> {
+        for attempt in 0..max_attempts {
+            if let Some(answer) = self.poll_direct_connection_answer(answer_token).await? {
+                return Ok(Some(answer));
+            }
+            if attempt + 1 < max_attempts {
+                tokio::time::sleep(interval).await;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fetches a LiveKit token from the server's `/rpc/token` endpoint.
+    ///
+    /// Requires that the `APIClient` has a valid `access_token` already set.
+    /// Uses the token as a Bearer auth header in the request.
+    pub async fn get_livekit_token(
+        &self,
+    ) -> Result<crate::livekit::TokenResponse, crate::errors::Error> {
+        let token = self
+            .access_token
+            .as_ref()
+            .ok_or_else(|| crate::errors::Error::Unauthorized)?;
+
+        let url = format!("{}/rpc/token", self.url.trim_end_matches('/'));
 
         // Use a blocking reqwest client (since function is synchronous)
-        let client = reqwest::Client::new();
-        let resp = client.get(&url).bearer_auth(token).send().await?;
+        let client = self.http_client();
+        let resp = self
+            .send_idempotent(self.sign_request(client.get(&url).bearer_auth(token)))
+            .await?;
 
         let body = resp.json().await?;
         Ok(body)
     }
+
+    /// Fails with [`Error::Internal`] if the server requires a newer client than
+    /// [`crate::VERDANT_CLIENT_VERSION`].
+    ///
+    /// Servers without `/auth/api/compatibility` predate version negotiation and accept any client.
+    pub async fn check_compatibility(&self) -> Result<(), Error> {
+        let url = format!("{}/auth/api/compatibility", self.url.trim_end_matches('/'));
+        let client = self.http_client();
+        let response = self.send_idempotent(client.get(&url)).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        let compatibility: CompatibilityResponse = response.error_for_status()?.json().await?;
+        check_client_version(&compatibility.min_client_version)
+    }
+
+    /// Checks whether the server is up, doesn't need a login.
+    ///
+    /// Any status other than 200 is returned as [`NetworkErrorKind::HttpError`](crate::errors::NetworkErrorKind::HttpError).
+    pub async fn health_check(&self) -> Result<HealthStatus, Error> {
+        let url = format!("{}/health", self.url.trim_end_matches('/'));
+        let client = self.http_client();
+        let status = self
+            .send_idempotent(client.get(&url))
+            .await?
+            .error_for_status()?
+            .json::<HealthStatus>()
+            .await?;
+        Ok(status)
+    }
+
+    /// Fetches the server's capabilities from `/.well-known/verdant`, doesn't need a login.
+    pub async fn get_server_info(&self) -> Result<ServerInfo, Error> {
+        let url = format!("{}/.well-known/verdant", self.url.trim_end_matches('/'));
+        let client = self.http_client();
+        let info = self
+            .send_idempotent(client.get(&url))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(info)
+    }
+
+    /// Capabilities fetched when the client was created from a discovery, `None` if
+    /// the server doesn't advertise them.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
+    }
+
+    /// Like [`APIClient::get_livekit_token`], reusing the last token until it expires.
+    pub async fn get_livekit_token_cached(
+        &mut self,
+    ) -> Result<crate::livekit::TokenResponse, Error> {
+        if let Some(token) = self
+            .livekit_token
+            .as_ref()
+            .filter(|token| !token.is_expired())
+        {
+            return Ok(token.clone());
+        }
+        let token = self.get_livekit_token().await?;
+        self.livekit_token = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Registers `username` with `password` on the server.
+    ///
+    /// A pre-registration challenge is requested first with the account details at
+    /// `/auth/api/register/challenge`, its id then binds the OPAQUE exchange at
+    /// `/auth/api/register/start` and `/auth/api/register/finish`.
+    pub async fn register_user(
+        &self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+        registration_details: RegistrationRequest,
+    ) -> Result<(), Error> {
+        let username = username.into();
+        let base = self.url.trim_end_matches('/');
+        let client = self.http_client();
+
+        let challenge: RegistrationChallenge = client
+            .post(format!("{}/auth/api/register/challenge", base))
+            .json(&registration_details)
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let opaque_client = client_auth::Client::new(password);
+        let (registration, request) = opaque_client.start_registration()?;
+        let start = RegistrationStart {
+            id: challenge.id,
+            username,
+            message: STANDARD.encode(request.serialize()),
+        };
+        let response: RegistrationStartResponse = client
+            .post(format!("{}/auth/api/register/start", base))
+            .json(&start)
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?
+            .json()
+            .await?;
+        let response =
+            opaque_ke::RegistrationResponse::deserialize(&STANDARD.decode(response.message)?)?;
+
+        let upload = opaque_client.finish_registration(registration, response)?;
+        let finish = RegistrationFinish {
+            id: challenge.id,
+            message: STANDARD.encode(upload.serialize()),
+        };
+        client
+            .post(format!("{}/auth/api/register/finish", base))
+            .json(&finish)
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Like [`APIClient::register_user`] for the username in `request`.
+    pub async fn register(
+        &self,
+        request: RegistrationRequest,
+        password: &str,
+    ) -> Result<(), Error> {
+        self.register_user(request.username.clone(), password, request)
+            .await
+    }
+
+    /// Lists the participants of `room_id` from `/rpc/rooms/{room_id}/participants`.
+    pub async fn get_participant_list(
+        &self,
+        room_id: Uuid,
+    ) -> Result<Vec<crate::livekit::Participant>, Error> {
+        let token = self.access_token.as_ref().ok_or(Error::Unauthorized)?;
+        let url = self.participants_url(room_id)?;
+        let client = self.http_client();
+        let participants = self
+            .send_idempotent(self.sign_request(client.get(url).bearer_auth(token)))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(participants)
+    }
+
+    /// Removes `identity` from `room_id`, requires admin rights on the server.
+    pub async fn remove_participant(&self, room_id: Uuid, identity: &str) -> Result<(), Error> {
+        let token = self.access_token.as_ref().ok_or(Error::Unauthorized)?;
+        let mut url = self.participants_url(room_id)?;
+        url.path_segments_mut()
+            .map_err(|_| Error::Internal(format!("invalid server url: {}", self.url)))?
+            .push(identity);
+        let client = self.http_client();
+        self.sign_request(client.delete(url).bearer_auth(token))
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn participants_url(&self, room_id: Uuid) -> Result<reqwest::Url, Error> {
+        let url = format!(
+            "{}/rpc/rooms/{}/participants",
+            self.url.trim_end_matches('/'),
+            room_id
+        );
+        reqwest::Url::parse(&url)
+            .map_err(|e| Error::Internal(format!("invalid url {}: {}", url, e)))
+    }
+
+    /// Opens the server-sent event stream of `room_id` at `/rpc/rooms/{room_id}/events`.
+    ///
+    /// Feed the response chunks to a [`crate::livekit::SseParser`], each event's
+    /// data is a JSON [`crate::livekit::RoomEvent`].
+    pub async fn room_events(&self, room_id: Uuid) -> Result<reqwest::Response, Error> {
+        let token = self.access_token.as_ref().ok_or(Error::Unauthorized)?;
+        let url = format!(
+            "{}/rpc/rooms/{}/events",
+            self.url.trim_end_matches('/'),
+            room_id
+        );
+        let client = self.http_client();
+        let resp = self
+            .send_idempotent(
+                self.sign_request(
+                    client
+                        .get(&url)
+                        .bearer_auth(token)
+                        .header(reqwest::header::ACCEPT, "text/event-stream"),
+                ),
+            )
+            .await?
+            .error_for_status()?;
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::DirectConnectionParams;
+    use crate::test_util::{MockResponse, mock_server, mock_server_raw, spawn_test_server};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn authorized_client(url: &str) -> APIClient {
+        let mut client = APIClient::new(
+            url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        );
+        client.set_access_token(Some("token".to_string()));
+        client
+    }
+
+    #[test]
+    fn credential_cache_is_loaded_from_disk() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("verdant-{}.cache", uuid::Uuid::new_v4()));
+        let client = authorized_client("http://localhost").enable_credential_cache(path.clone());
+        assert!(client.cached_credentials("password")?.is_none());
+
+        let exported = client_auth::Client::new("password")
+            .with_export_key(vec![3u8; 64])
+            .export_credentials()?;
+        write_private_file(&path, &exported)?;
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(&path)?.permissions().mode() & 0o777
+        };
+        let cached = client.cached_credentials("password");
+        let wrong = client.cached_credentials("wrong password");
+        std::fs::remove_file(&path)?;
+
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+        assert_eq!(*cached?.unwrap().export_key, vec![3u8; 64]);
+        assert!(matches!(wrong, Err(Error::AesGcmError(_))));
+        assert!(
+            authorized_client("http://localhost")
+                .cached_credentials("password")?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_server_times_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // accept, then never answer
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let timeout = Duration::from_millis(100);
+        let mut client = APIClientBuilder::new(
+            &url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .timeout(timeout)
+        .build()
+        .unwrap();
+        client.set_access_token(Some("token".to_string()));
+
+        let err = client
+            .get_participant_list(uuid::Uuid::new_v4())
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.timeout_duration(), Some(timeout));
+        assert_eq!(err.to_string(), "operation timed out after 100ms");
+    }
+
+    #[tokio::test]
+    async fn provided_client_is_used_for_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // accept, then never answer
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let http = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut client = APIClientBuilder::new(
+            &url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .with_client(http)
+        .build()
+        .unwrap();
+        client.set_access_token(Some("token".to_string()));
+
+        // only the provided client has a timeout configured
+        let err = client
+            .get_participant_list(uuid::Uuid::new_v4())
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn compatibility_is_checked_against_min_version() {
+        let (url, _) = mock_server(vec![
+            (200, r#"{"min_client_version":"0.0.1"}"#.to_string()),
+            (404, String::new()),
+            (200, r#"{"min_client_version":"999.0.0"}"#.to_string()),
+            (200, r#"{"min_client_version":"not a version"}"#.to_string()),
+        ])
+        .await;
+        let client = authorized_client(&url);
+
+        client.check_compatibility().await.unwrap();
+        client.check_compatibility().await.unwrap();
+        assert!(matches!(
+            client.check_compatibility().await,
+            Err(Error::Internal(msg)) if msg.starts_with("client too old")
+        ));
+        assert!(client.check_compatibility().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn outdated_client_does_not_log_in() {
+        let (url, requests) = mock_server(vec![(
+            200,
+            r#"{"min_client_version":"999.0.0"}"#.to_string(),
+        )])
+        .await;
+        let mut client = authorized_client(&url);
+
+        assert!(matches!(
+            client.login("alice", "password").await,
+            Err(Error::Internal(msg)) if msg.starts_with("client too old")
+        ));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["GET /auth/api/compatibility HTTP/1.1".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn livekit_token_is_cached_until_expired() {
+        let token = |expires_at: u64| {
+            format!(
+                r#"{{"room_id":"{}","token":"lk","room":"lobby","url":"wss://lk","expires_at":{}}}"#,
+                uuid::Uuid::nil(),
+                expires_at
+            )
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (url, requests) =
+            mock_server(vec![(200, token(now - 1)), (200, token(now + 600))]).await;
+        let mut client = authorized_client(&url);
+
+        // already expired, so the next call fetches a new one
+        assert_eq!(
+            client.get_livekit_token_cached().await.unwrap().expires_at,
+            Some(now - 1)
+        );
+        assert_eq!(
+            client.get_livekit_token_cached().await.unwrap().expires_at,
+            Some(now + 600)
+        );
+        assert_eq!(
+            client.get_livekit_token_cached().await.unwrap().expires_at,
+            Some(now + 600)
+        );
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn health_check_reports_status() {
+        let (server, _) = spawn_test_server().await;
+        let client = server.client();
+
+        let status = client.health_check().await.unwrap();
+        assert_eq!(status.status, "ok");
+        assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(status.authenticated_users, Some(0));
+
+        server.set_unavailable(true);
+        assert!(matches!(
+            client.health_check().await,
+            Err(Error::Network(crate::errors::NetworkErrorKind::HttpError(
+                503
+            )))
+        ));
+        assert_eq!(server.requests()[0], "GET /health HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn server_info_is_fetched_from_well_known() {
+        let (url, requests) = mock_server(vec![(
+            200,
+            r#"{"version":"0.4.0","supported_algorithms":["RS256"],"features":["p2p"]}"#
+                .to_string(),
+        )])
+        .await;
+        let client = APIClient::new(
+            &url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        );
+
+        let info = client.get_server_info().await.unwrap();
+        assert_eq!(info.version, "0.4.0");
+        assert_eq!(info.supported_algorithms, vec!["RS256".to_string()]);
+        assert!(!info.require_registration_invite);
+        assert!(info.has_feature("p2p"));
+        assert!(!info.has_feature("invites"));
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /.well-known/verdant HTTP/1.1"
+        );
+        assert!(client.server_info().is_none());
+    }
+
+    fn fast_retries(max_retries: u8) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let (url, requests) = mock_server(vec![
+            (503, String::new()),
+            (502, String::new()),
+            (
+                200,
+                r#"{"status":"ok","version":"0.3.1","uptime_secs":42}"#.to_string(),
+            ),
+        ])
+        .await;
+        let client = APIClient::builder(
+            &url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .retry_policy(fast_retries(2))
+        .build()
+        .unwrap();
+
+        assert_eq!(client.health_check().await.unwrap().uptime_secs, 42);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_after_max_retries() {
+        let (url, requests) = mock_server(vec![
+            (503, String::new()),
+            (503, String::new()),
+            (404, String::new()),
+        ])
+        .await;
+        let client = APIClient::builder(
+            &url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .retry_policy(fast_retries(1))
+        .build()
+        .unwrap();
+
+        assert!(matches!(
+            client.health_check().await,
+            Err(Error::Network(crate::errors::NetworkErrorKind::HttpError(
+                503
+            )))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // other client errors aren't retried
+        assert!(matches!(
+            client.health_check().await,
+            Err(Error::Network(crate::errors::NetworkErrorKind::HttpError(
+                404
+            )))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retry_delay_backs_off_with_jitter() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        for (retry, full) in [(0, 100), (1, 200), (2, 400), (3, 500), (40, 500)] {
+            let delay = policy.delay(retry);
+            assert!(
+                delay >= Duration::from_millis(full / 2),
+                "{retry}: {delay:?}"
+            );
+            assert!(delay <= Duration::from_millis(full), "{retry}: {delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn login_against_test_server() {
+        let (server, _) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+        let mut client = server.client();
+
+        let result = client.login("alice", "correct horse").await.unwrap();
+
+        assert!(
+            matches!(result, LoginResult::Success(token) if client.access_token.as_deref() == Some(token.as_str()))
+        );
+        assert_eq!(
+            client.claims().and_then(|c| c.sub.clone()),
+            Some("alice".to_string())
+        );
+        assert_eq!(server.login_count(), 1);
+        let livekit = client.get_livekit_token().await.unwrap();
+        assert_eq!(livekit.room, "lobby");
+    }
+
+    #[tokio::test]
+    async fn login_rejected_by_test_server() {
+        let (server, url) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+
+        let mut client = server.client();
+        assert!(client.login("alice", "wrong horse").await.is_err());
+        assert!(matches!(
+            client.login("mallory", "correct horse").await,
+            Ok(LoginResult::Unauthorized(
+                crate::auth::UnauthorizedReason::InvalidCredentials
+            ))
+        ));
+        assert!(client.access_token.is_none());
+        assert_eq!(server.login_count(), 0);
+
+        let mut unknown = authorized_client(&url);
+        assert!(unknown.get_livekit_token().await.is_err());
+        assert!(matches!(
+            unknown.refresh_token().await,
+            Err(Error::LoginUnauthorized(
+                crate::auth::UnauthorizedReason::SessionExpired
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn finalize_rejection_reports_its_reason() {
+        let (server, _) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+        server.reject_logins(crate::auth::UnauthorizedReason::AccountLocked);
+
+        let mut client = server.client();
+        let result = client.login("alice", "correct horse").await.unwrap();
+
+        assert!(matches!(
+            result,
+            LoginResult::Unauthorized(crate::auth::UnauthorizedReason::AccountLocked)
+        ));
+        assert!(client.access_token.is_none());
+        assert_eq!(server.login_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn replayed_completion_is_an_error() {
+        let (server, _) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+        server.replay_completions([7u8; 16]);
+
+        let mut client = server.client();
+        let result = client.login("alice", "correct horse").await;
+
+        assert!(matches!(result, Err(Error::ServerAuthentication)));
+        assert!(client.access_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn refresh_against_test_server() {
+        let (mut server, _) = spawn_test_server().await;
+        server.register_user("bob", "hunter2");
+        let mut client = server.client();
+        client.login("bob", "hunter2").await.unwrap();
+        let first = client.access_token.clone().unwrap();
+
+        let refreshed = client.refresh_token().await.unwrap();
+
+        assert_ne!(refreshed, first);
+        assert_eq!(client.access_token, Some(refreshed));
+        // the refreshed token is accepted, the replaced one isn't
+        client.get_livekit_token().await.unwrap();
+        let mut stale = authorized_client(&server.url());
+        stale.set_access_token(Some(first));
+        assert!(stale.refresh_token().await.is_err());
+
+        server.shutdown();
+        assert!(client.get_livekit_token().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn participant_list_is_fetched() {
+        let room_id = uuid::Uuid::new_v4();
+        let (url, requests) = mock_server(vec![(
+            200,
+            r#"[{"identity":"alice","name":"Alice","joined_at":1700000000,"is_publisher":true,"tracks":["TR_1"]},
+               {"identity":"bob","name":null,"joined_at":1700000100,"is_publisher":false,"tracks":[]}]"#
+                .to_string(),
+        )])
+        .await;
+
+        let participants = authorized_client(&url)
+            .get_participant_list(room_id)
+            .await
+            .unwrap();
+
+        assert_eq!(participants.len(), 2);
+        assert_eq!(participants[0].identity, "alice");
+        assert_eq!(participants[0].tracks, vec!["TR_1".to_string()]);
+        assert!(!participants[1].is_publisher && participants[1].name.is_none());
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            format!("GET /rpc/rooms/{}/participants HTTP/1.1", room_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn participant_is_removed() {
+        let room_id = uuid::Uuid::new_v4();
+        let (url, requests) = mock_server(vec![(200, String::new()), (403, String::new())]).await;
+        let client = authorized_client(&url);
+
+        client
+            .remove_participant(room_id, "bob smith")
+            .await
+            .unwrap();
+        assert!(client.remove_participant(room_id, "alice").await.is_err());
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            format!(
+                "DELETE /rpc/rooms/{}/participants/bob%20smith HTTP/1.1",
+                room_id
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn registration_runs_challenge_then_opaque_exchange() {
+        let (server, _) = spawn_test_server().await;
+        let details = RegistrationRequest {
+            first_name: "Heidi".to_string(),
+            last_name: "Example".to_string(),
+            username: "heidi".to_string(),
+            email: "heidi@example.com".to_string(),
+            gender: None,
+            nonce: None,
+        };
+        let mut client = server.client();
+        client
+            .register_user("heidi", "password", details.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.requests(),
+            vec![
+                "POST /auth/api/register/challenge HTTP/1.1".to_string(),
+                "POST /auth/api/register/start HTTP/1.1".to_string(),
+                "POST /auth/api/register/finish HTTP/1.1".to_string(),
+            ]
+        );
+        // the new account can log in, registering it again is refused
+        assert!(matches!(
+            client.login("heidi", "password").await,
+            Ok(LoginResult::Success(_))
+        ));
+        assert!(client.register(details, "password").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn changed_password_is_used_for_logins() {
+        let (server, _) = spawn_test_server().await;
+        server.register_user("ivan", "old password");
+        assert!(
+            server
+                .change_password("ivan", "wrong password", "new password")
+                .is_err()
+        );
+        server
+            .change_password("ivan", "old password", "new password")
+            .unwrap();
+
+        let mut client = server.client();
+        assert!(client.login("ivan", "old password").await.is_err());
+        assert!(matches!(
+            client.login("ivan", "new password").await,
+            Ok(LoginResult::Success(_))
+        ));
+        assert_eq!(server.login_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_errors_are_classified_by_cause() {
+        use crate::errors::NetworkErrorKind;
+
+        let client = build_http_client(None, None);
+        // .invalid never resolves
+        let dns = Error::from(
+            client
+                .get("http://verdant.invalid")
+                .send()
+                .await
+                .unwrap_err(),
+        );
+        assert!(matches!(dns, Error::Network(NetworkErrorKind::DnsFailure)));
+
+        // a plain HTTP server doesn't speak TLS
+        let (url, _) = mock_server(vec![(200, String::new())]).await;
+        let tls = client
+            .get(url.replace("http://", "https://"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            Error::from(tls),
+            Error::Network(NetworkErrorKind::TlsError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn from_url_accepts_tokens_signed_for_the_key_type() {
+        let (server, url) = spawn_test_server().await;
+        server.register_user("judy", "correct horse");
+
+        let mut client = APIClient::from_url(&url).await.unwrap();
+        assert_eq!(client.validation.algorithms, vec![Algorithm::EdDSA]);
+        assert!(matches!(
+            client.login("judy", "correct horse").await,
+            Ok(LoginResult::Success(_))
+        ));
+        assert_eq!(client.claims().unwrap().sub.as_deref(), Some("judy"));
+    }
+
+    #[test]
+    fn key_type_algorithms() {
+        assert_eq!(
+            KeyType::Rsa.algorithms(),
+            vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512]
+        );
+        assert_eq!(
+            KeyType::Ec.algorithms(),
+            vec![Algorithm::ES256, Algorithm::ES384]
+        );
+        assert_eq!(KeyType::Ed25519.algorithms(), vec![Algorithm::EdDSA]);
+        assert!(KeyType::Ed448.algorithms().is_empty());
+    }
+
+    fn signing_client() -> APIClient {
+        let mut client = APIClient::builder(
+            "http://localhost:8080",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .with_request_signing(true)
+        .build()
+        .unwrap();
+        client.session_key = Some(vec![7u8; 64].into());
+        client
+    }
+
+    #[test]
+    fn sign_request_adds_headers() {
+        let api = signing_client();
+        let request = api
+            .sign_request(Client::new().get("http://localhost:8080/rpc/token"))
+            .build()
+            .unwrap();
+
+        assert!(request.headers().contains_key(REQUEST_SIGNATURE_HEADER));
+        assert!(request.headers().contains_key(REQUEST_TIMESTAMP_HEADER));
+    }
+
+    #[test]
+    fn sign_request_hmac_matches() {
+        let api = signing_client();
+        let body = b"{\"hello\":\"world\"}".to_vec();
+        let request = api
+            .sign_request(
+                Client::new()
+                    .post("http://localhost:8080/rpc/token")
+                    .body(body.clone()),
+            )
+            .build()
+            .unwrap();
+
+        let timestamp: u64 = request.headers()[REQUEST_TIMESTAMP_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let signature = request.headers()[REQUEST_SIGNATURE_HEADER]
+            .to_str()
+            .unwrap();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&[7u8; 64]).unwrap();
+        mac.update(b"POST");
+        mac.update(b"http://localhost:8080/rpc/token");
+        mac.update(&Sha256::digest(&body));
+        mac.update(timestamp.to_string().as_bytes());
+        let expected = crate::crypto::hex_encode(&mac.finalize().into_bytes());
+
+        assert_eq!(signature, expected);
+    }
+
+    #[test]
+    fn sign_request_disabled_without_session_key() {
+        let api = APIClient::builder(
+            "http://localhost:8080",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .with_request_signing(true)
+        .build()
+        .unwrap();
+        let request = api
+            .sign_request(Client::new().get("http://localhost:8080/rpc/token"))
+            .build()
+            .unwrap();
+
+        assert!(!request.headers().contains_key(REQUEST_SIGNATURE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn direct_connection_offer_answer_round_trip() {
+        let answer = DirectConnectionAnswer {
+            answer_sdp: "v=0 answer".to_string(),
+            params: DirectConnectionParams {
+                ice_candidates: vec!["candidate:1 1 UDP 1 10.0.0.2 5000 typ host".to_string()],
+                dtls_fingerprint: "sha-256 AB:CD".to_string(),
+            },
+        };
+        let offer = DirectConnectionOfferResponse {
+            answer_token: "abc123".to_string(),
+        };
+        let (url, requests) = mock_server(vec![
+            (200, serde_json::to_string(&offer).unwrap()),
+            (204, String::new()),
+            (200, serde_json::to_string(&answer).unwrap()),
+        ])
+        .await;
+        let api = authorized_client(&url);
+
+        let token = api
+            .offer_direct_connection("bob", "v=0 offer")
+            .await
+            .unwrap();
+        assert_eq!(token, "abc123");
+
+        let received = api
+            .wait_for_direct_connection_answer(&token, Duration::from_millis(1), 3)
+            .await
+            .unwrap();
+        assert_eq!(received, Some(answer));
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("POST /rpc/p2p/offer"));
+        assert!(requests[1].starts_with("GET /rpc/p2p/answer/abc123"));
+        assert!(requests[2].starts_with("GET /rpc/p2p/answer/abc123"));
+    }
+
+    #[tokio::test]
+    async fn direct_connection_answer_pending() {
+        let (url, _) = mock_server(vec![(204, String::new())]).await;
+        let api = authorized_client(&url);
+
+        let received = api.poll_direct_connection_answer("abc123").await.unwrap();
+        assert_eq!(received, None);
+    }
+
+    #[test]
+    fn sni_override_rewrites_ip_urls() {
+        let (url, sni) = SniOverride::for_url("https://10.0.0.5:8443", "verdant.local.").unwrap();
+        assert_eq!(url, "https://verdant.local:8443");
+        assert_eq!(sni.hostname, "verdant.local");
+        assert_eq!(sni.addr, "10.0.0.5:8443".parse().unwrap());
+
+        let (url, sni) = SniOverride::for_url("https://[::1]", "verdant.local").unwrap();
+        assert_eq!(url, "https://verdant.local");
+        assert_eq!(sni.addr, "[::1]:443".parse().unwrap());
+    }
+
+    #[test]
+    fn sni_override_ignores_hostnames() {
+        assert!(SniOverride::for_url("https://example.com:8443", "verdant.local").is_none());
+
+        let api = APIClient::builder(
+            "https://example.com:8443",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .sni_hostname("verdant.local")
+        .build()
+        .unwrap();
+        assert_eq!(api.url, "https://example.com:8443");
+    }
+
+    #[test]
+    fn sni_override_ignores_plain_http() {
+        assert!(SniOverride::for_url("http://10.0.0.5:8080", "verdant.local").is_none());
+    }
+
+    #[tokio::test]
+    async fn sni_override_presents_hostname_over_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls;
+
+        // valid for the hostname and the IP alike, so only the SNI tells them apart
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::default();
+        params.subject_alt_names = vec![
+            rcgen::SanType::DnsName("verdant.local".try_into().unwrap()),
+            rcgen::SanType::IpAddress("127.0.0.1".parse().unwrap()),
+        ];
+        let cert = params.self_signed(&key).unwrap();
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor.accept(stream).await.unwrap();
+            let server_name = tls.get_ref().1.server_name().map(str::to_string);
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = tls.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed mid request");
+                request.extend_from_slice(&buf[..n]);
+            }
+            tls.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+            tls.shutdown().await.ok();
+            server_name
+        });
+
+        let (url, sni) =
+            SniOverride::for_url(&format!("https://127.0.0.1:{port}"), "verdant.local").unwrap();
+        let client = http_client_builder(Some(&sni), None)
+            .add_root_certificate(reqwest::Certificate::from_der(cert.der()).unwrap())
+            .build()
+            .unwrap();
+        let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+
+        assert_eq!(body, "ok");
+        assert_eq!(server.await.unwrap().as_deref(), Some("verdant.local"));
+    }
+
+    #[test]
+    fn builder_parses_keys() {
+        let (_, public_pem) = crate::crypto::generate_rsa_pkcs8_pair();
+        let api = APIClientBuilder::default()
+            .url("https://verdant.local")
+            .rsa_pem(&public_pem)
+            .validate_exp(false)
+            .build()
+            .unwrap();
+        assert_eq!(api.url, "https://verdant.local");
+        assert_eq!(
+            api.validation.algorithms,
+            vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512]
+        );
+        assert!(!api.validation.validate_exp);
+
+        let api = APIClientBuilder::default()
+            .url("https://verdant.local")
+            .ed25519_der(&[42u8; 32])
+            .add_algorithm(Algorithm::EdDSA)
+            .add_algorithm(Algorithm::EdDSA)
+            .build()
+            .unwrap();
+        assert_eq!(api.validation.algorithms, vec![Algorithm::EdDSA]);
+        assert!(api.validation.validate_exp);
+    }
+
+    #[test]
+    fn builder_reports_key_errors() {
+        assert!(matches!(
+            APIClientBuilder::default()
+                .url("https://verdant.local")
+                .build(),
+            Err(Error::Internal(_))
+        ));
+        assert!(matches!(
+            APIClientBuilder::default()
+                .url("https://verdant.local")
+                .rsa_pem("not a key")
+                .build(),
+            Err(Error::JSONWebToken(_))
+        ));
+    }
+
+    fn jwt(exp: u64) -> String {
+        #[derive(serde_derive::Serialize)]
+        struct Claims {
+            sub: String,
+            exp: u64,
+        }
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &Claims {
+                sub: "alice".to_string(),
+                exp,
+            },
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn token_expiry_reads_exp_claim() {
+        let mut api = authorized_client("http://localhost:8080");
+        assert_eq!(api.token_expires_at(), None);
+
+        api.set_access_token(Some(jwt(1_700_000_000)));
+        assert_eq!(api.token_expires_at(), Some(1_700_000_000));
+    }
+
+    #[tokio::test]
+    async fn refresh_token_replaces_access_token() {
+        let fresh = jwt(4_000_000_000);
+        let (url, requests) = mock_server(vec![(
+            200,
+            serde_json::to_string(&LoginResult::Success(fresh.clone())).unwrap(),
+        )])
+        .await;
+        let mut api = authorized_client(&url);
+
+        assert_eq!(api.refresh_token().await.unwrap(), fresh);
+        assert_eq!(api.access_token, Some(fresh));
+        assert!(requests.lock().unwrap()[0].starts_with("POST /auth/api/refresh"));
+    }
+
+    #[tokio::test]
+    async fn refresh_token_accepts_plain_token_body() {
+        let fresh = jwt(4_000_000_000);
+        let (url, _) = mock_server(vec![(200, format!(r#"{{"token":"{}"}}"#, fresh))]).await;
+        let mut api = authorized_client(&url);
+
+        assert_eq!(api.refresh_token().await.unwrap(), fresh);
+        assert_eq!(api.access_token, Some(fresh));
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn pubkey_json() -> String {
+        serde_json::to_string(&PubKeyResponse::encode_pubkey(
+            KeyType::Ed25519,
+            &[42u8; 32],
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn gzip_body_without_content_encoding_is_inflated() {
+        let (url, _) = mock_server_raw(vec![MockResponse {
+            status: 200,
+            headers: vec![("content-type", "application/json".to_string())],
+            body: gzip(pubkey_json().as_bytes()),
+        }])
+        .await;
+
+        let api = APIClient::from_url(&url).await.unwrap();
+        assert_eq!(api.url, url);
+    }
+
+    #[tokio::test]
+    async fn gzip_content_encoding_is_decoded() {
+        let (url, _) = mock_server_raw(vec![MockResponse {
+            status: 200,
+            headers: vec![
+                ("content-type", "application/json".to_string()),
+                ("content-encoding", "gzip".to_string()),
+            ],
+            body: gzip(pubkey_json().as_bytes()),
+        }])
+        .await;
+
+        assert!(APIClient::from_url(&url).await.is_ok());
+    }
+
+    #[test]
+    fn key_type_oid_mapping() {
+        for (oid, key_type) in [
+            (KeyType::RSA_OID, KeyType::Rsa),
+            (KeyType::EC_OID, KeyType::Ec),
+            (KeyType::ED25519_OID, KeyType::Ed25519),
+        ] {
+            assert_eq!(KeyType::from_oid(oid), key_type);
+            assert_eq!(key_type.to_oid(), Some(oid));
+        }
+        assert_eq!(KeyType::from_oid(KeyType::ED448_OID), KeyType::Ed448);
+        assert_eq!(KeyType::Ed448.to_oid(), None);
+        let unknown = KeyType::from_oid("1.2.3.4");
+        assert_eq!(unknown, KeyType::Unknown("1.2.3.4".to_string()));
+        assert_eq!(unknown.to_oid(), None);
+        assert_eq!(unknown.to_string(), "unknown key type (1.2.3.4)");
+        assert_eq!(KeyType::Ed25519.to_string(), "Ed25519");
+    }
+
+    /// SubjectPublicKeyInfo with `algorithm` (DER, including its parameters) and a zeroed key.
+    fn spki_der(algorithm: &[u8], key_len: usize) -> Vec<u8> {
+        let mut bit_string = vec![0x03, key_len as u8 + 1, 0x00];
+        bit_string.extend(std::iter::repeat_n(0x04, key_len));
+        let mut der = vec![0x30, (algorithm.len() + bit_string.len()) as u8];
+        der.extend_from_slice(algorithm);
+        der.extend_from_slice(&bit_string);
+        der
+    }
+
+    #[test]
+    fn pubkey_response_from_der() {
+        // id-Ed25519
+        let ed25519 = spki_der(&[0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70], 32);
+        // id-ecPublicKey with prime256v1
+        let ec = spki_der(
+            &[
+                0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+                0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07,
+            ],
+            65,
+        );
+        // id-Ed448
+        let ed448 = spki_der(&[0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x71], 57);
+        for (der, key_type) in [
+            (ed25519, KeyType::Ed25519),
+            (ec, KeyType::Ec),
+            (ed448, KeyType::Ed448),
+        ] {
+            let response = PubKeyResponse::from_der(&der).unwrap();
+            assert_eq!(response.key_type, key_type);
+            assert_eq!(response.to_der().unwrap(), der);
+        }
+        assert!(PubKeyResponse::from_der(&[0x30, 0x00]).is_err());
+    }
+
+    #[test]
+    fn pubkey_response_from_pem() {
+        let (_, public_pem) = crate::crypto::generate_rsa_pkcs8_pair();
+        let response = PubKeyResponse::from_pem(&public_pem).unwrap();
+        assert_eq!(response.key_type, KeyType::Rsa);
+        assert!(response.decode_pubkey().is_ok());
+        assert_eq!(
+            PubKeyResponse::from_der(&response.to_der().unwrap())
+                .unwrap()
+                .pubkey,
+            response.pubkey
+        );
+        assert!(PubKeyResponse::from_pem("-----BEGIN PUBLIC KEY-----\n!!\n").is_err());
+    }
+
+    #[test]
+    fn generated_ed25519_keys_decode() {
+        let (signing_key, verifying_key) = crate::crypto::generate_ed25519_pair();
+        let (private_pem, public_pem) = crate::crypto::ed25519_pair_to_pem(&signing_key);
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::EdDSA),
+            &serde_json::json!({ "sub": "alice", "exp": 4_000_000_000u64 }),
+            &jsonwebtoken::EncodingKey::from_ed_pem(private_pem.as_bytes()).unwrap(),
+        )
+        .unwrap();
+
+        let spki = PubKeyResponse::from_pem(&public_pem).unwrap();
+        let raw = PubKeyResponse::encode_pubkey(KeyType::Ed25519, verifying_key.as_bytes());
+        assert_eq!(spki.key_type, KeyType::Ed25519);
+        for response in [spki, raw] {
+            let key = response.decode_pubkey().unwrap();
+            let claims = jsonwebtoken::decode::<VerdantClaims>(
+                &token,
+                &key,
+                &Validation::new(Algorithm::EdDSA),
+            )
+            .unwrap()
+            .claims;
+            assert_eq!(claims.sub.as_deref(), Some("alice"));
+        }
+    }
+
+    #[test]
+    fn generated_rsa_keys_decode() {
+        use rsa::pkcs1::EncodeRsaPublicKey;
+        use rsa::pkcs8::DecodePublicKey;
+
+        let (private_pem, public_pem) = crate::crypto::generate_rsa_pkcs8_pair();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::RS256),
+            &serde_json::json!({ "sub": "alice", "exp": 4_000_000_000u64 }),
+            &jsonwebtoken::EncodingKey::from_rsa_pem(private_pem.as_bytes()).unwrap(),
+        )
+        .unwrap();
+
+        let spki = PubKeyResponse::from_pem(&public_pem).unwrap();
+        let pkcs1 = rsa::RsaPublicKey::from_public_key_pem(&public_pem)
+            .unwrap()
+            .to_pkcs1_der()
+            .unwrap();
+        let bare = PubKeyResponse::encode_pubkey(KeyType::Rsa, pkcs1.as_bytes());
+        assert_eq!(spki.key_type, KeyType::Rsa);
+        for response in [spki, bare] {
+            let key = response.decode_pubkey().unwrap();
+            let claims = jsonwebtoken::decode::<VerdantClaims>(
+                &token,
+                &key,
+                &Validation::new(Algorithm::RS256),
+            )
+            .unwrap()
+            .claims;
+            assert_eq!(claims.sub.as_deref(), Some("alice"));
+        }
+    }
+
+    #[test]
+    fn generated_ec_keys_decode() {
+        for ((private_pem, public_pem), algorithm) in [
+            (crate::crypto::generate_ec_p256_pair(), Algorithm::ES256),
+            (crate::crypto::generate_ec_p384_pair(), Algorithm::ES384),
+        ] {
+            let token = jsonwebtoken::encode(
+                &jsonwebtoken::Header::new(algorithm),
+                &serde_json::json!({ "sub": "alice", "exp": 4_000_000_000u64 }),
+                &jsonwebtoken::EncodingKey::from_ec_pem(private_pem.as_bytes()).unwrap(),
+            )
+            .unwrap();
+
+            let response = PubKeyResponse::from_pem(&public_pem).unwrap();
+            assert_eq!(response.key_type, KeyType::Ec);
+            let key = response.decode_pubkey().unwrap();
+            let claims =
+                jsonwebtoken::decode::<VerdantClaims>(&token, &key, &Validation::new(algorithm))
+                    .unwrap()
+                    .claims;
+            assert_eq!(claims.sub.as_deref(), Some("alice"));
+        }
+    }
+
+    #[test]
+    fn tampered_key_fails_hash_verification() {
+        let response = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let expected = STANDARD.encode(Sha256::digest([42u8; 32]));
+        assert_eq!(response.key_hash().unwrap(), expected);
+        assert!(response.verify_hash(&expected).is_ok());
+        let hex = crate::crypto::sha256_hex(&[42u8; 32]);
+        assert!(response.verify_hash(&hex).is_ok());
+        assert!(response.verify_hash(&hex.to_uppercase()).is_ok());
+
+        let tampered = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[43u8; 32]);
+        assert!(matches!(
+            tampered.verify_hash(&expected),
+            Err(Error::KeyHashMismatch(actual, advertised)) if actual != advertised && advertised == expected
+        ));
+        assert!(tampered.verify_hash(&hex).is_err());
+    }
+
+    #[tokio::test]
+    async fn key_not_matching_the_advertised_hash_is_rejected() {
+        let (url, _) = mock_server(vec![(200, pubkey_json()), (200, pubkey_json())]).await;
+        let advertised = STANDARD.encode(Sha256::digest([42u8; 32]));
+        assert!(
+            APIClient::from_url_checked(&url, None, Some(&advertised))
+                .await
+                .is_ok()
+        );
+
+        let other = STANDARD.encode(Sha256::digest([0u8; 32]));
+        assert!(matches!(
+            APIClient::from_url_checked(&url, None, Some(&other)).await,
+            Err(Error::KeyHashMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn oversized_gzip_body_is_rejected() {
+        let bomb = gzip(&vec![b' '; MAX_BODY as usize + 1]);
+        assert!(bomb.len() < 64 * 1024);
+        assert!(matches!(
+            decode_json_body::<PubKeyResponse>(&bomb),
+            Err(Error::Internal(_))
+        ));
+
+        // padding up to the limit is still accepted
+        let mut json = pubkey_json().into_bytes();
+        json.resize(MAX_BODY as usize, b' ');
+        let parsed: PubKeyResponse = decode_json_body(&gzip(&json)).unwrap();
+        assert_eq!(parsed.key_type, KeyType::Ed25519);
+    }
+
+    #[test]
+    fn plain_json_body_is_parsed() {
+        let parsed: PubKeyResponse = decode_json_body(pubkey_json().as_bytes()).unwrap();
+        assert_eq!(parsed.key_type, KeyType::Ed25519);
+    }
+
+    #[test]
+    fn validate_token_verifies_signature_and_expiry() {
+        let api = authorized_client("http://localhost:8080");
+        let token = jwt(4_000_000_000);
+        let (validated, claims) = api.validate_token(&token, &api.decoder).unwrap();
+        assert_eq!(validated, token);
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.exp, Some(4_000_000_000));
+
+        assert!(matches!(
+            api.validate_token(&jwt(1_000_000_000), &api.decoder),
+            Err(Error::JSONWebToken(_))
+        ));
+        assert!(matches!(
+            api.validate_token(&token, &DecodingKey::from_secret(b"other secret")),
+            Err(Error::JSONWebToken(_))
+        ));
+        assert!(api.validate_token("token", &api.decoder).is_err());
+    }
+
+    #[test]
+    fn display_name_is_read_from_claims() {
+        let mut api = authorized_client("http://localhost:8080");
+        assert_eq!(api.display_name(), None);
+
+        let claims = VerdantClaims {
+            sub: Some("alice".to_string()),
+            exp: Some(4_000_000_000),
+            iat: None,
+            display_name: Some("Alice".to_string()),
+            avatar_url: Some("https://example.com/alice.png".to_string()),
+        };
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        api.set_access_token(Some(token));
+
+        assert_eq!(api.display_name(), Some("Alice"));
+        assert_eq!(api.claims(), Some(&claims));
+
+        api.set_access_token(None);
+        assert_eq!(api.display_name(), None);
+    }
+
+    #[tokio::test]
+    async fn logout_clears_credentials() {
+        let (server, _) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+        let mut api = server.client();
+        api.login("alice", "correct horse").await.unwrap();
+        let token = api.access_token.clone();
+        assert!(api.session_key().is_some());
+
+        api.logout().await.unwrap();
+        assert_eq!(api.access_token, None);
+        assert_eq!(api.session_key(), None);
+        assert_eq!(
+            server.requests().last().map(String::as_str),
+            Some("POST /auth/api/logout HTTP/1.1")
+        );
+        // the server ended the session too
+        let mut stale = server.client();
+        stale.set_access_token(token);
+        assert!(stale.get_livekit_token().await.is_err());
+
+        // already logged out, no request is made
+        let sent = server.requests().len();
+        api.logout().await.unwrap();
+        assert_eq!(server.requests().len(), sent);
+    }
+
+    #[test]
+    fn logout_local_clears_credentials() {
+        let mut api = authorized_client("http://localhost:8080");
+        api.session_key = Some(vec![1u8; 64].into());
+
+        api.logout_local();
+        assert_eq!(api.access_token, None);
+        assert_eq!(api.claims(), None);
+        assert_eq!(api.session_key(), None);
+    }
 }
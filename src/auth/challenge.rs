@@ -1,5 +1,6 @@
-use crate::auth::LoginResult;
+use crate::auth::{LoginResult, UnauthorizedReason};
 use crate::client::auth::LoginRequest;
+use crate::errors::Error;
 use crate::server::auth::CredentialFinalization;
 use crate::server::auth::LoginResponse;
 use serde_derive::{Deserialize, Serialize};
@@ -7,13 +8,12 @@ use uuid::Uuid;
 
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
-use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 use std::fmt;
 use std::str::FromStr;
-
-type HmacSha256 = Hmac<Sha256>;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 
 /// Represents the client's final message in the authenticated login flow.
 ///
@@ -36,6 +36,9 @@ pub struct LoginUpload {
     /// HMAC tag computed over the transcript and label `"client"`,
     /// confirming possession of the session key.
     client_tag: [u8; 32],
+    /// [`Transcript::nonce`] of the transcript the tag covers.
+    #[serde(default)]
+    nonce: [u8; 16],
 }
 
 impl LoginUpload {
@@ -47,9 +50,13 @@ impl LoginUpload {
     /// - `session_key`: The shared session key (`K_session`) derived from OPAQUE.
     /// - `request`: The original `LoginRequest` sent by the client.
     /// - `response`: The `LoginResponse` sent by the server.
+    /// - `max_transcript_size`: Upper bound on the transcript, usually [`Transcript::MAX_SIZE`].
+    /// - `session_nonce`: If set, appended to the transcript as the [`SESSION_NONCE_LABEL`]
+    ///   field, the server must verify with the same nonce.
     ///
     /// # Returns
-    /// A `LoginUpload` containing the client’s final message and HMAC confirmation tag.
+    /// A `LoginUpload` containing the client’s final message and HMAC confirmation tag,
+    /// or [`Error::TranscriptTooLarge`] if the exchange exceeds `max_transcript_size`.
     ///
     /// # Security
     /// The HMAC is computed as:
@@ -61,12 +68,49 @@ impl LoginUpload {
         session_key: &[u8],
         request: &LoginRequest,
         response: &LoginResponse,
+        max_transcript_size: usize,
+        session_nonce: Option<Uuid>,
+    ) -> Result<Self, Error> {
+        let mut builder = Self::builder(id, upload, session_key, request, response)
+            .max_transcript_size(max_transcript_size);
+        if let Some(nonce) = session_nonce {
+            builder = builder.session_nonce(nonce);
+        }
+        builder.build()
+    }
+
+    /// Starts a [`LoginUploadBuilder`], for uploads bound to a TLS channel or a server id.
+    pub fn builder<'a>(
+        id: Uuid,
+        upload: CredentialFinalization,
+        session_key: &'a [u8],
+        request: &'a LoginRequest,
+        response: &'a LoginResponse,
+    ) -> LoginUploadBuilder<'a> {
+        LoginUploadBuilder {
+            id,
+            upload,
+            session_key,
+            request,
+            response,
+            max_transcript_size: Transcript::MAX_SIZE,
+            session_nonce: None,
+            channel_binding: None,
+            server_id: None,
+        }
+    }
+
+    /// Computes the client tag over a precomputed [`Transcript`].
+    pub fn from_transcript(
+        id: Uuid,
+        upload: CredentialFinalization,
+        session_key: &[u8],
+        transcript: Transcript,
     ) -> Self {
         let k_confirm = derive_k_confirm(session_key);
-        let transcript = Transcript::compute_transcript(request, response);
 
         // Client HMAC binds the transcript and "client" label
-        let mut data = transcript.into_inner().clone();
+        let mut data = transcript.authenticated_data();
         data.extend_from_slice(b"client");
 
         let client_tag = compute_hmac(&k_confirm, data);
@@ -75,6 +119,7 @@ impl LoginUpload {
             id,
             upload,
             client_tag,
+            nonce: transcript.nonce(),
         }
     }
 
@@ -82,38 +127,145 @@ impl LoginUpload {
     /// and transcript messages.
     ///
     /// Returns `true` if the tag matches, meaning the client and server
-    /// derived the same session key.
+    /// derived the same session key. The transcript is recomputed with the upload's
+    /// [`LoginUpload::nonce`]. Oversized transcripts never verify.
     pub fn verify(
         &self,
         session_key: &[u8],
         request: &LoginRequest,
         response: &LoginResponse,
     ) -> bool {
-        let transcript = Transcript::compute_transcript(request, response);
+        match Transcript::compute_transcript(request, response, None) {
+            Ok(transcript) => {
+                self.verify_transcript(session_key, &transcript.with_nonce(self.nonce))
+            }
+            Err(_) => false,
+        }
+    }
 
-        self.verify_transcript(session_key, &transcript)
+    /// Like [`LoginUpload::verify`], but only accepts tags bound to `server_id`.
+    pub fn verify_for_server(
+        &self,
+        session_key: &[u8],
+        request: &LoginRequest,
+        response: &LoginResponse,
+        server_id: &[u8],
+    ) -> bool {
+        match Transcript::compute_transcript_with_nonce(request, response, self.nonce) {
+            Ok(transcript) => {
+                self.verify_transcript(session_key, &transcript.with_server_id(server_id))
+            }
+            Err(_) => false,
+        }
     }
 
-    /// Verifies the tag using a precomputed [`Transcript`]
+    /// Verifies the tag using a precomputed [`Transcript`], including its nonce.
     pub fn verify_transcript(&self, session_key: &[u8], transcript: &Transcript) -> bool {
         let k_confirm = derive_k_confirm(session_key);
 
-        let mut data = transcript.clone().into_inner();
+        let mut data = transcript.authenticated_data();
         data.extend_from_slice(b"client");
 
         let expected = compute_hmac(&k_confirm, data);
-        expected == self.client_tag
+        expected.ct_eq(&self.client_tag).into()
     }
 
     pub fn id(&self) -> Uuid {
         self.id
     }
 
+    /// Nonce of the client's transcript, the server answers with a [`LoginCompletion`]
+    /// over the same one.
+    pub fn nonce(&self) -> [u8; 16] {
+        self.nonce
+    }
+
     pub fn finalization(&self) -> CredentialFinalization {
         self.upload.clone()
     }
 }
 
+/// Builder for a [`LoginUpload`] whose transcript carries more than the two messages,
+/// see [`LoginUpload::builder`].
+///
+/// ```ignore
+/// let upload = LoginUpload::builder(id, finalization, &session_key, &request, &response)
+///     .channel_binding(&tls_exporter)
+///     .server_id(b"verdant.example")
+///     .build()?;
+/// ```
+pub struct LoginUploadBuilder<'a> {
+    id: Uuid,
+    upload: CredentialFinalization,
+    session_key: &'a [u8],
+    request: &'a LoginRequest,
+    response: &'a LoginResponse,
+    max_transcript_size: usize,
+    session_nonce: Option<Uuid>,
+    channel_binding: Option<&'a [u8]>,
+    server_id: Option<&'a [u8]>,
+}
+
+impl<'a> LoginUploadBuilder<'a> {
+    /// Upper bound on the transcript, [`Transcript::MAX_SIZE`] unless set.
+    pub fn max_transcript_size(mut self, max_transcript_size: usize) -> Self {
+        self.max_transcript_size = max_transcript_size;
+        self
+    }
+
+    /// Appends `nonce` as the [`SESSION_NONCE_LABEL`] field, the server must verify
+    /// with the same nonce.
+    pub fn session_nonce(mut self, nonce: Uuid) -> Self {
+        self.session_nonce = Some(nonce);
+        self
+    }
+
+    /// Binds the tag to the TLS channel.
+    ///
+    /// `channel_binding` must come from the TLS session's channel binding API
+    /// (`tls-exporter` per RFC 9266, or `tls-unique` on TLS 1.2), see
+    /// [`Transcript::with_channel_binding`]. The server must verify with a transcript
+    /// bound to the binding it observed on its side of the connection.
+    pub fn channel_binding(mut self, channel_binding: &'a [u8]) -> Self {
+        self.channel_binding = Some(channel_binding);
+        self
+    }
+
+    /// Binds the tag to the server identified by `server_id`, see
+    /// [`Transcript::with_server_id`]. The server verifies with
+    /// [`LoginUpload::verify_for_server`] and its own id.
+    pub fn server_id(mut self, server_id: &'a [u8]) -> Self {
+        self.server_id = Some(server_id);
+        self
+    }
+
+    /// Computes the transcript and the client tag over it.
+    ///
+    /// Returns [`Error::TranscriptTooLarge`] if the exchange exceeds the maximum size.
+    pub fn build(self) -> Result<LoginUpload, Error> {
+        let mut transcript = Transcript::compute_transcript_with_max(
+            self.request,
+            self.response,
+            self.max_transcript_size,
+        )?;
+        if let Some(channel_binding) = self.channel_binding {
+            transcript = transcript.with_channel_binding(channel_binding);
+        }
+        if let Some(nonce) = self.session_nonce {
+            transcript.append_uuid(SESSION_NONCE_LABEL, nonce);
+        }
+        if let Some(server_id) = self.server_id {
+            transcript = transcript.with_server_id(server_id);
+        }
+        Ok(LoginUpload::from_transcript(
+            self.id,
+            self.upload,
+            self.session_key,
+            transcript,
+        ))
+    }
+}
+
 /// Represents the server's final message in the login exchange.
 ///
 /// This is sent after the client’s `LoginUpload` is validated and
@@ -128,13 +280,22 @@ pub struct LoginCompletion {
     /// HMAC tag computed over the transcript and `"server"` label,
     /// confirming the server’s possession of the session key.
     server_tag: [u8; 32],
+    /// [`Transcript::nonce`] of the transcript the tag covers.
+    #[serde(default)]
+    nonce: [u8; 16],
+    /// unix timestamp (seconds) the session ends at, covered by `server_tag`.
+    #[serde(default)]
+    expires_at: Option<u64>,
 }
 
 impl LoginCompletion {
-    pub fn unauthorized() -> Self {
+    /// A rejection, the all-zero `server_tag` never verifies.
+    pub fn unauthorized(reason: UnauthorizedReason) -> Self {
         Self {
-            result: LoginResult::Unauthorized,
+            result: LoginResult::Unauthorized(reason),
             server_tag: [0u8; 32],
+            nonce: [0u8; 16],
+            expires_at: None,
         }
     }
     /// Constructs a new `LoginCompletion` message.
@@ -144,45 +305,197 @@ impl LoginCompletion {
     /// - `session_key`: The shared session key derived from the OPAQUE exchange.
     /// - `request`: Original login request.
     /// - `response`: Server’s initial response message.
+    /// - `session_nonce`: If set, appended to the transcript as the [`SESSION_NONCE_LABEL`] field.
     ///
     /// # Security
     /// The HMAC is computed as:
     /// `HMAC(K_confirm, transcript || "server")`
-    pub fn new(result: LoginResult, session_key: &[u8], transcript: Transcript) -> Self {
+    pub fn new(
+        result: LoginResult,
+        session_key: &[u8],
+        transcript: Transcript,
+        session_nonce: Option<Uuid>,
+    ) -> Self {
+        Self::signed(result, session_key, transcript, session_nonce, None)
+    }
+
+    /// Like [`LoginCompletion::new`] for a session that ends after `ttl`.
+    ///
+    /// The expiry is appended to the transcript as the [`EXPIRES_AT_LABEL`] field, so it
+    /// can't be changed without invalidating the tag. See [`LoginCompletion::is_expired`].
+    pub fn with_expiry(
+        result: LoginResult,
+        session_key: &[u8],
+        transcript: Transcript,
+        session_nonce: Option<Uuid>,
+        ttl: Duration,
+    ) -> Self {
+        let expires_at = unix_now().saturating_add(ttl.as_secs());
+        Self::signed(
+            result,
+            session_key,
+            transcript,
+            session_nonce,
+            Some(expires_at),
+        )
+    }
+
+    fn signed(
+        result: LoginResult,
+        session_key: &[u8],
+        transcript: Transcript,
+        session_nonce: Option<Uuid>,
+        expires_at: Option<u64>,
+    ) -> Self {
+        let mut transcript = transcript;
+        if let Some(nonce) = session_nonce {
+            transcript.append_uuid(SESSION_NONCE_LABEL, nonce);
+        }
+        if let Some(expires_at) = expires_at {
+            transcript.append_u64(EXPIRES_AT_LABEL, expires_at);
+        }
         let k_confirm = derive_k_confirm(session_key);
 
         // Server HMAC binds the same transcript and "server" label
-        let mut data = transcript.clone().into_inner();
+        let mut data = transcript.authenticated_data();
         data.extend_from_slice(b"server");
 
         let server_tag = compute_hmac(&k_confirm, data);
 
-        Self { result, server_tag }
+        Self {
+            result,
+            server_tag,
+            nonce: transcript.nonce(),
+            expires_at,
+        }
+    }
+
+    /// Like [`LoginCompletion::new`], additionally binding the tag to the TLS channel.
+    ///
+    /// `channel_binding` must come from the TLS session's channel binding API,
+    /// see [`Transcript::with_channel_binding`].
+    pub fn new_with_channel_binding(
+        result: LoginResult,
+        session_key: &[u8],
+        transcript: Transcript,
+        channel_binding: &[u8],
+    ) -> Self {
+        Self::new(
+            result,
+            session_key,
+            transcript.with_channel_binding(channel_binding),
+            None,
+        )
+    }
+
+    /// Like [`LoginCompletion::new`] for a server identifying itself with `server_id`,
+    /// see [`Transcript::with_server_id`].
+    pub fn new_with_server_id(
+        result: LoginResult,
+        session_key: &[u8],
+        transcript: Transcript,
+        server_id: &[u8],
+    ) -> Self {
+        Self::new(
+            result,
+            session_key,
+            transcript.with_server_id(server_id),
+            None,
+        )
     }
 
     /// Verifies the server’s confirmation tag.
     ///
     /// Returns `true` if both sides derived the same session key and
-    /// the transcript matches.
+    /// the transcript matches. The transcript is recomputed with the completion's
+    /// [`LoginCompletion::nonce`], callers should check it's the one they uploaded.
+    /// Oversized transcripts never verify.
     pub fn verify(
         &self,
         session_key: &[u8],
         request: &LoginRequest,
         response: &LoginResponse,
     ) -> bool {
-        let transcript = Transcript::compute_transcript(request, response);
-        self.transcript_verify(session_key, &transcript)
+        match Transcript::compute_transcript(request, response, None) {
+            Ok(transcript) => {
+                self.transcript_verify(session_key, &transcript.with_nonce(self.nonce))
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Like [`LoginCompletion::verify`], but only accepts tags from the server `server_id`.
+    pub fn verify_for_server(
+        &self,
+        session_key: &[u8],
+        request: &LoginRequest,
+        response: &LoginResponse,
+        server_id: &[u8],
+    ) -> bool {
+        match Transcript::compute_transcript_with_nonce(request, response, self.nonce) {
+            Ok(transcript) => {
+                self.transcript_verify(session_key, &transcript.with_server_id(server_id))
+            }
+            Err(_) => false,
+        }
     }
 
-    /// Verifies the tag using a precomputed [`Transcript`]
+    /// Verifies the tag using a precomputed [`Transcript`], including its nonce and
+    /// the completion's expiry.
     pub fn transcript_verify(&self, session_key: &[u8], transcript: &Transcript) -> bool {
         let k_confirm = derive_k_confirm(session_key);
-        let mut data = transcript.clone().into_inner();
+        let mut transcript = transcript.clone();
+        if let Some(expires_at) = self.expires_at {
+            transcript.append_u64(EXPIRES_AT_LABEL, expires_at);
+        }
+        let mut data = transcript.authenticated_data();
         data.extend_from_slice(b"server");
 
         let expected = compute_hmac(&k_confirm, data);
-        expected == self.server_tag
+        expected.ct_eq(&self.server_tag).into()
+    }
+
+    pub fn nonce(&self) -> [u8; 16] {
+        self.nonce
     }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// `true` once the expiry set by [`LoginCompletion::with_expiry`] has passed,
+    /// completions without one never expire.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| unix_now() >= expires_at)
+    }
+}
+
+/// Label of the optional session nonce field appended with [`Transcript::append_uuid`].
+pub const SESSION_NONCE_LABEL: &[u8] = b"SESSION_NONCE";
+
+/// Label of the [`LoginCompletion::expires_at`] field appended with [`Transcript::append_u64`].
+pub const EXPIRES_AT_LABEL: &[u8] = b"EXPIRES_AT";
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Label of the field appended by [`Transcript::with_channel_binding`].
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"CHANNEL_BINDING";
+
+/// Label of the field [`Transcript::with_server_id`] puts in front of the transcript.
+pub const SERVER_ID_LABEL: &[u8] = b"SERVER_ID";
+
+/// Labels of the [`Transcript::version`] and [`Transcript::nonce`] fields covered by the tags.
+const TRANSCRIPT_VERSION_LABEL: &[u8] = b"TRANSCRIPT_VERSION";
+const TRANSCRIPT_NONCE_LABEL: &[u8] = b"TRANSCRIPT_NONCE";
+
+fn default_transcript_version() -> u16 {
+    Transcript::VERSION
 }
 
 /// Derives a confirmation key `K_confirm` from the session key `K_session`.
@@ -194,9 +507,8 @@ impl LoginCompletion {
 /// Uses [HKDF](https://datatracker.ietf.org/doc/html/rfc5869) with SHA-256
 /// to expand the session key with the context string `"confirmation"`.
 pub(crate) fn derive_k_confirm(k_session: &[u8]) -> [u8; 32] {
-    let hk = Hkdf::<Sha256>::new(None, k_session);
     let mut okm = [0u8; 32];
-    hk.expand(b"confirmation", &mut okm).expect("HKDF expand");
+    okm.copy_from_slice(&crate::crypto::hkdf_expand(k_session, b"confirmation", 32));
     okm
 }
 
@@ -217,9 +529,34 @@ fn compute_hmac(k_confirm: &[u8], data: impl AsRef<[u8]>) -> [u8; 32] {
 )]
 pub struct Transcript {
     pub(crate) transcript: Vec<u8>,
+    /// format version, bumped when the transcript layout changes.
+    #[serde(default = "default_transcript_version")]
+    version: u16,
+    /// per login nonce, random for computed transcripts so captured tags can't be replayed.
+    #[serde(default)]
+    nonce: [u8; 16],
+    /// identifies the server the login was with, see [`Transcript::with_server_id`].
+    #[serde(default)]
+    server_id: Option<Vec<u8>>,
 }
 
 impl Transcript {
+    /// Default upper bound on transcript size in bytes.
+    ///
+    /// Bounds the allocation a malicious peer can cause with an oversized `LoginResponse`.
+    pub const MAX_SIZE: usize = 65536;
+
+    /// Prefix of every login transcript, separating it from other protocols' hashes.
+    pub const DOMAIN_SEPARATOR: &'static [u8] = b"LOGIN_TRANSCRIPT_V1";
+
+    /// Current [`Transcript::version`].
+    pub const VERSION: u16 = 1;
+
+    /// Starts a transcript whose parts are appended by step number, see [`TranscriptBuilder`].
+    pub fn builder() -> TranscriptBuilder {
+        TranscriptBuilder::default()
+    }
+
     /// Computes a deterministic binary transcript over the login request and response.
     ///
     /// The transcript is serialized using `bincode` for compact, stable encoding
@@ -229,39 +566,140 @@ impl Transcript {
     /// # Returns
     /// A concatenated byte vector:
     /// ```text
-    /// LOGIN_TRANSCRIPT_V1 || bincode(LoginRequest) || bincode(LoginResponse)
+    /// LOGIN_TRANSCRIPT_V1 || bincode(LoginRequest) || bincode(LoginResponse) [|| channel binding]
     /// ```
+    /// `channel_binding` is appended as by [`Transcript::with_channel_binding`], with `None`
+    /// the transcript only covers the two messages.
     ///
     /// # Purpose
     /// This transcript ensures both sides are confirming *the same exchange context*,
     /// protecting against message substitution, reordering, or replay attacks.
-    pub fn compute_transcript(request: &LoginRequest, response: &LoginResponse) -> Self {
-        let mut transcript = Vec::new();
+    ///
+    /// Every call picks a fresh random [`Transcript::nonce`], the verifying side sets the
+    /// peer's with [`Transcript::with_nonce`] or [`Transcript::compute_transcript_with_nonce`].
+    ///
+    /// Returns [`Error::TranscriptTooLarge`] if the transcript exceeds [`Transcript::MAX_SIZE`].
+    pub fn compute_transcript(
+        request: &LoginRequest,
+        response: &LoginResponse,
+        channel_binding: Option<&[u8]>,
+    ) -> Result<Self, Error> {
+        let transcript = Self::compute_transcript_with_max(request, response, Self::MAX_SIZE)?;
+        Ok(match channel_binding {
+            Some(channel_binding) => transcript.with_channel_binding(channel_binding),
+            None => transcript,
+        })
+    }
 
-        // Serialize deterministically
-        let req_bytes = bincode::encode_to_vec(request, bincode::config::standard())
-            .expect("Failed to serialize request");
-        let res_bytes = bincode::serde::encode_to_vec(response, bincode::config::standard())
-            .expect("Failed to serialize response");
+    /// Same as [`Transcript::compute_transcript`] with the peer's nonce.
+    pub fn compute_transcript_with_nonce(
+        request: &LoginRequest,
+        response: &LoginResponse,
+        nonce: [u8; 16],
+    ) -> Result<Self, Error> {
+        Ok(Self::compute_transcript(request, response, None)?.with_nonce(nonce))
+    }
+
+    /// Same as [`Transcript::compute_transcript`] with a caller supplied size limit.
+    pub fn compute_transcript_with_max(
+        request: &LoginRequest,
+        response: &LoginResponse,
+        max_size: usize,
+    ) -> Result<Self, Error> {
+        // Serialize deterministically, giving up as soon as the limit is passed
+        let mut budget = LimitedWriter::new(Self::DOMAIN_SEPARATOR.len(), max_size);
+        let req_bytes = budget.encode(|writer| {
+            bincode::encode_into_writer(request, writer, bincode::config::standard())
+        })?;
+        let res_bytes = budget.encode(|writer| {
+            bincode::serde::encode_into_writer(response, writer, bincode::config::standard())
+        })?;
 
-        transcript.extend_from_slice(b"LOGIN_TRANSCRIPT_V1");
-        transcript.extend_from_slice(&req_bytes);
-        transcript.extend_from_slice(&res_bytes);
+        let mut builder = Self::builder();
+        builder
+            .append_step(0, &req_bytes)?
+            .append_step(1, &res_bytes)?;
 
-        Self { transcript }
+        let transcript = Self::new_checked(builder.build().transcript, max_size)?;
+        Ok(transcript.with_nonce(rand::random()))
     }
 
-    pub fn decode(val: impl Into<String>) -> Result<Self, crate::errors::Error> {
+    pub fn decode(val: impl Into<String>) -> Result<Self, Error> {
         let value = val.into();
         Ok(Transcript::from_str(&value)?)
     }
 
     pub fn into_inner(self) -> Vec<u8> {
+        #[cfg(debug_assertions)]
+        assert!(
+            self.transcript.starts_with(Self::DOMAIN_SEPARATOR),
+            "login transcript is missing its domain separator"
+        );
         self.transcript
     }
 
+    /// Wraps already computed transcript bytes, with an all-zero nonce.
     pub fn new(data: Vec<u8>) -> Self {
-        Self { transcript: data }
+        Self {
+            transcript: data,
+            version: Self::VERSION,
+            nonce: [0u8; 16],
+            server_id: None,
+        }
+    }
+
+    /// Like [`Transcript::new`] but rejects data longer than `max_size`.
+    pub fn new_checked(data: Vec<u8>, max_size: usize) -> Result<Self, Error> {
+        if data.len() > max_size {
+            return Err(Error::TranscriptTooLarge(data.len(), max_size));
+        }
+        Ok(Self::new(data))
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn nonce(&self) -> [u8; 16] {
+        self.nonce
+    }
+
+    pub fn with_nonce(mut self, nonce: [u8; 16]) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn server_id(&self) -> Option<&[u8]> {
+        self.server_id.as_deref()
+    }
+
+    /// Binds the transcript to the server it was exchanged with.
+    ///
+    /// With several servers sharing users, a server can't pass off its transcript as
+    /// another one's: the tags are computed with the [`SERVER_ID_LABEL`] field in front of
+    /// the domain separator, so they only verify against a transcript with the same id.
+    pub fn with_server_id(mut self, server_id: &[u8]) -> Self {
+        self.server_id = Some(server_id.to_vec());
+        self
+    }
+
+    /// What the confirmation tags are computed over: the transcript followed by the
+    /// version and nonce fields (see [`Transcript::append_field`]), behind the server id
+    /// field if there is one.
+    pub(crate) fn authenticated_data(&self) -> Vec<u8> {
+        let mut transcript = self.clone();
+        transcript
+            .append_u64(TRANSCRIPT_VERSION_LABEL, u64::from(self.version))
+            .append_field(TRANSCRIPT_NONCE_LABEL, &self.nonce);
+        let data = transcript.into_inner();
+        match &self.server_id {
+            Some(server_id) => {
+                let mut prefix = Transcript::new(Vec::new());
+                prefix.append_field(SERVER_ID_LABEL, server_id);
+                [prefix.into_bytes(), data].concat()
+            }
+            None => data,
+        }
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -275,6 +713,154 @@ impl Transcript {
     pub fn append(&mut self, data: &[u8]) {
         self.transcript.extend_from_slice(data);
     }
+
+    /// Appends a labelled field of additional authenticated data.
+    ///
+    /// Lets protocol extensions (server timestamps, nonces, ...) be bound to the
+    /// confirmation tags without bumping the version marker. Appends:
+    /// ```text
+    /// u16_be(len(label)) || label || u32_be(len(data)) || data
+    /// ```
+    /// Fields are order dependent, both sides must append them in the same order.
+    ///
+    /// # Panics
+    /// If `label` is longer than `u16::MAX` or `data` longer than `u32::MAX` bytes.
+    pub fn append_field(&mut self, label: &[u8], data: &[u8]) -> &mut Self {
+        let label_len = u16::try_from(label.len()).expect("transcript field label too long");
+        let data_len = u32::try_from(data.len()).expect("transcript field data too long");
+        self.transcript.extend_from_slice(&label_len.to_be_bytes());
+        self.transcript.extend_from_slice(label);
+        self.transcript.extend_from_slice(&data_len.to_be_bytes());
+        self.transcript.extend_from_slice(data);
+        self
+    }
+
+    /// Binds another round of a multi-round exchange (e.g. a 2FA challenge) into the
+    /// transcript, after the rounds before it. Appends:
+    /// ```text
+    /// u32_le(len(label)) || label || u32_le(len(data)) || data
+    /// ```
+    /// Every round should get its own unique, non-empty label so rounds can't be
+    /// mistaken for each other, both sides must extend in the same order.
+    ///
+    /// # Panics
+    /// If `label` or `data` is longer than `u32::MAX` bytes.
+    pub fn extend(&mut self, label: &[u8], data: &[u8]) -> &mut Self {
+        debug_assert!(!label.is_empty(), "transcript round label is empty");
+        for part in [label, data] {
+            let len = u32::try_from(part.len()).expect("transcript round too long");
+            self.transcript.extend_from_slice(&len.to_le_bytes());
+            self.transcript.extend_from_slice(part);
+        }
+        self
+    }
+
+    /// Appends `value` as a big-endian field, see [`Transcript::append_field`].
+    pub fn append_u64(&mut self, label: &[u8], value: u64) -> &mut Self {
+        self.append_field(label, &value.to_be_bytes())
+    }
+
+    /// Appends the 16 bytes of `id` as a field, see [`Transcript::append_field`].
+    pub fn append_uuid(&mut self, label: &[u8], id: Uuid) -> &mut Self {
+        self.append_field(label, id.as_bytes())
+    }
+
+    /// Binds the transcript to the underlying TLS channel.
+    ///
+    /// `channel_binding` should be obtained from the TLS session's channel binding API:
+    /// the `tls-exporter` value ([RFC 9266](https://datatracker.ietf.org/doc/html/rfc9266))
+    /// on TLS 1.3, or `tls-unique` on TLS 1.2. A TLS terminating proxy relaying the
+    /// OPAQUE messages sees a different channel on each side, so the confirmation tags
+    /// no longer verify.
+    ///
+    /// Appends the [`CHANNEL_BINDING_LABEL`] field, see [`Transcript::append_field`].
+    pub fn with_channel_binding(mut self, channel_binding: &[u8]) -> Self {
+        self.append_field(CHANNEL_BINDING_LABEL, channel_binding);
+        self
+    }
+}
+
+/// Assembles a [`Transcript`] from numbered steps of the exchange.
+///
+/// Steps must be appended with strictly increasing numbers, so two callers can't
+/// silently produce different transcripts by appending the same parts in a different order.
+#[derive(Debug, Default, Clone)]
+pub struct TranscriptBuilder {
+    steps: Vec<(u64, Vec<u8>)>,
+}
+
+impl TranscriptBuilder {
+    /// Appends the bytes of `step`.
+    ///
+    /// Returns [`Error::TranscriptStepOutOfOrder`] unless `step` is greater than every
+    /// step appended before it.
+    pub fn append_step(&mut self, step: u64, data: &[u8]) -> Result<&mut Self, Error> {
+        if let Some(&(last_step, _)) = self.steps.last()
+            && step <= last_step
+        {
+            return Err(Error::TranscriptStepOutOfOrder(step, last_step));
+        }
+        self.steps.push((step, data.to_vec()));
+        Ok(self)
+    }
+
+    /// Concatenates the steps in order behind [`Transcript::DOMAIN_SEPARATOR`].
+    pub fn build(mut self) -> Transcript {
+        self.steps.sort_by_key(|(step, _)| *step);
+        let mut transcript = Transcript::DOMAIN_SEPARATOR.to_vec();
+        for (_, data) in self.steps {
+            transcript.extend_from_slice(&data);
+        }
+        Transcript::new(transcript)
+    }
+}
+
+/// bincode writer with a byte budget shared across the messages of one transcript.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    used: usize,
+    max_size: usize,
+    overflow: Option<usize>,
+}
+
+impl LimitedWriter {
+    /// `used` counts the bytes the transcript already holds before the messages.
+    fn new(used: usize, max_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            used,
+            max_size,
+            overflow: None,
+        }
+    }
+
+    /// Runs `encode` against the remaining budget and returns the bytes it wrote.
+    fn encode(
+        &mut self,
+        encode: impl FnOnce(&mut Self) -> Result<(), bincode::error::EncodeError>,
+    ) -> Result<Vec<u8>, Error> {
+        let result = encode(self);
+        if let Some(len) = self.overflow {
+            return Err(Error::TranscriptTooLarge(len, self.max_size));
+        }
+        result.expect("Failed to serialize login message");
+        Ok(std::mem::take(&mut self.buf))
+    }
+}
+
+impl bincode::enc::write::Writer for LimitedWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), bincode::error::EncodeError> {
+        let used = self.used + bytes.len();
+        if used > self.max_size {
+            self.overflow = Some(used);
+            return Err(bincode::error::EncodeError::Other(
+                "transcript size limit exceeded",
+            ));
+        }
+        self.used = used;
+        self.buf.extend_from_slice(bytes);
+        Ok(())
+    }
 }
 
 impl fmt::Display for Transcript {
@@ -287,17 +873,19 @@ impl FromStr for Transcript {
     type Err = base64::DecodeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        STANDARD.decode(s).map(|bytes| Self { transcript: bytes })
+        STANDARD.decode(s).map(Self::new)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::register_user;
+    use crate::client::auth::Client;
+    use crate::server::auth::{Server, ServerSetup};
     use bincode;
     use rand::{RngCore, rngs::OsRng};
     use serde_json;
-    use std::str::FromStr;
     use uuid::Uuid;
 
     fn random_session_key() -> [u8; 32] {
@@ -334,4 +922,378 @@ mod tests {
         assert_eq!(original, decoded);
         assert_eq!(decoded.as_bytes(), data.as_slice());
     }
+
+    #[test]
+    fn transcript_rejects_oversized_data() {
+        let data = vec![0u8; Transcript::MAX_SIZE + 1];
+        let result = Transcript::new_checked(data, Transcript::MAX_SIZE);
+        assert!(matches!(
+            result,
+            Err(Error::TranscriptTooLarge(len, max)) if len == Transcript::MAX_SIZE + 1 && max == Transcript::MAX_SIZE
+        ));
+
+        let data = vec![0u8; Transcript::MAX_SIZE];
+        assert!(Transcript::new_checked(data, Transcript::MAX_SIZE).is_ok());
+    }
+
+    #[test]
+    fn login_transcript_is_well_below_limit() -> Result<(), Error> {
+        let (request, response, _) = login_exchange()?;
+
+        let transcript = Transcript::compute_transcript(&request, &response, None)?;
+        assert!(transcript.as_bytes().len() < Transcript::MAX_SIZE / 16);
+        Ok(())
+    }
+
+    fn login_exchange() -> Result<(LoginRequest, LoginResponse, CredentialFinalization), Error> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup);
+        let stored = register_user(&server, "user", "password")?;
+        let client = Client::new("password");
+
+        let (client_login, credential_request) = client.start_login()?;
+        let request = LoginRequest::new("user", credential_request.clone());
+        let (_, credential_response) = server.start_login(stored, credential_request, "user")?;
+        let response = LoginResponse::PAKE((Uuid::new_v4(), credential_response.clone()));
+        let (_, finalization) = client.finish_login(client_login, credential_response)?;
+        Ok((request, response, finalization))
+    }
+
+    #[test]
+    fn channel_binding_must_match() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+        let client_binding = [1u8; 32];
+        let proxy_binding = [2u8; 32];
+
+        let upload = LoginUpload::builder(Uuid::new_v4(), finalization, &key, &request, &response)
+            .channel_binding(&client_binding)
+            .build()?;
+        let transcript =
+            Transcript::compute_transcript_with_nonce(&request, &response, upload.nonce())?;
+
+        assert!(upload.verify_transcript(
+            &key,
+            &transcript.clone().with_channel_binding(&client_binding)
+        ));
+        assert!(!upload.verify_transcript(
+            &key,
+            &transcript.clone().with_channel_binding(&proxy_binding)
+        ));
+        // an unbound transcript doesn't verify either
+        assert!(!upload.verify(&key, &request, &response));
+
+        let completion = LoginCompletion::new_with_channel_binding(
+            LoginResult::Unauthorized(UnauthorizedReason::InvalidCredentials),
+            &key,
+            transcript.clone(),
+            &client_binding,
+        );
+        assert!(completion.transcript_verify(
+            &key,
+            &transcript.clone().with_channel_binding(&client_binding)
+        ));
+        assert!(
+            !completion.transcript_verify(&key, &transcript.with_channel_binding(&proxy_binding))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn transcript_builder_concatenates_steps() -> Result<(), Error> {
+        let mut builder = Transcript::builder();
+        builder
+            .append_step(0, b"request")?
+            .append_step(5, b"response")?;
+        let transcript = builder.build();
+
+        let mut expected = Transcript::DOMAIN_SEPARATOR.to_vec();
+        expected.extend_from_slice(b"requestresponse");
+        assert_eq!(transcript.into_inner(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn transcript_builder_rejects_out_of_order_steps() -> Result<(), Error> {
+        let mut builder = Transcript::builder();
+        builder.append_step(1, b"response")?;
+        assert!(matches!(
+            builder.append_step(0, b"request"),
+            Err(Error::TranscriptStepOutOfOrder(0, 1))
+        ));
+        assert!(matches!(
+            builder.append_step(1, b"again"),
+            Err(Error::TranscriptStepOutOfOrder(1, 1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn append_field_encoding() {
+        let mut transcript = Transcript::new(b"T".to_vec());
+        transcript.append_field(b"ab", b"xyz").append_u64(b"t", 1);
+
+        let mut expected = b"T".to_vec();
+        expected.extend_from_slice(&[0, 2, b'a', b'b', 0, 0, 0, 3, b'x', b'y', b'z']);
+        expected.extend_from_slice(&[0, 1, b't', 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(transcript.as_bytes(), expected.as_slice());
+    }
+
+    #[test]
+    fn appended_fields_are_order_dependent() {
+        let id = Uuid::new_v4();
+        let mut first = Transcript::new(Vec::new());
+        first.append_u64(b"timestamp", 42).append_uuid(b"nonce", id);
+        let mut second = Transcript::new(Vec::new());
+        second
+            .append_uuid(b"nonce", id)
+            .append_u64(b"timestamp", 42);
+        assert_ne!(first, second);
+
+        // the length prefixes keep label/data boundaries unambiguous
+        let mut split = Transcript::new(Vec::new());
+        split.append_field(b"ab", b"c");
+        let mut shifted = Transcript::new(Vec::new());
+        shifted.append_field(b"a", b"bc");
+        assert_ne!(split, shifted);
+    }
+
+    #[test]
+    fn session_nonce_must_match() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+        let nonce = Uuid::new_v4();
+
+        let upload = LoginUpload::new(
+            Uuid::new_v4(),
+            finalization,
+            &key,
+            &request,
+            &response,
+            Transcript::MAX_SIZE,
+            Some(nonce),
+        )?;
+        let transcript =
+            Transcript::compute_transcript_with_nonce(&request, &response, upload.nonce())?;
+        let mut bound = transcript.clone();
+        bound.append_uuid(SESSION_NONCE_LABEL, nonce);
+        assert!(upload.verify_transcript(&key, &bound));
+        assert!(!upload.verify(&key, &request, &response));
+
+        let completion = LoginCompletion::new(
+            LoginResult::Unauthorized(UnauthorizedReason::InvalidCredentials),
+            &key,
+            transcript.clone(),
+            Some(nonce),
+        );
+        assert!(completion.transcript_verify(&key, &bound));
+        assert!(!completion.transcript_verify(&key, &transcript));
+        Ok(())
+    }
+
+    #[test]
+    fn transcript_nonce_is_authenticated() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+
+        let first = Transcript::compute_transcript(&request, &response, None)?;
+        let second = Transcript::compute_transcript(&request, &response, None)?;
+        assert_eq!(first.version(), Transcript::VERSION);
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert_ne!(first.nonce(), second.nonce());
+
+        let upload =
+            LoginUpload::from_transcript(Uuid::new_v4(), finalization, &key, first.clone());
+        assert_eq!(upload.nonce(), first.nonce());
+        assert!(upload.verify(&key, &request, &response));
+        assert!(upload.verify_transcript(&key, &first));
+        assert!(!upload.verify_transcript(&key, &second));
+
+        let completion =
+            LoginCompletion::new(LoginResult::PasswordReset, &key, first.clone(), None);
+        assert_eq!(completion.nonce(), upload.nonce());
+        assert!(completion.verify(&key, &request, &response));
+        assert!(!completion.transcript_verify(&key, &second));
+
+        // the nonce travels with the messages
+        let json = serde_json::to_string(&completion).unwrap();
+        let decoded: LoginCompletion = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(&key, &request, &response));
+        Ok(())
+    }
+
+    #[test]
+    fn compute_transcript_stops_at_the_size_limit() -> Result<(), Error> {
+        let (request, response, _) = login_exchange()?;
+        let full =
+            Transcript::compute_transcript_with_max(&request, &response, Transcript::MAX_SIZE)?;
+        let len = full.as_bytes().len();
+
+        assert!(Transcript::compute_transcript_with_max(&request, &response, len).is_ok());
+        // too small for anything, for the request, and for the response alone
+        for max in [0, Transcript::DOMAIN_SEPARATOR.len() + 1, len - 1] {
+            assert!(matches!(
+                Transcript::compute_transcript_with_max(&request, &response, max),
+                Err(Error::TranscriptTooLarge(actual, limit)) if actual > max && limit == max
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compute_transcript_appends_channel_binding() -> Result<(), Error> {
+        let (request, response, _) = login_exchange()?;
+        let binding = [3u8; 32];
+
+        let unbound = Transcript::compute_transcript(&request, &response, None)?;
+        let bound = Transcript::compute_transcript(&request, &response, Some(&binding))?;
+        assert_eq!(
+            unbound.as_bytes(),
+            Transcript::compute_transcript_with_max(&request, &response, Transcript::MAX_SIZE)?
+                .as_bytes()
+        );
+        assert_eq!(
+            bound.as_bytes(),
+            unbound.with_channel_binding(&binding).as_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn any_flipped_tag_bit_fails_verification() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+        let transcript = Transcript::compute_transcript(&request, &response, None)?;
+        let mut upload =
+            LoginUpload::from_transcript(Uuid::new_v4(), finalization, &key, transcript.clone());
+        let mut completion =
+            LoginCompletion::new(LoginResult::PasswordReset, &key, transcript.clone(), None);
+        assert!(upload.verify_transcript(&key, &transcript));
+        assert!(completion.transcript_verify(&key, &transcript));
+
+        for bit in 0..256 {
+            let mask = 1u8 << (bit % 8);
+            upload.client_tag[bit / 8] ^= mask;
+            completion.server_tag[bit / 8] ^= mask;
+            assert!(!upload.verify_transcript(&key, &transcript));
+            assert!(!completion.transcript_verify(&key, &transcript));
+            upload.client_tag[bit / 8] ^= mask;
+            completion.server_tag[bit / 8] ^= mask;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn expiry_is_covered_by_the_tag() -> Result<(), Error> {
+        let (request, response, _) = login_exchange()?;
+        let key = random_session_key();
+        let transcript = Transcript::compute_transcript(&request, &response, None)?;
+
+        let completion = LoginCompletion::with_expiry(
+            LoginResult::PasswordReset,
+            &key,
+            transcript.clone(),
+            None,
+            Duration::from_secs(60),
+        );
+        assert!(completion.transcript_verify(&key, &transcript));
+        assert!(!completion.is_expired());
+
+        let mut extended = completion.clone();
+        extended.expires_at = completion.expires_at.map(|at| at + 3600);
+        assert!(!extended.transcript_verify(&key, &transcript));
+        let mut unlimited = completion.clone();
+        unlimited.expires_at = None;
+        assert!(!unlimited.transcript_verify(&key, &transcript));
+
+        let expired = LoginCompletion::with_expiry(
+            LoginResult::PasswordReset,
+            &key,
+            transcript,
+            None,
+            Duration::ZERO,
+        );
+        assert!(expired.is_expired());
+        assert!(!LoginCompletion::unauthorized(UnauthorizedReason::RateLimited).is_expired());
+        Ok(())
+    }
+
+    #[test]
+    fn extended_rounds_are_order_dependent() {
+        let mut first = Transcript::new(b"T".to_vec());
+        first.extend(b"otp", b"123456").extend(b"challenge", b"abc");
+        let mut second = Transcript::new(b"T".to_vec());
+        second
+            .extend(b"challenge", b"abc")
+            .extend(b"otp", b"123456");
+        assert_ne!(first.as_bytes(), second.as_bytes());
+
+        let mut expected = b"T".to_vec();
+        expected.extend_from_slice(&[3, 0, 0, 0, b'o', b't', b'p', 6, 0, 0, 0]);
+        expected.extend_from_slice(b"123456");
+        assert!(first.as_bytes().starts_with(&expected));
+    }
+
+    #[test]
+    fn channel_binding_and_server_id_combine() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+        let binding = [1u8; 32];
+
+        let upload = LoginUpload::builder(Uuid::new_v4(), finalization, &key, &request, &response)
+            .channel_binding(&binding)
+            .server_id(b"a.example")
+            .build()?;
+        let transcript =
+            Transcript::compute_transcript_with_nonce(&request, &response, upload.nonce())?;
+
+        assert!(
+            upload.verify_transcript(
+                &key,
+                &transcript
+                    .clone()
+                    .with_channel_binding(&binding)
+                    .with_server_id(b"a.example")
+            )
+        );
+        assert!(
+            !upload.verify_transcript(&key, &transcript.clone().with_channel_binding(&binding))
+        );
+        assert!(!upload.verify_for_server(&key, &request, &response, b"a.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn channel_binding_is_a_labelled_field() {
+        let bound = Transcript::new(b"T".to_vec()).with_channel_binding(b"cb");
+        let mut expected = Transcript::new(b"T".to_vec());
+        expected.append_field(CHANNEL_BINDING_LABEL, b"cb");
+        assert_eq!(bound.as_bytes(), expected.as_bytes());
+    }
+
+    #[test]
+    fn server_id_must_match() -> Result<(), Error> {
+        let (request, response, finalization) = login_exchange()?;
+        let key = random_session_key();
+
+        let upload = LoginUpload::builder(Uuid::new_v4(), finalization, &key, &request, &response)
+            .server_id(b"a.example")
+            .build()?;
+        assert!(upload.verify_for_server(&key, &request, &response, b"a.example"));
+        assert!(!upload.verify_for_server(&key, &request, &response, b"b.example"));
+        assert!(!upload.verify(&key, &request, &response));
+
+        let transcript =
+            Transcript::compute_transcript_with_nonce(&request, &response, upload.nonce())?;
+        let completion = LoginCompletion::new_with_server_id(
+            LoginResult::PasswordReset,
+            &key,
+            transcript,
+            b"a.example",
+        );
+        assert!(completion.verify_for_server(&key, &request, &response, b"a.example"));
+        assert!(!completion.verify_for_server(&key, &request, &response, b"b.example"));
+        assert!(!completion.verify(&key, &request, &response));
+        Ok(())
+    }
 }
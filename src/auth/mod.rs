@@ -1,13 +1,44 @@
 pub mod challenge;
 pub mod registration;
+use crate::auth::registration::RegistrationNonce;
 use crate::client::auth::Client;
 use crate::errors::ProtocolError;
 use crate::server::auth::Server;
+use opaque_ke::key_exchange::group::KeGroup;
 use serde_derive::{Deserialize, Serialize};
-
+use sha2::digest::OutputSizeUser;
+use sha2::digest::core_api::CoreProxy;
+#[allow(deprecated)]
+use sha2::digest::generic_array::ArrayLength;
+use sha2::digest::typenum::{Sum, U32};
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+/// Ristretto255 with TripleDH, used unless a [`Client`] or [`Server`] is given another suite.
 pub struct DefaultCipherSuite;
 
-use opaque_ke::CipherSuite;
+pub use opaque_ke::CipherSuite;
+
+/// Output size of `CS`'s OPRF hash, opaque-ke keeps its own alias for it private.
+pub type OprfHashLen<CS> = <<<<CS as CipherSuite>::OprfCs as voprf::CipherSuite>::Hash as CoreProxy>::Core as OutputSizeUser>::OutputSize;
+/// Nonce and OPRF hash prefixing a masked credential response (the nonce is 32 bytes).
+pub type MaskedNonceLen<CS> = Sum<U32, OprfHashLen<CS>>;
+/// Length of a masked credential response: nonce, OPRF hash and the server's public key.
+///
+/// Logins are only generic over suites for which opaque-ke can size this, so
+/// [`Client::finish_login`] and [`Server::start_login`] repeat its bounds:
+/// `U32: Add<OprfHashLen<CS>>`, `MaskedNonceLen<CS>: ByteLen + Add<PkLen>` and
+/// `MaskedResponseLen<CS>: ByteLen`.
+pub type MaskedResponseLen<CS> =
+    Sum<MaskedNonceLen<CS>, <<CS as CipherSuite>::KeGroup as KeGroup>::PkLen>;
+
+/// `ArrayLength<u8>`, which generic-array 0.14 deprecates but opaque-ke still bounds on.
+#[allow(deprecated)]
+pub trait ByteLen: ArrayLength<u8> {}
+
+#[allow(deprecated)]
+impl<T: ArrayLength<u8>> ByteLen for T {}
 
 impl CipherSuite for DefaultCipherSuite {
     type OprfCs = opaque_ke::Ristretto255;
@@ -16,41 +47,300 @@ impl CipherSuite for DefaultCipherSuite {
     type Ksf = opaque_ke::ksf::Identity;
 }
 
+/// [`DefaultCipherSuite`] with Argon2id password hardening.
+///
+/// Without [`Client::with_ksf`] argon2's default parameters are used, tune them with
+/// [`Argon2CipherSuite::builder`]. The parameters are part of the credentials: registration
+/// and every login have to use the same ones.
+pub struct Argon2CipherSuite;
+
+impl CipherSuite for Argon2CipherSuite {
+    type OprfCs = opaque_ke::Ristretto255;
+    type KeGroup = opaque_ke::Ristretto255;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = argon2::Argon2<'static>;
+}
+
+impl Argon2CipherSuite {
+    pub fn builder() -> Argon2Builder {
+        Argon2Builder::default()
+    }
+}
+
+/// Argon2id parameters for [`Argon2CipherSuite`], defaults to argon2's recommended ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Builder {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Default for Argon2Builder {
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Builder {
+    /// Memory cost in KiB, at least 8 per lane.
+    pub fn memory_kib(mut self, memory_kib: u32) -> Self {
+        self.memory_kib = memory_kib;
+        self
+    }
+
+    pub fn iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Number of lanes.
+    pub fn parallelism(mut self, parallelism: u32) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Fails with [`crate::errors::Error::Internal`] if argon2 rejects the parameters.
+    pub fn build(self) -> Result<argon2::Argon2<'static>, crate::errors::Error> {
+        let params = argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| {
+                crate::errors::Error::Internal(format!("invalid argon2 parameters: {e}"))
+            })?;
+        Ok(argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params,
+        ))
+    }
+}
+
+/// How a [`Server`] turns usernames into the OPAQUE credential identifier.
+///
+/// Registration and login derive their keys from it, so "Alice" can't log in as "alice"
+/// unless the policy maps both to the same identifier. Changing the policy of a server with
+/// existing registrations locks out users whose names it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum UsernamePolicy {
+    /// usernames are used as given.
+    #[default]
+    CaseSensitive,
+    Lowercase,
+    /// Unicode NFKC followed by lowercasing, e.g. "ＡＬＩＣＥ" becomes "alice".
+    Normalize,
+}
+
+impl UsernamePolicy {
+    pub fn apply(self, username: &str) -> String {
+        match self {
+            UsernamePolicy::CaseSensitive => username.to_string(),
+            UsernamePolicy::Lowercase => username.to_lowercase(),
+            UsernamePolicy::Normalize => username.nfkc().collect::<String>().to_lowercase(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum LoginResult {
     /// Login Successful Access Token Within.
     Success(String),
     /// Password reset required, prompt user, or generate appropriately
     PasswordReset,
-    Unauthorized,
+    Unauthorized(UnauthorizedReason),
     UnknownServer(String),
 }
 
-/// takes in a username and password and produces a ServerRegistration
-pub fn register_user(
-    server: &Server,
+/// Why a login (or token refresh) was rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnauthorizedReason {
+    InvalidCredentials,
+    AccountLocked,
+    AccountNotFound,
+    SessionExpired,
+    RateLimited,
+    /// the session was ended with a logout.
+    LoggedOut,
+    /// the server couldn't be reached or didn't answer in time, the credentials weren't checked.
+    ServerUnreachable,
+}
+
+impl UnauthorizedReason {
+    /// Best guess at the reason behind a failed login request.
+    ///
+    /// HTTP statuses map to `AccountNotFound` (404), `AccountLocked` (423),
+    /// `RateLimited` (429) and `SessionExpired` (401), other network errors and timeouts
+    /// to `ServerUnreachable`, anything else is `InvalidCredentials`.
+    pub fn from_error(error: &crate::errors::Error) -> Self {
+        use crate::errors::Error;
+        let status = match error {
+            Error::LoginUnauthorized(reason) => return *reason,
+            _ => error.http_status(),
+        };
+        match (status, error) {
+            (Some(401), _) => UnauthorizedReason::SessionExpired,
+            (Some(404), _) => UnauthorizedReason::AccountNotFound,
+            (Some(423), _) => UnauthorizedReason::AccountLocked,
+            (Some(429), _) => UnauthorizedReason::RateLimited,
+            (_, Error::Network(_) | Error::Timeout(_)) => UnauthorizedReason::ServerUnreachable,
+            _ => UnauthorizedReason::InvalidCredentials,
+        }
+    }
+}
+
+impl fmt::Display for UnauthorizedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            UnauthorizedReason::InvalidCredentials => "invalid credentials",
+            UnauthorizedReason::AccountLocked => "account locked",
+            UnauthorizedReason::AccountNotFound => "account not found",
+            UnauthorizedReason::SessionExpired => "session expired",
+            UnauthorizedReason::RateLimited => "rate limited",
+            UnauthorizedReason::LoggedOut => "logged out",
+            UnauthorizedReason::ServerUnreachable => "server unreachable",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl fmt::Display for LoginResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // the token is a credential, keep it out of messages
+            LoginResult::Success(_) => write!(f, "login successful"),
+            LoginResult::PasswordReset => write!(f, "password reset required"),
+            LoginResult::Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+            LoginResult::UnknownServer(server) => write!(f, "unknown server: {}", server),
+        }
+    }
+}
+
+/// Every variant but [`LoginResult::Success`] describes a failed login.
+impl std::error::Error for LoginResult {}
+
+/// takes in a username and password and produces a ServerRegistration, the username
+/// goes through the server's [`UsernamePolicy`].
+pub fn register_user<CS: CipherSuite>(
+    server: &Server<CS>,
     username: impl Into<String>,
     password: impl Into<String>,
-) -> Result<crate::server::auth::ServerRegistration, ProtocolError> {
-    let client = Client::new(password);
+) -> Result<crate::server::auth::ServerRegistration<CS>, ProtocolError> {
+    let client = Client::<CS>::with_cipher_suite(password);
     let (client_reg, regreq) = client.start_registration()?;
     let response = server.start_registration(regreq, username)?;
     let upload = client.finish_registration(client_reg, response)?;
     Ok(server.finish_registration(upload))
 }
 
+/// like [`register_user`], but safe to retry.
+///
+/// if `nonce` was already used to register `username` (and hasn't expired) the
+/// stored `ServerRegistration` is returned without re-running the OPAQUE protocol.
+pub fn register_user_idempotent<CS: CipherSuite>(
+    nonce: Uuid,
+    server: &Server<CS>,
+    username: impl Into<String>,
+    password: impl Into<String>,
+) -> Result<crate::server::auth::ServerRegistration<CS>, ProtocolError> {
+    let username = server.username_policy().apply(&username.into());
+    if let Some(existing) = server.registrations().get(&nonce, &username) {
+        return Ok(existing);
+    }
+    let registration = register_user(server, username.clone(), password)?;
+    // a concurrent retry may have finished first, both callers get its record
+    Ok(server
+        .registrations()
+        .insert_if_absent(RegistrationNonce::new(nonce, username), registration))
+}
+
+/// Async [`register_user`]: the client side OPAQUE computations run on tokio's blocking
+/// pool so they don't stall the runtime.
+///
+/// Returns [`Error::TaskFailed`](crate::errors::Error::TaskFailed) if a blocking task panics
+/// or the runtime shuts down while it runs.
+pub async fn register_user_async<CS>(
+    server: &Server<CS>,
+    username: impl Into<String>,
+    password: impl Into<String>,
+) -> Result<crate::server::auth::ServerRegistration<CS>, crate::errors::Error>
+where
+    CS: CipherSuite + Send + 'static,
+    CS::Ksf: Send + 'static,
+    opaque_ke::ClientRegistration<CS>: Send,
+    opaque_ke::RegistrationRequest<CS>: Send,
+    opaque_ke::RegistrationResponse<CS>: Send,
+    opaque_ke::RegistrationUpload<CS>: Send,
+{
+    let username = username.into();
+    let client = Client::<CS>::with_cipher_suite(password);
+    let (client, started) = tokio::task::spawn_blocking(move || {
+        let started = client.start_registration();
+        (client, started)
+    })
+    .await?;
+    let (client_reg, regreq) = started?;
+    let response = server.start_registration(regreq, username)?;
+    let upload =
+        tokio::task::spawn_blocking(move || client.finish_registration(client_reg, response))
+            .await??;
+    Ok(server.finish_registration(upload))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::client::auth::LoginRequest;
     use crate::server::auth::CredentialRequest;
     use crate::server::auth::LoginResponse;
+    use crate::server::auth::{ServerRegistration, ServerSetup};
     use crate::{client::auth::Client, server::auth::Server};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
     use opaque_ke::errors::ProtocolError;
     use rand::rngs::OsRng;
+    use std::time::Duration;
     use uuid::Uuid;
 
     #[test]
+    fn login_result_display() {
+        assert_eq!(
+            LoginResult::Success("secret-token".to_string()).to_string(),
+            "login successful"
+        );
+        assert_eq!(
+            LoginResult::PasswordReset.to_string(),
+            "password reset required"
+        );
+        assert_eq!(
+            LoginResult::Unauthorized(UnauthorizedReason::AccountLocked).to_string(),
+            "unauthorized: account locked"
+        );
+        assert_eq!(
+            LoginResult::UnknownServer("https://a.example".to_string()).to_string(),
+            "unknown server: https://a.example"
+        );
+    }
+
+    #[test]
+    fn login_result_into_error() {
+        use crate::errors::Error;
+        assert!(matches!(
+            Error::from(LoginResult::Unauthorized(UnauthorizedReason::RateLimited)),
+            Error::LoginUnauthorized(UnauthorizedReason::RateLimited)
+        ));
+        assert!(matches!(
+            Error::from(LoginResult::PasswordReset),
+            Error::Internal(msg) if msg == "password reset required"
+        ));
+        assert!(matches!(
+            Error::from(LoginResult::UnknownServer("https://a.example".to_string())),
+            Error::Internal(msg) if msg == "https://a.example"
+        ));
+    }
+
+    #[test]
+    #[allow(unused_mut, clippy::needless_borrows_for_generic_args)]
     fn serialization_round_trip() -> Result<(), crate::errors::Error> {
         let setup = ServerSetup::new(&mut OsRng);
         let server = Server::new(setup);
@@ -66,9 +356,9 @@ mod tests {
         let parsed_request = serde_json::from_str(&request_json)?;
 
         assert_eq!(request, parsed_request);
-        let parsed_credential_request = CredentialRequest::deserialize(&base64::decode(
-            &parsed_request.credentials.as_bytes(),
-        )?)?;
+        let parsed_credential_request = CredentialRequest::deserialize(
+            &STANDARD.decode(&parsed_request.credentials.as_bytes())?,
+        )?;
         assert_eq!(parsed_credential_request, credential_request);
 
         let (server_login, credential_response) =
@@ -92,6 +382,191 @@ mod tests {
         Ok(())
     }
 
+    /// Same primitives as the default, but a distinct type the generics have to carry through.
+    struct CustomCipherSuite;
+
+    impl CipherSuite for CustomCipherSuite {
+        type OprfCs = opaque_ke::Ristretto255;
+        type KeGroup = opaque_ke::Ristretto255;
+        type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+        type Ksf = opaque_ke::ksf::Identity;
+    }
+
+    #[test]
+    fn custom_cipher_suite_round_trip() -> Result<(), crate::errors::Error> {
+        let server = Server::from_setup(ServerSetup::<CustomCipherSuite>::new(&mut OsRng));
+        let registration = register_user(&server, "grace", "password")?;
+
+        let client = Client::<CustomCipherSuite>::with_cipher_suite("password");
+        let (client_login, request) = client.start_login()?;
+        let (server_login, response) = server.start_login(registration, request, "grace")?;
+        let (client_key, finalization) = client.finish_login(client_login, response)?;
+        let server_key = server.finish_login(server_login, finalization)?;
+        assert_eq!(client_key, server_key);
+        Ok(())
+    }
+
+    fn argon2_login(
+        server: &Server<Argon2CipherSuite>,
+        stored: crate::server::auth::ServerRegistration<Argon2CipherSuite>,
+        client: Client<Argon2CipherSuite>,
+    ) -> Result<bool, crate::errors::Error> {
+        let (client_login, request) = client.start_login()?;
+        let (server_login, response) = server.start_login(stored, request, "ivan")?;
+        let Ok((client_key, finalization)) = client.finish_login(client_login, response) else {
+            return Ok(false);
+        };
+        Ok(client_key == server.finish_login(server_login, finalization)?)
+    }
+
+    #[test]
+    fn argon2_suite_needs_the_same_parameters() -> Result<(), crate::errors::Error> {
+        // cheap parameters, the defaults take a while in debug builds
+        let params = Argon2CipherSuite::builder()
+            .memory_kib(64)
+            .iterations(1)
+            .parallelism(1);
+        let server = Server::from_setup(ServerSetup::<Argon2CipherSuite>::new(&mut OsRng));
+
+        let client =
+            Client::<Argon2CipherSuite>::with_cipher_suite("password").with_ksf(params.build()?);
+        let (client_reg, request) = client.start_registration()?;
+        let response = server.start_registration(request, "ivan")?;
+        let stored = server.finish_registration(client.finish_registration(client_reg, response)?);
+
+        assert!(argon2_login(&server, stored.clone(), client)?);
+        let other = Client::<Argon2CipherSuite>::with_cipher_suite("password")
+            .with_ksf(params.iterations(2).build()?);
+        assert!(!argon2_login(&server, stored, other)?);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_argon2_parameters_are_rejected() {
+        assert!(Argon2CipherSuite::builder().parallelism(0).build().is_err());
+        assert!(Argon2CipherSuite::builder().memory_kib(1).build().is_err());
+        assert!(Argon2CipherSuite::builder().build().is_ok());
+    }
+
+    #[test]
+    fn idempotent_registration_returns_stored_record() -> Result<(), ProtocolError> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup);
+        let nonce = Uuid::new_v4();
+
+        let first = register_user_idempotent(nonce, &server, "frank", "password")?;
+        let second = register_user_idempotent(nonce, &server, "frank", "password")?;
+        assert_eq!(first.serialize(), second.serialize());
+        assert_eq!(server.registrations().len(), 1);
+
+        // a fresh nonce re-runs the protocol and produces a new record
+        let third = register_user_idempotent(Uuid::new_v4(), &server, "frank", "password")?;
+        assert_ne!(first.serialize(), third.serialize());
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent_registration_is_scoped_to_username() -> Result<(), ProtocolError> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup);
+        let nonce = Uuid::new_v4();
+
+        let frank = register_user_idempotent(nonce, &server, "frank", "password")?;
+        let grace = register_user_idempotent(nonce, &server, "grace", "password")?;
+        assert_ne!(frank.serialize(), grace.serialize());
+        assert_eq!(
+            server
+                .registrations()
+                .get(&nonce, "frank")
+                .map(|r| r.serialize()),
+            Some(frank.serialize())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_idempotent_registrations_share_one_record() {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup);
+        let nonce = Uuid::new_v4();
+
+        let records: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| register_user_idempotent(nonce, &server, "heidi", "password"))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap().unwrap().serialize())
+                .collect()
+        });
+        assert!(records.iter().all(|record| *record == records[0]));
+        assert_eq!(
+            server
+                .registrations()
+                .get(&nonce, "heidi")
+                .map(|r| r.serialize()),
+            Some(records[0])
+        );
+        assert_eq!(server.registrations().len(), 1);
+    }
+
+    #[test]
+    fn replayed_registration_request_is_memoized() -> Result<(), ProtocolError> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup).with_registration_cache(Duration::from_secs(60));
+        let client = Client::new("password");
+        let (_, request) = client.start_registration()?;
+
+        let first = server.start_registration_cached(request.clone(), "heidi")?;
+        let replayed = server.start_registration_cached(request, "heidi")?;
+        assert_eq!(first.serialize(), replayed.serialize());
+        assert_eq!(server.registration_cache().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn registration_cache_is_scoped_to_username() -> Result<(), ProtocolError> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup).with_registration_cache(Duration::from_secs(60));
+        let client = Client::new("password");
+        let (_, request) = client.start_registration()?;
+
+        // the OPRF evaluation is keyed on the username, so each user needs their own answer
+        let heidi = server.start_registration_cached(request.clone(), "heidi")?;
+        let ivan = server.start_registration_cached(request.clone(), "ivan")?;
+        assert_ne!(heidi.serialize(), ivan.serialize());
+        assert_eq!(
+            ivan.serialize(),
+            server.start_registration(request, "ivan")?.serialize()
+        );
+        assert_eq!(server.registration_cache().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn registration_cache_entries_expire() -> Result<(), ProtocolError> {
+        let setup = ServerSetup::new(&mut OsRng);
+        let server = Server::new(setup).with_registration_cache(Duration::from_millis(20));
+        let client = Client::new("password");
+        let (_, request) = client.start_registration()?;
+        let key = request.serialize().to_vec();
+
+        server.start_registration_cached(request.clone(), "ivan")?;
+        let cache = server.registration_cache().unwrap();
+        assert!(cache.get("ivan", &key).is_some());
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(cache.get("ivan", &key).is_none());
+        assert!(cache.is_empty());
+
+        // a fresh response is computed and cached again
+        server.start_registration_cached(request, "ivan")?;
+        assert!(cache.get("ivan", &key).is_some());
+        Ok(())
+    }
+
     fn init_logger() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
@@ -155,6 +630,84 @@ mod tests {
     }
 
     #[test]
+    fn unexpected_session_key_length_is_rejected() -> Result<(), crate::errors::Error> {
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let client = Client::new("hunter2");
+        let (client_reg, reg_request) = client.start_registration()?;
+        let reg_response = server.start_registration(reg_request, "bob")?;
+        let stored =
+            server.finish_registration(client.finish_registration(client_reg, reg_response)?);
+
+        let (client_login, credential_request) = client.start_login()?;
+        let (server_login, credential_response) =
+            server.start_login(stored, credential_request, "bob")?;
+        let (_, client_finalization) = client.finish_login(client_login, credential_response)?;
+        let result = server.finish_login_checked(
+            server_login,
+            client_finalization,
+            Server::SESSION_KEY_LEN / 2,
+        );
+
+        assert!(
+            matches!(result, Err(crate::errors::Error::Internal(msg)) if msg == "invalid session key")
+        );
+
+        let (client_login, credential_request) = client.start_login()?;
+        let stored = register_user(&server, "carol", "hunter2")?;
+        let (server_login, credential_response) =
+            server.start_login(stored, credential_request, "carol")?;
+        let (client_key, client_finalization) =
+            client.finish_login(client_login, credential_response)?;
+        let server_key = server.finish_login_checked(
+            server_login,
+            client_finalization,
+            Server::SESSION_KEY_LEN,
+        )?;
+        assert_eq!(client_key, server_key);
+        Ok(())
+    }
+
+    #[test]
+    fn username_policies() {
+        assert_eq!(UsernamePolicy::default(), UsernamePolicy::CaseSensitive);
+        assert_eq!(UsernamePolicy::CaseSensitive.apply("Alice"), "Alice");
+        assert_eq!(UsernamePolicy::Lowercase.apply("Alice"), "alice");
+        assert_eq!(UsernamePolicy::Lowercase.apply("ＡＬＩＣＥ"), "ａｌｉｃｅ");
+        assert_eq!(UsernamePolicy::Normalize.apply("ＡＬＩＣＥ"), "alice");
+        assert_eq!(UsernamePolicy::Normalize.apply("ﬁona"), "fiona");
+    }
+
+    fn login_as(server: &Server, stored: ServerRegistration, username: &str) -> bool {
+        let client = Client::new("hunter2");
+        let (client_login, credential_request) = client.start_login().unwrap();
+        let (server_login, credential_response) = server
+            .start_login(stored, credential_request, username)
+            .unwrap();
+        match client.finish_login(client_login, credential_response) {
+            Ok((client_key, finalization)) => {
+                server.finish_login(server_login, finalization).unwrap() == client_key
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn username_policy_applies_to_registration_and_login() -> Result<(), ProtocolError> {
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = register_user(&server, "Alice", "hunter2")?;
+        assert!(login_as(&server, stored.clone(), "Alice"));
+        assert!(!login_as(&server, stored, "alice"));
+
+        let server = Server::new(ServerSetup::new(&mut OsRng))
+            .with_username_policy(UsernamePolicy::Normalize);
+        let stored = register_user(&server, "Alice", "hunter2")?;
+        assert!(login_as(&server, stored.clone(), "alice"));
+        assert!(login_as(&server, stored, "ＡＬＩＣＥ"));
+        Ok(())
+    }
+
+    #[test]
+    #[allow(unused_variables)]
     fn test_login_with_wrong_password_fails() -> Result<(), ProtocolError> {
         init_logger();
         let setup = ServerSetup::new(&mut OsRng);
@@ -261,4 +814,104 @@ mod tests {
 
         Ok(())
     }
+
+    const REASONS: [UnauthorizedReason; 7] = [
+        UnauthorizedReason::InvalidCredentials,
+        UnauthorizedReason::AccountLocked,
+        UnauthorizedReason::AccountNotFound,
+        UnauthorizedReason::SessionExpired,
+        UnauthorizedReason::RateLimited,
+        UnauthorizedReason::LoggedOut,
+        UnauthorizedReason::ServerUnreachable,
+    ];
+
+    #[test]
+    fn unauthorized_reasons_roundtrip() {
+        for reason in REASONS {
+            let json = serde_json::to_string(&LoginResult::Unauthorized(reason)).unwrap();
+            let decoded: LoginResult = serde_json::from_str(&json).unwrap();
+            assert!(matches!(decoded, LoginResult::Unauthorized(r) if r == reason));
+
+            let completion = crate::auth::challenge::LoginCompletion::unauthorized(reason);
+            assert!(matches!(completion.result, LoginResult::Unauthorized(r) if r == reason));
+
+            let error = crate::errors::Error::LoginUnauthorized(reason);
+            assert_eq!(UnauthorizedReason::from_error(&error), reason);
+            assert!(error.to_string().ends_with(&reason.to_string()));
+        }
+    }
+
+    #[test]
+    fn network_errors_are_server_unreachable() {
+        use crate::errors::{Error, NetworkErrorKind};
+
+        for error in [
+            Error::Network(NetworkErrorKind::ConnectionRefused),
+            Error::Network(NetworkErrorKind::DnsFailure),
+            Error::Network(NetworkErrorKind::HttpError(503)),
+            Error::Timeout(std::time::Duration::from_secs(1)),
+        ] {
+            assert_eq!(
+                UnauthorizedReason::from_error(&error),
+                UnauthorizedReason::ServerUnreachable
+            );
+        }
+        // statuses with a meaning of their own keep it
+        assert_eq!(
+            UnauthorizedReason::from_error(&Error::Network(NetworkErrorKind::HttpError(429))),
+            UnauthorizedReason::RateLimited
+        );
+    }
+
+    #[test]
+    fn unknown_errors_are_invalid_credentials() {
+        let error = crate::errors::Error::Opaque(ProtocolError::InvalidLoginError);
+        assert_eq!(
+            UnauthorizedReason::from_error(&error),
+            UnauthorizedReason::InvalidCredentials
+        );
+    }
+
+    #[tokio::test]
+    async fn async_registration_allows_login() -> Result<(), crate::errors::Error> {
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = register_user_async(&server, "grace", "password").await?;
+
+        let client = Client::new("password");
+        let (client_login, credential_request) = client.start_login()?;
+        let (server_login, credential_response) =
+            server.start_login(stored.clone(), credential_request, "grace")?;
+        let (client_key, finalization) = client.finish_login(client_login, credential_response)?;
+        assert_eq!(client_key, server.finish_login(server_login, finalization)?);
+
+        // a wrong password doesn't get through
+        let client = Client::new("wrong password");
+        let (client_login, credential_request) = client.start_login()?;
+        let (_, credential_response) = server.start_login(stored, credential_request, "grace")?;
+        assert!(
+            client
+                .finish_login(client_login, credential_response)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn async_registration_with_custom_suite() -> Result<(), crate::errors::Error> {
+        let server = Server::from_setup(ServerSetup::<CustomCipherSuite>::new(&mut OsRng));
+        // the executor's only thread stays free while the client side runs
+        let (stored, ticked) = tokio::join!(
+            register_user_async(&server, "heidi", "password"),
+            tokio::task::yield_now()
+        );
+        let () = ticked;
+
+        let client = Client::<CustomCipherSuite>::with_cipher_suite("password");
+        let (client_login, credential_request) = client.start_login()?;
+        let (server_login, credential_response) =
+            server.start_login(stored?, credential_request, "heidi")?;
+        let (client_key, finalization) = client.finish_login(client_login, credential_response)?;
+        assert_eq!(client_key, server.finish_login(server_login, finalization)?);
+        Ok(())
+    }
 }
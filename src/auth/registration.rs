@@ -1,4 +1,12 @@
+use crate::auth::{CipherSuite, DefaultCipherSuite};
+use crate::server::auth::ServerRegistration;
+use opaque_ke::RegistrationResponse;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegistrationRequest {
     pub first_name: String,
@@ -6,4 +14,195 @@ pub struct RegistrationRequest {
     pub username: String,
     pub email: String,
     pub gender: Option<String>,
+    /// client generated nonce used to detect retried registrations.
+    #[serde(default)]
+    pub nonce: Option<Uuid>,
+}
+
+/// Server answer to the pre-registration challenge, `id` ties together the
+/// OPAQUE registration messages that follow.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationChallenge {
+    pub id: Uuid,
+}
+
+/// First OPAQUE registration message, `message` is the base64 encoded
+/// `opaque_ke::RegistrationRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationStart {
+    pub id: Uuid,
+    pub username: String,
+    pub message: String,
+}
+
+/// Server answer to [`RegistrationStart`], `message` is the base64 encoded
+/// `opaque_ke::RegistrationResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationStartResponse {
+    pub message: String,
+}
+
+/// Final OPAQUE registration message, `message` is the base64 encoded
+/// `opaque_ke::RegistrationUpload`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationFinish {
+    pub id: Uuid,
+    pub message: String,
+}
+
+/// A nonce identifying a single registration attempt.
+///
+/// Clients send the same nonce when retrying a registration so the server can
+/// return the already completed record instead of overwriting it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationNonce {
+    pub id: Uuid,
+    pub username: String,
+    /// unix timestamp (seconds) of when the registration completed.
+    pub created_at: u64,
+}
+
+impl RegistrationNonce {
+    pub fn new(id: Uuid, username: impl Into<String>) -> Self {
+        Self {
+            id,
+            username: username.into(),
+            created_at: unix_now(),
+        }
+    }
+
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        unix_now().saturating_sub(self.created_at) > ttl.as_secs()
+    }
+}
+
+/// Completed registrations with their nonce, keyed by the nonce id and username.
+type CompletedRegistrations<CS> =
+    HashMap<(Uuid, String), (RegistrationNonce, ServerRegistration<CS>)>;
+
+/// Recently completed registrations keyed by their [`RegistrationNonce`] id and username.
+///
+/// Entries are kept for `ttl` and pruned lazily on access.
+pub struct RegistrationStore<CS: CipherSuite = DefaultCipherSuite> {
+    ttl: Duration,
+    completed: Mutex<CompletedRegistrations<CS>>,
+}
+
+impl<CS: CipherSuite> RegistrationStore<CS> {
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            completed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// returns the stored registration if `nonce` was already used for `username`
+    /// and has not expired.
+    pub fn get(&self, nonce: &Uuid, username: &str) -> Option<ServerRegistration<CS>> {
+        let mut completed = self.completed.lock().expect("registration store poisoned");
+        completed.retain(|_, (entry, _)| !entry.is_expired(self.ttl));
+        completed
+            .get(&(*nonce, username.to_string()))
+            .map(|(_, registration)| registration.clone())
+    }
+
+    pub fn insert(&self, nonce: RegistrationNonce, registration: ServerRegistration<CS>) {
+        let mut completed = self.completed.lock().expect("registration store poisoned");
+        completed.insert((nonce.id, nonce.username.clone()), (nonce, registration));
+    }
+
+    /// Stores `registration` unless the nonce was already used for the same username,
+    /// returns whichever registration is stored afterwards. Check and insert happen under
+    /// one lock, so concurrent retries all end up with the same record.
+    pub fn insert_if_absent(
+        &self,
+        nonce: RegistrationNonce,
+        registration: ServerRegistration<CS>,
+    ) -> ServerRegistration<CS> {
+        let mut completed = self.completed.lock().expect("registration store poisoned");
+        completed.retain(|_, (entry, _)| !entry.is_expired(self.ttl));
+        let (_, stored) = completed
+            .entry((nonce.id, nonce.username.clone()))
+            .or_insert((nonce, registration));
+        stored.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.completed
+            .lock()
+            .expect("registration store poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<CS: CipherSuite> Default for RegistrationStore<CS> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_TTL)
+    }
+}
+
+/// cached responses by username and serialized request.
+type CachedResponses<CS> = HashMap<(String, Vec<u8>), (Instant, RegistrationResponse<CS>)>;
+
+/// Memoizes registration responses keyed by the username and the serialized
+/// `RegistrationRequest`, so a replayed request receives the response it got the first time.
+///
+/// Entries older than `ttl` are evicted on access.
+pub struct RegistrationResponseCache<CS: CipherSuite = DefaultCipherSuite> {
+    ttl: Duration,
+    responses: Mutex<CachedResponses<CS>>,
+}
+
+impl<CS: CipherSuite> RegistrationResponseCache<CS> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            responses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn get(&self, username: &str, request: &[u8]) -> Option<RegistrationResponse<CS>> {
+        let mut responses = self.responses.lock().expect("registration cache poisoned");
+        responses.retain(|_, (created, _)| created.elapsed() < self.ttl);
+        responses
+            .get(&(username.to_string(), request.to_vec()))
+            .map(|(_, response)| response.clone())
+    }
+
+    pub fn insert(&self, username: String, request: Vec<u8>, response: RegistrationResponse<CS>) {
+        let mut responses = self.responses.lock().expect("registration cache poisoned");
+        responses.insert((username, request), (Instant::now(), response));
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses
+            .lock()
+            .expect("registration cache poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
@@ -2,14 +2,35 @@ use opaque_ke::errors::ProtocolError;
 use opaque_ke::{
     ClientLogin, ClientLoginFinishParameters, ClientRegistration,
     ClientRegistrationFinishParameters, CredentialFinalization, CredentialRequest,
-    CredentialResponse, RegistrationRequest, RegistrationUpload,
+    CredentialResponse, Identifiers, RegistrationRequest, RegistrationUpload,
 };
 
-use crate::auth::DefaultCipherSuite;
+use crate::auth::{
+    ByteLen, CipherSuite, DefaultCipherSuite, MaskedNonceLen, MaskedResponseLen, OprfHashLen,
+};
+use crate::errors::Error;
+use crate::server::auth::{Server, ServerRegistration};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use opaque_ke::key_exchange::group::KeGroup;
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
+use sha2::digest::typenum::U32;
+use std::marker::PhantomData;
+use std::ops::Add;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+use zeroize::{Zeroize, Zeroizing};
 
+use rand::RngCore;
 use rand::rngs::OsRng;
 
+/// Default pattern usernames must match, see [`LoginRequest::new_checked`].
+pub static USERNAME_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_\-.@]+$").expect("valid username pattern"));
+
 #[derive(bincode::Encode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct LoginRequest {
     pub username: String,
@@ -17,39 +38,337 @@ pub struct LoginRequest {
 }
 
 impl LoginRequest {
+    /// Maximum username length in bytes.
+    pub const MAX_USERNAME_LEN: usize = 255;
+
+    /// Unchecked constructor, prefer [`LoginRequest::new_checked`] for user supplied input.
     pub fn new(
         username: impl Into<String>,
         credentials: CredentialRequest<DefaultCipherSuite>,
     ) -> Self {
-        let credentials = base64::encode(credentials.serialize().as_slice().to_vec());
+        let credentials = encode_credential_request(&credentials);
         Self {
             username: username.into(),
             credentials,
         }
     }
+
+    /// Like [`LoginRequest::new`] but validates the username against [`USERNAME_PATTERN`].
+    pub fn new_checked(
+        username: impl Into<String>,
+        credentials: CredentialRequest<DefaultCipherSuite>,
+    ) -> Result<Self, Error> {
+        Self::new_checked_with_pattern(username, credentials, &USERNAME_PATTERN)
+    }
+
+    /// Like [`LoginRequest::new_checked`] with a custom username pattern.
+    pub fn new_checked_with_pattern(
+        username: impl Into<String>,
+        credentials: CredentialRequest<DefaultCipherSuite>,
+        pattern: &Regex,
+    ) -> Result<Self, Error> {
+        let username = username.into();
+        validate_username(&username, pattern)?;
+        Ok(Self::new(username, credentials))
+    }
+}
+
+/// Base64 encodes a [`CredentialRequest`] for embedding in JSON messages.
+pub fn encode_credential_request(req: &CredentialRequest<DefaultCipherSuite>) -> String {
+    STANDARD.encode(req.serialize())
+}
+
+/// Inverse of [`encode_credential_request`].
+pub fn decode_credential_request(s: &str) -> Result<CredentialRequest<DefaultCipherSuite>, Error> {
+    Ok(CredentialRequest::deserialize(&STANDARD.decode(s)?)?)
+}
+
+/// Base64 encodes a [`CredentialResponse`] for embedding in JSON messages.
+pub fn encode_credential_response(res: &CredentialResponse<DefaultCipherSuite>) -> String {
+    STANDARD.encode(res.serialize())
+}
+
+/// Inverse of [`encode_credential_response`].
+pub fn decode_credential_response(
+    s: &str,
+) -> Result<CredentialResponse<DefaultCipherSuite>, Error> {
+    Ok(CredentialResponse::deserialize(&STANDARD.decode(s)?)?)
+}
+
+/// Base64 encodes a [`CredentialFinalization`] for embedding in JSON messages.
+pub fn encode_credential_finalization(
+    finalization: &CredentialFinalization<DefaultCipherSuite>,
+) -> String {
+    STANDARD.encode(finalization.serialize())
+}
+
+/// Inverse of [`encode_credential_finalization`].
+pub fn decode_credential_finalization(
+    s: &str,
+) -> Result<CredentialFinalization<DefaultCipherSuite>, Error> {
+    Ok(CredentialFinalization::deserialize(&STANDARD.decode(s)?)?)
+}
+
+/// Checks that `username` is non-empty, at most [`LoginRequest::MAX_USERNAME_LEN`] bytes,
+/// contains no null bytes and matches `pattern`.
+pub fn validate_username(username: &str, pattern: &Regex) -> Result<(), Error> {
+    if username.is_empty() {
+        return Err(Error::InvalidUsername("username is empty".to_string()));
+    }
+    if username.len() > LoginRequest::MAX_USERNAME_LEN {
+        return Err(Error::InvalidUsername(format!(
+            "username is {} bytes, the maximum is {}",
+            username.len(),
+            LoginRequest::MAX_USERNAME_LEN
+        )));
+    }
+    if username.contains('\0') {
+        return Err(Error::InvalidUsername(
+            "username contains a null byte".to_string(),
+        ));
+    }
+    if !pattern.is_match(username) {
+        return Err(Error::InvalidUsername(format!(
+            "username doesn't match {}",
+            pattern.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Key material produced by a successful OPAQUE login, zeroed on drop.
+///
+/// Neither `Debug` nor comparisons expose the keys, compare the fields directly if needed.
+#[derive(Clone)]
+pub struct SessionKeys {
+    /// key shared with the server for this session.
+    pub session_key: Zeroizing<Vec<u8>>,
+    /// client-only key, stable across logins for the same password.
+    pub export_key: Zeroizing<Vec<u8>>,
+}
+
+impl SessionKeys {
+    /// Derives `len` bytes of application key material from the session key,
+    /// bound to `context` (HKDF-SHA256, see [`crate::crypto::hkdf_expand`]).
+    pub fn derive(&self, context: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(crate::crypto::hkdf_expand(&self.session_key, context, len))
+    }
+
+    /// [`SessionKeys::derive`] with a string label as context, e.g. `"encryption"`.
+    pub fn derive_named(&self, label: &str, len: usize) -> Zeroizing<Vec<u8>> {
+        self.derive(label.as_bytes(), len)
+    }
+
+    /// Like [`SessionKeys::derive`] but from the export key, so the output is the same for
+    /// every login with the password and never known to the server, e.g. for a client-side
+    /// encrypted vault.
+    pub fn derive_export(&self, context: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(crate::crypto::hkdf_expand(&self.export_key, context, len))
+    }
+}
+
+impl std::fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("session_key", &"<redacted>")
+            .field("export_key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Credentials cached on the client so a password can be checked while offline,
+/// see [`Client::export_credentials`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClientCredentialCache {
+    /// OPAQUE export key of the registration, stable across logins with the same password.
+    pub export_key: Zeroizing<Vec<u8>>,
+    pub cached_at: SystemTime,
+}
+
+impl std::fmt::Debug for ClientCredentialCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCredentialCache")
+            .field("export_key", &"<redacted>")
+            .field("cached_at", &self.cached_at)
+            .finish()
+    }
 }
 
-pub struct Client {
+const CREDENTIAL_CACHE_SALT_LEN: usize = 16;
+const CREDENTIAL_CACHE_NONCE_LEN: usize = 12;
+
+/// AES-256-GCM keyed with Argon2id(password, salt) at argon2's default cost, the cache
+/// can be read without the server so the password has to be expensive to guess.
+fn credential_cache_cipher(password: &str, salt: &[u8]) -> Result<Aes256Gcm, Error> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2::Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::Internal(format!("credential cache key derivation failed: {e}")))?;
+    Ok(Aes256Gcm::new_from_slice(key.as_ref()).expect("32 byte key"))
+}
+
+/// OPAQUE client side, generic over the [`CipherSuite`] so a suite backed by e.g. P-256
+/// hardware can be used instead of [`DefaultCipherSuite`].
+pub struct Client<CS: CipherSuite = DefaultCipherSuite> {
+    password: String,
+    /// OPAQUE export key put in the credential cache, see [`Client::with_export_key`].
+    export_key: Vec<u8>,
+    /// password hardening function, `None` uses the suite's default parameters.
+    ksf: Option<CS::Ksf>,
+    client_identity: Option<Vec<u8>>,
+    server_identity: Option<Vec<u8>>,
+    suite: PhantomData<CS>,
+}
+
+/// Builds a [`Client`] with OPAQUE identifiers for mutual entity authentication.
+///
+/// Without identifiers OPAQUE binds the login to the public keys, with them the server
+/// has to pass the same ones to [`Server::start_login_with_identifiers`].
+pub struct ClientBuilder<CS: CipherSuite = DefaultCipherSuite> {
     password: String,
+    client_identity: Option<Vec<u8>>,
+    server_identity: Option<Vec<u8>>,
+    suite: PhantomData<CS>,
+}
+
+impl<CS: CipherSuite> Default for ClientBuilder<CS> {
+    fn default() -> Self {
+        Self {
+            password: String::new(),
+            client_identity: None,
+            server_identity: None,
+            suite: PhantomData,
+        }
+    }
+}
+
+impl<CS: CipherSuite> ClientBuilder<CS> {
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = password.into();
+        self
+    }
+
+    /// usually the username.
+    pub fn client_identity(mut self, identity: Vec<u8>) -> Self {
+        self.client_identity = Some(identity);
+        self
+    }
+
+    /// usually the server's hostname.
+    pub fn server_identity(mut self, identity: Vec<u8>) -> Self {
+        self.server_identity = Some(identity);
+        self
+    }
+
+    pub fn build(mut self) -> Client<CS> {
+        let mut client = Client::with_cipher_suite(self.password.as_str());
+        client.client_identity = self.client_identity.take();
+        client.server_identity = self.server_identity.take();
+        client
+    }
+}
+
+impl<CS: CipherSuite> Drop for ClientBuilder<CS> {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
 }
 
 impl Client {
     pub fn new(password: impl Into<String>) -> Self {
+        Self::with_cipher_suite(password)
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Decrypts credentials produced by [`Client::export_credentials`].
+    ///
+    /// Fails with [`Error::AesGcmError`] if `password` isn't the one they were exported with.
+    pub fn load_credentials(password: &str, data: &[u8]) -> Result<ClientCredentialCache, Error> {
+        if data.len() < CREDENTIAL_CACHE_SALT_LEN + CREDENTIAL_CACHE_NONCE_LEN {
+            return Err(Error::Internal("credential cache too short".to_string()));
+        }
+        let (salt, rest) = data.split_at(CREDENTIAL_CACHE_SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(CREDENTIAL_CACHE_NONCE_LEN);
+        let nonce: [u8; CREDENTIAL_CACHE_NONCE_LEN] = nonce.try_into().expect("nonce length");
+        let plaintext = Zeroizing::new(
+            credential_cache_cipher(password, salt)?.decrypt(&Nonce::from(nonce), ciphertext)?,
+        );
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// Restores a client from [`Client::export_credentials`], checking `password` offline.
+    pub fn import_credentials(password: impl Into<String>, data: &[u8]) -> Result<Self, Error> {
+        let password = password.into();
+        let cache = Self::load_credentials(&password, data)?;
+        Ok(Self::new(password).with_export_key(cache.export_key.to_vec()))
+    }
+}
+
+impl<CS: CipherSuite> Client<CS> {
+    /// Like [`Client::new`] for a custom cipher suite, e.g.
+    /// `Client::<MySuite>::with_cipher_suite(password)`.
+    pub fn with_cipher_suite(password: impl Into<String>) -> Self {
         Self {
             password: password.into(),
+            export_key: Vec::new(),
+            ksf: None,
+            client_identity: None,
+            server_identity: None,
+            suite: PhantomData,
+        }
+    }
+
+    fn identifiers(&self) -> Identifiers<'_> {
+        Identifiers {
+            client: self.client_identity.as_deref(),
+            server: self.server_identity.as_deref(),
         }
     }
 
+    /// Hardens the password with `ksf` instead of the suite's default, e.g. an Argon2 instance
+    /// from [`crate::auth::Argon2Builder`]. Registration and login must use the same one.
+    pub fn with_ksf(mut self, ksf: CS::Ksf) -> Self {
+        self.ksf = Some(ksf);
+        self
+    }
+
+    /// Sets the OPAQUE export key included in [`Client::export_credentials`],
+    /// usually the one from [`Client::finish_login_with_keys`].
+    pub fn with_export_key(mut self, export_key: Vec<u8>) -> Self {
+        self.export_key = export_key;
+        self
+    }
+
+    pub fn export_key(&self) -> &[u8] {
+        &self.export_key
+    }
+
+    /// Exports the cached credentials encrypted with a key derived from the password.
+    ///
+    /// The output is `salt || nonce || AES-256-GCM(Argon2id(password, salt), json(cache))`
+    /// with a fresh salt and nonce each time, only the same password can import it again.
+    pub fn export_credentials(&self) -> Result<Vec<u8>, Error> {
+        let cache = ClientCredentialCache {
+            export_key: Zeroizing::new(self.export_key.clone()),
+            cached_at: SystemTime::now(),
+        };
+        let plaintext = Zeroizing::new(serde_json::to_vec(&cache)?);
+        let mut salt = [0u8; CREDENTIAL_CACHE_SALT_LEN];
+        let mut nonce = [0u8; CREDENTIAL_CACHE_NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = credential_cache_cipher(&self.password, &salt)?
+            .encrypt(&Nonce::from(nonce), plaintext.as_slice())?;
+        Ok([&salt[..], &nonce[..], &ciphertext].concat())
+    }
+
     // Step 1: Registration start
     pub fn start_registration(
         &self,
-    ) -> Result<
-        (
-            ClientRegistration<DefaultCipherSuite>,
-            RegistrationRequest<DefaultCipherSuite>,
-        ),
-        ProtocolError,
-    > {
+    ) -> Result<(ClientRegistration<CS>, RegistrationRequest<CS>), ProtocolError> {
         let mut rng = OsRng;
         let start = ClientRegistration::start(&mut rng, self.password.as_bytes())?;
         Ok((start.state, start.message))
@@ -58,45 +377,466 @@ impl Client {
     // Step 2: Finish registration using server response
     pub fn finish_registration(
         &self,
-        registration: ClientRegistration<DefaultCipherSuite>,
-        response: opaque_ke::RegistrationResponse<DefaultCipherSuite>,
-    ) -> Result<RegistrationUpload<DefaultCipherSuite>, ProtocolError> {
+        registration: ClientRegistration<CS>,
+        response: opaque_ke::RegistrationResponse<CS>,
+    ) -> Result<RegistrationUpload<CS>, ProtocolError> {
+        let (upload, _) = self.finish_registration_with_export_key(registration, response)?;
+        Ok(upload)
+    }
+
+    /// Like [`Client::finish_registration`] but also returns the OPAQUE export key, the same
+    /// one [`Client::finish_login_with_keys`] yields on later logins.
+    pub fn finish_registration_with_export_key(
+        &self,
+        registration: ClientRegistration<CS>,
+        response: opaque_ke::RegistrationResponse<CS>,
+    ) -> Result<(RegistrationUpload<CS>, Zeroizing<Vec<u8>>), ProtocolError> {
         let mut rng = OsRng;
         let result = registration.finish(
             &mut rng,
             self.password.as_bytes(),
             response,
-            ClientRegistrationFinishParameters::default(),
+            ClientRegistrationFinishParameters::new(self.identifiers(), self.ksf.as_ref()),
         )?;
-        Ok(result.message)
+        Ok((result.message, Zeroizing::new(result.export_key.to_vec())))
     }
 
     // Step 3: Start login (authentication)
     pub fn start_login(
         &self,
-    ) -> Result<
-        (
-            ClientLogin<DefaultCipherSuite>,
-            opaque_ke::CredentialRequest<DefaultCipherSuite>,
-        ),
-        ProtocolError,
-    > {
+    ) -> Result<(ClientLogin<CS>, opaque_ke::CredentialRequest<CS>), ProtocolError> {
         let mut rng = OsRng;
-        let result = ClientLogin::<DefaultCipherSuite>::start(&mut rng, self.password.as_bytes())?;
+        let result = ClientLogin::<CS>::start(&mut rng, self.password.as_bytes())?;
         Ok((result.state, result.message))
     }
 
     // Step 4: Finish login
     pub fn finish_login(
         &self,
-        client_login: ClientLogin<DefaultCipherSuite>,
-        credential_response: CredentialResponse<DefaultCipherSuite>,
-    ) -> Result<(Vec<u8>, CredentialFinalization<DefaultCipherSuite>), ProtocolError> {
+        client_login: ClientLogin<CS>,
+        credential_response: CredentialResponse<CS>,
+    ) -> Result<(Zeroizing<Vec<u8>>, CredentialFinalization<CS>), ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
+        self.finish_login_with_keys(client_login, credential_response)
+            .map(|(keys, message)| (keys.session_key, message))
+    }
+
+    /// Like [`Client::finish_login`] but also returns the OPAQUE export key.
+    pub fn finish_login_with_keys(
+        &self,
+        client_login: ClientLogin<CS>,
+        credential_response: CredentialResponse<CS>,
+    ) -> Result<(SessionKeys, CredentialFinalization<CS>), ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
         let result = client_login.finish(
             self.password.as_bytes(),
             credential_response,
-            ClientLoginFinishParameters::default(),
+            ClientLoginFinishParameters::new(None, self.identifiers(), self.ksf.as_ref()),
+        )?;
+        let keys = SessionKeys {
+            session_key: Zeroizing::new(result.session_key.to_vec()),
+            export_key: Zeroizing::new(result.export_key.to_vec()),
+        };
+        Ok((keys, result.message))
+    }
+
+    /// Replaces the password of `username` by registering again with `new_password`.
+    ///
+    /// A full OPAQUE login with this client's password against `stored` has to succeed
+    /// first, otherwise [`ProtocolError::InvalidLoginError`] is returned and the caller keeps
+    /// the old record. The new record is sealed with the same KSF parameters and identifiers
+    /// as this client, on success it replaces `stored`.
+    pub fn change_password(
+        &self,
+        new_password: impl Into<String>,
+        server: &Server<CS>,
+        stored: ServerRegistration<CS>,
+        username: &str,
+    ) -> Result<ServerRegistration<CS>, ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
+        let (client_login, request) = self.start_login()?;
+        let (server_login, response) =
+            server.start_login_with_identifiers(stored, request, username, self.identifiers())?;
+        let (client_key, finalization) = self.finish_login(client_login, response)?;
+        match server.finish_login(server_login, finalization) {
+            Ok(server_key) if server_key == client_key => {}
+            _ => return Err(ProtocolError::InvalidLoginError),
+        }
+
+        let new_password = Zeroizing::new(new_password.into());
+        let mut rng = OsRng;
+        let start = ClientRegistration::<CS>::start(&mut rng, new_password.as_bytes())?;
+        let response = server.start_registration(start.message, username)?;
+        let result = start.state.finish(
+            &mut rng,
+            new_password.as_bytes(),
+            response,
+            ClientRegistrationFinishParameters::new(self.identifiers(), self.ksf.as_ref()),
+        )?;
+        Ok(server.finish_registration(result.message))
+    }
+}
+
+/// Overwrites the password (and cached registration) so it doesn't linger on the heap.
+impl<CS: CipherSuite> Drop for Client<CS> {
+    fn drop(&mut self) {
+        self.password.zeroize();
+        self.export_key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trips_binary_data() {
+        let data = [0xfbu8, 0xff, 0xbf, 0xff];
+        let encoded = STANDARD.encode(data);
+        // exercises both non-alphanumeric characters and padding
+        assert_eq!(encoded, "+/+//w==");
+        assert_eq!(STANDARD.decode(&encoded).unwrap(), data);
+    }
+
+    fn credentials() -> CredentialRequest<DefaultCipherSuite> {
+        Client::new("password").start_login().unwrap().1
+    }
+
+    fn session_keys() -> SessionKeys {
+        SessionKeys {
+            session_key: vec![1u8; 64].into(),
+            export_key: vec![2u8; 64].into(),
+        }
+    }
+
+    #[test]
+    fn derived_keys_depend_on_context() {
+        let keys = session_keys();
+        let encryption = keys.derive_named("encryption", 32);
+        let mac = keys.derive_named("mac", 32);
+
+        assert_eq!(encryption.len(), 32);
+        assert_ne!(encryption, mac);
+        assert_eq!(encryption, keys.derive(b"encryption", 32));
+        assert_eq!(keys.derive_named("mac", 16)[..], mac[..16]);
+    }
+
+    #[test]
+    fn export_key_is_stable_across_logins() -> Result<(), Error> {
+        use crate::server::auth::ServerSetup;
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let client = Client::new("password");
+        let (client_reg, request) = client.start_registration()?;
+        let response = server.start_registration(request, "judy")?;
+        let (upload, export_key) =
+            client.finish_registration_with_export_key(client_reg, response)?;
+        let stored = server.finish_registration(upload);
+
+        let mut logins = Vec::new();
+        for _ in 0..2 {
+            let (client_login, request) = client.start_login()?;
+            let (_, response) = server.start_login(stored.clone(), request, "judy")?;
+            let (keys, _) = client.finish_login_with_keys(client_login, response)?;
+            assert_eq!(keys.export_key, export_key);
+            logins.push(keys);
+        }
+        assert_eq!(
+            logins[0].derive_export(b"vault", 32),
+            logins[1].derive_export(b"vault", 32)
+        );
+        assert_ne!(
+            logins[0].derive(b"vault", 32),
+            logins[1].derive(b"vault", 32)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_redacted_in_debug_output() {
+        let keys = format!("{:?}", session_keys());
+        assert!(!keys.contains("1, 1"));
+        assert!(!keys.contains("2, 2"));
+        assert!(keys.contains("<redacted>"));
+
+        let cache = ClientCredentialCache {
+            export_key: vec![7u8; 64].into(),
+            cached_at: SystemTime::now(),
+        };
+        assert!(!format!("{:?}", cache).contains("7, 7"));
+    }
+
+    #[test]
+    fn derived_keys_match_confirmation_kdf() {
+        let keys = session_keys();
+        assert_eq!(
+            keys.derive(b"confirmation", 32)[..],
+            crate::auth::challenge::derive_k_confirm(&keys.session_key)[..]
+        );
+    }
+
+    #[test]
+    fn credentials_roundtrip() -> Result<(), Error> {
+        let client = Client::new("password").with_export_key(vec![7u8; 64]);
+        let exported = client.export_credentials()?;
+
+        let imported = Client::import_credentials("password", &exported)?;
+        assert_eq!(imported.export_key(), client.export_key());
+        let cache = Client::load_credentials("password", &exported)?;
+        assert!(cache.cached_at <= SystemTime::now());
+        // a fresh salt and nonce every export
+        let again = client.export_credentials()?;
+        assert_ne!(
+            again[..CREDENTIAL_CACHE_SALT_LEN],
+            exported[..CREDENTIAL_CACHE_SALT_LEN]
+        );
+        assert_ne!(again, exported);
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_need_the_same_password() {
+        let exported = Client::new("password")
+            .with_export_key(vec![7u8; 64])
+            .export_credentials()
+            .unwrap();
+
+        assert!(matches!(
+            Client::import_credentials("wrong password", &exported),
+            Err(Error::AesGcmError(_))
+        ));
+        assert!(Client::import_credentials("password", &exported[..20]).is_err());
+
+        let mut tampered = exported.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Client::import_credentials("password", &tampered).is_err());
+    }
+
+    #[test]
+    fn credential_messages_roundtrip() -> Result<(), Error> {
+        use crate::auth::register_user;
+        use crate::server::auth::{Server, ServerSetup};
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = register_user(&server, "ivan", "password")?;
+        let client = Client::new("password");
+        let (client_login, request) = client.start_login()?;
+
+        let encoded = encode_credential_request(&request);
+        assert_eq!(decode_credential_request(&encoded)?, request);
+        assert_eq!(
+            LoginRequest::new("ivan", request.clone()).credentials,
+            encoded
+        );
+
+        let (_, response) = server.start_login(stored, request, "ivan")?;
+        let encoded = encode_credential_response(&response);
+        assert_eq!(decode_credential_response(&encoded)?, response);
+
+        let (_, finalization) = client.finish_login(client_login, response)?;
+        let encoded = encode_credential_finalization(&finalization);
+        assert_eq!(decode_credential_finalization(&encoded)?, finalization);
+
+        assert!(decode_credential_request("not base64!").is_err());
+        assert!(decode_credential_response(&STANDARD.encode([0u8; 3])).is_err());
+        assert!(decode_credential_finalization("").is_err());
+        Ok(())
+    }
+
+    fn can_login(server: &Server, stored: &ServerRegistration, password: &str) -> bool {
+        let client = Client::new(password);
+        let (client_login, request) = client.start_login().unwrap();
+        let (server_login, response) = server.start_login(stored.clone(), request, "judy").unwrap();
+        match client.finish_login(client_login, response) {
+            Ok((key, finalization)) => {
+                server.finish_login(server_login, finalization).unwrap() == key
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn password_change_replaces_the_record() -> Result<(), Error> {
+        use crate::server::auth::ServerSetup;
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = crate::auth::register_user(&server, "judy", "old password")?;
+
+        let changed = Client::new("old password").change_password(
+            "new password",
+            &server,
+            stored.clone(),
+            "judy",
         )?;
-        Ok((result.session_key.as_slice().to_vec(), result.message))
+        assert!(can_login(&server, &changed, "new password"));
+        assert!(!can_login(&server, &changed, "old password"));
+        // the old record is untouched until the caller swaps it out
+        assert!(can_login(&server, &stored, "old password"));
+        Ok(())
+    }
+
+    #[test]
+    fn password_change_needs_the_old_password() -> Result<(), Error> {
+        use crate::server::auth::ServerSetup;
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = crate::auth::register_user(&server, "judy", "old password")?;
+
+        let result =
+            Client::new("guess").change_password("new password", &server, stored.clone(), "judy");
+        assert!(matches!(result, Err(ProtocolError::InvalidLoginError)));
+        assert!(can_login(&server, &stored, "old password"));
+        Ok(())
+    }
+
+    #[test]
+    fn password_change_keeps_the_ksf_and_identifiers() -> Result<(), Error> {
+        use crate::auth::Argon2CipherSuite;
+        use crate::server::auth::ServerSetup;
+        use opaque_ke::Identifiers;
+
+        // cheap parameters, the defaults take a while in debug builds
+        let params = Argon2CipherSuite::builder()
+            .memory_kib(64)
+            .iterations(1)
+            .parallelism(1);
+        let server = Server::from_setup(ServerSetup::<Argon2CipherSuite>::new(&mut OsRng));
+        let client =
+            |password: &str, iterations: u32| -> Result<Client<Argon2CipherSuite>, Error> {
+                Ok(ClientBuilder::<Argon2CipherSuite>::default()
+                    .password(password)
+                    .client_identity(b"judy".to_vec())
+                    .server_identity(b"verdant.example".to_vec())
+                    .build()
+                    .with_ksf(params.iterations(iterations).build()?))
+            };
+        let can_login = |stored: &ServerRegistration<Argon2CipherSuite>,
+                         client: Client<Argon2CipherSuite>| {
+            let identifiers = Identifiers {
+                client: Some(b"judy".as_slice()),
+                server: Some(b"verdant.example".as_slice()),
+            };
+            let (client_login, request) = client.start_login().unwrap();
+            let (server_login, response) = server
+                .start_login_with_identifiers(stored.clone(), request, "judy", identifiers)
+                .unwrap();
+            match client.finish_login(client_login, response) {
+                Ok((key, finalization)) => {
+                    server.finish_login(server_login, finalization).unwrap() == key
+                }
+                Err(_) => false,
+            }
+        };
+
+        let old = client("old password", 1)?;
+        let (client_reg, request) = old.start_registration()?;
+        let response = server.start_registration(request, "judy")?;
+        let stored = server.finish_registration(old.finish_registration(client_reg, response)?);
+
+        let changed = old.change_password("new password", &server, stored, "judy")?;
+        assert!(can_login(&changed, client("new password", 1)?));
+        // sealed with the tuned parameters, not the suite's defaults
+        assert!(!can_login(&changed, client("new password", 2)?));
+        Ok(())
+    }
+
+    #[test]
+    fn identities_have_to_match_the_server() -> Result<(), Error> {
+        use crate::server::auth::ServerSetup;
+        use opaque_ke::Identifiers;
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let client = Client::builder()
+            .password("password")
+            .client_identity(b"judy".to_vec())
+            .server_identity(b"verdant.example".to_vec())
+            .build();
+        let (client_reg, request) = client.start_registration()?;
+        let response = server.start_registration(request, "judy")?;
+        let stored = server.finish_registration(client.finish_registration(client_reg, response)?);
+
+        let identifiers = Identifiers {
+            client: Some(b"judy".as_slice()),
+            server: Some(b"verdant.example".as_slice()),
+        };
+        let (client_login, request) = client.start_login()?;
+        let (server_login, response) =
+            server.start_login_with_identifiers(stored.clone(), request, "judy", identifiers)?;
+        let (client_key, finalization) = client.finish_login(client_login, response)?;
+        assert_eq!(client_key, server.finish_login(server_login, finalization)?);
+
+        // the server leaving them out changes the transcript
+        let (client_login, request) = client.start_login()?;
+        let (_, response) = server.start_login(stored, request, "judy")?;
+        assert!(client.finish_login(client_login, response).is_err());
+        Ok(())
+    }
+
+    fn assert_invalid(result: Result<LoginRequest, Error>) {
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
+    }
+
+    #[test]
+    fn valid_usernames_are_accepted() {
+        for username in ["alice", "bob.smith", "carol_1", "dave-2", "eve@example.com"] {
+            assert!(LoginRequest::new_checked(username, credentials()).is_ok());
+        }
+        let longest = "a".repeat(LoginRequest::MAX_USERNAME_LEN);
+        assert!(LoginRequest::new_checked(longest, credentials()).is_ok());
+    }
+
+    #[test]
+    fn empty_username_is_rejected() {
+        assert_invalid(LoginRequest::new_checked("", credentials()));
+    }
+
+    #[test]
+    fn long_username_is_rejected() {
+        let username = "a".repeat(LoginRequest::MAX_USERNAME_LEN + 1);
+        assert_invalid(LoginRequest::new_checked(username, credentials()));
+
+        // the limit is in bytes, not characters
+        let username = "é".repeat(LoginRequest::MAX_USERNAME_LEN / 2 + 1);
+        let pattern = Regex::new(".+").unwrap();
+        assert_invalid(LoginRequest::new_checked_with_pattern(
+            username,
+            credentials(),
+            &pattern,
+        ));
+    }
+
+    #[test]
+    fn null_byte_is_rejected() {
+        let pattern = Regex::new("(?s).+").unwrap();
+        assert_invalid(LoginRequest::new_checked_with_pattern(
+            "ali\0ce",
+            credentials(),
+            &pattern,
+        ));
+    }
+
+    #[test]
+    fn pattern_mismatch_is_rejected() {
+        assert_invalid(LoginRequest::new_checked("alice smith", credentials()));
+        assert_invalid(LoginRequest::new_checked("alice/../root", credentials()));
+
+        let pattern = Regex::new("^[a-z]+$").unwrap();
+        assert_invalid(LoginRequest::new_checked_with_pattern(
+            "Alice",
+            credentials(),
+            &pattern,
+        ));
     }
 }
@@ -0,0 +1,535 @@
+use crate::errors::Error;
+use keycast::discovery::Discovery;
+use lru::LruCache;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "tracing")]
+use tracing::warn;
+
+/// Identifies the server behind a discovery independently of the address it was seen at.
+pub trait ServerIdentity {
+    /// stable key for the server, the hash of its public key.
+    fn server_key(&self) -> String;
+
+    /// url the server was advertised at.
+    fn server_url(&self) -> Option<String>;
+
+    /// Like [`ServerIdentity::server_url`], failing with [`Error::MissingIpAddr`]
+    /// if the server advertised no address.
+    fn primary_url(&self) -> Result<String, Error> {
+        self.server_url().ok_or(Error::MissingIpAddr)
+    }
+
+    /// `true` if both discoveries advertise the same server, even if its address changed.
+    fn same_server(&self, other: &Self) -> bool {
+        self.server_key() == other.server_key()
+    }
+}
+
+impl ServerIdentity for Discovery {
+    fn server_key(&self) -> String {
+        self.pubkey_hash.hash.clone()
+    }
+
+    fn server_url(&self) -> Option<String> {
+        self.urls().first().map(|url| url.to_string())
+    }
+}
+
+/// Outcome of [`KnownServers::observe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Observation {
+    /// first time this server has been seen.
+    New,
+    /// a known server re-advertised itself with different details (e.g. a new IP).
+    Updated { previous_url: Option<String> },
+    /// identical to the last advertisement.
+    Unchanged,
+}
+
+/// Tracks servers seen by the discovery task, deduplicated by public key.
+pub struct KnownServers<D = Discovery> {
+    known: Vec<D>,
+    known_pubkeys: HashSet<String>,
+}
+
+impl<D: ServerIdentity + PartialEq + Clone> KnownServers<D> {
+    pub fn new() -> Self {
+        Self {
+            known: Vec::new(),
+            known_pubkeys: HashSet::new(),
+        }
+    }
+
+    /// Records `discovery`, reporting whether it is a new server, an update of a known one,
+    /// or a repeat of the last advertisement.
+    pub fn observe(&mut self, discovery: &D) -> Observation {
+        if !self.known_pubkeys.contains(&discovery.server_key()) {
+            self.known_pubkeys.insert(discovery.server_key());
+            self.known.push(discovery.clone());
+            return Observation::New;
+        }
+        let existing = self
+            .known
+            .iter_mut()
+            .find(|known| known.same_server(discovery))
+            .expect("known_pubkeys and known are kept in sync");
+        if existing == discovery {
+            return Observation::Unchanged;
+        }
+        let previous_url = existing.server_url();
+        *existing = discovery.clone();
+        Observation::Updated { previous_url }
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+}
+
+impl<D: ServerIdentity + PartialEq + Clone> Default for KnownServers<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryCacheEntry<D = Discovery> {
+    pub discovery: D,
+    pub discovered_at: Instant,
+}
+
+/// Bounded cache of discovered servers keyed by server url.
+///
+/// Once `max_size` servers are known, inserting a new one evicts the least recently used.
+pub struct DiscoveryCache<D = Discovery> {
+    entries: LruCache<String, DiscoveryCacheEntry<D>>,
+    max_size: usize,
+}
+
+impl<D> DiscoveryCache<D> {
+    pub const DEFAULT_MAX_SIZE: usize = 64;
+
+    /// `max_size` is clamped to at least one entry.
+    pub fn new(max_size: usize) -> Self {
+        let max_size = max_size.max(1);
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(max_size).expect("max_size is non-zero")),
+            max_size,
+        }
+    }
+
+    /// Inserts or refreshes the server at `url`, returning the evicted discovery if the cache was full.
+    pub fn insert(&mut self, url: impl Into<String>, discovery: D) -> Option<D> {
+        let url = url.into();
+        let entry = DiscoveryCacheEntry {
+            discovery,
+            discovered_at: Instant::now(),
+        };
+        match self.entries.push(url.clone(), entry) {
+            Some((evicted, entry)) if evicted != url => Some(entry.discovery),
+            _ => None,
+        }
+    }
+
+    /// Looks up `url` without affecting its recency.
+    pub fn get(&self, url: &str) -> Option<&D> {
+        self.entries.peek(url).map(|entry| &entry.discovery)
+    }
+
+    pub fn entry(&self, url: &str) -> Option<&DiscoveryCacheEntry<D>> {
+        self.entries.peek(url)
+    }
+
+    /// Marks `url` as recently used.
+    pub fn touch(&mut self, url: &str) -> bool {
+        self.entries.get(url).is_some()
+    }
+
+    pub fn remove(&mut self, url: &str) -> Option<D> {
+        self.entries.pop(url).map(|entry| entry.discovery)
+    }
+
+    /// Resets the discovery time of `url`, e.g. when the server advertised itself again.
+    pub fn refresh(&mut self, url: &str) -> bool {
+        match self.entries.get_mut(url) {
+            Some(entry) => {
+                entry.discovered_at = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and returns every discovery that hasn't been refreshed for longer than `ttl`.
+    pub fn expire(&mut self, ttl: Duration) -> Vec<D> {
+        let stale: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.discovered_at.elapsed() > ttl)
+            .map(|(url, _)| url.clone())
+            .collect();
+        stale.iter().filter_map(|url| self.remove(url)).collect()
+    }
+
+    /// Iterates from most to least recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &D)> {
+        self.entries
+            .iter()
+            .map(|(url, entry)| (url.as_str(), &entry.discovery))
+    }
+
+    /// Cloned discoveries, most recently used first, unaffected by later changes to the cache.
+    pub fn snapshot(&self) -> Vec<D>
+    where
+        D: Clone,
+    {
+        self.iter()
+            .map(|(_, discovery)| discovery.clone())
+            .collect()
+    }
+
+    /// Urls of the cached servers, most recently used first.
+    pub fn urls(&self) -> Vec<String> {
+        self.iter().map(|(url, _)| url.to_string()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+impl<D> Default for DiscoveryCache<D> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_SIZE)
+    }
+}
+
+/// A line of the [`DiscoveryStore`] file.
+#[derive(Serialize, Deserialize)]
+struct StoredDiscovery<D> {
+    discovery: D,
+    /// unix timestamp (seconds) of the advertisement.
+    seen_at: u64,
+}
+
+/// Discovered servers saved to a file, so they can be listed right after a restart
+/// instead of waiting for mDNS to find them again.
+///
+/// The file holds one JSON object per line, new discoveries are appended.
+#[derive(Debug, Clone)]
+pub struct DiscoveryStore {
+    path: PathBuf,
+}
+
+impl DiscoveryStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `discovery`, seen now.
+    pub async fn append<D: serde::Serialize>(&self, discovery: &D) -> Result<(), Error> {
+        let stored = StoredDiscovery {
+            discovery,
+            seen_at: unix_now(),
+        };
+        let mut line = serde_json::to_string(&stored)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads the saved discoveries, least recently seen first, skipping the ones
+    /// not seen within `ttl`. A missing file holds no discoveries.
+    ///
+    /// The file is rewritten without the skipped entries and with one line per server,
+    /// so it doesn't grow without bound.
+    pub async fn load<D>(&self, ttl: Option<Duration>) -> Result<Vec<D>, Error>
+    where
+        D: ServerIdentity + serde::Serialize + DeserializeOwned,
+    {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let now = unix_now();
+        let mut latest: HashMap<String, StoredDiscovery<D>> = HashMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let stored: StoredDiscovery<D> = match serde_json::from_str(line) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    // e.g. a line cut short by a crash while appending
+                    warn!(error = %e, "skipping unreadable saved discovery");
+                    continue;
+                }
+            };
+            if ttl.is_some_and(|ttl| now.saturating_sub(stored.seen_at) > ttl.as_secs()) {
+                continue;
+            }
+            let Some(url) = stored.discovery.server_url() else {
+                continue;
+            };
+            if latest
+                .get(&url)
+                .is_none_or(|previous| previous.seen_at <= stored.seen_at)
+            {
+                latest.insert(url, stored);
+            }
+        }
+        let mut stored: Vec<StoredDiscovery<D>> = latest.into_values().collect();
+        stored.sort_by_key(|stored| stored.seen_at);
+
+        let mut compacted = String::new();
+        for entry in &stored {
+            compacted.push_str(&serde_json::to_string(entry)?);
+            compacted.push('\n');
+        }
+        tokio::fs::write(&self.path, compacted).await?;
+        Ok(stored.into_iter().map(|stored| stored.discovery).collect())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Beacon {
+        pubkey: &'static str,
+        url: &'static str,
+    }
+
+    impl ServerIdentity for Beacon {
+        fn server_key(&self) -> String {
+            self.pubkey.to_string()
+        }
+
+        fn server_url(&self) -> Option<String> {
+            Some(self.url.to_string())
+        }
+    }
+
+    #[test]
+    fn same_server_compares_pubkeys_only() {
+        let a = Beacon {
+            pubkey: "key-a",
+            url: "https://10.0.0.1",
+        };
+        let moved = Beacon {
+            pubkey: "key-a",
+            url: "https://10.0.0.2",
+        };
+        let b = Beacon {
+            pubkey: "key-b",
+            url: "https://10.0.0.1",
+        };
+        assert!(a.same_server(&moved));
+        assert!(!a.same_server(&b));
+    }
+
+    #[test]
+    fn primary_url_is_the_advertised_url() {
+        let a = Beacon {
+            pubkey: "key-a",
+            url: "https://10.0.0.1",
+        };
+        assert_eq!(a.primary_url().unwrap(), "https://10.0.0.1");
+    }
+
+    #[test]
+    fn known_servers_detects_updates() {
+        let mut known = KnownServers::new();
+        let a = Beacon {
+            pubkey: "key-a",
+            url: "https://10.0.0.1",
+        };
+        let moved = Beacon {
+            pubkey: "key-a",
+            url: "https://10.0.0.2",
+        };
+        let b = Beacon {
+            pubkey: "key-b",
+            url: "https://10.0.0.3",
+        };
+
+        assert_eq!(known.observe(&a), Observation::New);
+        assert_eq!(known.observe(&a), Observation::Unchanged);
+        assert_eq!(
+            known.observe(&moved),
+            Observation::Updated {
+                previous_url: Some("https://10.0.0.1".to_string())
+            }
+        );
+        assert_eq!(known.observe(&moved), Observation::Unchanged);
+        assert_eq!(known.observe(&b), Observation::New);
+        assert_eq!(known.len(), 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = DiscoveryCache::new(2);
+        assert_eq!(cache.insert("https://a", 1), None);
+        assert_eq!(cache.insert("https://b", 2), None);
+
+        // touching `a` makes `b` the least recently used
+        assert!(cache.touch("https://a"));
+        assert_eq!(cache.insert("https://c", 3), Some(2));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("https://a"), Some(&1));
+        assert_eq!(cache.get("https://b"), None);
+        assert_eq!(cache.get("https://c"), Some(&3));
+    }
+
+    #[test]
+    fn reinserting_updates_in_place() {
+        let mut cache = DiscoveryCache::new(2);
+        cache.insert("https://a", 1);
+        cache.insert("https://b", 2);
+        assert_eq!(cache.insert("https://a", 10), None);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("https://a"), Some(&10));
+        // `a` is now most recently used so `b` is evicted next
+        assert_eq!(cache.insert("https://c", 3), Some(2));
+    }
+
+    #[test]
+    fn get_does_not_affect_recency() {
+        let mut cache = DiscoveryCache::new(2);
+        cache.insert("https://a", 1);
+        cache.insert("https://b", 2);
+        assert_eq!(cache.get("https://a"), Some(&1));
+        assert_eq!(cache.insert("https://c", 3), Some(1));
+
+        let order: Vec<_> = cache.iter().map(|(url, _)| url).collect();
+        assert_eq!(order, vec!["https://c", "https://b"]);
+    }
+
+    #[test]
+    fn default_size() {
+        let cache: DiscoveryCache<u32> = DiscoveryCache::default();
+        assert_eq!(cache.max_size(), 64);
+        assert_eq!(DiscoveryCache::<u32>::new(0).max_size(), 1);
+    }
+
+    #[test]
+    fn snapshots_are_independent_of_the_cache() {
+        let mut cache = DiscoveryCache::new(4);
+        cache.insert("https://a", 1);
+        cache.insert("https://b", 2);
+
+        let snapshot = cache.snapshot();
+        let urls = cache.urls();
+        cache.insert("https://c", 3);
+        cache.remove("https://a");
+
+        assert_eq!(snapshot, vec![2, 1]);
+        assert_eq!(urls, vec!["https://b".to_string(), "https://a".to_string()]);
+        assert_eq!(cache.snapshot(), vec![3, 2]);
+    }
+
+    #[test]
+    fn expire_removes_stale_entries() {
+        let mut cache = DiscoveryCache::new(4);
+        cache.insert("https://a", 1);
+        cache.insert("https://b", 2);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.refresh("https://b"));
+        assert!(!cache.refresh("https://c"));
+
+        assert_eq!(cache.expire(Duration::from_millis(10)), vec![1]);
+        assert_eq!(cache.urls(), vec!["https://b".to_string()]);
+        assert_eq!(cache.expire(Duration::from_secs(60)), Vec::<u32>::new());
+        assert_eq!(cache.expire(Duration::ZERO), vec![2]);
+        assert!(cache.is_empty());
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Saved {
+        pubkey: String,
+        url: String,
+    }
+
+    impl ServerIdentity for Saved {
+        fn server_key(&self) -> String {
+            self.pubkey.clone()
+        }
+
+        fn server_url(&self) -> Option<String> {
+            Some(self.url.clone())
+        }
+    }
+
+    fn saved(pubkey: &str, url: &str) -> Saved {
+        Saved {
+            pubkey: pubkey.to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn store_keeps_the_latest_fresh_discoveries() {
+        let path =
+            std::env::temp_dir().join(format!("verdant-discoveries-{}", uuid::Uuid::new_v4()));
+        let store = DiscoveryStore::new(&path);
+        assert_eq!(store.load::<Saved>(None).await.unwrap(), Vec::new());
+
+        store.append(&saved("a", "https://a")).await.unwrap();
+        store.append(&saved("b", "https://b")).await.unwrap();
+        store.append(&saved("a2", "https://a")).await.unwrap();
+        let stale = StoredDiscovery {
+            discovery: saved("c", "https://c"),
+            seen_at: unix_now() - 3600,
+        };
+        let mut contents = tokio::fs::read_to_string(&path).await.unwrap();
+        contents.push_str(&serde_json::to_string(&stale).unwrap());
+        contents.push_str("\n{\"discovery\":");
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        let loaded: Vec<Saved> = store.load(Some(Duration::from_secs(60))).await.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.contains(&saved("a2", "https://a")));
+        assert!(loaded.contains(&saved("b", "https://b")));
+        // rewritten without the stale, duplicate and broken lines
+        let contents = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(contents.lines().count(), 2);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -1,19 +1,30 @@
+#[cfg(not(feature = "full"))]
+use alloc::string::{String, ToString};
+#[cfg(feature = "full")]
 use std::string::FromUtf8Error;
+#[cfg(feature = "full")]
 use thiserror::Error;
 /// Common result type for this crate.
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
+#[cfg(feature = "full")]
 pub type ProtocolError = opaque_ke::errors::ProtocolError;
 
 /// Error type that unifies opaque-ke protocol errors and reqwest HTTP errors.
+#[cfg(feature = "full")]
 #[derive(Debug, Error)]
 pub enum Error {
     /// Errors produced by the opaque-ke protocol implementation.
     #[error("OPAQUE protocol error: {0}")]
     Opaque(#[from] opaque_ke::errors::ProtocolError),
 
-    /// Errors produced by reqwest HTTP client.
+    /// reqwest errors that aren't network failures, e.g. a body that couldn't be decoded.
+    /// See [`Error::from_reqwest_error`].
     #[error("HTTP request error: {0}")]
-    Http(#[from] reqwest::Error),
+    Http(reqwest::Error),
+
+    /// the server couldn't be reached or answered with an error status.
+    #[error("network error: {0:?}")]
+    Network(NetworkErrorKind),
 
     /// Fallback catch-all with a human readable message.
     #[error("internal error: {0}")]
@@ -48,6 +59,191 @@ pub enum Error {
     JsonErr(#[from] serde_json::Error),
     #[error("unauthorized, no access_token set")]
     Unauthorized,
+    #[error("unauthorized: {0}")]
+    LoginUnauthorized(crate::auth::UnauthorizedReason),
+    #[error("invalid username: {0}")]
+    InvalidUsername(String),
+    #[error("transcript too large: {0} bytes exceeds the maximum of {1}")]
+    TranscriptTooLarge(usize, usize),
+    #[error("transcript step {0} appended after step {1}")]
+    TranscriptStepOutOfOrder(u64, u64),
+    /// the login completion wasn't signed for this session, e.g. a replayed one.
+    #[error("failed to verify server authenticity")]
+    ServerAuthentication,
+    /// a blocking task panicked or was cancelled, e.g. by the runtime shutting down.
+    #[error("background task failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    /// the request didn't complete within the configured timeout.
+    #[error("operation timed out after {0:?}")]
+    Timeout(std::time::Duration),
+}
+
+/// Converts a failed login into an error, see [`crate::auth::LoginResult`].
+///
+/// `Unauthorized` keeps its reason as [`Error::LoginUnauthorized`].
+#[cfg(feature = "full")]
+impl From<crate::auth::LoginResult> for Error {
+    fn from(result: crate::auth::LoginResult) -> Self {
+        use crate::auth::LoginResult;
+        match result {
+            LoginResult::Unauthorized(reason) => Error::LoginUnauthorized(reason),
+            LoginResult::PasswordReset => Error::Internal("password reset required".to_string()),
+            LoginResult::UnknownServer(server) => Error::Internal(server),
+            LoginResult::Success(_) => Error::Internal("login succeeded".to_string()),
+        }
+    }
+}
+
+/// What went wrong talking to a server, see [`Error::Network`].
+#[cfg(feature = "full")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkErrorKind {
+    /// no connection could be made, e.g. refused, reset or the host is unreachable.
+    ConnectionRefused,
+    DnsFailure,
+    TlsError,
+    /// the server answered with this (error) status.
+    HttpError(u16),
+}
+
+#[cfg(feature = "full")]
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::from_reqwest_error(e)
+    }
+}
+
+#[cfg(feature = "full")]
+impl Error {
+    /// Classifies a reqwest error into a [`NetworkErrorKind`], errors that aren't about
+    /// reaching the server (decoding, building the request, ...) and connect errors of an
+    /// unknown cause stay [`Error::Http`].
+    ///
+    /// Timeouts stay [`Error::Http`] as well, the [`crate::api::APIClient`] that set the
+    /// timeout reports them as [`Error::Timeout`].
+    pub fn from_reqwest_error(e: reqwest::Error) -> Error {
+        if let Some(status) = e.status() {
+            return Error::Network(NetworkErrorKind::HttpError(status.as_u16()));
+        }
+        if e.is_connect()
+            && !e.is_timeout()
+            && let Some(kind) = std::error::Error::source(&e).and_then(classify_connect_error)
+        {
+            return Error::Network(kind);
+        }
+        Error::Http(e)
+    }
+
+    /// status of a failed HTTP response.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            Error::Network(NetworkErrorKind::HttpError(status)) => Some(*status),
+            Error::Http(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// `true` for HTTP 5xx responses, i.e. failures that another server might not have.
+    pub fn is_server_error(&self) -> bool {
+        self.http_status()
+            .is_some_and(|status| (500..600).contains(&status))
+    }
+
+    /// `true` for failures that may go away by trying again: timeouts, refused
+    /// connections and 5xx responses.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network(NetworkErrorKind::ConnectionRefused) => true,
+            _ => self.is_timeout() || self.is_server_error(),
+        }
+    }
+
+    /// `true` if the operation gave up waiting, including HTTP timeouts without a known limit.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout(_) => true,
+            Error::Http(e) => e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// how long was waited before giving up, if known.
+    pub fn timeout_duration(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::Timeout(duration) => Some(*duration),
+            _ => None,
+        }
+    }
+}
+
+/// A failed DNS lookup. The HTTP clients of [`crate::api::APIClient`] resolve hostnames
+/// with a resolver returning this, so [`Error::from_reqwest_error`] can tell it apart.
+#[cfg(feature = "full")]
+#[derive(Debug, Error)]
+#[error("DNS lookup failed: {0}")]
+pub struct DnsError(#[source] pub std::io::Error);
+
+/// Tells the reasons a connection can fail apart by the types in the error's sources,
+/// reqwest doesn't expose them directly. `None` if none of them is recognized.
+#[cfg(feature = "full")]
+fn classify_connect_error(err: &(dyn std::error::Error + 'static)) -> Option<NetworkErrorKind> {
+    use std::io::ErrorKind;
+
+    if err.is::<DnsError>() {
+        return Some(NetworkErrorKind::DnsFailure);
+    }
+    if err.is::<rustls::Error>() {
+        return Some(NetworkErrorKind::TlsError);
+    }
+    if let Some(io) = err.downcast_ref::<std::io::Error>() {
+        if matches!(
+            io.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::AddrNotAvailable
+        ) {
+            return Some(NetworkErrorKind::ConnectionRefused);
+        }
+        // `io::Error::source` skips the error it wraps, e.g. the rustls one of a TLS failure
+        if let Some(kind) = io.get_ref().and_then(|inner| classify_connect_error(inner)) {
+            return Some(kind);
+        }
+    }
+    err.source().and_then(classify_connect_error)
+}
+
+/// Reduced error type of the `crypto-only` build, without the protocol and HTTP errors.
+///
+/// `std::io::Error` isn't available under `no_std`, I/O failures carry their message instead.
+#[cfg(not(feature = "full"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Fallback catch-all with a human readable message.
+    Internal(String),
+    IOError(String),
+}
+
+#[cfg(not(feature = "full"))]
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Internal(msg) => write!(f, "internal error: {}", msg),
+            Error::IOError(msg) => write!(f, "IO Error: {}", msg),
+        }
+    }
+}
+
+#[cfg(all(not(feature = "full"), feature = "std"))]
+impl std::error::Error for Error {}
+
+#[cfg(all(not(feature = "full"), feature = "std"))]
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e.to_string())
+    }
 }
 
 impl From<&str> for Error {
@@ -61,3 +257,74 @@ impl From<String> for Error {
         Error::Internal(s)
     }
 }
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reqwest_errors_are_classified() {
+        let (url, _) = crate::test_util::mock_server(vec![
+            (503, String::new()),
+            (404, String::new()),
+            (200, "not json".to_string()),
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let get = |client: &reqwest::Client| client.get(&url).send();
+
+        let unavailable = Error::from(get(&client).await.unwrap().error_for_status().unwrap_err());
+        assert!(matches!(
+            unavailable,
+            Error::Network(NetworkErrorKind::HttpError(503))
+        ));
+        assert!(unavailable.is_retryable() && unavailable.is_server_error());
+
+        let not_found = Error::from(get(&client).await.unwrap().error_for_status().unwrap_err());
+        assert_eq!(not_found.http_status(), Some(404));
+        assert!(!not_found.is_retryable());
+
+        let decode = Error::from(get(&client).await.unwrap().json::<u32>().await.unwrap_err());
+        assert!(matches!(decode, Error::Http(_)));
+        assert!(!decode.is_retryable());
+
+        // nothing listens on the discard port
+        let refused = Error::from(client.get("http://127.0.0.1:9").send().await.unwrap_err());
+        assert!(matches!(
+            refused,
+            Error::Network(NetworkErrorKind::ConnectionRefused)
+        ));
+        assert!(refused.is_retryable());
+    }
+
+    #[test]
+    fn timeouts_are_retryable() {
+        assert!(Error::Timeout(std::time::Duration::from_secs(1)).is_retryable());
+        assert!(Error::Timeout(std::time::Duration::from_secs(1)).is_timeout());
+        assert!(!Error::Internal("nope".to_string()).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn http_timeouts_are_timeouts() {
+        // accepts connections but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", silent.local_addr().unwrap());
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let error = Error::from(client.get(&url).send().await.unwrap_err());
+        assert!(matches!(error, Error::Http(_)));
+        assert!(error.is_timeout() && error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn failed_tasks_are_errors() {
+        let cancelled = tokio::spawn(std::future::pending::<()>());
+        cancelled.abort();
+        let error = Error::from(cancelled.await.unwrap_err());
+        assert!(matches!(error, Error::TaskFailed(_)));
+        assert!(!error.is_retryable());
+    }
+}
@@ -1,46 +1,55 @@
 use crate::services::VerdantErr;
 use jni::JNIEnv;
-use jni::objects::JString;
+use jni::objects::{JObject, JString, JValue};
 use jni::sys::jint;
 use jni_sys::*;
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
+use std::ffi::CString;
 use std::ptr;
 
 use serde_json;
 use tokio::runtime::Runtime;
 
-use crate::services::{LoginRequest, VerdantCmd, VerdantService, VerdantUiCmd};
-use keycast::discovery::Discovery;
+use crate::auth::registration::RegistrationRequest;
+use crate::native::VerdantEventTag;
+use crate::services::{EventCallback, VerdantService, VerdantUiCmd};
+use uuid::Uuid;
 
-pub const VERDANT_SERVER_DISCOVERED: i64 = 1;
-pub const VERDANT_LOGIN_RESULT: i64 = 2;
-pub const VERDANT_LK_RESPONSE: i64 = 3;
+// the tags `VerdantEventListener.onEvent` receives, the values of `VerdantEventTag`.
+// Before events carried tags these were 1 (server discovered), 2 (login result) and 3, the
+// first two swapped to match the C tags, see `jni/VerdantEventListener.java`.
+pub const VERDANT_SERVER_DISCOVERED: i64 = VerdantEventTag::ServerDiscovered as i64;
+pub const VERDANT_LOGIN_RESULT: i64 = VerdantEventTag::LoginResult as i64;
+pub const VERDANT_LK_RESPONSE: i64 = VerdantEventTag::LkToken as i64;
+/// a `RegistrationResult` event, the answer to `register` and `registerUser`.
+pub const VERDANT_REGISTRATION_RESULT: i64 = VerdantEventTag::RegistrationResult as i64;
 
-#[repr(C)]
-struct VerdantEventFFI<'r> {
-    tag: jlong,
-    payload: JString<'r>,
+/// Thrown by the `jint` returning functions, see `jni/VerdantException.java`.
+const VERDANT_EXCEPTION: &str = "org/qrespite/verdant/VerdantException";
+
+/// Throws a `VerdantException` with `message`, returning the value to hand back to Java,
+/// which ignores it once the exception is pending.
+fn throw(env: &mut JNIEnv, message: impl AsRef<str>) -> jint {
+    // if the class can't be loaded a NoClassDefFoundError is pending instead
+    let _ = env.throw_new(VERDANT_EXCEPTION, message.as_ref());
+    0
 }
 
-impl<'r> VerdantEventFFI<'r> {
-    pub fn new(env: &mut JNIEnv<'r>, msg: &str, tag: jlong) -> Self {
-        let payload = env.new_string(msg).expect("failed to create payload");
-        Self { tag, payload }
+/// Reads the Java string argument `name`, `None` with a pending `NullPointerException` if it
+/// is null or a `VerdantException` if it can't be read.
+fn jstring_to_rust(env: &mut JNIEnv, jstr: &JString, name: &str) -> Option<String> {
+    if jstr.is_null() {
+        let _ = env.throw_new("java/lang/NullPointerException", format!("{name} is null"));
+        return None;
     }
-
-    pub fn empty(env: &mut JNIEnv<'r>) -> Self {
-        Self {
-            payload: env.new_string("").expect("failed to create string"),
-            tag: 0,
+    match env.get_string(jstr) {
+        Ok(value) => Some(value.into()),
+        Err(e) => {
+            throw(env, format!("failed to read {name}: {e}"));
+            None
         }
     }
 }
 
-unsafe fn jstring_to_rust(env: &mut JNIEnv, jstr: JString) -> String {
-    env.get_string(&jstr).expect("failed to get string").into()
-}
-
 /// Create a new Tokio runtime
 #[unsafe(no_mangle)]
 pub extern "system" fn Java_org_qrespite_verdant_VerdantService_VerdantRuntimeNew(
@@ -108,6 +117,32 @@ pub extern "system" fn Java_org_qrespite_verdant_VerdantService_VerdantServiceFr
     }
 }
 
+/// Frees the service and then the runtime from `Activity.onDestroy`, either may be 0.
+///
+/// Waits for the service's tasks to finish, and for an event listener or refresh callback
+/// that is running, none is called once this returns. Both pointers are invalid afterwards.
+/// Must not be called from an event listener.
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_onDestroy(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    rt_ptr: jlong,
+) {
+    // the service blocks on the runtime while shutting down, so it goes first
+    if svc_ptr != 0 {
+        let svc = unsafe { Box::from_raw(svc_ptr as *mut VerdantService) };
+        if let Err(e) = svc.shutdown() {
+            let _ = env.throw_new(VERDANT_EXCEPTION, format!("service shutdown failed: {e}"));
+        }
+    }
+    if rt_ptr != 0 {
+        let runtime = unsafe { Box::from_raw(rt_ptr as *mut Runtime) };
+        // don't hang onDestroy on a stuck blocking task
+        runtime.shutdown_timeout(std::time::Duration::from_secs(5));
+    }
+}
+
 /// Login
 #[unsafe(no_mangle)]
 pub extern "system" fn Java_org_qrespite_verdant_VerdantService_login(
@@ -119,24 +154,263 @@ pub extern "system" fn Java_org_qrespite_verdant_VerdantService_login(
     jpassword: JString,
 ) -> jint {
     if svc_ptr == 0 {
-        return -1;
+        return throw(&mut env, "the service is null");
     }
 
-    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+    let svc = unsafe { &mut *(svc_ptr as *mut VerdantService) };
 
     // Convert Java strings to Rust
-    let url = unsafe { jstring_to_rust(&mut env, jurl) };
-    let username = unsafe { jstring_to_rust(&mut env, jusername) };
-    let password = unsafe { jstring_to_rust(&mut env, jpassword) };
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+    let Some(username) = jstring_to_rust(&mut env, &jusername, "username") else {
+        return 0;
+    };
+    let Some(password) = jstring_to_rust(&mut env, &jpassword, "password") else {
+        return 0;
+    };
+
+    match svc.login_sync(url, username, password) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+/// List the participants of a room, answered with a `ParticipantList` event
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_listParticipants(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+    jroom_id: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+    let Some(room_id) = jstring_to_rust(&mut env, &jroom_id, "room id") else {
+        return 0;
+    };
+    let room_id = match Uuid::parse_str(&room_id) {
+        Ok(room_id) => room_id,
+        Err(e) => return throw(&mut env, format!("room id is not a UUID: {e}")),
+    };
+
+    match VerdantService::list_participants(svc.tx(), url, room_id) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_refreshToken(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+
+    match VerdantService::refresh_token(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+/// Create an account, `jrequest` is a JSON encoded `RegistrationRequest`.
+/// Answered with a `Registered` event
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_register(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+    jrequest: JString,
+    jpassword: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+    let Some(request) = jstring_to_rust(&mut env, &jrequest, "request") else {
+        return 0;
+    };
+    let request = match serde_json::from_str::<RegistrationRequest>(&request) {
+        Ok(request) => request,
+        Err(e) => {
+            return throw(
+                &mut env,
+                format!("request is not a JSON encoded RegistrationRequest: {e}"),
+            );
+        }
+    };
+    let Some(password) = jstring_to_rust(&mut env, &jpassword, "password") else {
+        return 0;
+    };
+
+    match VerdantService::register(svc.tx(), url, request, password) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+/// Like `register`, for servers only asking for a username and email.
+/// Answered with a `Registered` event, the server doesn't need to be discovered or added first.
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_registerUser(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+    jusername: JString,
+    jpassword: JString,
+    jemail: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+    let Some(username) = jstring_to_rust(&mut env, &jusername, "username") else {
+        return 0;
+    };
+    let Some(password) = jstring_to_rust(&mut env, &jpassword, "password") else {
+        return 0;
+    };
+    let Some(email) = jstring_to_rust(&mut env, &jemail, "email") else {
+        return 0;
+    };
+    let request = RegistrationRequest {
+        first_name: String::new(),
+        last_name: String::new(),
+        username,
+        email,
+        gender: None,
+        nonce: None,
+    };
+
+    match VerdantService::register(svc.tx(), url, request, password) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_logout(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+
+    match VerdantService::logout(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_ping(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+
+    match VerdantService::ping(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_) => throw(&mut env, "the service has shut down"),
+    }
+}
+
+/// Add a server by url without waiting for it to be discovered, answered with a `ServerAdded` event
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_addServer(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &mut *(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
 
-    let tx = svc.tx().clone();
-    match VerdantService::login(&tx, url, username, password) {
+    svc.add_server_sync(url);
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_healthCheck(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    jurl: JString,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let Some(url) = jstring_to_rust(&mut env, &jurl, "url") else {
+        return 0;
+    };
+
+    match VerdantService::health_check(svc.tx(), url) {
         Ok(_) => 0,
-        Err(_) => -2,
+        Err(_) => throw(&mut env, "the service has shut down"),
     }
 }
 
-/// Try receive event
+/// Try receive event, a no-op `Error` event if nothing is pending. Returns `null` with a
+/// pending `VerdantException` if the service is null or the event can't be handed to Java.
 #[unsafe(no_mangle)]
 pub extern "system" fn Java_org_qrespite_verdant_VerdantService_TryRecv<'r>(
     mut env: JNIEnv<'r>,
@@ -144,21 +418,206 @@ pub extern "system" fn Java_org_qrespite_verdant_VerdantService_TryRecv<'r>(
     svc_ptr: jlong,
 ) -> JString<'r> {
     if svc_ptr == 0 {
-        return env.new_string("").expect("failed to create empty JString");
+        throw(&mut env, "the service is null");
+        return JObject::null().into();
+    }
+    let svc = unsafe { &mut *(svc_ptr as *mut VerdantService) };
+
+    let event = svc
+        .try_recv()
+        .unwrap_or_else(|| VerdantUiCmd::Error(VerdantErr::noop()));
+    let event = match serde_json::to_string(&event) {
+        Ok(event) => event,
+        Err(e) => {
+            throw(&mut env, format!("failed to encode the event: {e}"));
+            return JObject::null().into();
+        }
+    };
+    match env.new_string(event) {
+        Ok(event) => event,
+        Err(e) => {
+            // e.g. an OutOfMemoryError may already be pending
+            if !env.exception_check().unwrap_or(false) {
+                throw(&mut env, format!("failed to create the event string: {e}"));
+            }
+            JObject::null().into()
+        }
+    }
+}
+
+/// The discovered servers as JSON encoded `Discovery` strings, most recently seen first.
+/// Returns an empty array if nothing was discovered yet, `null` with a pending
+/// `VerdantException` if the service is null.
+///
+/// On the Java side, e.g. with Gson:
+///
+/// ```java
+/// public List<Discovery> getDiscoveredServers() {
+///     Gson gson = new Gson();
+///     List<Discovery> servers = new ArrayList<>();
+///     for (String json : getDiscoveries(svcPtr)) {
+///         servers.add(gson.fromJson(json, Discovery.class));
+///     }
+///     return servers;
+/// }
+/// ```
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_getDiscoveries(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+) -> jni::sys::jobjectArray {
+    if svc_ptr == 0 {
+        throw(&mut env, "the service is null");
+        return ptr::null_mut();
+    }
+
+    let svc = unsafe { &*(svc_ptr as *mut VerdantService) };
+
+    let discoveries: Vec<String> = svc
+        .discoveries_snapshot()
+        .iter()
+        .filter_map(|discovery| serde_json::to_string(discovery).ok())
+        .collect();
+    let result = (|| -> jni::errors::Result<jni::sys::jobjectArray> {
+        let array = env.new_object_array(
+            discoveries.len() as jint,
+            "java/lang/String",
+            JObject::null(),
+        )?;
+        for (i, json) in discoveries.iter().enumerate() {
+            let json = env.new_string(json)?;
+            env.set_object_array_element(&array, i as jint, &json)?;
+            env.delete_local_ref(json)?;
+        }
+        Ok(array.into_raw())
+    })();
+    match result {
+        Ok(array) => array,
+        Err(e) => {
+            // e.g. an OutOfMemoryError may already be pending
+            if !env.exception_check().unwrap_or(false) {
+                throw(
+                    &mut env,
+                    format!("failed to build the discoveries array: {e}"),
+                );
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Deliver events to `callback_obj`, an `org.qrespite.verdant.VerdantEventListener`, instead
+/// of `TryRecv`. `tag` and `payload` are the same as for the C `verdant_service_set_callback`.
+///
+/// Threading model: `onEvent` is always called on one native thread owned by the service,
+/// "verdant-events", one event at a time, never on the thread that set the listener. The
+/// thread is attached to the JVM on the first event and detached when it exits after the
+/// service shut down. Android listeners must post to the main looper themselves. Passing
+/// `null` drops events until another listener is set. `onEvent` must not free the service.
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_qrespite_verdant_VerdantService_setEventCallback(
+    mut env: JNIEnv,
+    _class: jni_sys::jclass,
+    svc_ptr: jlong,
+    callback_obj: JObject,
+) -> jint {
+    if svc_ptr == 0 {
+        return throw(&mut env, "the service is null");
     }
+
     let svc = unsafe { &mut *(svc_ptr as *mut VerdantService) };
 
-    match svc.try_recv() {
-        Some(evt) => {
-            let event = serde_json::to_string(&evt).unwrap();
-            env.new_string(event)
-                .expect("failed to create event JString")
+    let callback = if callback_obj.is_null() {
+        None
+    } else {
+        let vm = match env.get_java_vm() {
+            Ok(vm) => vm,
+            Err(e) => return throw(&mut env, format!("failed to get the JavaVM: {e}")),
+        };
+        let listener = match env.new_global_ref(&callback_obj) {
+            Ok(listener) => listener,
+            Err(e) => return throw(&mut env, format!("failed to reference the listener: {e}")),
+        };
+        Some(Box::new(move |evt: VerdantUiCmd| {
+            let event = crate::native::event_to_ffi(evt);
+            let payload = (!event.payload.is_null())
+                .then(|| unsafe { CString::from_raw(event.payload) })
+                .map(|payload| payload.to_string_lossy().into_owned());
+            // detached by the jni crate once the verdant-events thread exits
+            let mut env = match vm.attach_current_thread_permanently() {
+                Ok(env) => env,
+                Err(_) => return,
+            };
+            let result = env.with_local_frame(4, |env| -> jni::errors::Result<()> {
+                let payload = match &payload {
+                    Some(payload) => JObject::from(env.new_string(payload)?),
+                    None => JObject::null(),
+                };
+                env.call_method(
+                    &listener,
+                    "onEvent",
+                    "(JLjava/lang/String;)V",
+                    &[JValue::Long(event.tag as jlong), JValue::Object(&payload)],
+                )?;
+                Ok(())
+            });
+            if result.is_err() && env.exception_check().unwrap_or(false) {
+                // an exception thrown by onEvent must not leak into the next call
+                let _ = env.exception_describe();
+                let _ = env.exception_clear();
+            }
+        }) as EventCallback)
+    };
+
+    match svc.set_event_callback(callback) {
+        Ok(()) => 0,
+        Err(e) => throw(&mut env, format!("failed to start the event thread: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{LoginResult, UnauthorizedReason};
+
+    /// tag `setEventCallback` hands to the listener for `event`.
+    fn delivered_tag(event: VerdantUiCmd) -> i64 {
+        let event = crate::native::event_to_ffi(event);
+        if !event.payload.is_null() {
+            drop(unsafe { CString::from_raw(event.payload) });
         }
-        None => {
-            let noop = VerdantUiCmd::Error(VerdantErr::noop());
-            let noop_str = serde_json::to_string(&noop).unwrap();
-            env.new_string(&noop_str)
-                .expect("failed to create empty JString")
+        event.tag as i64
+    }
+
+    #[test]
+    fn constants_match_the_delivered_tags() {
+        let login = VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+            UnauthorizedReason::InvalidCredentials,
+        ));
+        assert_eq!(delivered_tag(login), VERDANT_LOGIN_RESULT);
+        let registered = VerdantUiCmd::Registered {
+            url: "https://verdant.example".to_string(),
+            username: "alice".to_string(),
+        };
+        assert_eq!(delivered_tag(registered), VERDANT_REGISTRATION_RESULT);
+        assert_eq!(
+            VERDANT_SERVER_DISCOVERED,
+            VerdantEventTag::ServerDiscovered as i64
+        );
+        assert_eq!(VERDANT_LK_RESPONSE, VerdantEventTag::LkToken as i64);
+    }
+
+    #[test]
+    fn java_constants_match() {
+        let java = include_str!("../jni/VerdantEventListener.java");
+        for (name, value) in [
+            ("LOGIN_RESULT", VERDANT_LOGIN_RESULT),
+            ("SERVER_DISCOVERED", VERDANT_SERVER_DISCOVERED),
+            ("LK_RESPONSE", VERDANT_LK_RESPONSE),
+            ("REGISTRATION_RESULT", VERDANT_REGISTRATION_RESULT),
+        ] {
+            assert!(java.contains(&format!("long {name} = {value};")), "{name}");
         }
     }
 }
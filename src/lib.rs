@@ -1,12 +1,44 @@
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+
+extern crate alloc;
+
+/// Version of this crate, sent to servers with every request so they can reject
+/// clients that are too old.
+pub const VERDANT_CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Version of the wire format spoken with servers, bumped whenever a request or
+/// response changes incompatibly.
+pub const VERDANT_PROTOCOL_VERSION: u32 = 1;
+
+#[cfg(feature = "full")]
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "full")]
 pub mod api;
+#[cfg(feature = "full")]
 pub mod auth;
+#[cfg(feature = "full")]
 pub mod client;
+#[cfg(feature = "full")]
 pub mod config;
 pub mod crypto;
+#[cfg(feature = "full")]
+pub mod discovery;
 pub mod errors;
 #[cfg(feature = "jni")]
 pub mod jni;
+#[cfg(feature = "full")]
 pub mod livekit;
+#[cfg(feature = "full")]
 pub mod native;
+#[cfg(feature = "full")]
+pub mod p2p;
+#[cfg(feature = "full")]
+pub mod plugin;
+#[cfg(feature = "full")]
 pub mod server;
+#[cfg(feature = "full")]
 pub mod services;
+#[cfg(all(feature = "full", any(test, feature = "test-utils")))]
+pub mod test_util;
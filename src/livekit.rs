@@ -1,4 +1,8 @@
+use crate::errors::Error;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
@@ -7,4 +11,211 @@ pub struct TokenResponse {
     pub token: String,
     pub room: String,
     pub url: String,
+    /// unix timestamp in seconds after which `token` is rejected, if known.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// The claims of a LiveKit access token read by [`TokenResponse::from_jwt`].
+#[derive(Deserialize)]
+struct LiveKitClaims {
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(default)]
+    video: Option<LiveKitVideoGrant>,
+    /// not a standard LiveKit claim, set by verdant servers.
+    #[serde(default)]
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LiveKitVideoGrant {
+    #[serde(default)]
+    room: Option<String>,
+}
+
+impl TokenResponse {
+    /// Builds a response from a LiveKit access token, without verifying its signature.
+    ///
+    /// The room comes from the video grant, `room_id` is parsed from the room name
+    /// (nil if it isn't a UUID) and `url` is empty unless the token carries a `url` claim.
+    pub fn from_jwt(jwt: &str) -> Result<Self, Error> {
+        let payload = jwt
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| Error::Internal("malformed LiveKit token".to_string()))?;
+        let claims: LiveKitClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+        let room = claims
+            .video
+            .and_then(|video| video.room)
+            .unwrap_or_default();
+        Ok(Self {
+            room_id: Uuid::parse_str(&room).unwrap_or_default(),
+            token: jwt.to_string(),
+            room,
+            url: claims.url.unwrap_or_default(),
+            expires_at: claims.exp,
+        })
+    }
+
+    /// `true` once `expires_at` has passed, tokens without an expiry never expire.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at < unix_now())
+    }
+
+    /// how long the token stays valid, `None` if it has no expiry.
+    /// Zero for expired tokens.
+    pub fn time_until_expiry(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| Duration::from_secs(expires_at.saturating_sub(unix_now())))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A participant of a LiveKit room, as listed by `/rpc/rooms/{room_id}/participants`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Participant {
+    pub identity: String,
+    pub name: Option<String>,
+    /// unix timestamp in seconds.
+    pub joined_at: u64,
+    pub is_publisher: bool,
+    /// ids of the tracks the participant publishes.
+    pub tracks: Vec<String>,
+}
+
+/// Kind of change reported in a [`RoomEvent`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RoomEventType {
+    ParticipantJoined,
+    ParticipantLeft,
+    RecordingStarted,
+    RecordingStopped,
+    RoomClosed,
+}
+
+/// A single event from the `/rpc/rooms/{room_id}/events` server-sent event stream.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RoomEvent {
+    pub event_type: RoomEventType,
+    /// identity of the participant that joined or left.
+    #[serde(default)]
+    pub participant: Option<String>,
+}
+
+/// Incremental parser for a `text/event-stream` body.
+///
+/// Only the `data` field is used, comments and other fields are skipped.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next chunk of the body, returning the data of every event it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                // a blank line dispatches the event
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(value) = line.strip_prefix("data:") {
+                self.data
+                    .push(value.strip_prefix(' ').unwrap_or(value).to_string());
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(expires_at: Option<u64>) -> TokenResponse {
+        TokenResponse {
+            room_id: Uuid::nil(),
+            token: "token".to_string(),
+            room: "lobby".to_string(),
+            url: "wss://livekit.example".to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn token_expiry() {
+        let now = unix_now();
+        let expired = token(Some(now - 10));
+        assert!(expired.is_expired());
+        assert_eq!(expired.time_until_expiry(), Some(Duration::ZERO));
+
+        let valid = token(Some(now + 600));
+        assert!(!valid.is_expired());
+        assert!(valid.time_until_expiry().unwrap() > Duration::from_secs(590));
+
+        assert!(!token(None).is_expired());
+        assert_eq!(token(None).time_until_expiry(), None);
+    }
+
+    #[test]
+    fn token_response_from_jwt() {
+        let room_id = Uuid::new_v4();
+        let payload = URL_SAFE_NO_PAD.encode(format!(
+            r#"{{"exp":1700000000,"sub":"alice","video":{{"room":"{}","roomJoin":true}},"url":"wss://lk.example"}}"#,
+            room_id
+        ));
+        let jwt = format!("eyJhbGciOiJIUzI1NiJ9.{}.c2ln", payload);
+
+        let response = TokenResponse::from_jwt(&jwt).unwrap();
+        assert_eq!(response.room_id, room_id);
+        assert_eq!(response.room, room_id.to_string());
+        assert_eq!(response.url, "wss://lk.example");
+        assert_eq!(response.expires_at, Some(1_700_000_000));
+        assert_eq!(response.token, jwt);
+        assert!(response.is_expired());
+        assert!(TokenResponse::from_jwt("not a jwt").is_err());
+    }
+
+    #[test]
+    fn sse_events_split_across_chunks() {
+        let mut parser = SseParser::new();
+        assert!(parser.push(b": keep-alive\n\nevent: room\nda").is_empty());
+        assert!(parser.push(b"ta: {\"a\":\r\n").is_empty());
+        assert_eq!(
+            parser.push(b"data: 1}\r\n\r\ndata:x\n\n"),
+            vec!["{\"a\":\n1}".to_string(), "x".to_string()]
+        );
+        assert!(parser.push(b"data: unterminated\n").is_empty());
+    }
+
+    #[test]
+    fn room_event_json() {
+        let event: RoomEvent =
+            serde_json::from_str(r#"{"event_type":"ParticipantJoined","participant":"alice"}"#)
+                .unwrap();
+        assert_eq!(event.event_type, RoomEventType::ParticipantJoined);
+        assert_eq!(event.participant.as_deref(), Some("alice"));
+
+        let event: RoomEvent = serde_json::from_str(r#"{"event_type":"RoomClosed"}"#).unwrap();
+        assert_eq!(event.participant, None);
+    }
 }
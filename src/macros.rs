@@ -0,0 +1,48 @@
+//! no-op stand-ins for the `tracing` event macros, used when the `tracing` feature is disabled.
+//!
+//! The fields and message arguments are still evaluated by reference, so values only
+//! logged don't turn into unused variables.
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! noop_event {
+    () => {};
+    ($name:ident = % $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        noop_event!($($($rest)*)?);
+    };
+    ($name:ident = ? $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        noop_event!($($($rest)*)?);
+    };
+    ($name:ident = $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        noop_event!($($($rest)*)?);
+    };
+    ($message:literal $(, $arg:expr)* $(,)?) => {
+        let _ = format_args!($message $(, $arg)*);
+    };
+    ($name:ident $(, $($rest:tt)*)?) => {
+        let _ = &$name;
+        noop_event!($($($rest)*)?);
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! error {
+    ($($arg:tt)*) => {{ noop_event!($($arg)*); }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {{ noop_event!($($arg)*); }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! info {
+    ($($arg:tt)*) => {{ noop_event!($($arg)*); }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {{ noop_event!($($arg)*); }};
+}
@@ -1,13 +1,74 @@
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
+use std::os::raw::{c_char, c_int, c_void};
 use std::ptr;
+use std::time::Duration;
 
 use serde_json;
 
 use tokio::runtime::Runtime;
 
-use crate::services::{VerdantService, VerdantUiCmd};
- // for type references in comments // adjust paths if needed
+use crate::auth::UnauthorizedReason;
+use crate::auth::registration::RegistrationRequest;
+use crate::services::{ConnectionState, EventCallback, VerdantService, VerdantUiCmd};
+use uuid::Uuid;
+// for type references in comments // adjust paths if needed
+
+thread_local! {
+    /// message of the last failed call on this thread, see `verdant_error_get_last`.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `message` as this thread's last error, see `verdant_error_get_last`.
+fn set_last_error(message: impl Into<String>) {
+    let mut message = message.into();
+    // interior NULs would cut the message short on the C side
+    message.retain(|c| c != '\0');
+    let message = CString::new(message).expect("NUL bytes were removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// [`set_last_error`], then returns `result`, e.g. `return fail(-1, "...")`.
+fn fail<T>(result: T, message: impl Into<String>) -> T {
+    set_last_error(message);
+    result
+}
+
+/// Describe the last failed call made on this thread, like `errno`. Returns NULL if no call
+/// failed since the last `verdant_error_clear`.
+/// The string is UTF-8, owned by the library and must not be freed. It stays valid until the
+/// next failing call or `verdant_error_clear` on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn verdant_error_get_last() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Forget the last error of this thread, `verdant_error_get_last` returns NULL afterwards.
+#[unsafe(no_mangle)]
+pub extern "C" fn verdant_error_clear() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// The library version, e.g. "0.1.0". The string is static and must not be freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn verdant_get_version() -> *const c_char {
+    const VERSION: &CStr =
+        match CStr::from_bytes_with_nul(concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes()) {
+            Ok(version) => version,
+            Err(_) => panic!("crate version contains a NUL byte"),
+        };
+    VERSION.as_ptr()
+}
+
+/// Version of the wire format spoken with servers, bumped whenever it changes incompatibly.
+#[unsafe(no_mangle)]
+pub extern "C" fn verdant_get_protocol_version() -> u32 {
+    crate::VERDANT_PROTOCOL_VERSION
+}
 
 /// Opaque C handle
 #[repr(C)]
@@ -22,6 +83,19 @@ pub enum VerdantEventTag {
     LoginResult = 1,
     ServerDiscovered = 2,
     LkToken = 3,
+    DirectConnectionOffer = 4,
+    Disconnected = 5,
+    UserProfile = 6,
+    RoomUpdate = 7,
+    ParticipantList = 8,
+    ServerFailover = 9,
+    HealthStatus = 10,
+    ServerUnreachable = 11,
+    ServerLost = 12,
+    /// a `verdant_service_register` succeeded, see `VerdantUiCmd::Registered`.
+    RegistrationResult = 13,
+    Pong = 14,
+    ServerAdded = 15,
     Error = 0xFFFFisize,
 }
 
@@ -33,6 +107,55 @@ pub enum LoginResultTag {
     UnknownServer,
 }
 
+/// Reason carried by an `Unauthorized` login result, the `LoginResult` event payload
+/// encodes it as `{"Unauthorized":"<variant name>"}`.
+#[repr(C)]
+pub enum UnauthorizedReasonTag {
+    InvalidCredentials,
+    AccountLocked,
+    AccountNotFound,
+    SessionExpired,
+    RateLimited,
+    LoggedOut,
+    ServerUnreachable,
+}
+
+impl From<UnauthorizedReason> for UnauthorizedReasonTag {
+    fn from(reason: UnauthorizedReason) -> Self {
+        match reason {
+            UnauthorizedReason::InvalidCredentials => UnauthorizedReasonTag::InvalidCredentials,
+            UnauthorizedReason::AccountLocked => UnauthorizedReasonTag::AccountLocked,
+            UnauthorizedReason::AccountNotFound => UnauthorizedReasonTag::AccountNotFound,
+            UnauthorizedReason::SessionExpired => UnauthorizedReasonTag::SessionExpired,
+            UnauthorizedReason::RateLimited => UnauthorizedReasonTag::RateLimited,
+            UnauthorizedReason::LoggedOut => UnauthorizedReasonTag::LoggedOut,
+            UnauthorizedReason::ServerUnreachable => UnauthorizedReasonTag::ServerUnreachable,
+        }
+    }
+}
+
+/// Values returned by `verdant_service_get_state`.
+#[repr(C)]
+pub enum ConnectionStateTag {
+    Idle = 0,
+    Discovering = 1,
+    Authenticating = 2,
+    Connected = 3,
+    Error = 4,
+}
+
+impl From<&ConnectionState> for ConnectionStateTag {
+    fn from(state: &ConnectionState) -> Self {
+        match state {
+            ConnectionState::Idle => ConnectionStateTag::Idle,
+            ConnectionState::Discovering => ConnectionStateTag::Discovering,
+            ConnectionState::Authenticating => ConnectionStateTag::Authenticating,
+            ConnectionState::Connected { .. } => ConnectionStateTag::Connected,
+            ConnectionState::Error(_) => ConnectionStateTag::Error,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct LoginResultFFI {
     pub tag: u32,
@@ -41,6 +164,9 @@ pub struct LoginResultFFI {
 
 /// A simple FFI-safe event result. `payload` is a JSON string whose ownership is transferred
 /// to the caller. The caller must call `verdant_free_cstring(payload)` when done.
+/// `payload` holds the event's data without the event's name, which `tag` already gives:
+/// an event carrying one value sends just that value (e.g. the `LoginResult` or `Discovery`),
+/// others an object of their fields, e.g. `{"url":"...","username":"..."}` for `RegistrationResult`.
 #[repr(C)]
 pub struct VerdantEventFFI {
     pub tag: u32,             // VerdantEventTag as u32
@@ -73,7 +199,8 @@ pub struct DiscoveryFFI {
 /// Create a new VerdantService.
 /// - `start_discovery`: if non-zero, discovery is enabled
 /// - `rt_ptr`: optional pointer to a tokio::runtime::Runtime (if you have one).
-///      If null, a new Runtime will be created internally.
+///   If null, a new Runtime will be created internally.
+///
 /// Returns a pointer to `VerdantServiceHandle` (null on failure).
 #[unsafe(no_mangle)]
 pub extern "C" fn verdant_service_new(
@@ -83,7 +210,7 @@ pub extern "C" fn verdant_service_new(
     // obtain runtime reference
     let runtime = if rt_ptr.is_null() {
         // return null bc this should be created externally
-        return std::ptr::null_mut();
+        return fail(ptr::null_mut(), "verdant_service_new: null runtime");
     } else {
         rt_ptr
     };
@@ -99,18 +226,19 @@ pub extern "C" fn verdant_service_new(
             let handle = Box::new(VerdantServiceHandle { inner: svc_ptr });
             Box::into_raw(handle)
         }
-        Err(_e) => {
+        Err(e) => {
             // On error, if we created the runtime locally, free it.
             if rt_ptr.is_null() {
                 unsafe { drop(Box::from_raw(runtime)) };
             }
-            ptr::null_mut()
+            fail(ptr::null_mut(), format!("verdant_service_new: {e}"))
         }
     }
 }
 
 /// Free the service and all associated resources. Safe to call with null.
 #[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn verdant_service_free(h: *mut VerdantServiceHandle) {
     if h.is_null() {
         return;
@@ -124,6 +252,7 @@ pub extern "C" fn verdant_service_free(h: *mut VerdantServiceHandle) {
 
 /// Send a login command. Returns 0 on success, non-zero on failure (e.g., bad args or send error).
 #[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn verdant_service_login(
     h: *mut VerdantServiceHandle,
     url: *const c_char,
@@ -131,13 +260,13 @@ pub extern "C" fn verdant_service_login(
     password: *const c_char,
 ) -> c_int {
     if h.is_null() || url.is_null() || username.is_null() || password.is_null() {
-        return -1;
+        return fail(-1, "verdant_service_login: null argument");
     }
     let handle = unsafe { &*h };
     if handle.inner.is_null() {
-        return -1;
+        return fail(-1, "verdant_service_login: the service was already freed");
     }
-    let svc = unsafe { &*handle.inner };
+    let svc = unsafe { &mut *handle.inner };
 
     // safely copy strings
     let url = unsafe { CStr::from_ptr(url) }
@@ -150,12 +279,306 @@ pub extern "C" fn verdant_service_login(
         .to_string_lossy()
         .into_owned();
 
-    // clone sender and send using VerdantService::login helper
-    // tx() returns &UnboundedSender<VerdantCmd>, so clone it
-    let tx = svc.tx().clone();
-    match VerdantService::login(&tx, url, username, password) {
+    // login_sync keeps track of the login for verdant_service_get_state
+    match svc.login_sync(url, username, password) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(-2, "verdant_service_login: the service has shut down"),
+    }
+}
+
+/// Request the participants of `room_id` (a UUID string) on the server at `url`, answered
+/// with a `ParticipantList` event. Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_list_participants(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+    room_id: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() || room_id.is_null() {
+        return fail(-1, "verdant_service_list_participants: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_list_participants: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+    let room_id = match unsafe { CStr::from_ptr(room_id) }
+        .to_str()
+        .ok()
+        .and_then(|id| Uuid::parse_str(id).ok())
+    {
+        Some(room_id) => room_id,
+        None => {
+            return fail(
+                -1,
+                "verdant_service_list_participants: room_id is not a UUID",
+            );
+        }
+    };
+
+    match VerdantService::list_participants(svc.tx(), url, room_id) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(
+            -2,
+            "verdant_service_list_participants: the service has shut down",
+        ),
+    }
+}
+
+/// Refresh the session with the server at `url` now, answered with a `LoginResult` event
+/// (or `Error` if the refresh failed). Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_refresh_token(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() {
+        return fail(-1, "verdant_service_refresh_token: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_refresh_token: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+
+    match VerdantService::refresh_token(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(
+            -2,
+            "verdant_service_refresh_token: the service has shut down",
+        ),
+    }
+}
+
+/// Create the account `username` on the server at `url`, for servers only asking for a
+/// username and email. The server doesn't need to be discovered or added first.
+/// Answered with a `RegistrationResult` event, or an `Error` event if the registration failed.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_register(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    email: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() || username.is_null() || password.is_null() || email.is_null() {
+        return fail(-1, "verdant_service_register: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_register: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+    let username = unsafe { CStr::from_ptr(username) }
+        .to_string_lossy()
+        .into_owned();
+    let password = unsafe { CStr::from_ptr(password) }
+        .to_string_lossy()
+        .into_owned();
+    let email = unsafe { CStr::from_ptr(email) }
+        .to_string_lossy()
+        .into_owned();
+    let request = RegistrationRequest {
+        first_name: String::new(),
+        last_name: String::new(),
+        username,
+        email,
+        gender: None,
+        nonce: None,
+    };
+
+    match VerdantService::register(svc.tx(), url, request, password) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(-2, "verdant_service_register: the service has shut down"),
+    }
+}
+
+/// Like `verdant_service_register` with every field of the account, `request` is a JSON
+/// encoded `RegistrationRequest`.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_register_request(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+    request: *const c_char,
+    password: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() || request.is_null() || password.is_null() {
+        return fail(-1, "verdant_service_register_request: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_register_request: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+    let request: RegistrationRequest = match unsafe { CStr::from_ptr(request) }
+        .to_str()
+        .ok()
+        .and_then(|json| serde_json::from_str(json).ok())
+    {
+        Some(request) => request,
+        None => {
+            return fail(
+                -1,
+                "verdant_service_register_request: request is not a JSON encoded RegistrationRequest",
+            );
+        }
+    };
+    let password = unsafe { CStr::from_ptr(password) }
+        .to_string_lossy()
+        .into_owned();
+
+    match VerdantService::register(svc.tx(), url, request, password) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(
+            -2,
+            "verdant_service_register_request: the service has shut down",
+        ),
+    }
+}
+
+/// End the session with the server at `url`, failures are reported as an `Error` event.
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_logout(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() {
+        return fail(-1, "verdant_service_logout: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(-1, "verdant_service_logout: the service was already freed");
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+
+    match VerdantService::logout(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(-2, "verdant_service_logout: the service has shut down"),
+    }
+}
+
+/// Check the round trip time to the server at `url`, answered with a `Pong` event (or `Error`).
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_ping(h: *mut VerdantServiceHandle, url: *const c_char) -> c_int {
+    if h.is_null() || url.is_null() {
+        return fail(-1, "verdant_service_ping: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(-1, "verdant_service_ping: the service was already freed");
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+
+    match VerdantService::ping(svc.tx(), url) {
+        Ok(_) => 0,
+        Err(_send_err) => fail(-2, "verdant_service_ping: the service has shut down"),
+    }
+}
+
+/// Add the server at `url` without waiting for it to be discovered, e.g. when mDNS is blocked.
+/// Answered with a `ServerAdded` event (or `Error`). Returns 0 on success, -1 on bad args.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_add_server(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() {
+        return fail(-1, "verdant_service_add_server: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_add_server: the service was already freed",
+        );
+    }
+    let svc = unsafe { &mut *handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+
+    svc.add_server_sync(url);
+    0
+}
+
+/// Check whether the server at `url` is up, answered with a `HealthStatus` event (or `Error`).
+/// Returns 0 on success, -1 on bad args, -2 on send error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_health_check(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+) -> c_int {
+    if h.is_null() || url.is_null() {
+        return fail(-1, "verdant_service_health_check: null argument");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_health_check: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let url = unsafe { CStr::from_ptr(url) }
+        .to_string_lossy()
+        .into_owned();
+
+    match VerdantService::health_check(svc.tx(), url) {
         Ok(_) => 0,
-        Err(_send_err) => -2,
+        Err(_send_err) => fail(
+            -2,
+            "verdant_service_health_check: the service has shut down",
+        ),
     }
 }
 
@@ -163,8 +586,10 @@ pub extern "C" fn verdant_service_login(
 /// If no event is available, returns an event with tag = None and payload = NULL.
 /// Caller is responsible for freeing `payload` if non-null by calling `verdant_free_cstring`.
 #[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn verdant_service_try_recv(h: *mut VerdantServiceHandle) -> VerdantEventFFI {
     if h.is_null() {
+        set_last_error("verdant_service_try_recv: null service handle");
         return VerdantEventFFI {
             tag: VerdantEventTag::None as u32,
             payload: ptr::null_mut(),
@@ -172,6 +597,7 @@ pub extern "C" fn verdant_service_try_recv(h: *mut VerdantServiceHandle) -> Verd
     }
     let handle = unsafe { &mut *h };
     if handle.inner.is_null() {
+        set_last_error("verdant_service_try_recv: the service was already freed");
         return VerdantEventFFI {
             tag: VerdantEventTag::None as u32,
             payload: ptr::null_mut(),
@@ -180,57 +606,7 @@ pub extern "C" fn verdant_service_try_recv(h: *mut VerdantServiceHandle) -> Verd
     let svc = unsafe { &mut *handle.inner };
 
     match svc.try_recv() {
-        Some(evt) => {
-            // Serialize the inner payload to JSON so C can parse it easily.
-            match evt {
-                VerdantUiCmd::LoginResult(login_res) => {
-                    // login_res is serde-serializable
-                    match serde_json::to_string(&login_res) {
-                        Ok(json) => {
-                            let c = CString::new(json).unwrap_or_default().into_raw();
-                            VerdantEventFFI {
-                                tag: VerdantEventTag::LoginResult as u32,
-                                payload: c,
-                            }
-                        }
-                        Err(_) => VerdantEventFFI {
-                            tag: VerdantEventTag::Error as u32,
-                            payload: ptr::null_mut(),
-                        },
-                    }
-                }
-                VerdantUiCmd::ServerDiscovered(discovery) => {
-                    // serialize discovery (Discovery must be serde serializable)
-                    match serde_json::to_string(&discovery) {
-                        Ok(json) => {
-                            let c = CString::new(json).unwrap_or_default().into_raw();
-                            VerdantEventFFI {
-                                tag: VerdantEventTag::ServerDiscovered as u32,
-                                payload: c,
-                            }
-                        }
-                        Err(_) => VerdantEventFFI {
-                            tag: VerdantEventTag::Error as u32,
-                            payload: ptr::null_mut(),
-                        },
-                    }
-                }
-                VerdantUiCmd::LkToken(token) => match serde_json::to_string(&token) {
-                    Ok(json) => {
-                        let c = CString::new(json).unwrap_or_default().into_raw();
-                        VerdantEventFFI {
-                            tag: VerdantEventTag::LkToken as u32,
-                            payload: c,
-                        }
-                    }
-                    Err(_) => VerdantEventFFI {
-                        tag: VerdantEventTag::Error as u32,
-                        payload: ptr::null_mut(),
-                    },
-                },
-                _ => unimplemented!(),
-            }
-        }
+        Some(evt) => event_to_ffi(evt),
         None => VerdantEventFFI {
             tag: VerdantEventTag::None as u32,
             payload: ptr::null_mut(),
@@ -238,8 +614,391 @@ pub extern "C" fn verdant_service_try_recv(h: *mut VerdantServiceHandle) -> Verd
     }
 }
 
+/// Like `verdant_service_try_recv`, waiting up to `timeout_ms` milliseconds for an event.
+/// Returns an event with tag = None and payload = NULL if none arrived in time.
+/// Blocks the calling thread, so it must not be called from a thread running the Tokio
+/// runtime (e.g. from a refresh callback), that fails with tag = None and sets the last error.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_recv_timeout(
+    h: *mut VerdantServiceHandle,
+    timeout_ms: u64,
+) -> VerdantEventFFI {
+    let none = VerdantEventFFI {
+        tag: VerdantEventTag::None as u32,
+        payload: ptr::null_mut(),
+    };
+    if h.is_null() {
+        return fail(none, "verdant_service_recv_timeout: null service handle");
+    }
+    let handle = unsafe { &mut *h };
+    if handle.inner.is_null() {
+        return fail(
+            none,
+            "verdant_service_recv_timeout: the service was already freed",
+        );
+    }
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return fail(
+            none,
+            "verdant_service_recv_timeout: called from within the Tokio runtime",
+        );
+    }
+    let svc = unsafe { &mut *handle.inner };
+
+    match svc.recv_timeout(Duration::from_millis(timeout_ms)) {
+        Some(evt) => event_to_ffi(evt),
+        None => none,
+    }
+}
+
+/// Converts `evt` for C, serializing the inner payload to JSON so C can parse it easily,
+/// see [`VerdantEventFFI`].
+pub(crate) fn event_to_ffi(evt: VerdantUiCmd) -> VerdantEventFFI {
+    use serde_json::json;
+
+    match evt {
+        VerdantUiCmd::LoginResult(result) => json_event(VerdantEventTag::LoginResult, &result),
+        VerdantUiCmd::ServerDiscovered(discovery) => {
+            json_event(VerdantEventTag::ServerDiscovered, &discovery)
+        }
+        VerdantUiCmd::LkToken(token) => json_event(VerdantEventTag::LkToken, &token),
+        VerdantUiCmd::DirectConnectionOffer {
+            peer_username,
+            offer_sdp,
+            answer_token,
+        } => json_event(
+            VerdantEventTag::DirectConnectionOffer,
+            &json!({
+                "peer_username": peer_username,
+                "offer_sdp": offer_sdp,
+                "answer_token": answer_token,
+            }),
+        ),
+        VerdantUiCmd::Disconnected { url } => {
+            json_event(VerdantEventTag::Disconnected, &json!({ "url": url }))
+        }
+        VerdantUiCmd::UserProfile {
+            url,
+            display_name,
+            avatar_url,
+        } => json_event(
+            VerdantEventTag::UserProfile,
+            &json!({ "url": url, "display_name": display_name, "avatar_url": avatar_url }),
+        ),
+        VerdantUiCmd::RoomUpdate {
+            room_id,
+            event_type,
+            participant,
+        } => json_event(
+            VerdantEventTag::RoomUpdate,
+            &json!({ "room_id": room_id, "event_type": event_type, "participant": participant }),
+        ),
+        VerdantUiCmd::ParticipantList(participants) => {
+            json_event(VerdantEventTag::ParticipantList, &participants)
+        }
+        VerdantUiCmd::ServerFailover { url, fallback_url } => json_event(
+            VerdantEventTag::ServerFailover,
+            &json!({ "url": url, "fallback_url": fallback_url }),
+        ),
+        VerdantUiCmd::HealthStatus(url, status) => json_event(
+            VerdantEventTag::HealthStatus,
+            &json!({ "url": url, "status": status }),
+        ),
+        VerdantUiCmd::Pong(url, rtt) => {
+            json_event(VerdantEventTag::Pong, &json!({ "url": url, "rtt": rtt }))
+        }
+        VerdantUiCmd::ServerUnreachable(url) => {
+            json_event(VerdantEventTag::ServerUnreachable, &json!({ "url": url }))
+        }
+        VerdantUiCmd::ServerAdded { url } => {
+            json_event(VerdantEventTag::ServerAdded, &json!({ "url": url }))
+        }
+        VerdantUiCmd::Registered { url, username } => json_event(
+            VerdantEventTag::RegistrationResult,
+            &json!({ "url": url, "username": username }),
+        ),
+        VerdantUiCmd::ServerLost(discovery) => json_event(VerdantEventTag::ServerLost, &discovery),
+        VerdantUiCmd::Error(err) => json_event(VerdantEventTag::Error, &err),
+    }
+}
+
+/// An event tagged `tag` with `payload` encoded as JSON, an `Error` event without
+/// payload if it can't be encoded.
+fn json_event(tag: VerdantEventTag, payload: &impl serde::Serialize) -> VerdantEventFFI {
+    match serde_json::to_string(payload) {
+        Ok(json) => VerdantEventFFI {
+            tag: tag as u32,
+            payload: CString::new(json).unwrap_or_default().into_raw(),
+        },
+        Err(_) => VerdantEventFFI {
+            tag: VerdantEventTag::Error as u32,
+            payload: ptr::null_mut(),
+        },
+    }
+}
+
+/// Called on the `verdant-events` thread with each event, see `verdant_service_set_callback`.
+/// `payload` is only valid during the call.
+pub type VerdantEventCallback =
+    extern "C" fn(tag: u32, payload: *const c_char, userdata: *mut c_void);
+
+/// Deliver events to `cb` instead of `verdant_service_try_recv`.
+///
+/// Threading model: `cb` is always called on a single thread owned by the service,
+/// `verdant-events`, one event at a time and never on the caller's thread. Toolkits that
+/// require a UI thread should post the event to it from `cb`. `payload` is freed once `cb`
+/// returns, copy it to keep it. Passing a null `cb` drops events until another callback is set.
+/// After `verdant_service_free` returns `cb` is never called again, so `userdata` can be freed.
+/// `verdant_service_free` must not be called from within `cb`.
+///
+/// Events delivered to `cb` don't update the mirrored state, see
+/// [`VerdantService::set_event_callback`]: `verdant_service_get_state`, `get_display_name`,
+/// `get_server_urls` and `get_discoveries` are stale once a callback is set.
+///
+/// Returns 0 on success, -1 on bad args, -2 if the event thread couldn't be started.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_set_callback(
+    h: *mut VerdantServiceHandle,
+    cb: Option<VerdantEventCallback>,
+    userdata: *mut c_void,
+) -> c_int {
+    if h.is_null() {
+        return fail(-1, "verdant_service_set_callback: null service handle");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_set_callback: the service was already freed",
+        );
+    }
+    let svc = unsafe { &mut *handle.inner };
+
+    let callback = cb.map(|cb| {
+        let userdata = CallbackUserData(userdata);
+        Box::new(move |evt: VerdantUiCmd| {
+            let event = event_to_ffi(evt);
+            cb(event.tag, event.payload, userdata.get());
+            if !event.payload.is_null() {
+                unsafe { drop(CString::from_raw(event.payload)) };
+            }
+        }) as EventCallback
+    });
+    match svc.set_event_callback(callback) {
+        Ok(()) => 0,
+        Err(e) => fail(
+            -2,
+            format!("verdant_service_set_callback: couldn't start the event thread: {e}"),
+        ),
+    }
+}
+
+/// Get the state of the service as a `ConnectionStateTag`, -1 on bad args.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_get_state(h: *mut VerdantServiceHandle) -> c_int {
+    if h.is_null() {
+        return fail(-1, "verdant_service_get_state: null service handle");
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_get_state: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+    ConnectionStateTag::from(svc.state()) as c_int
+}
+
+/// Get the display name of the user logged into `url`, as received through a
+/// `UserProfile` event. Returns NULL if unknown.
+/// Caller is responsible for freeing the result by calling `verdant_free_cstring`.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_get_display_name(
+    h: *mut VerdantServiceHandle,
+    url: *const c_char,
+) -> *mut c_char {
+    if h.is_null() || url.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_display_name: null argument",
+        );
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_display_name: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+    let url = unsafe { CStr::from_ptr(url) }.to_string_lossy();
+
+    match svc.display_name(&url) {
+        Some(name) => CString::new(name).unwrap_or_default().into_raw(),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Get the urls of the discovered servers, most recently seen first.
+/// Like every string array of the library the result holds `*out_count` strings followed
+/// by a NULL entry, it is empty (only the NULL) if nothing was discovered yet.
+/// Returns NULL on bad args. Caller is responsible for freeing each string with
+/// `verdant_free_cstring` and then the array with `verdant_free_string_array`.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_get_server_urls(
+    h: *mut VerdantServiceHandle,
+    out_count: *mut usize,
+) -> *mut *mut c_char {
+    if h.is_null() || out_count.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_server_urls: null argument",
+        );
+    }
+    unsafe { *out_count = 0 };
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_server_urls: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let mut urls: Vec<*mut c_char> = svc
+        .server_urls_snapshot()
+        .into_iter()
+        .map(|url| CString::new(url).unwrap_or_default().into_raw())
+        .collect();
+    let count = urls.len();
+    urls.push(ptr::null_mut());
+    unsafe { *out_count = count };
+    Box::into_raw(urls.into_boxed_slice()) as *mut *mut c_char
+}
+
+/// Get the discovered servers as JSON encoded `Discovery` strings, most recently seen first.
+/// The array holds `*out_count` strings followed by a NULL entry, like
+/// `verdant_service_get_server_urls` it is empty (only the NULL) if nothing was discovered yet. Servers added with `verdant_service_add_server` aren't
+/// discoveries, they advertised no key, and are only listed once discovered on the LAN.
+/// Returns NULL on bad args. Caller is responsible for freeing each string with
+/// `verdant_free_cstring` and then the array with `verdant_free_string_array`.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_get_discoveries(
+    h: *mut VerdantServiceHandle,
+    out_count: *mut usize,
+) -> *mut *mut c_char {
+    if h.is_null() || out_count.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_discoveries: null argument",
+        );
+    }
+    unsafe { *out_count = 0 };
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            ptr::null_mut(),
+            "verdant_service_get_discoveries: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let mut discoveries: Vec<*mut c_char> = svc
+        .discoveries_snapshot()
+        .iter()
+        .filter_map(|discovery| serde_json::to_string(discovery).ok())
+        .map(|json| CString::new(json).unwrap_or_default().into_raw())
+        .collect();
+    let count = discoveries.len();
+    discoveries.push(ptr::null_mut());
+    unsafe { *out_count = count };
+    Box::into_raw(discoveries.into_boxed_slice()) as *mut *mut c_char
+}
+
+/// Free an array returned by `verdant_service_get_server_urls` or
+/// `verdant_service_get_discoveries`, `count` must be the count it reported.
+/// The strings in it are not freed, free them with `verdant_free_cstring` first.
+/// Safe to call with NULL.
+#[unsafe(no_mangle)]
+pub extern "C" fn verdant_free_string_array(arr: *mut *mut c_char, count: usize) {
+    if arr.is_null() {
+        return;
+    }
+    // `count` strings and the NULL terminator
+    let len = count.saturating_add(1);
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(arr, len)) });
+}
+
+/// Called after every background token refresh. `url` is the server that was refreshed and
+/// `token` the new access token, or NULL if the refresh failed. Both strings are only valid
+/// for the duration of the call. Invoked from a tokio worker thread.
+pub type VerdantRefreshCallback =
+    extern "C" fn(user_data: *mut c_void, url: *const c_char, token: *const c_char);
+
+/// `user_data` pointer handed back to C callbacks, the caller guarantees it may be
+/// used from any thread.
+struct CallbackUserData(*mut c_void);
+unsafe impl Send for CallbackUserData {}
+unsafe impl Sync for CallbackUserData {}
+
+impl CallbackUserData {
+    // accessed through a method so closures capture the whole (Send) wrapper
+    // rather than just the raw pointer field.
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// Install (or clear, by passing NULL) the token refresh callback.
+/// Returns 0 on success, -1 if the handle is null.
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn verdant_service_set_refresh_callback(
+    h: *mut VerdantServiceHandle,
+    callback: Option<VerdantRefreshCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    if h.is_null() {
+        return fail(
+            -1,
+            "verdant_service_set_refresh_callback: null service handle",
+        );
+    }
+    let handle = unsafe { &*h };
+    if handle.inner.is_null() {
+        return fail(
+            -1,
+            "verdant_service_set_refresh_callback: the service was already freed",
+        );
+    }
+    let svc = unsafe { &*handle.inner };
+
+    let hook = callback.map(|callback| {
+        let user_data = CallbackUserData(user_data);
+        Box::new(move |url: &str, token: Option<&str>| {
+            let url = CString::new(url).unwrap_or_default();
+            let token = token.map(|token| CString::new(token).unwrap_or_default());
+            callback(
+                user_data.get(),
+                url.as_ptr(),
+                token.as_ref().map_or(ptr::null(), |token| token.as_ptr()),
+            );
+        }) as crate::services::RefreshHook
+    });
+    svc.set_refresh_hook(hook);
+    0
+}
+
 /// Free a C string returned by the above APIs (or any CString you create via `into_raw()`).
 #[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn verdant_free_cstring(s: *mut c_char) {
     if s.is_null() {
         return;
@@ -259,7 +1018,7 @@ pub struct RuntimeHandle {
 pub extern "C" fn verdant_runtime_new() -> RuntimeHandle {
     let ptr = match Runtime::new() {
         Ok(rt) => Box::into_raw(Box::new(rt)),
-        Err(_) => ptr::null_mut(),
+        Err(e) => fail(ptr::null_mut(), format!("verdant_runtime_new: {e}")),
     };
     RuntimeHandle { ptr }
 }
@@ -267,6 +1026,7 @@ pub extern "C" fn verdant_runtime_new() -> RuntimeHandle {
 /// Free a Tokio runtime created with `verdant_runtime_new()`.
 /// Safe to call with NULL.
 #[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn verdant_runtime_free(rt: *mut RuntimeHandle) {
     if rt.is_null() {
         return;
@@ -278,3 +1038,190 @@ pub extern "C" fn verdant_runtime_free(rt: *mut RuntimeHandle) {
         drop(Box::from_raw((*rt).ptr));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_matches_the_crate() {
+        let version = unsafe { CStr::from_ptr(verdant_get_version()) };
+        assert_eq!(version.to_str().unwrap(), crate::VERDANT_CLIENT_VERSION);
+        assert_eq!(
+            verdant_get_protocol_version(),
+            crate::VERDANT_PROTOCOL_VERSION
+        );
+    }
+
+    /// decodes and frees the payload of `event`.
+    fn payload(event: VerdantEventFFI) -> serde_json::Value {
+        let json = unsafe { CString::from_raw(event.payload) };
+        serde_json::from_str(json.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn event_payloads_leave_out_the_event_name() {
+        use crate::auth::LoginResult;
+
+        let event = event_to_ffi(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+            UnauthorizedReason::AccountLocked,
+        )));
+        assert_eq!(event.tag, VerdantEventTag::LoginResult as u32);
+        assert_eq!(
+            payload(event),
+            serde_json::json!({ "Unauthorized": "AccountLocked" })
+        );
+
+        let event = event_to_ffi(VerdantUiCmd::Registered {
+            url: "https://verdant".to_string(),
+            username: "alice".to_string(),
+        });
+        assert_eq!(event.tag, VerdantEventTag::RegistrationResult as u32);
+        assert_eq!(
+            payload(event),
+            serde_json::json!({ "url": "https://verdant", "username": "alice" })
+        );
+
+        let event = event_to_ffi(VerdantUiCmd::ServerUnreachable(
+            "https://verdant".to_string(),
+        ));
+        assert_eq!(event.tag, VerdantEventTag::ServerUnreachable as u32);
+        assert_eq!(
+            payload(event),
+            serde_json::json!({ "url": "https://verdant" })
+        );
+
+        let event = event_to_ffi(VerdantUiCmd::Error(crate::services::VerdantErr::new(
+            -1, "failed",
+        )));
+        assert_eq!(event.tag, VerdantEventTag::Error as u32);
+        assert_eq!(payload(event)["message"], "failed");
+    }
+
+    #[test]
+    fn registration_is_answered_with_a_registration_result() {
+        let mut runtime = verdant_runtime_new();
+        let h = verdant_service_new(0, runtime.ptr);
+        let rt = unsafe { &*runtime.ptr };
+        let (_server, url) = rt.block_on(crate::test_util::spawn_test_server());
+        let url = CString::new(url).unwrap();
+        let username = CString::new("alice").unwrap();
+        let password = CString::new("correct horse").unwrap();
+        let email = CString::new("alice@example.com").unwrap();
+
+        assert_eq!(
+            verdant_service_register(
+                h,
+                url.as_ptr(),
+                ptr::null(),
+                password.as_ptr(),
+                email.as_ptr()
+            ),
+            -1
+        );
+        assert_eq!(
+            verdant_service_register(
+                h,
+                url.as_ptr(),
+                username.as_ptr(),
+                password.as_ptr(),
+                email.as_ptr()
+            ),
+            0
+        );
+        let event = verdant_service_recv_timeout(h, 5000);
+        assert_eq!(event.tag, VerdantEventTag::RegistrationResult as u32);
+        assert_eq!(payload(event)["username"], "alice");
+
+        verdant_service_free(h);
+        verdant_runtime_free(&mut runtime);
+    }
+
+    #[test]
+    fn discoveries_are_a_terminated_array() {
+        let mut runtime = verdant_runtime_new();
+        let h = verdant_service_new(0, runtime.ptr);
+        assert!(!h.is_null());
+        // refuses connections right away, the service doesn't wait on the added server
+        let url = CString::new("http://127.0.0.1:1").unwrap();
+        assert_eq!(verdant_service_add_server(h, url.as_ptr()), 0);
+
+        let mut count = usize::MAX;
+        let arr = verdant_service_get_discoveries(h, &mut count);
+        assert!(!arr.is_null());
+        // added servers aren't discoveries
+        assert_eq!(count, 0);
+        let entries = unsafe { std::slice::from_raw_parts(arr, count + 1) };
+        assert!(entries[count].is_null());
+        for entry in &entries[..count] {
+            verdant_free_cstring(*entry);
+        }
+        verdant_free_string_array(arr, count);
+
+        let arr = verdant_service_get_server_urls(h, &mut count);
+        assert!(!arr.is_null());
+        assert_eq!(count, 0);
+        assert!(unsafe { *arr }.is_null());
+        verdant_free_string_array(arr, count);
+
+        assert!(verdant_service_get_discoveries(h, ptr::null_mut()).is_null());
+        assert!(!verdant_error_get_last().is_null());
+        verdant_error_clear();
+        assert!(verdant_error_get_last().is_null());
+
+        verdant_service_free(h);
+        verdant_runtime_free(&mut runtime);
+    }
+    #[test]
+    fn discovered_servers_are_listed() {
+        use crate::api::{KeyType, PubKeyResponse};
+        use crate::services::VerdantCmd;
+        use crate::test_util::mock_server;
+        use keycast::discovery::Discovery;
+
+        let mut runtime = verdant_runtime_new();
+        let h = verdant_service_new(0, runtime.ptr);
+        assert!(!h.is_null());
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let rt = unsafe { &*runtime.ptr };
+        let (url, _) = rt.block_on(mock_server(vec![(
+            200,
+            serde_json::to_string(&pubkey).unwrap(),
+        )]));
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let discovery = crate::test_util::test_discovery(port, pubkey.key_hash().unwrap());
+        let svc = unsafe { &*(*h).inner };
+        svc.tx()
+            .send(VerdantCmd::ServerDiscovered(Box::new(discovery.clone())))
+            .unwrap();
+        let event = verdant_service_recv_timeout(h, 5000);
+        assert_eq!(event.tag, VerdantEventTag::ServerDiscovered as u32);
+        verdant_free_cstring(event.payload);
+
+        let mut count = 0;
+        let arr = verdant_service_get_discoveries(h, &mut count);
+        assert_eq!(count, 1);
+        let entries = unsafe { std::slice::from_raw_parts(arr, count + 1) };
+        let json = unsafe { CStr::from_ptr(entries[0]) }.to_str().unwrap();
+        assert_eq!(serde_json::from_str::<Discovery>(json).unwrap(), discovery);
+        assert!(entries[count].is_null());
+        for entry in &entries[..count] {
+            verdant_free_cstring(*entry);
+        }
+        verdant_free_string_array(arr, count);
+
+        let arr = verdant_service_get_server_urls(h, &mut count);
+        assert_eq!(count, 1);
+        let entries = unsafe { std::slice::from_raw_parts(arr, count + 1) };
+        let server_url = unsafe { CStr::from_ptr(entries[0]) }.to_str().unwrap();
+        assert_eq!(server_url.trim_end_matches('/'), url.trim_end_matches('/'));
+        assert!(entries[count].is_null());
+        for entry in &entries[..count] {
+            verdant_free_cstring(*entry);
+        }
+        verdant_free_string_array(arr, count);
+
+        verdant_service_free(h);
+        verdant_runtime_free(&mut runtime);
+    }
+}
@@ -0,0 +1,28 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Connection parameters exchanged alongside an SDP offer or answer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DirectConnectionParams {
+    pub ice_candidates: Vec<String>,
+    pub dtls_fingerprint: String,
+}
+
+/// body of `POST /rpc/p2p/offer`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DirectConnectionOffer {
+    pub peer_username: String,
+    pub offer_sdp: String,
+}
+
+/// response to `POST /rpc/p2p/offer`, the token is used to poll for the peer's answer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DirectConnectionOfferResponse {
+    pub answer_token: String,
+}
+
+/// response to `GET /rpc/p2p/answer/{answer_token}` once the peer has answered.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DirectConnectionAnswer {
+    pub answer_sdp: String,
+    pub params: DirectConnectionParams,
+}
@@ -0,0 +1,202 @@
+use crate::services::{VerdantCmd, VerdantUiCmd};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+#[cfg(feature = "tracing")]
+use tracing::{debug, info, warn};
+
+/// What the service should do with a command or event after a plugin has seen it.
+#[derive(Debug, Clone)]
+pub enum PluginResult {
+    /// pass it on to the next plugin, then handle it as usual.
+    Continue,
+    /// stop processing it and send this event to the UI instead.
+    Intercept(VerdantUiCmd),
+    /// silently discard it.
+    Drop,
+}
+
+/// Extension point for [`crate::services::VerdantService`].
+///
+/// Plugins run in the order they were added, on the service task, before each command
+/// is dispatched and before each event reaches the UI. All methods default to doing nothing.
+pub trait ServicePlugin {
+    fn on_command(&self, _cmd: &VerdantCmd) -> PluginResult {
+        PluginResult::Continue
+    }
+
+    fn on_event(&self, _event: &VerdantUiCmd) -> PluginResult {
+        PluginResult::Continue
+    }
+
+    /// awaited once when the service task starts, before any command is handled.
+    fn on_startup(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+pub type BoxedPlugin = Box<dyn ServicePlugin + Send + Sync>;
+pub type Plugins = Arc<Vec<BoxedPlugin>>;
+
+/// Runs `cmd` through `plugins`, returning the first result that isn't [`PluginResult::Continue`].
+pub(crate) fn run_command_plugins(plugins: &[BoxedPlugin], cmd: &VerdantCmd) -> PluginResult {
+    for plugin in plugins {
+        match plugin.on_command(cmd) {
+            PluginResult::Continue => {}
+            result => return result,
+        }
+    }
+    PluginResult::Continue
+}
+
+#[derive(Clone)]
+enum UiTx {
+    Unbounded(UnboundedSender<VerdantUiCmd>),
+    /// the receiver is reachable from the sender so the oldest event can be dropped
+    /// when the channel is full, weak so the channel still closes once the UI is gone.
+    Bounded {
+        tx: Sender<VerdantUiCmd>,
+        rx: Weak<Mutex<Receiver<VerdantUiCmd>>>,
+    },
+}
+
+/// Receiving end of a [`UiSender`].
+pub(crate) enum UiReceiver {
+    Unbounded(UnboundedReceiver<VerdantUiCmd>),
+    Bounded(Arc<Mutex<Receiver<VerdantUiCmd>>>),
+}
+
+impl UiReceiver {
+    /// A receiver whose sender is already gone.
+    pub(crate) fn closed() -> Self {
+        let (_, rx) = mpsc::unbounded_channel();
+        UiReceiver::Unbounded(rx)
+    }
+
+    /// Blocks the current thread until the next event, must not be called from async code.
+    pub(crate) fn blocking_recv(&mut self) -> Option<VerdantUiCmd> {
+        match self {
+            UiReceiver::Unbounded(rx) => rx.blocking_recv(),
+            // a full channel never blocks here, so the sender dropping the oldest event
+            // doesn't wait on this lock for long
+            UiReceiver::Bounded(rx) => rx.lock().expect("ui receiver poisoned").blocking_recv(),
+        }
+    }
+
+    pub(crate) fn try_recv(&mut self) -> Result<VerdantUiCmd, TryRecvError> {
+        match self {
+            UiReceiver::Unbounded(rx) => rx.try_recv(),
+            UiReceiver::Bounded(rx) => rx.lock().expect("ui receiver poisoned").try_recv(),
+        }
+    }
+
+    pub(crate) fn poll_recv(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<VerdantUiCmd>> {
+        match self {
+            UiReceiver::Unbounded(rx) => rx.poll_recv(cx),
+            // the lock is only held while polling, never across an await
+            UiReceiver::Bounded(rx) => rx.lock().expect("ui receiver poisoned").poll_recv(cx),
+        }
+    }
+}
+
+/// Sender for UI events that runs each event through the plugins' [`ServicePlugin::on_event`].
+#[derive(Clone)]
+pub struct UiSender {
+    tx: UiTx,
+    plugins: Plugins,
+}
+
+impl UiSender {
+    pub fn new(tx: UnboundedSender<VerdantUiCmd>, plugins: Plugins) -> Self {
+        Self {
+            tx: UiTx::Unbounded(tx),
+            plugins,
+        }
+    }
+
+    /// A sender holding at most `capacity` undelivered events, or an unbounded one
+    /// if `capacity` is `None`. See [`UiSender::send`] for what happens when it is full.
+    pub(crate) fn channel(capacity: Option<usize>, plugins: Plugins) -> (Self, UiReceiver) {
+        match capacity {
+            None => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                (Self::new(tx, plugins), UiReceiver::Unbounded(rx))
+            }
+            Some(capacity) => {
+                let (tx, rx) = mpsc::channel(capacity.max(1));
+                let rx = Arc::new(Mutex::new(rx));
+                let sender = Self {
+                    tx: UiTx::Bounded {
+                        tx,
+                        rx: Arc::downgrade(&rx),
+                    },
+                    plugins,
+                };
+                (sender, UiReceiver::Bounded(rx))
+            }
+        }
+    }
+
+    /// Sends `event`, or its replacement if a plugin intercepted it. Dropped events count as sent.
+    ///
+    /// A bounded channel that is full drops its oldest event to make room. Waiting for
+    /// the UI instead would stall the service (token refreshes, health checks) behind a slow
+    /// consumer and deadlock a UI waiting on the service, so old events are lost instead and
+    /// the UI should resync from snapshots like [`crate::services::VerdantService::discoveries_snapshot`].
+    pub fn send(&self, event: VerdantUiCmd) -> Result<(), SendError<VerdantUiCmd>> {
+        let mut event = event;
+        for plugin in self.plugins.iter() {
+            match plugin.on_event(&event) {
+                PluginResult::Continue => {}
+                PluginResult::Intercept(replacement) => {
+                    event = replacement;
+                    break;
+                }
+                PluginResult::Drop => return Ok(()),
+            }
+        }
+        let (tx, rx) = match &self.tx {
+            UiTx::Unbounded(tx) => return tx.send(event),
+            UiTx::Bounded { tx, rx } => (tx, rx),
+        };
+        let event = match tx.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(event)) => return Err(SendError(event)),
+            Err(TrySendError::Full(event)) => event,
+        };
+        if let Some(rx) = rx.upgrade() {
+            let dropped = rx.lock().expect("ui receiver poisoned").try_recv().ok();
+            warn!(
+                dropped = dropped.as_ref().map(VerdantUiCmd::kind),
+                "ui channel full, dropped oldest event"
+            );
+        }
+        match tx.try_send(event) {
+            Err(TrySendError::Closed(event)) => Err(SendError(event)),
+            // still full if another sender refilled it, the event is dropped
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reference plugin logging every command and event with `tracing`.
+///
+/// Only the variant names are logged since commands may carry passwords.
+pub struct LoggingPlugin;
+
+impl ServicePlugin for LoggingPlugin {
+    fn on_command(&self, cmd: &VerdantCmd) -> PluginResult {
+        info!(command = cmd.kind(), "command");
+        PluginResult::Continue
+    }
+
+    fn on_event(&self, event: &VerdantUiCmd) -> PluginResult {
+        debug!(event = event.kind(), "event");
+        PluginResult::Continue
+    }
+}
@@ -1,22 +1,36 @@
 use opaque_ke::{RegistrationRequest, RegistrationResponse, RegistrationUpload};
 
-pub type ServerSetup = opaque_ke::ServerSetup<DefaultCipherSuite>;
-pub type ServerLogin = opaque_ke::ServerLogin<DefaultCipherSuite>;
-pub type CredentialRequest = opaque_ke::CredentialRequest<DefaultCipherSuite>;
-pub type CredentialResponse = opaque_ke::CredentialResponse<DefaultCipherSuite>;
-pub type ServerRegistration = opaque_ke::ServerRegistration<DefaultCipherSuite>;
-pub type CredentialFinalization = opaque_ke::CredentialFinalization<DefaultCipherSuite>;
+pub type ServerSetup<CS = DefaultCipherSuite> = opaque_ke::ServerSetup<CS>;
+pub type ServerLogin<CS = DefaultCipherSuite> = opaque_ke::ServerLogin<CS>;
+pub type CredentialRequest<CS = DefaultCipherSuite> = opaque_ke::CredentialRequest<CS>;
+pub type CredentialResponse<CS = DefaultCipherSuite> = opaque_ke::CredentialResponse<CS>;
+pub type ServerRegistration<CS = DefaultCipherSuite> = opaque_ke::ServerRegistration<CS>;
+pub type CredentialFinalization<CS = DefaultCipherSuite> = opaque_ke::CredentialFinalization<CS>;
 
 use serde_derive::{Deserialize, Serialize};
 
-use crate::auth::DefaultCipherSuite;
-use opaque_ke::ServerLoginStartParameters;
+use crate::auth::registration::{RegistrationResponseCache, RegistrationStore};
+use crate::auth::{
+    ByteLen, CipherSuite, DefaultCipherSuite, MaskedNonceLen, MaskedResponseLen, OprfHashLen,
+    UsernamePolicy,
+};
+use crate::errors::Error;
 use opaque_ke::errors::ProtocolError;
+use opaque_ke::{Identifiers, ServerLoginStartParameters};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
+use opaque_ke::key_exchange::group::KeGroup;
 use rand::rngs::OsRng;
+use sha2::digest::{
+    OutputSizeUser,
+    typenum::{Sum, U32, Unsigned},
+};
+use std::ops::Add;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)] // a wire message, built once per login
 pub enum LoginResponse {
     OTP(String),
     /// used for opaque login, a UUID to identify the session, and a credential response.
@@ -24,51 +38,195 @@ pub enum LoginResponse {
     AccessDenied,
 }
 
-pub struct Server {
-    setup: ServerSetup,
+/// Persists a [`ServerSetup`], it holds the OPRF key every stored registration depends on,
+/// so a server has to reload the same one after restarting.
+pub trait ServerSetupExt: Sized {
+    fn save_setup(&self) -> Vec<u8>;
+    fn load_setup(bytes: &[u8]) -> Result<Self, Error>;
+}
+
+// oprf seed, private key and fake private key, the bounds of opaque-ke's `ServerSetup::serialize`
+impl<CS: CipherSuite> ServerSetupExt for ServerSetup<CS>
+where
+    OprfHashLen<CS>: Add<<CS::KeGroup as KeGroup>::SkLen>,
+    Sum<OprfHashLen<CS>, <CS::KeGroup as KeGroup>::SkLen>:
+        ByteLen + Add<<CS::KeGroup as KeGroup>::SkLen>,
+    Sum<Sum<OprfHashLen<CS>, <CS::KeGroup as KeGroup>::SkLen>, <CS::KeGroup as KeGroup>::SkLen>:
+        ByteLen,
+{
+    fn save_setup(&self) -> Vec<u8> {
+        self.serialize().to_vec()
+    }
+
+    fn load_setup(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(ServerSetup::<CS>::deserialize(bytes)?)
+    }
+}
+
+/// OPAQUE server side, generic over the [`CipherSuite`] like [`crate::client::auth::Client`].
+pub struct Server<CS: CipherSuite = DefaultCipherSuite> {
+    setup: ServerSetup<CS>,
+    registrations: RegistrationStore<CS>,
+    registration_cache: Option<RegistrationResponseCache<CS>>,
+    username_policy: UsernamePolicy,
     // e.g. a database of username -> StoredUserRecord
 }
 
 impl Server {
+    /// Length of the session key a login derives. TripleDh derives it with the
+    /// OPRF suite's hash, SHA-512 for Ristretto255.
+    pub const SESSION_KEY_LEN: usize = <sha2::Sha512 as OutputSizeUser>::OutputSize::USIZE;
+
     pub fn new(setup: ServerSetup) -> Self {
-        Self { setup }
+        Self::from_setup(setup)
+    }
+}
+
+impl<CS: CipherSuite> Server<CS> {
+    /// Like [`Server::new`] for a custom cipher suite.
+    pub fn from_setup(setup: ServerSetup<CS>) -> Self {
+        Self {
+            setup,
+            registrations: RegistrationStore::default(),
+            registration_cache: None,
+            username_policy: UsernamePolicy::default(),
+        }
+    }
+
+    /// Maps usernames with `policy` before registering or logging them in.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = policy;
+        self
+    }
+
+    pub fn username_policy(&self) -> UsernamePolicy {
+        self.username_policy
+    }
+
+    /// Enables memoization of registration responses for `ttl`, see
+    /// [`Server::start_registration_cached`].
+    pub fn with_registration_cache(mut self, ttl: Duration) -> Self {
+        self.registration_cache = Some(RegistrationResponseCache::new(ttl));
+        self
+    }
+
+    pub fn registration_cache(&self) -> Option<&RegistrationResponseCache<CS>> {
+        self.registration_cache.as_ref()
+    }
+
+    /// the setup this server was created with, see [`ServerSetupExt::save_setup`].
+    pub fn setup(&self) -> &ServerSetup<CS> {
+        &self.setup
+    }
+
+    /// recently completed registrations, used to deduplicate client retries.
+    pub fn registrations(&self) -> &RegistrationStore<CS> {
+        &self.registrations
     }
 
     // Step 1: Handle registration request
     pub fn start_registration(
         &self,
-        request: RegistrationRequest<DefaultCipherSuite>,
+        request: RegistrationRequest<CS>,
         username: impl Into<String>,
-    ) -> Result<RegistrationResponse<DefaultCipherSuite>, ProtocolError> {
-        let username = username.into();
+    ) -> Result<RegistrationResponse<CS>, ProtocolError> {
+        let username = self.username_policy.apply(&username.into());
         let response =
-            ServerRegistration::start(&self.setup, request, username.as_bytes())?.message;
+            ServerRegistration::<CS>::start(&self.setup, request, username.as_bytes())?.message;
         Ok(response)
     }
 
-    // Step 2: Finalize registration and store record
-    pub fn finish_registration(
+    /// Like [`Server::start_registration`], but a replayed request (same username and
+    /// serialized bytes) within the cache TTL receives the previously computed response.
+    ///
+    /// Falls back to [`Server::start_registration`] if no cache was configured
+    /// with [`Server::with_registration_cache`].
+    pub fn start_registration_cached(
         &self,
-        upload: RegistrationUpload<DefaultCipherSuite>,
-    ) -> ServerRegistration {
-        ServerRegistration::finish(upload)
+        request: RegistrationRequest<CS>,
+        username: impl Into<String>,
+    ) -> Result<RegistrationResponse<CS>, ProtocolError> {
+        let cache = match &self.registration_cache {
+            Some(cache) => cache,
+            None => return self.start_registration(request, username),
+        };
+        let username = self.username_policy.apply(&username.into());
+        let key = request.serialize().to_vec();
+        if let Some(response) = cache.get(&username, &key) {
+            return Ok(response);
+        }
+        let response = self.start_registration(request, username.clone())?;
+        cache.insert(username, key, response.clone());
+        Ok(response)
+    }
+
+    // Step 2: Finalize registration and store record
+    pub fn finish_registration(&self, upload: RegistrationUpload<CS>) -> ServerRegistration<CS> {
+        ServerRegistration::<CS>::finish(upload)
     }
 
     // Step 3: Handle login start
     pub fn start_login(
         &self,
-        registration: ServerRegistration,
-        credential_request: CredentialRequest,
+        registration: ServerRegistration<CS>,
+        credential_request: CredentialRequest<CS>,
+        username: &str,
+    ) -> Result<(ServerLogin<CS>, CredentialResponse<CS>), ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
+        self.start_login_with_parameters(
+            registration,
+            credential_request,
+            username,
+            ServerLoginStartParameters::default(),
+        )
+    }
+
+    /// Like [`Server::start_login`] for clients built with identities, see
+    /// [`crate::client::auth::ClientBuilder`]. Both sides have to use the same ones.
+    pub fn start_login_with_identifiers(
+        &self,
+        registration: ServerRegistration<CS>,
+        credential_request: CredentialRequest<CS>,
         username: &str,
-    ) -> Result<(ServerLogin, CredentialResponse), ProtocolError> {
+        identifiers: Identifiers<'_>,
+    ) -> Result<(ServerLogin<CS>, CredentialResponse<CS>), ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
+        let parameters = ServerLoginStartParameters {
+            context: None,
+            identifiers,
+        };
+        self.start_login_with_parameters(registration, credential_request, username, parameters)
+    }
+
+    fn start_login_with_parameters(
+        &self,
+        registration: ServerRegistration<CS>,
+        credential_request: CredentialRequest<CS>,
+        username: &str,
+        parameters: ServerLoginStartParameters<'_, '_>,
+    ) -> Result<(ServerLogin<CS>, CredentialResponse<CS>), ProtocolError>
+    where
+        U32: Add<OprfHashLen<CS>>,
+        MaskedNonceLen<CS>: ByteLen + Add<<CS::KeGroup as KeGroup>::PkLen>,
+        MaskedResponseLen<CS>: ByteLen,
+    {
         let mut rng = OsRng;
-        let result = ServerLogin::start(
+        let username = self.username_policy.apply(username);
+        let result = ServerLogin::<CS>::start(
             &mut rng,
             &self.setup,
             Some(registration),
             credential_request,
             username.as_bytes(),
-            ServerLoginStartParameters::default(),
+            parameters,
         )?;
         Ok((result.state, result.message))
     }
@@ -76,11 +234,67 @@ impl Server {
     // Step 4: Finish login
     pub fn finish_login(
         &self,
-        server_login: ServerLogin,
-        client_finalization: CredentialFinalization,
-    ) -> Result<Vec<u8>, ProtocolError> {
+        server_login: ServerLogin<CS>,
+        client_finalization: CredentialFinalization<CS>,
+    ) -> Result<Zeroizing<Vec<u8>>, ProtocolError> {
         // now both sides share a session key!
         let result = server_login.finish(client_finalization)?;
-        Ok(result.session_key.as_slice().to_vec())
+        Ok(Zeroizing::new(result.session_key.to_vec()))
+    }
+
+    /// Like [`Server::finish_login`], but rejects a session key that isn't `expected_key_len`
+    /// bytes long or is all zero, either of which means something went wrong deriving it.
+    pub fn finish_login_checked(
+        &self,
+        server_login: ServerLogin<CS>,
+        client_finalization: CredentialFinalization<CS>,
+        expected_key_len: usize,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let session_key = self.finish_login(server_login, client_finalization)?;
+        check_session_key(&session_key, expected_key_len)?;
+        Ok(session_key)
+    }
+}
+
+fn check_session_key(session_key: &[u8], expected_key_len: usize) -> Result<(), Error> {
+    if session_key.len() != expected_key_len || session_key.iter().all(|&b| b == 0) {
+        return Err(Error::Internal("invalid session key".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_key_validation() {
+        assert!(
+            check_session_key(&[7u8; Server::SESSION_KEY_LEN], Server::SESSION_KEY_LEN).is_ok()
+        );
+        assert!(check_session_key(&[7u8; 32], Server::SESSION_KEY_LEN).is_err());
+        assert!(
+            check_session_key(&[0u8; Server::SESSION_KEY_LEN], Server::SESSION_KEY_LEN).is_err()
+        );
+    }
+
+    #[test]
+    fn registrations_survive_a_reloaded_setup() -> Result<(), Error> {
+        use crate::client::auth::Client;
+
+        let server = Server::new(ServerSetup::new(&mut OsRng));
+        let stored = crate::auth::register_user(&server, "mallory", "password")?;
+        let saved = server.setup().save_setup();
+        drop(server);
+
+        let server = Server::new(ServerSetup::load_setup(&saved)?);
+        let client = Client::new("password");
+        let (client_login, request) = client.start_login()?;
+        let (server_login, response) = server.start_login(stored, request, "mallory")?;
+        let (client_key, finalization) = client.finish_login(client_login, response)?;
+        assert_eq!(client_key, server.finish_login(server_login, finalization)?);
+
+        assert!(ServerSetup::<DefaultCipherSuite>::load_setup(&saved[1..]).is_err());
+        Ok(())
     }
 }
@@ -74,5 +74,7 @@ pub struct RequiredRoute {
 }
 
 pub struct RequiredRoutes {
+    // not read yet
+    #[allow(dead_code)]
     routes: Vec<RequiredRoute>,
 }
@@ -1,12 +1,29 @@
-use crate::api::APIClient;
-use crate::auth::LoginResult;
-use crate::livekit::TokenResponse;
-use keycast::discovery::{Beacon, Discovery, ServiceIdent, WaitFor};
+use crate::api::{APIClient, HealthStatus};
+use crate::auth::registration::RegistrationRequest;
+use crate::auth::{LoginResult, UnauthorizedReason};
+use crate::discovery::{DiscoveryCache, DiscoveryStore, ServerIdentity};
+#[cfg(feature = "mdns")]
+use crate::discovery::{KnownServers, Observation};
+use crate::livekit::{Participant, RoomEvent, RoomEventType, SseParser, TokenResponse};
+use crate::plugin::{
+    BoxedPlugin, PluginResult, Plugins, UiReceiver, UiSender, run_command_plugins,
+};
+use keycast::discovery::Discovery;
+#[cfg(feature = "mdns")]
+use keycast::discovery::{Beacon, ServiceIdent, WaitFor};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+#[cfg(feature = "tracing")]
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 pub struct ServiceState {}
 
+/// Error reported to the UI with [`VerdantUiCmd::Error`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct VerdantErr {
     errorcode: i32,
@@ -14,26 +31,78 @@ pub struct VerdantErr {
 }
 
 impl VerdantErr {
+    pub fn new(errorcode: i32, message: impl Into<String>) -> Self {
+        Self {
+            errorcode,
+            message: message.into(),
+        }
+    }
+
     pub fn noop() -> Self {
         Self {
             errorcode: 0,
             message: String::from("nothing to do, this is used for debugging"),
         }
     }
+
+    /// `true` for [`VerdantErr::noop`], which the JNI layer sends when there is no event.
+    pub fn is_noop(&self) -> bool {
+        self.errorcode == 0
+    }
+
+    pub fn errorcode(&self) -> i32 {
+        self.errorcode
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for VerdantErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.message, self.errorcode)
+    }
+}
+
+impl std::error::Error for VerdantErr {}
+
+/// What the service is currently doing, as far as the UI is concerned.
+/// See [`VerdantService::state`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionState {
+    /// not logged in, and not looking for servers.
+    Idle,
+    /// looking for servers through mDNS.
+    Discovering,
+    /// a login sent with [`VerdantService::login_sync`] hasn't been answered yet.
+    Authenticating,
+    /// logged into the server at `url`.
+    Connected { url: String },
+    /// the last login failed.
+    Error(String),
+}
+
+impl ConnectionState {
+    /// the state when not logged in.
+    fn idle(discovering: bool) -> Self {
+        if discovering {
+            ConnectionState::Discovering
+        } else {
+            ConnectionState::Idle
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LkTokenRecord {
     pub server: String,
-    pub response: TokenResponse
+    pub response: TokenResponse,
 }
 
 impl LkTokenRecord {
     pub fn new(server: String, response: TokenResponse) -> Self {
-        Self {
-            server,
-            response
-        }
+        Self { server, response }
     }
 }
 
@@ -42,9 +111,60 @@ pub enum VerdantUiCmd {
     LoginResult(LoginResult),
     /// this variant is in both [`VerdantUiCmd`] and in [`VerdantCmd`] because it can result
     /// from the background service through mdns_sd, and through the user manually entering needed information.
-    ServerDiscovered(Discovery),
+    ServerDiscovered(Box<Discovery>),
     /// a means of identifying the server when sending back token response
-    LkToken(LkTokenRecord),
+    LkToken(Box<LkTokenRecord>),
+    /// the server accepted a [`VerdantCmd::RequestDirectConnection`], poll the
+    /// `answer_token` for the peer's answer.
+    DirectConnectionOffer {
+        peer_username: String,
+        offer_sdp: String,
+        answer_token: String,
+    },
+    /// profile information from the access token, sent right after a successful
+    /// login to `url` when the server included a display name.
+    UserProfile {
+        url: String,
+        display_name: String,
+        avatar_url: Option<String>,
+    },
+    /// a session could not be kept alive (e.g. the token refresh failed),
+    /// the user needs to log into `url` again.
+    Disconnected {
+        url: String,
+    },
+    /// something changed in a room subscribed to with [`VerdantCmd::SubscribeRoomEvents`].
+    RoomUpdate {
+        room_id: Uuid,
+        event_type: RoomEventType,
+        participant: Option<String>,
+    },
+    /// logging into `url` failed with a server error and `fallback_url` was used instead,
+    /// the session continues under `fallback_url`.
+    ServerFailover {
+        url: String,
+        fallback_url: String,
+    },
+    /// answer to [`VerdantCmd::ListParticipants`].
+    ParticipantList(Vec<Participant>),
+    /// the server at the url answered a health check.
+    HealthStatus(String, HealthStatus),
+    /// the server at the url failed two health checks in a row.
+    ServerUnreachable(String),
+    /// a server added with [`VerdantCmd::AddServer`] answered with its key and can be logged into.
+    ServerAdded {
+        url: String,
+    },
+    /// answer to [`VerdantCmd::Ping`] with the round trip time.
+    Pong(String, Duration),
+    /// a [`VerdantCmd::Register`] on the server at `url` succeeded, the user can log in now.
+    Registered {
+        url: String,
+        username: String,
+    },
+    /// the server hasn't advertised itself for longer than
+    /// [`VerdantServiceConfig::discovery_ttl`] and was forgotten.
+    ServerLost(Box<Discovery>),
     Error(VerdantErr),
 }
 
@@ -74,24 +194,384 @@ pub enum VerdantCmd {
     Login(LoginRequest),
     /// this variant is in both [`VerdantUiCmd`] and in [`VerdantCmd`] because it can result
     /// from the background service through mdns_sd, and through the user manually entering needed information.
-    ServerDiscovered(Discovery),
+    ServerDiscovered(Box<Discovery>),
+    /// a known server (same public key) re-advertised itself with different details,
+    /// e.g. after its IP address changed.
+    UpdateServer {
+        previous_url: Option<String>,
+        discovery: Box<Discovery>,
+    },
+    /// offer a direct peer-to-peer connection to another user logged into the server at `url`.
+    RequestDirectConnection {
+        url: String,
+        peer_username: String,
+        offer_sdp: String,
+    },
+    /// end the session with the server at `url`, answered with
+    /// [`UnauthorizedReason::LoggedOut`], or an error if there's no client for `url`.
+    Logout {
+        url: String,
+    },
+    /// check whether the server at `url` answers, answered with [`VerdantUiCmd::Pong`]
+    /// or [`VerdantUiCmd::Error`].
+    Ping {
+        url: String,
+    },
+    /// refresh the session with the server at `url` now, regardless of its token's expiry.
+    /// Answered with [`VerdantUiCmd::LoginResult`] or [`VerdantUiCmd::Error`].
+    RefreshToken {
+        url: String,
+    },
+    /// stream [`VerdantUiCmd::RoomUpdate`]s for `room_id` from the server at `url`.
+    SubscribeRoomEvents {
+        url: String,
+        room_id: Uuid,
+    },
+    UnsubscribeRoomEvents {
+        url: String,
+        room_id: Uuid,
+    },
+    /// fetch the participants of `room_id`, answered with [`VerdantUiCmd::ParticipantList`].
+    ListParticipants {
+        url: String,
+        room_id: Uuid,
+    },
+    /// add a server that wasn't discovered (e.g. mDNS is blocked), by url. Answered with
+    /// [`VerdantUiCmd::ServerAdded`], failures are reported as [`VerdantUiCmd::Error`].
+    AddServer {
+        url: String,
+    },
+    /// forget the server at `url`, ending its session and room subscriptions locally.
+    RemoveServer {
+        url: String,
+    },
+    /// check whether the server at `url` is up, answered with [`VerdantUiCmd::HealthStatus`].
+    HealthCheck {
+        url: String,
+    },
+    /// create an account on the server at `url`, answered with [`VerdantUiCmd::Registered`]
+    /// or [`VerdantUiCmd::Error`].
+    Register {
+        url: String,
+        request: Box<RegistrationRequest>,
+        password: String,
+    },
+}
+
+/// Commands the service sends itself, on their own channel so they can't be sent
+/// through [`VerdantService::tx`]. Plugins don't see them.
+#[derive(Debug)]
+pub(crate) enum InternalCmd {
+    /// sent periodically by the token refresh task, refreshes every session
+    /// whose token expires within `within_secs`.
+    RefreshExpiring { within_secs: u64 },
+    /// sent periodically by the health polling task, checks every known server.
+    HealthCheckAll,
+    /// a known server advertised itself again without changes.
+    #[cfg(feature = "mdns")]
+    ServerSeen(Discovery),
+    /// sent periodically by the discovery expiry task, forgets every discovery older than
+    /// `ttl` and reports it with [`VerdantUiCmd::ServerLost`].
+    ExpireDiscoveries { ttl: Duration },
+    /// sent by [`VerdantService::shutdown`], the service task exits once it sees it.
+    Shutdown,
+}
+
+impl VerdantCmd {
+    /// url of the server the command is for, if it targets one by url.
+    fn url(&self) -> Option<&str> {
+        match self {
+            VerdantCmd::Login(request) => Some(&request.url),
+            VerdantCmd::ServerDiscovered(_) | VerdantCmd::UpdateServer { .. } => None,
+            VerdantCmd::RequestDirectConnection { url, .. }
+            | VerdantCmd::Logout { url }
+            | VerdantCmd::Ping { url }
+            | VerdantCmd::RefreshToken { url }
+            | VerdantCmd::SubscribeRoomEvents { url, .. }
+            | VerdantCmd::UnsubscribeRoomEvents { url, .. }
+            | VerdantCmd::ListParticipants { url, .. }
+            | VerdantCmd::AddServer { url }
+            | VerdantCmd::RemoveServer { url }
+            | VerdantCmd::HealthCheck { url }
+            | VerdantCmd::Register { url, .. } => Some(url),
+        }
+    }
+
+    /// name of the variant, safe to log (unlike `Debug`, which includes passwords).
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VerdantCmd::Login(_) => "Login",
+            VerdantCmd::ServerDiscovered(_) => "ServerDiscovered",
+            VerdantCmd::UpdateServer { .. } => "UpdateServer",
+            VerdantCmd::RequestDirectConnection { .. } => "RequestDirectConnection",
+            VerdantCmd::Logout { .. } => "Logout",
+            VerdantCmd::Ping { .. } => "Ping",
+            VerdantCmd::RefreshToken { .. } => "RefreshToken",
+            VerdantCmd::SubscribeRoomEvents { .. } => "SubscribeRoomEvents",
+            VerdantCmd::UnsubscribeRoomEvents { .. } => "UnsubscribeRoomEvents",
+            VerdantCmd::ListParticipants { .. } => "ListParticipants",
+            VerdantCmd::AddServer { .. } => "AddServer",
+            VerdantCmd::RemoveServer { .. } => "RemoveServer",
+            VerdantCmd::HealthCheck { .. } => "HealthCheck",
+            VerdantCmd::Register { .. } => "Register",
+        }
+    }
+}
+
+impl VerdantUiCmd {
+    /// name of the variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VerdantUiCmd::LoginResult(_) => "LoginResult",
+            VerdantUiCmd::ServerDiscovered(_) => "ServerDiscovered",
+            VerdantUiCmd::LkToken(_) => "LkToken",
+            VerdantUiCmd::DirectConnectionOffer { .. } => "DirectConnectionOffer",
+            VerdantUiCmd::UserProfile { .. } => "UserProfile",
+            VerdantUiCmd::Disconnected { .. } => "Disconnected",
+            VerdantUiCmd::RoomUpdate { .. } => "RoomUpdate",
+            VerdantUiCmd::ServerFailover { .. } => "ServerFailover",
+            VerdantUiCmd::ParticipantList(_) => "ParticipantList",
+            VerdantUiCmd::HealthStatus(..) => "HealthStatus",
+            VerdantUiCmd::ServerUnreachable(_) => "ServerUnreachable",
+            VerdantUiCmd::Registered { .. } => "Registered",
+            VerdantUiCmd::Pong(..) => "Pong",
+            VerdantUiCmd::ServerAdded { .. } => "ServerAdded",
+            VerdantUiCmd::ServerLost(_) => "ServerLost",
+            VerdantUiCmd::Error(_) => "Error",
+        }
+    }
+}
+
+/// Called after every token refresh with the server url and the
+/// new token, or `None` if the refresh failed.
+pub type RefreshHook = Box<dyn Fn(&str, Option<&str>) + Send + Sync>;
+
+/// Called with every UI event, see [`VerdantService::set_event_callback`].
+pub type EventCallback = Box<dyn Fn(VerdantUiCmd) + Send>;
+
+/// Configuration for [`VerdantService::with_config`].
+#[derive(Debug, Clone)]
+pub struct VerdantServiceConfig {
+    /// browse for servers with mDNS.
+    pub discovery: bool,
+    /// proactively refresh access tokens before they expire.
+    pub enable_token_refresh: bool,
+    /// refresh tokens expiring within this many seconds.
+    pub refresh_before_expiry_secs: u64,
+    /// how often sessions are checked for expiring tokens.
+    pub refresh_check_interval: Duration,
+    /// maximum number of discovered servers remembered, see [`DiscoveryCache`].
+    pub max_discoveries: usize,
+    /// retry logins failing with a 5xx error against the server's fallback urls.
+    pub failover_on_5xx: bool,
+    /// how often every known server is health checked, `None` disables polling.
+    pub health_check_interval: Option<Duration>,
+    /// forget discovered servers that haven't advertised themselves for this long,
+    /// `None` keeps them until removed.
+    pub discovery_ttl: Option<Duration>,
+    /// most UI events held until [`VerdantService::try_recv`] picks them up, `None` is
+    /// unbounded. Once full the oldest event is dropped, see [`UiSender::send`].
+    pub channel_capacity: Option<usize>,
+    /// file discovered servers are saved to, see [`DiscoveryStore`]. The servers saved
+    /// there are verified and announced like fresh discoveries on startup, without the
+    /// ones not seen within [`VerdantServiceConfig::discovery_ttl`].
+    pub persist_path: Option<PathBuf>,
+}
+
+impl Default for VerdantServiceConfig {
+    fn default() -> Self {
+        Self {
+            discovery: true,
+            enable_token_refresh: true,
+            refresh_before_expiry_secs: 120,
+            refresh_check_interval: Duration::from_secs(30),
+            max_discoveries: DiscoveryCache::<Discovery>::DEFAULT_MAX_SIZE,
+            failover_on_5xx: true,
+            health_check_interval: None,
+            discovery_ttl: None,
+            channel_capacity: None,
+            persist_path: None,
+        }
+    }
+}
+
+/// Builder for [`VerdantService`], used to install [`crate::plugin::ServicePlugin`]s.
+#[derive(Default)]
+pub struct VerdantServiceBuilder {
+    config: VerdantServiceConfig,
+    plugins: Vec<BoxedPlugin>,
+}
+
+impl VerdantServiceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(mut self, config: VerdantServiceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Bounds the UI event channel, see [`VerdantServiceConfig::channel_capacity`].
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.config.channel_capacity = Some(capacity);
+        self
+    }
+
+    /// Saves discovered servers to `path`, see [`VerdantServiceConfig::persist_path`].
+    pub fn persist_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.persist_path = Some(path.into());
+        self
+    }
+
+    /// Adds a plugin, plugins run in the order they were added.
+    pub fn with_plugin(mut self, plugin: BoxedPlugin) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    pub fn build(
+        self,
+        runtime: &tokio::runtime::Runtime,
+    ) -> Result<VerdantService, keycast::errors::BeaconError> {
+        VerdantService::spawn(runtime, self.config, Arc::new(self.plugins))
+    }
 }
 
 // for now empty but will hold ongoing [`Discovery`]
 pub struct VerdantService {
     handle: tokio::runtime::Handle,
     discovery_handle: Option<tokio::task::JoinHandle<()>>,
-    service_handle: tokio::task::JoinHandle<()>,
-    discovered: Vec<Discovery>,
+    refresh_handle: Option<tokio::task::JoinHandle<()>>,
+    health_handle: Option<tokio::task::JoinHandle<()>>,
+    expiry_handle: Option<tokio::task::JoinHandle<()>>,
+    /// `None` once shut down.
+    service_handle: Option<tokio::task::JoinHandle<()>>,
+    refresh_hook: Arc<Mutex<Option<RefreshHook>>>,
+    discovered: DiscoveryCache,
     cmd_tx: mpsc::UnboundedSender<VerdantCmd>,
-    ui_rx: mpsc::UnboundedReceiver<VerdantUiCmd>,
+    internal_tx: UnboundedSender<InternalCmd>,
+    ui_rx: UiReceiver,
+    /// display names seen in [`VerdantUiCmd::UserProfile`] events, keyed by server url.
+    display_names: HashMap<String, String>,
+    /// urls added with [`VerdantService::add_server_sync`].
+    added_servers: HashSet<String>,
+    state: ConnectionState,
+    /// set once [`VerdantService::set_event_callback`] started the event thread.
+    event_callback: Option<Arc<Mutex<Option<EventCallback>>>>,
+    /// url of the login sent with [`VerdantService::login_sync`] awaiting its result.
+    pending_login: Option<String>,
+}
+
+/// Listens for servers advertising themselves on the LAN, the discovered ones are sent
+/// to the service task which in turn notifies the UI.
+#[cfg(feature = "mdns")]
+fn spawn_discovery(
+    handle: &tokio::runtime::Handle,
+    cmd_tx: UnboundedSender<VerdantCmd>,
+    internal_tx: UnboundedSender<InternalCmd>,
+    store: Option<DiscoveryStore>,
+    discovery_ttl: Option<Duration>,
+    restored: Option<JoinHandle<Vec<Discovery>>>,
+) -> Option<JoinHandle<()>> {
+    let mut known: KnownServers = KnownServers::new();
+    let store_handle = handle.clone();
+    let discovery_handle = handle.spawn(async move {
+        // the store compacts its file while loading, appending before that would be lost
+        if let Some(restored) = restored
+            && let Ok(restored) = restored.await
+        {
+            for discovery in &restored {
+                known.observe(discovery);
+            }
+        }
+        let ident = ServiceIdent::TCP("verdant".to_string());
+        let result = Beacon::discover(
+            ident,
+            WaitFor::Continous,
+            Some(Box::new(move |result| {
+                let discovery = match result {
+                    Ok(discovery) => discovery,
+                    Err(e) => {
+                        warn!(error = %e, "ignoring invalid beacon");
+                        return;
+                    }
+                };
+                debug!(discovery = ?discovery, "new discovery");
+                let cmd = match known.observe(&discovery) {
+                    Observation::New => VerdantCmd::ServerDiscovered(Box::new(discovery)),
+                    Observation::Updated { previous_url } => VerdantCmd::UpdateServer {
+                        previous_url,
+                        discovery: Box::new(discovery),
+                    },
+                    // only needed to keep the discovery from expiring
+                    Observation::Unchanged => {
+                        if discovery_ttl.is_some() {
+                            let _ = internal_tx.send(InternalCmd::ServerSeen(discovery));
+                        }
+                        return;
+                    }
+                };
+                // the service task is busy with the command, save the discovery on the side
+                if let (
+                    Some(store),
+                    VerdantCmd::ServerDiscovered(discovery)
+                    | VerdantCmd::UpdateServer { discovery, .. },
+                ) = (&store, &cmd)
+                {
+                    let store = store.clone();
+                    let discovery = discovery.clone();
+                    store_handle.spawn(async move {
+                        if let Err(e) = store.append(&discovery).await {
+                            warn!(path = %store.path().display(), error = %e, "failed to save discovery");
+                        }
+                    });
+                }
+                match cmd_tx.send(cmd) {
+                    Ok(_) => {}
+                    Err(e) => error!(error = %e, "send error"),
+                };
+            })),
+        )
+        .await;
+        if let Err(e) = result {
+            error!(error = %e, "LAN discovery stopped");
+        }
+    });
+    Some(discovery_handle)
 }
 
-async fn discover(service: &str) -> Result<Vec<Discovery>, keycast::errors::BeaconError> {
-    let ident = ServiceIdent::TCP(service.to_string());
+#[cfg(not(feature = "mdns"))]
+fn spawn_discovery(
+    _handle: &tokio::runtime::Handle,
+    _cmd_tx: UnboundedSender<VerdantCmd>,
+    _internal_tx: UnboundedSender<InternalCmd>,
+    _store: Option<DiscoveryStore>,
+    _discovery_ttl: Option<Duration>,
+    _restored: Option<JoinHandle<Vec<Discovery>>>,
+) -> Option<JoinHandle<()>> {
+    warn!("built without the `mdns` feature, LAN discovery is disabled");
+    None
+}
 
-    let beacons = Beacon::discover(ident, WaitFor::Continous, None).await?;
-    Ok(beacons)
+/// Sends the discoveries saved in `store` to the service task like fresh ones, returning
+/// them so LAN discovery doesn't announce them a second time.
+async fn restore_discoveries(
+    store: DiscoveryStore,
+    discovery_ttl: Option<Duration>,
+    cmd_tx: UnboundedSender<VerdantCmd>,
+) -> Vec<Discovery> {
+    let saved = match store.load::<Discovery>(discovery_ttl).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!(path = %store.path().display(), error = %e, "failed to load saved discoveries");
+            return Vec::new();
+        }
+    };
+    for discovery in &saved {
+        let _ = cmd_tx.send(VerdantCmd::ServerDiscovered(Box::new(discovery.clone())));
+    }
+    saved
 }
 
 impl VerdantService {
@@ -101,57 +581,157 @@ impl VerdantService {
         runtime: &tokio::runtime::Runtime,
         discovery: bool,
     ) -> Result<Self, keycast::errors::BeaconError> {
-        let (ui_tx, ui_rx) = mpsc::unbounded_channel();
+        Self::with_config(
+            runtime,
+            VerdantServiceConfig {
+                discovery,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`VerdantService::new`], forgetting discovered servers that haven't
+    /// advertised themselves for `discovery_ttl`.
+    pub fn with_discovery_ttl(
+        runtime: &tokio::runtime::Runtime,
+        discovery: bool,
+        discovery_ttl: Duration,
+    ) -> Result<Self, keycast::errors::BeaconError> {
+        Self::with_config(
+            runtime,
+            VerdantServiceConfig {
+                discovery,
+                discovery_ttl: Some(discovery_ttl),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_config(
+        runtime: &tokio::runtime::Runtime,
+        config: VerdantServiceConfig,
+    ) -> Result<Self, keycast::errors::BeaconError> {
+        VerdantServiceBuilder::new().config(config).build(runtime)
+    }
+
+    pub fn builder() -> VerdantServiceBuilder {
+        VerdantServiceBuilder::new()
+    }
+
+    fn spawn(
+        runtime: &tokio::runtime::Runtime,
+        config: VerdantServiceConfig,
+        plugins: Plugins,
+    ) -> Result<Self, keycast::errors::BeaconError> {
+        let discovery = config.discovery;
+        let discovery_ttl = config.discovery_ttl;
+        let failover_on_5xx = config.failover_on_5xx;
+        let max_discoveries = config.max_discoveries;
+        let store = config.persist_path.map(DiscoveryStore::new);
+        let (ui_tx, ui_rx) = UiSender::channel(config.channel_capacity, plugins.clone());
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (internal_tx, internal_rx) = mpsc::unbounded_channel();
         let handle = runtime.handle().clone();
-        // clone the command tx for the discovery thread to notify the service of additional servers
-        // which will in turn notify the UI thread.
-        let cmd_tx_clone = cmd_tx.clone();
         {
-            let discovered = Vec::new();
+            let discovered = DiscoveryCache::new(config.max_discoveries);
+            let restored = store.clone().map(|store| {
+                handle.spawn(restore_discoveries(store, discovery_ttl, cmd_tx.clone()))
+            });
             let discovery_handle = if discovery {
-                let mut known = discovered.clone();
-                let discovery_handle = handle.spawn(async move {
-                    let ident = ServiceIdent::TCP("verdant".to_string());
-                    Beacon::discover(
-                        ident,
-                        WaitFor::Continous,
-                        Some(Box::new(move |result| {
-                            let discovery = result.unwrap();
-                            println!("new discovery: {:?}", discovery);
-                            if !known.contains(&discovery) {
-                                known.push(discovery.clone());
-                                match cmd_tx_clone.send(VerdantCmd::ServerDiscovered(discovery)) {
-                                    Ok(_) => {}
-                                    Err(e) => eprintln!("send error: {}", e),
-                                };
-                            }
-                        })),
-                    )
-                    .await;
-                    // to be implemented
-                });
-                Some(discovery_handle)
+                spawn_discovery(
+                    &handle,
+                    cmd_tx.clone(),
+                    internal_tx.clone(),
+                    store,
+                    discovery_ttl,
+                    restored,
+                )
+            } else {
+                None
+            };
+            let refresh_handle = if config.enable_token_refresh {
+                let refresh_tx = internal_tx.clone();
+                let within_secs = config.refresh_before_expiry_secs;
+                let period = config.refresh_check_interval;
+                Some(handle.spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        if refresh_tx
+                            .send(InternalCmd::RefreshExpiring { within_secs })
+                            .is_err()
+                        {
+                            // service loop has shut down
+                            break;
+                        }
+                    }
+                }))
             } else {
                 None
             };
-            let discovered_clients = discovered.clone();
+            let health_handle = config.health_check_interval.map(|period| {
+                let health_tx = internal_tx.clone();
+                handle.spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        if health_tx.send(InternalCmd::HealthCheckAll).is_err() {
+                            // service loop has shut down
+                            break;
+                        }
+                    }
+                })
+            });
+            let expiry_handle = discovery_ttl.map(|ttl| {
+                let expiry_tx = internal_tx.clone();
+                let period = (ttl / 2).max(Duration::from_secs(1));
+                handle.spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        if expiry_tx
+                            .send(InternalCmd::ExpireDiscoveries { ttl })
+                            .is_err()
+                        {
+                            // service loop has shut down
+                            break;
+                        }
+                    }
+                })
+            });
+            let refresh_hook: Arc<Mutex<Option<RefreshHook>>> = Arc::new(Mutex::new(None));
+            let service_refresh_hook = refresh_hook.clone();
             let service_handle = handle.spawn(async move {
-                let mut clients = HashMap::new();
-                for discovered_client in discovered_clients.into_iter() {
-                    let url = discovered_client.urls().get(0).unwrap().to_string();
-                    let client = APIClient::from_discovery(discovered_client).await.unwrap();
-                    clients.insert(url, client);
-                }
-                verdant_service(cmd_rx, ui_tx, clients).await
+                let clients = HashMap::new();
+                verdant_service(
+                    cmd_rx,
+                    internal_rx,
+                    ui_tx,
+                    clients,
+                    service_refresh_hook,
+                    plugins,
+                    failover_on_5xx,
+                    max_discoveries,
+                )
+                .await
             });
             Ok(Self {
                 handle,
                 discovery_handle,
+                refresh_handle,
+                health_handle,
+                expiry_handle,
+                refresh_hook,
+                display_names: HashMap::new(),
+                added_servers: HashSet::new(),
+                state: ConnectionState::idle(discovery),
+                pending_login: None,
+                event_callback: None,
                 discovered,
                 ui_rx,
                 cmd_tx,
-                service_handle,
+                internal_tx,
+                service_handle: Some(service_handle),
             })
         }
     }
@@ -170,85 +750,2218 @@ impl VerdantService {
         cmd_tx.send(request)
     }
 
-    pub fn discoveries(&self) -> &Vec<Discovery> {
+    /// Like [`VerdantService::login`], tracking the login in [`VerdantService::state`].
+    pub fn login_sync(
+        &mut self,
+        url: impl Into<String>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        let url = url.into();
+        Self::login(&self.cmd_tx, url.clone(), username, password)?;
+        self.state = ConnectionState::Authenticating;
+        self.pending_login = Some(url);
+        Ok(())
+    }
+
+    pub fn list_participants(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+        room_id: Uuid,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::ListParticipants {
+            url: url.into(),
+            room_id,
+        })
+    }
+
+    pub fn refresh_token(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::RefreshToken { url: url.into() })
+    }
+
+    pub fn register(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+        request: RegistrationRequest,
+        password: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::Register {
+            url: url.into(),
+            request: Box::new(request),
+            password: password.into(),
+        })
+    }
+
+    pub fn logout(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::Logout { url: url.into() })
+    }
+
+    pub fn ping(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::Ping { url: url.into() })
+    }
+
+    pub fn health_check(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::HealthCheck { url: url.into() })
+    }
+
+    /// Adds the server at `url` without waiting for it to be discovered, answered with
+    /// [`VerdantUiCmd::ServerAdded`]. Unlike [`VerdantService::add_server_sync`] the server
+    /// isn't counted by [`VerdantService::server_count`].
+    pub fn add_server(
+        cmd_tx: &UnboundedSender<VerdantCmd>,
+        url: impl Into<String>,
+    ) -> Result<(), mpsc::error::SendError<VerdantCmd>> {
+        cmd_tx.send(VerdantCmd::AddServer { url: url.into() })
+    }
+
+    /// Adds the server at `url` without waiting for it to be discovered. Doesn't block,
+    /// the service fetches the server's key in the background.
+    pub fn add_server_sync(&mut self, url: String) {
+        self.added_servers.insert(url.clone());
+        let _ = Self::add_server(&self.cmd_tx, url);
+    }
+
+    /// Forgets the server at `url`, whether it was discovered or added.
+    pub fn remove_server_sync(&mut self, url: &str) {
+        self.added_servers.remove(url);
+        self.discovered.remove(url);
+        let _ = self.cmd_tx.send(VerdantCmd::RemoveServer {
+            url: url.to_string(),
+        });
+    }
+
+    /// number of known servers, discovered or added.
+    pub fn server_count(&self) -> usize {
+        self.discovered.len()
+            + self
+                .added_servers
+                .iter()
+                .filter(|url| self.discovered.get(url).is_none())
+                .count()
+    }
+
+    /// Installs a hook called after every background token refresh.
+    pub fn set_refresh_hook(&self, hook: Option<RefreshHook>) {
+        *self.refresh_hook.lock().expect("refresh hook poisoned") = hook;
+    }
+
+    /// Delivers every UI event to `callback` instead of [`VerdantService::try_recv`].
+    ///
+    /// The first call starts a `verdant-events` thread, `callback` is always called on it
+    /// one event at a time. Later calls replace the callback, `None` drops events until
+    /// another callback is set. Events delivered this way don't update the state mirrored
+    /// by the service, e.g. [`VerdantService::state`] and [`VerdantService::discoveries`].
+    ///
+    /// Dropping the service waits for a running callback to return, so it must not be
+    /// dropped from within the callback.
+    pub fn set_event_callback(
+        &mut self,
+        callback: Option<EventCallback>,
+    ) -> Result<(), crate::errors::Error> {
+        if let Some(current) = &self.event_callback {
+            *current.lock().expect("event callback poisoned") = callback;
+            return Ok(());
+        }
+        let current = Arc::new(Mutex::new(callback));
+        let mut ui_rx = std::mem::replace(&mut self.ui_rx, UiReceiver::closed());
+        let thread_callback = current.clone();
+        std::thread::Builder::new()
+            .name("verdant-events".to_string())
+            .spawn(move || {
+                // ends once the service task is gone
+                while let Some(event) = ui_rx.blocking_recv() {
+                    let callback = thread_callback.lock().expect("event callback poisoned");
+                    if let Some(callback) = &*callback {
+                        callback(event);
+                    }
+                }
+            })?;
+        self.event_callback = Some(current);
+        Ok(())
+    }
+
+    /// servers seen in [`VerdantUiCmd::ServerDiscovered`] events received so far.
+    pub fn discoveries(&self) -> &DiscoveryCache {
         &self.discovered
     }
 
+    /// Owned copy of [`VerdantService::discoveries`], e.g. to hold across an await point.
+    pub fn discoveries_snapshot(&self) -> Vec<Discovery> {
+        self.discovered.snapshot()
+    }
+
+    /// Urls of the discovered servers, most recently seen first.
+    pub fn server_urls_snapshot(&self) -> Vec<String> {
+        self.discovered.urls()
+    }
+
+    /// what the service is doing, updated as events are received.
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    /// display name for the user logged into `url`, once its
+    /// [`VerdantUiCmd::UserProfile`] event has been received.
+    pub fn display_name(&self, url: &str) -> Option<&str> {
+        self.display_names.get(url).map(String::as_str)
+    }
+
+    /// Stops the service: the service task finishes the command it is handling and exits,
+    /// the discovery and periodic tasks are aborted.
+    ///
+    /// Waits for the tasks to finish unless called from within an async context, where
+    /// blocking would stall the runtime, the tasks are then only told to stop.
+    /// Either way the event callback and refresh hook are never called once this returns.
+    pub fn shutdown(mut self) -> Result<(), crate::errors::Error> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), crate::errors::Error> {
+        // no callbacks once stopped, events still queued are dropped by the event thread.
+        // both are called with their lock held, so this waits for a running callback.
+        if let Some(callback) = &self.event_callback {
+            callback.lock().expect("event callback poisoned").take();
+        }
+        self.refresh_hook
+            .lock()
+            .expect("refresh hook poisoned")
+            .take();
+        let service_handle = match self.service_handle.take() {
+            Some(service_handle) => service_handle,
+            None => return Ok(()),
+        };
+        // fails if the service task already exited, which is what we want anyway
+        let _ = self.internal_tx.send(InternalCmd::Shutdown);
+        let tasks: Vec<_> = [
+            self.discovery_handle.take(),
+            self.refresh_handle.take(),
+            self.health_handle.take(),
+            self.expiry_handle.take(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        for task in &tasks {
+            task.abort();
+        }
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Ok(());
+        }
+        let result = self.handle.block_on(service_handle);
+        for task in tasks {
+            let _ = self.handle.block_on(task);
+        }
+        match result {
+            Ok(()) => Ok(()),
+            // the runtime shut down first
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(crate::errors::Error::Internal(format!(
+                "service task failed: {}",
+                e
+            ))),
+        }
+    }
+
     pub fn try_recv(&mut self) -> Option<VerdantUiCmd> {
         match self.ui_rx.try_recv() {
-            Ok(val) => Some(val),
+            Ok(val) => {
+                self.observe(&val);
+                Some(val)
+            }
             Err(_e) => None,
         }
     }
-}
 
-async fn verdant_service(
-    mut cmd_rx: UnboundedReceiver<VerdantCmd>,
-    ui_tx: UnboundedSender<VerdantUiCmd>,
-    mut clients: HashMap<String, APIClient>,
-) {
-    while let Some(event) = cmd_rx.recv().await {
+    /// Blocks the current thread for up to `timeout` waiting for the next UI event,
+    /// `None` if none arrived in time or the service task has exited.
+    ///
+    /// # Panics
+    ///
+    /// If called from within a tokio runtime, use [`VerdantService::try_recv`] there.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<VerdantUiCmd> {
+        let ui_rx = &mut self.ui_rx;
+        let event = self
+            .handle
+            .block_on(async {
+                // the timer has to be created inside the runtime
+                tokio::time::timeout(timeout, std::future::poll_fn(|cx| ui_rx.poll_recv(cx))).await
+            })
+            .ok()??;
+        self.observe(&event);
+        Some(event)
+    }
+
+    /// Waits for the next UI event, `None` once the service task has exited.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_for_event(&mut self) -> Option<VerdantUiCmd> {
+        let event = std::future::poll_fn(|cx| self.ui_rx.poll_recv(cx)).await?;
+        self.observe(&event);
+        Some(event)
+    }
+
+    /// The UI events as a stream, ending once the service task has exited.
+    #[cfg(feature = "tokio")]
+    pub fn event_stream(&mut self) -> impl tokio_stream::Stream<Item = VerdantUiCmd> + '_ {
+        EventStream { service: self }
+    }
+
+    /// Updates the state mirrored from UI events, e.g. [`VerdantService::discoveries`].
+    fn observe(&mut self, event: &VerdantUiCmd) {
         match event {
-            VerdantCmd::ServerDiscovered(discovery) => {
-                let url = discovery.urls().get(0).unwrap().clone();
-                let client = APIClient::from_discovery(discovery.clone()).await.unwrap();
-                clients.insert(url, client);
-                ui_tx
-                    .send(VerdantUiCmd::ServerDiscovered(discovery))
-                    .unwrap();
+            VerdantUiCmd::ServerDiscovered(discovery) => {
+                if let Ok(url) = discovery.primary_url() {
+                    self.discovered.insert(url, (**discovery).clone());
+                }
             }
-            VerdantCmd::Login(request) => {
-                if let Some(client) = clients.get_mut(&request.url) {
-                    let result = match client.login(&request.username, &request.password).await {
-                        Ok(result) => result,
-                        Err(e) => {
-                            eprintln!("login error: {}", e);
-                            LoginResult::Unauthorized
-                        }
-                    };
-                    println!("login result: {} {:?}", &request.username, result);
-                    let cmd = VerdantUiCmd::LoginResult(result);
-                    ui_tx.send(cmd).unwrap();
-
-                    // now request token
-                    if let Ok(response) = client.get_livekit_token().await {
-                        ui_tx
-                            .send(VerdantUiCmd::LkToken(LkTokenRecord::new(request.url.to_string(), response)))
-                            .unwrap();
+            VerdantUiCmd::ServerLost(discovery) => {
+                if let Some(url) = discovery.server_url() {
+                    self.discovered.remove(&url);
+                }
+            }
+            VerdantUiCmd::UserProfile {
+                url, display_name, ..
+            } => {
+                self.display_names.insert(url.clone(), display_name.clone());
+            }
+            VerdantUiCmd::LoginResult(result) => {
+                let pending = self.pending_login.take();
+                self.state = match (result, pending) {
+                    (LoginResult::Success(_), Some(url)) => ConnectionState::Connected { url },
+                    // a refresh of the current session
+                    (LoginResult::Success(_), None) => return,
+                    (LoginResult::Unauthorized(UnauthorizedReason::LoggedOut), None) => {
+                        ConnectionState::idle(self.discovery_handle.is_some())
                     }
-                } else {
-                    match APIClient::from_url(&request.url).await {
-                        Ok(mut client) => {
-                            let result =
-                                match client.login(&request.username, &request.password).await {
-                                    Ok(result) => result,
-                                    Err(e) => {
-                                        eprintln!("login error: {}", e);
-                                        LoginResult::Unauthorized
-                                    }
-                                };
-                            println!("login result: {} {:?}", &request.username, result);
-                            let cmd = VerdantUiCmd::LoginResult(result);
-                            ui_tx.send(cmd).unwrap();
-
-                            // now request token
-                            if let Ok(response) = client.get_livekit_token().await {
-                                ui_tx
-                                    .send(VerdantUiCmd::LkToken(LkTokenRecord::new(request.url.to_string(), response)))
-                                    .unwrap();
-                            }
-                            clients.insert(request.url.clone(), client);
-                        }
-                        Err(e) => {
-                            let qualified = format!("error: unknown server: {}, because of: {}", request.url, e);
-                            let result = VerdantUiCmd::LoginResult(LoginResult::UnknownServer(
-                                qualified,
-                            ));
-                            ui_tx.send(result).unwrap();
-                        }
+                    (LoginResult::Unauthorized(reason), _) => {
+                        ConnectionState::Error(reason.to_string())
+                    }
+                    (LoginResult::PasswordReset, _) => {
+                        ConnectionState::Error("password reset required".to_string())
                     }
+                    (LoginResult::UnknownServer(url), _) => {
+                        ConnectionState::Error(format!("unknown server: {}", url))
+                    }
+                };
+            }
+            VerdantUiCmd::ServerFailover { fallback_url, .. } if self.pending_login.is_some() => {
+                self.pending_login = None;
+                self.state = ConnectionState::Connected {
+                    url: fallback_url.clone(),
+                };
+            }
+            VerdantUiCmd::Disconnected { url } => {
+                if matches!(&self.state, ConnectionState::Connected { url: current } if current == url)
+                {
+                    self.state = ConnectionState::idle(self.discovery_handle.is_some());
                 }
             }
+            VerdantUiCmd::Error(err) if self.pending_login.is_some() => {
+                self.pending_login = None;
+                self.state = ConnectionState::Error(err.message.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stream returned by [`VerdantService::event_stream`].
+#[cfg(feature = "tokio")]
+struct EventStream<'a> {
+    service: &'a mut VerdantService,
+}
+
+#[cfg(feature = "tokio")]
+impl tokio_stream::Stream for EventStream<'_> {
+    type Item = VerdantUiCmd;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<VerdantUiCmd>> {
+        let service = &mut *self.service;
+        let event = std::task::ready!(service.ui_rx.poll_recv(cx));
+        if let Some(event) = &event {
+            service.observe(event);
+        }
+        std::task::Poll::Ready(event)
+    }
+}
+
+impl Drop for VerdantService {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!(error = %e, "service shutdown failed");
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Moves the login of `session`, the client at the server's previous address, to `client`
+/// if `previous` announced the same key as `discovery`. Otherwise the logged in `session`
+/// is handed back, the user has to log in again.
+fn carry_session(
+    client: &mut APIClient,
+    session: Option<APIClient>,
+    previous: Option<&Discovery>,
+    discovery: &Discovery,
+) -> Option<APIClient> {
+    let session = session.filter(|session| session.access_token.is_some())?;
+    match previous {
+        Some(previous) if previous.pubkey_hash.hash == discovery.pubkey_hash.hash => {
+            client.take_session(session);
+            None
+        }
+        _ => Some(session),
+    }
+}
+
+/// Sends [`VerdantUiCmd::UserProfile`] if the client's token carries a display name.
+fn send_user_profile(url: &str, client: &APIClient, ui_tx: &UiSender) {
+    let claims = match client.claims() {
+        Some(claims) => claims,
+        None => return,
+    };
+    if let Some(display_name) = &claims.display_name {
+        let _ = ui_tx.send(VerdantUiCmd::UserProfile {
+            url: url.to_string(),
+            display_name: display_name.clone(),
+            avatar_url: claims.avatar_url.clone(),
+        });
+    }
+}
+
+/// Spawns a token refresh for every client expiring within `within_secs`. Like a login,
+/// each refresh holds the server's client until it's reported back on `done_tx`.
+fn refresh_expiring(
+    clients: &mut HashMap<String, APIClient>,
+    pending: &mut HashSet<String>,
+    within_secs: u64,
+    ui_tx: &UiSender,
+    refresh_hook: &Arc<Mutex<Option<RefreshHook>>>,
+    done_tx: &UnboundedSender<LoginDone>,
+) {
+    let deadline = unix_now() + within_secs;
+    let expiring: Vec<String> = clients
+        .iter()
+        .filter(|(_, client)| {
+            client
+                .token_expires_at()
+                .is_some_and(|expires_at| expires_at <= deadline)
+        })
+        .map(|(url, _)| url.clone())
+        .collect();
+    for url in expiring {
+        let Some(mut client) = clients.remove(&url) else {
+            continue;
+        };
+        pending.insert(url.clone());
+        let ui_tx = ui_tx.clone();
+        let refresh_hook = refresh_hook.clone();
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            refresh_client(&url, &mut client, &ui_tx, &refresh_hook).await;
+            let _ = done_tx.send((url.clone(), vec![(url, client)]));
+        });
+    }
+}
+
+/// Refreshes the token of `client`, disconnecting it if that fails.
+async fn refresh_client(
+    url: &str,
+    client: &mut APIClient,
+    ui_tx: &UiSender,
+    refresh_hook: &Mutex<Option<RefreshHook>>,
+) {
+    let result = client.refresh_token().await;
+    if let Some(hook) = refresh_hook.lock().expect("refresh hook poisoned").as_ref() {
+        hook(url, result.as_ref().ok().map(String::as_str));
+    }
+    let cmd = match result {
+        Ok(token) => VerdantUiCmd::LoginResult(LoginResult::Success(token)),
+        Err(e) => {
+            error!(url = %url, error = %e, "token refresh failed");
+            client.set_access_token(None);
+            VerdantUiCmd::Disconnected {
+                url: url.to_string(),
+            }
+        }
+    };
+    let _ = ui_tx.send(cmd);
+}
+
+/// A finished health check: the server's url, the outcome and whether a failure is
+/// reported as [`VerdantUiCmd::Error`].
+type HealthDone = (String, Result<HealthStatus, crate::errors::Error>, bool);
+
+/// Spawns a health check of `client` so a slow server doesn't hold up the service task,
+/// the outcome is sent to `done_tx`.
+fn spawn_health_check(
+    url: &str,
+    client: &APIClient,
+    report_error: bool,
+    done_tx: &UnboundedSender<HealthDone>,
+) {
+    let url = url.to_string();
+    let client = client.without_session();
+    let done_tx = done_tx.clone();
+    tokio::spawn(async move {
+        let result = client.health_check().await;
+        let _ = done_tx.send((url, result, report_error));
+    });
+}
+
+/// Records a health check of `url`, tracking consecutive failures per server in `failures`.
+///
+/// Sends [`VerdantUiCmd::HealthStatus`] on success and [`VerdantUiCmd::ServerUnreachable`]
+/// once the server failed twice in a row.
+fn record_health(
+    url: &str,
+    result: Result<HealthStatus, crate::errors::Error>,
+    failures: &mut HashMap<String, u32>,
+    ui_tx: &UiSender,
+) -> Result<(), crate::errors::Error> {
+    match result {
+        Ok(status) => {
+            failures.remove(url);
+            let _ = ui_tx.send(VerdantUiCmd::HealthStatus(url.to_string(), status));
+            Ok(())
+        }
+        Err(e) => {
+            warn!(url = %url, error = %e, "health check failed");
+            let count = failures.entry(url.to_string()).or_insert(0);
+            *count += 1;
+            if *count == 2 {
+                let _ = ui_tx.send(VerdantUiCmd::ServerUnreachable(url.to_string()));
+            }
+            Err(e)
         }
     }
 }
+
+/// Converts the room's server-sent events into [`VerdantUiCmd::RoomUpdate`]s until the stream ends.
+async fn forward_room_events(mut response: reqwest::Response, room_id: Uuid, ui_tx: UiSender) {
+    let mut parser = SseParser::new();
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                error!(room_id = %room_id, error = %e, "room event stream failed");
+                break;
+            }
+        };
+        for data in parser.push(&chunk) {
+            match serde_json::from_str::<RoomEvent>(&data) {
+                Ok(event) => {
+                    let _ = ui_tx.send(VerdantUiCmd::RoomUpdate {
+                        room_id,
+                        event_type: event.event_type,
+                        participant: event.participant,
+                    });
+                }
+                Err(e) => error!(room_id = %room_id, error = %e, "invalid room event"),
+            }
+        }
+    }
+}
+
+/// Server url whose login finished, with the clients to (re)insert into the service's map.
+type LoginDone = (String, Vec<(String, APIClient)>);
+
+/// Spawns the login for `request`, reporting the server's client back on `done_tx`.
+fn start_login(
+    clients: &mut HashMap<String, APIClient>,
+    pending_logins: &mut HashSet<String>,
+    request: LoginRequest,
+    ui_tx: &UiSender,
+    done_tx: &UnboundedSender<LoginDone>,
+    failover_on_5xx: bool,
+) {
+    pending_logins.insert(request.url.clone());
+    let client = clients.remove(&request.url);
+    let ui_tx = ui_tx.clone();
+    let done_tx = done_tx.clone();
+    tokio::spawn(async move {
+        let url = request.url.clone();
+        let clients = run_login(client, request, &ui_tx, failover_on_5xx).await;
+        let _ = done_tx.send((url, clients));
+    });
+}
+
+/// Logs into `request.url` with `client`, or a new client if the server isn't known yet.
+///
+/// If the server answers with a 5xx error and `failover_on_5xx` is set, the client's
+/// fallback urls are tried in order, the first one answering is reported with
+/// [`VerdantUiCmd::ServerFailover`] and returned along with the original client. It isn't
+/// reported as [`VerdantUiCmd::ServerDiscovered`]: a fallback url may be a hostname and
+/// has no advertised key hash, so there's no [`Discovery`] to describe it.
+async fn run_login(
+    client: Option<APIClient>,
+    request: LoginRequest,
+    ui_tx: &UiSender,
+    failover_on_5xx: bool,
+) -> Vec<(String, APIClient)> {
+    info!(url = %request.url, username = %request.username, "handling login");
+    let mut client = match client {
+        Some(client) => client,
+        None => match APIClient::from_url(&request.url).await {
+            Ok(client) => client,
+            Err(e) => {
+                let qualified =
+                    format!("error: unknown server: {}, because of: {}", request.url, e);
+                let _ = ui_tx.send(VerdantUiCmd::LoginResult(LoginResult::UnknownServer(
+                    qualified,
+                )));
+                return Vec::new();
+            }
+        },
+    };
+    let result = client.login(&request.username, &request.password).await;
+    if failover_on_5xx
+        && result
+            .as_ref()
+            .is_err_and(crate::errors::Error::is_server_error)
+    {
+        for fallback_url in client.fallback_urls().to_vec() {
+            info!(url = %request.url, fallback_url = %fallback_url, "server error, trying fallback");
+            let mut fallback = match APIClient::from_url(&fallback_url).await {
+                Ok(fallback) => fallback,
+                Err(e) => {
+                    error!(fallback_url = %fallback_url, error = %e, "fallback unreachable");
+                    continue;
+                }
+            };
+            let fallback_result = fallback.login(&request.username, &request.password).await;
+            if fallback_result
+                .as_ref()
+                .is_err_and(crate::errors::Error::is_server_error)
+            {
+                continue;
+            }
+            let _ = ui_tx.send(VerdantUiCmd::ServerFailover {
+                url: request.url.clone(),
+                fallback_url: fallback_url.clone(),
+            });
+            finish_login(&fallback_url, &fallback, fallback_result, ui_tx).await;
+            return vec![(request.url, client), (fallback_url, fallback)];
+        }
+    }
+    finish_login(&request.url, &client, result, ui_tx).await;
+    vec![(request.url, client)]
+}
+
+/// Reports the outcome of a login to `url`, then fetches a LiveKit token.
+async fn finish_login(
+    url: &str,
+    client: &APIClient,
+    result: Result<LoginResult, crate::errors::Error>,
+    ui_tx: &UiSender,
+) {
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            error!(url = %url, error = %e, "login error");
+            LoginResult::Unauthorized(UnauthorizedReason::from_error(&e))
+        }
+    };
+    debug!(result = ?result, "login result");
+    let _ = ui_tx.send(VerdantUiCmd::LoginResult(result));
+    send_user_profile(url, client, ui_tx);
+
+    // now request token
+    if let Ok(response) = client.get_livekit_token().await {
+        let _ = ui_tx.send(VerdantUiCmd::LkToken(Box::new(LkTokenRecord::new(
+            url.to_string(),
+            response,
+        ))));
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip(
+        cmd_rx,
+        internal_rx,
+        ui_tx,
+        clients,
+        refresh_hook,
+        plugins,
+        max_discoveries
+    ))
+)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn verdant_service(
+    mut cmd_rx: UnboundedReceiver<VerdantCmd>,
+    mut internal_rx: UnboundedReceiver<InternalCmd>,
+    ui_tx: UiSender,
+    mut clients: HashMap<String, APIClient>,
+    refresh_hook: Arc<Mutex<Option<RefreshHook>>>,
+    plugins: Plugins,
+    failover_on_5xx: bool,
+    max_discoveries: usize,
+) {
+    for plugin in plugins.iter() {
+        plugin.on_startup().await;
+    }
+    // logins run on their own task, holding the server's client until they finish.
+    // at most one login per server is in flight, later commands for it wait in `waiting`
+    // and are moved to `replay` once it's done.
+    let mut pending_logins: HashSet<String> = HashSet::new();
+    let mut waiting: HashMap<String, VecDeque<VerdantCmd>> = HashMap::new();
+    let mut replay: VecDeque<VerdantCmd> = VecDeque::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel::<LoginDone>();
+    let mut room_subscriptions: HashMap<(String, Uuid), JoinHandle<()>> = HashMap::new();
+    // consecutive failed health checks per server
+    let mut health_failures: HashMap<String, u32> = HashMap::new();
+    // health checks run on their own task too, `health_checks` of them are in flight
+    let (health_tx, mut health_rx) = mpsc::unbounded_channel::<HealthDone>();
+    let mut health_checks: usize = 0;
+    // when each server was last advertised, for expiring discoveries
+    let mut discovered: DiscoveryCache = DiscoveryCache::new(max_discoveries);
+    let mut cmd_open = true;
+    let mut internal_open = true;
+    loop {
+        // commands held back by a login run first, in the order they arrived
+        let event = match replay.pop_front() {
+            Some(cmd) => cmd,
+            None => tokio::select! {
+                // commands queued before a shutdown are still handled
+                biased;
+                Some((url, done)) = done_rx.recv(), if !pending_logins.is_empty() => {
+                    pending_logins.remove(&url);
+                    clients.extend(done);
+                    // a queued login holds back the commands after it again
+                    if let Some(queued) = waiting.remove(&url) {
+                        replay.extend(queued);
+                    }
+                    continue;
+                }
+                Some((url, result, report_error)) = health_rx.recv(), if health_checks > 0 => {
+                    health_checks -= 1;
+                    // the server may have been removed while it was checked
+                    if !clients.contains_key(&url) && !pending_logins.contains(&url) {
+                        continue;
+                    }
+                    if let Err(e) = record_health(&url, result, &mut health_failures, &ui_tx)
+                        && report_error
+                    {
+                        let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())));
+                    }
+                    continue;
+                }
+                cmd = cmd_rx.recv(), if cmd_open => match cmd {
+                    Some(cmd) => cmd,
+                    None => {
+                        cmd_open = false;
+                        continue;
+                    }
+                },
+                internal = internal_rx.recv(), if internal_open => {
+                    match internal {
+                        Some(InternalCmd::Shutdown) => {
+                            info!("shutting down");
+                            for (_, task) in room_subscriptions.drain() {
+                                task.abort();
+                            }
+                            break;
+                        }
+                        Some(InternalCmd::RefreshExpiring { within_secs }) => {
+                            debug!(within_secs, "checking for expiring tokens");
+                            refresh_expiring(
+                                &mut clients,
+                                &mut pending_logins,
+                                within_secs,
+                                &ui_tx,
+                                &refresh_hook,
+                                &done_tx,
+                            );
+                        }
+                        Some(InternalCmd::HealthCheckAll) => {
+                            debug!("checking health of all servers");
+                            for (url, client) in clients.iter() {
+                                spawn_health_check(url, client, false, &health_tx);
+                                health_checks += 1;
+                            }
+                        }
+                        #[cfg(feature = "mdns")]
+                        Some(InternalCmd::ServerSeen(discovery)) => {
+                            // expired earlier, the server is back
+                            if let Some(url) = discovery.server_url()
+                                && !discovered.refresh(&url)
+                            {
+                                info!(url = %url, "expired discovery seen again");
+                                discovered.insert(url, discovery.clone());
+                                let _ = ui_tx.send(VerdantUiCmd::ServerDiscovered(Box::new(discovery)));
+                            }
+                        }
+                        Some(InternalCmd::ExpireDiscoveries { ttl }) => {
+                            for discovery in discovered.expire(ttl) {
+                                info!(urls = ?discovery.urls(), "discovery expired");
+                                let _ = ui_tx.send(VerdantUiCmd::ServerLost(Box::new(discovery)));
+                            }
+                        }
+                        None => internal_open = false,
+                    }
+                    continue;
+                }
+                else => break,
+            },
+        };
+        // the client is away while logging in, so later commands for the server wait for it
+        if let Some(url) = event.url().filter(|url| pending_logins.contains(*url)) {
+            debug!(url = %url, command = event.kind(), "login in progress, queueing");
+            waiting.entry(url.to_string()).or_default().push_back(event);
+            continue;
+        }
+        match run_command_plugins(&plugins, &event) {
+            PluginResult::Continue => {}
+            PluginResult::Intercept(ui_event) => {
+                let _ = ui_tx.send(ui_event);
+                continue;
+            }
+            PluginResult::Drop => continue,
+        }
+        match event {
+            VerdantCmd::ServerDiscovered(discovery) => {
+                info!(urls = ?discovery.urls(), "handling server discovered");
+                let url = match discovery.primary_url() {
+                    Ok(url) => url,
+                    Err(e) => {
+                        error!(error = %e, "discovered server has no url");
+                        continue;
+                    }
+                };
+                // e.g. a beacon whose key doesn't match its advertised hash
+                let client = match APIClient::from_discovery((*discovery).clone()).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!(url = %url, error = %e, "rejecting discovered server");
+                        let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(
+                            -1,
+                            format!("error: discovered server {} rejected: {}", url, e),
+                        )));
+                        continue;
+                    }
+                };
+                clients.insert(url.clone(), client);
+                discovered.insert(url, (*discovery).clone());
+                let _ = ui_tx.send(VerdantUiCmd::ServerDiscovered(discovery));
+            }
+            VerdantCmd::UpdateServer {
+                previous_url,
+                discovery,
+            } => {
+                info!(previous_url = ?previous_url, urls = ?discovery.urls(), "handling server update");
+                let previous = previous_url
+                    .as_deref()
+                    .and_then(|previous| discovered.remove(previous));
+                let Some(url) = discovery.server_url() else {
+                    error!("updated server has no url");
+                    continue;
+                };
+                let session = previous_url.and_then(|previous| clients.remove(&previous));
+                // the new address is verified like a fresh discovery
+                let (dropped, verified) =
+                    match APIClient::from_discovery((*discovery).clone()).await {
+                        Ok(mut client) => {
+                            let dropped =
+                                carry_session(&mut client, session, previous.as_ref(), &discovery);
+                            clients.insert(url.clone(), client);
+                            (dropped, Ok(()))
+                        }
+                        Err(e) => (
+                            session.filter(|session| session.access_token.is_some()),
+                            Err(e),
+                        ),
+                    };
+                if let Some(session) = dropped {
+                    warn!(url = %url, "server update ended the session, log in again");
+                    let _ = ui_tx.send(VerdantUiCmd::Disconnected { url: session.url });
+                }
+                match verified {
+                    Ok(()) => {
+                        discovered.insert(url, (*discovery).clone());
+                        let _ = ui_tx.send(VerdantUiCmd::ServerDiscovered(discovery));
+                    }
+                    // e.g. a beacon whose key doesn't match its advertised hash
+                    Err(e) => {
+                        error!(url = %url, error = %e, "rejecting updated server");
+                        let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(
+                            -1,
+                            format!("error: updated server {} rejected: {}", url, e),
+                        )));
+                    }
+                }
+            }
+            VerdantCmd::Login(request) => {
+                start_login(
+                    &mut clients,
+                    &mut pending_logins,
+                    request,
+                    &ui_tx,
+                    &done_tx,
+                    failover_on_5xx,
+                );
+            }
+            VerdantCmd::RequestDirectConnection {
+                url,
+                peer_username,
+                offer_sdp,
+            } => {
+                info!(url = %url, peer_username = %peer_username, "handling direct connection request");
+                let cmd = match clients.get(&url) {
+                    Some(client) => match client
+                        .offer_direct_connection(&peer_username, &offer_sdp)
+                        .await
+                    {
+                        Ok(answer_token) => VerdantUiCmd::DirectConnectionOffer {
+                            peer_username,
+                            offer_sdp,
+                            answer_token,
+                        },
+                        Err(e) => VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())),
+                    },
+                    None => VerdantUiCmd::Error(VerdantErr::new(
+                        -1,
+                        format!("error: unknown server: {}", url),
+                    )),
+                };
+                let _ = ui_tx.send(cmd);
+            }
+            VerdantCmd::Logout { url } => {
+                info!(url = %url, "handling logout");
+                let Some(client) = clients.get_mut(&url) else {
+                    let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(
+                        -1,
+                        format!("error: unknown server: {}", url),
+                    )));
+                    continue;
+                };
+                if let Err(e) = client.logout().await {
+                    error!(url = %url, error = %e, "logout error");
+                    let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())));
+                }
+                // the local session is gone either way
+                let _ = ui_tx.send(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+                    UnauthorizedReason::LoggedOut,
+                )));
+            }
+            VerdantCmd::Ping { url } => {
+                debug!(url = %url, "pinging server");
+                let cmd = match clients.get(&url) {
+                    Some(client) => {
+                        let started = std::time::Instant::now();
+                        match client.get_server_info().await {
+                            Ok(_) => VerdantUiCmd::Pong(url, started.elapsed()),
+                            Err(e) => VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())),
+                        }
+                    }
+                    None => VerdantUiCmd::Error(VerdantErr::new(
+                        -1,
+                        format!("error: unknown server: {}", url),
+                    )),
+                };
+                let _ = ui_tx.send(cmd);
+            }
+            VerdantCmd::RefreshToken { url } => {
+                info!(url = %url, "refreshing token");
+                let cmd = match clients.get_mut(&url) {
+                    Some(client) => {
+                        let result = client.refresh_token().await;
+                        if let Some(hook) =
+                            refresh_hook.lock().expect("refresh hook poisoned").as_ref()
+                        {
+                            hook(&url, result.as_ref().ok().map(String::as_str));
+                        }
+                        match result {
+                            Ok(token) => VerdantUiCmd::LoginResult(LoginResult::Success(token)),
+                            Err(e) => {
+                                error!(url = %url, error = %e, "token refresh failed");
+                                VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string()))
+                            }
+                        }
+                    }
+                    None => VerdantUiCmd::Error(VerdantErr::new(
+                        -1,
+                        format!("error: unknown server: {}", url),
+                    )),
+                };
+                let _ = ui_tx.send(cmd);
+            }
+            VerdantCmd::SubscribeRoomEvents { url, room_id } => {
+                info!(url = %url, room_id = %room_id, "subscribing to room events");
+                room_subscriptions.retain(|_, task| !task.is_finished());
+                let response = match clients.get(&url) {
+                    Some(client) => client.room_events(room_id).await,
+                    None => Err(format!("error: unknown server: {}", url).into()),
+                };
+                match response {
+                    Ok(response) => {
+                        let task =
+                            tokio::spawn(forward_room_events(response, room_id, ui_tx.clone()));
+                        if let Some(previous) = room_subscriptions.insert((url, room_id), task) {
+                            previous.abort();
+                        }
+                    }
+                    Err(e) => {
+                        let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())));
+                    }
+                }
+            }
+            VerdantCmd::UnsubscribeRoomEvents { url, room_id } => {
+                info!(url = %url, room_id = %room_id, "unsubscribing from room events");
+                if let Some(task) = room_subscriptions.remove(&(url, room_id)) {
+                    task.abort();
+                }
+            }
+            VerdantCmd::ListParticipants { url, room_id } => {
+                info!(url = %url, room_id = %room_id, "listing participants");
+                let cmd = match clients.get(&url) {
+                    Some(client) => match client.get_participant_list(room_id).await {
+                        Ok(participants) => VerdantUiCmd::ParticipantList(participants),
+                        Err(e) => VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string())),
+                    },
+                    None => VerdantUiCmd::Error(VerdantErr::new(
+                        -1,
+                        format!("error: unknown server: {}", url),
+                    )),
+                };
+                let _ = ui_tx.send(cmd);
+            }
+            VerdantCmd::AddServer { url } => {
+                info!(url = %url, "adding server");
+                let cmd = if clients.contains_key(&url) {
+                    VerdantUiCmd::ServerAdded { url }
+                } else {
+                    match APIClient::from_url(url.clone()).await {
+                        Ok(client) => {
+                            clients.insert(url.clone(), client);
+                            VerdantUiCmd::ServerAdded { url }
+                        }
+                        Err(e) => {
+                            error!(url = %url, error = %e, "failed to add server");
+                            VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string()))
+                        }
+                    }
+                };
+                let _ = ui_tx.send(cmd);
+            }
+            VerdantCmd::RemoveServer { url } => {
+                info!(url = %url, "removing server");
+                clients.remove(&url);
+                health_failures.remove(&url);
+                discovered.remove(&url);
+                room_subscriptions.retain(|(server, _), task| {
+                    if *server == url {
+                        task.abort();
+                    }
+                    *server != url
+                });
+            }
+            VerdantCmd::HealthCheck { url } => {
+                debug!(url = %url, "checking server health");
+                match clients.get(&url) {
+                    Some(client) => {
+                        spawn_health_check(&url, client, true, &health_tx);
+                        health_checks += 1;
+                    }
+                    None => {
+                        let _ = ui_tx.send(VerdantUiCmd::Error(VerdantErr::new(
+                            -1,
+                            format!("error: unknown server: {}", url),
+                        )));
+                    }
+                }
+            }
+            VerdantCmd::Register {
+                url,
+                request,
+                password,
+            } => {
+                info!(url = %url, username = %request.username, "handling registration");
+                let username = request.username.clone();
+                let result = match clients.get(&url) {
+                    Some(client) => client.register(*request, &password).await,
+                    None => match APIClient::from_url(url.clone()).await {
+                        Ok(client) => {
+                            let result = client.register(*request, &password).await;
+                            clients.insert(url.clone(), client);
+                            result
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+                let cmd = match result {
+                    Ok(()) => VerdantUiCmd::Registered { url, username },
+                    Err(e) => {
+                        error!(url = %url, error = %e, "registration failed");
+                        VerdantUiCmd::Error(VerdantErr::new(-1, e.to_string()))
+                    }
+                };
+                let _ = ui_tx.send(cmd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::ServicePlugin;
+    use crate::test_util::{
+        MockResponse, mock_server, mock_server_raw, spawn_test_server, spawn_test_service,
+        test_discovery,
+    };
+    use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+
+    fn ui_channel() -> (UiSender, UnboundedReceiver<VerdantUiCmd>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (UiSender::new(tx, Arc::new(Vec::new())), rx)
+    }
+
+    fn jwt(exp: u64) -> String {
+        #[derive(serde_derive::Serialize)]
+        struct Claims {
+            exp: u64,
+        }
+        jsonwebtoken::encode(
+            &Header::default(),
+            &Claims { exp },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    fn client_with_token(url: &str, token: String) -> APIClient {
+        let mut client = APIClient::builder(
+            url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .skip_compatibility_check(true)
+        .build()
+        .unwrap();
+        client.set_access_token(Some(token));
+        client
+    }
+
+    #[tokio::test]
+    async fn expiring_tokens_are_refreshed() {
+        let fresh = jwt(unix_now() + 3600);
+        let (url, requests) = mock_server(vec![(
+            200,
+            serde_json::to_string(&LoginResult::Success(fresh.clone())).unwrap(),
+        )])
+        .await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 5)));
+        let (ui_tx, mut ui_rx) = ui_channel();
+        let refreshed = Arc::new(Mutex::new(Vec::new()));
+        let seen = refreshed.clone();
+        let hook: RefreshHook = Box::new(move |url, token| {
+            seen.lock()
+                .unwrap()
+                .push((url.to_string(), token.map(str::to_string)));
+        });
+        let hook = Arc::new(Mutex::new(Some(hook)));
+        let mut pending = HashSet::new();
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+
+        refresh_expiring(&mut clients, &mut pending, 120, &ui_tx, &hook, &done_tx);
+        assert!(clients.is_empty());
+        assert!(pending.contains(&url));
+        let (refreshed_url, done) = done_rx.recv().await.unwrap();
+        assert_eq!(refreshed_url, url);
+        clients.extend(done);
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(clients[&url].access_token, Some(fresh.clone()));
+        assert!(matches!(
+            ui_rx.try_recv(),
+            Ok(VerdantUiCmd::LoginResult(LoginResult::Success(token))) if token == fresh
+        ));
+        assert_eq!(*refreshed.lock().unwrap(), vec![(url, Some(fresh))]);
+    }
+
+    #[test]
+    fn user_profile_is_sent_when_token_has_display_name() {
+        let claims = crate::api::VerdantClaims {
+            display_name: Some("Alice".to_string()),
+            ..Default::default()
+        };
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let client = client_with_token("http://localhost", token);
+        let (ui_tx, mut ui_rx) = ui_channel();
+
+        send_user_profile("http://localhost", &client, &ui_tx);
+        assert!(matches!(
+            ui_rx.try_recv(),
+            Ok(VerdantUiCmd::UserProfile { display_name, avatar_url: None, .. }) if display_name == "Alice"
+        ));
+
+        let client = client_with_token("http://localhost", jwt(unix_now()));
+        send_user_profile("http://localhost", &client, &ui_tx);
+        assert!(ui_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn long_lived_tokens_are_left_alone() {
+        let url = "http://127.0.0.1:9".to_string();
+        let token = jwt(unix_now() + 3600);
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, token.clone()));
+        let (ui_tx, mut ui_rx) = ui_channel();
+
+        let mut pending = HashSet::new();
+        let (done_tx, _done_rx) = mpsc::unbounded_channel();
+
+        refresh_expiring(
+            &mut clients,
+            &mut pending,
+            120,
+            &ui_tx,
+            &Arc::new(Mutex::new(None)),
+            &done_tx,
+        );
+
+        assert!(pending.is_empty());
+        assert_eq!(clients[&url].access_token, Some(token));
+        assert!(ui_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_disconnects() {
+        let (url, _) = mock_server(vec![(500, String::new())]).await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 5)));
+        let (ui_tx, mut ui_rx) = ui_channel();
+
+        let mut pending = HashSet::new();
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+
+        refresh_expiring(
+            &mut clients,
+            &mut pending,
+            120,
+            &ui_tx,
+            &Arc::new(Mutex::new(None)),
+            &done_tx,
+        );
+        clients.extend(done_rx.recv().await.unwrap().1);
+
+        assert_eq!(clients[&url].access_token, None);
+        assert!(matches!(
+            ui_rx.try_recv(),
+            Ok(VerdantUiCmd::Disconnected { url: disconnected }) if disconnected == url
+        ));
+    }
+
+    /// rejects logins to one server and renames every error event.
+    struct TestPlugin;
+
+    impl ServicePlugin for TestPlugin {
+        fn on_command(&self, cmd: &VerdantCmd) -> PluginResult {
+            match cmd {
+                VerdantCmd::Login(request) if request.url == "https://blocked" => {
+                    PluginResult::Intercept(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+                        UnauthorizedReason::AccountLocked,
+                    )))
+                }
+                VerdantCmd::Logout { .. } => PluginResult::Drop,
+                _ => PluginResult::Continue,
+            }
+        }
+
+        fn on_event(&self, event: &VerdantUiCmd) -> PluginResult {
+            match event {
+                VerdantUiCmd::Error(_) => {
+                    PluginResult::Intercept(VerdantUiCmd::Error(VerdantErr::new(42, "rewritten")))
+                }
+                VerdantUiCmd::LkToken(_) => PluginResult::Drop,
+                _ => PluginResult::Continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plugins_intercept_commands() {
+        let plugins: Plugins = Arc::new(vec![Box::new(TestPlugin) as BoxedPlugin]);
+        let (cmd_tx, _, mut ui_rx, service) = spawn_test_service(HashMap::new(), plugins, true);
+
+        cmd_tx
+            .send(VerdantCmd::Logout {
+                url: "https://blocked".to_string(),
+            })
+            .unwrap();
+        VerdantService::login(&cmd_tx, "https://blocked", "alice", "password").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        // the logout was dropped, the login intercepted before any network access
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(_)))
+        ));
+        assert!(ui_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn full_ui_channel_drops_oldest_event() {
+        let (ui_tx, mut ui_rx) = UiSender::channel(Some(2), Arc::new(Vec::new()));
+        for url in ["https://a", "https://b", "https://c"] {
+            ui_tx
+                .send(VerdantUiCmd::ServerUnreachable(url.to_string()))
+                .unwrap();
+        }
+
+        for expected in ["https://b", "https://c"] {
+            assert!(matches!(
+                ui_rx.try_recv(),
+                Ok(VerdantUiCmd::ServerUnreachable(url)) if url == expected
+            ));
+        }
+        assert!(ui_rx.try_recv().is_err());
+
+        drop(ui_rx);
+        assert!(
+            ui_tx
+                .send(VerdantUiCmd::ServerUnreachable("https://d".to_string()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn plugins_transform_events() {
+        let (tx, mut ui_rx) = mpsc::unbounded_channel();
+        let ui_tx = UiSender::new(tx, Arc::new(vec![Box::new(TestPlugin) as BoxedPlugin]));
+
+        ui_tx
+            .send(VerdantUiCmd::Error(VerdantErr::new(-1, "original")))
+            .unwrap();
+        ui_tx
+            .send(VerdantUiCmd::Disconnected {
+                url: "https://example".to_string(),
+            })
+            .unwrap();
+
+        assert!(matches!(
+            ui_rx.try_recv(),
+            Ok(VerdantUiCmd::Error(err)) if err == VerdantErr::new(42, "rewritten")
+        ));
+        assert!(matches!(
+            ui_rx.try_recv(),
+            Ok(VerdantUiCmd::Disconnected { .. })
+        ));
+        assert!(ui_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn concurrent_logins_to_one_server_are_serialized() {
+        let (url, requests) = mock_server((0..4).map(|_| (500, String::new())).collect()).await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 3600)));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        VerdantService::login(&cmd_tx, &url, "alice", "first").unwrap();
+        VerdantService::login(&cmd_tx, &url, "alice", "second").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        for _ in 0..2 {
+            assert!(matches!(
+                ui_rx.recv().await,
+                Some(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(_)))
+            ));
+        }
+        assert!(ui_rx.recv().await.is_none());
+        // the second login only started once the first one (and its token request) finished
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].starts_with("POST") && requests[2].starts_with("POST"));
+        assert!(requests[1].starts_with("GET") && requests[3].starts_with("GET"));
+    }
+
+    #[tokio::test]
+    async fn commands_wait_for_a_login_in_flight() {
+        let (url, requests) = mock_server(vec![
+            (500, String::new()),
+            (500, String::new()),
+            (200, String::new()),
+        ])
+        .await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 3600)));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        VerdantService::login(&cmd_tx, &url, "alice", "password").unwrap();
+        VerdantService::logout(&cmd_tx, &url).unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(reason)))
+                if reason != UnauthorizedReason::LoggedOut
+        ));
+        // the logout found the client once the login handed it back
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+                UnauthorizedReason::LoggedOut
+            )))
+        ));
+        assert!(ui_rx.recv().await.is_none());
+        assert!(requests.lock().unwrap()[2].starts_with("POST /auth/api/logout"));
+    }
+
+    #[tokio::test]
+    async fn room_events_become_room_updates() {
+        let body = concat!(
+            "data: {\"event_type\":\"ParticipantJoined\",\"participant\":\"alice\"}\n\n",
+            ": keep-alive\n\n",
+            "data: not json\n\n",
+            "data: {\"event_type\":\"RoomClosed\"}\n\n",
+        );
+        let (url, requests) = mock_server_raw(vec![MockResponse {
+            status: 200,
+            headers: vec![("content-type", "text/event-stream".to_string())],
+            body: body.as_bytes().to_vec(),
+        }])
+        .await;
+        let room_id = Uuid::new_v4();
+        let client = client_with_token(&url, jwt(unix_now() + 3600));
+        let (ui_tx, mut ui_rx) = ui_channel();
+
+        let response = client.room_events(room_id).await.unwrap();
+        forward_room_events(response, room_id, ui_tx).await;
+
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            format!("GET /rpc/rooms/{}/events HTTP/1.1", room_id)
+        );
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::RoomUpdate { room_id: id, event_type: RoomEventType::ParticipantJoined, participant: Some(p) })
+                if id == room_id && p == "alice"
+        ));
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::RoomUpdate {
+                event_type: RoomEventType::RoomClosed,
+                participant: None,
+                ..
+            })
+        ));
+        assert!(ui_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribing_to_unknown_server_reports_error() {
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(HashMap::new(), Arc::new(Vec::new()), true);
+
+        cmd_tx
+            .send(VerdantCmd::SubscribeRoomEvents {
+                url: "http://unknown".to_string(),
+                room_id: Uuid::new_v4(),
+            })
+            .unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn discovery_with_mismatched_key_is_reported() {
+        use crate::api::{KeyType, PubKeyResponse};
+
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let (url, _) = mock_server(vec![(200, serde_json::to_string(&pubkey).unwrap())]).await;
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let discovery = test_discovery(port, crate::crypto::sha256_base64("another key"));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(HashMap::new(), Arc::new(Vec::new()), true);
+
+        cmd_tx
+            .send(VerdantCmd::ServerDiscovered(Box::new(discovery)))
+            .unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Error(_))));
+        assert!(ui_rx.recv().await.is_none());
+    }
+
+    fn logged_in_client(url: &str) -> APIClient {
+        let mut client = APIClient::new(
+            url,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        );
+        client.set_access_token(Some("token".to_string()));
+        client
+    }
+
+    #[test]
+    fn session_moves_only_if_the_key_is_unchanged() {
+        let previous = test_discovery(1, "key".to_string());
+        let same_key = test_discovery(2, "key".to_string());
+        let new_key = test_discovery(2, "other key".to_string());
+        let fresh = || {
+            APIClient::new(
+                "http://127.0.0.1:2",
+                DecodingKey::from_secret(b"secret"),
+                Validation::default(),
+            )
+        };
+
+        let mut client = fresh();
+        let session = Some(logged_in_client("http://127.0.0.1:1"));
+        assert!(carry_session(&mut client, session, Some(&previous), &same_key).is_none());
+        assert_eq!(client.access_token.as_deref(), Some("token"));
+
+        for stored in [Some(&previous), None] {
+            let mut client = fresh();
+            let session = Some(logged_in_client("http://127.0.0.1:1"));
+            let dropped = carry_session(&mut client, session, stored, &new_key).unwrap();
+            assert_eq!(dropped.url, "http://127.0.0.1:1");
+            assert!(client.access_token.is_none());
+        }
+
+        // nothing to end without a login
+        let mut client = fresh();
+        let session = APIClient::new(
+            "http://127.0.0.1:1",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        );
+        assert!(carry_session(&mut client, Some(session), None, &new_key).is_none());
+    }
+
+    #[tokio::test]
+    async fn update_to_an_unverified_key_ends_the_session() {
+        use crate::api::{KeyType, PubKeyResponse};
+
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let (url, _) = mock_server(vec![
+            (200, serde_json::to_string(&pubkey).unwrap()),
+            (404, String::new()),
+        ])
+        .await;
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let discovery = test_discovery(port, pubkey.key_hash().unwrap());
+        let previous_url = "http://10.0.0.1:8080".to_string();
+        let clients = HashMap::from([(previous_url.clone(), logged_in_client(&previous_url))]);
+
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        // the old address was never verified, so its key can't be compared
+        cmd_tx
+            .send(VerdantCmd::UpdateServer {
+                previous_url: Some(previous_url.clone()),
+                discovery: Box::new(discovery),
+            })
+            .unwrap();
+        cmd_tx
+            .send(VerdantCmd::RefreshToken { url: url.clone() })
+            .unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Disconnected { url }) if url == previous_url
+        ));
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ServerDiscovered(_))
+        ));
+        // the new client has no token to refresh
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Error(e)) if !e.message.contains("unknown server")
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_to_a_mismatched_key_is_rejected() {
+        use crate::api::{KeyType, PubKeyResponse};
+
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let advertised = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[43u8; 32]);
+        let (url, _) = mock_server(vec![(200, serde_json::to_string(&pubkey).unwrap())]).await;
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let discovery = test_discovery(port, advertised.key_hash().unwrap());
+        let previous_url = "http://10.0.0.1:8080".to_string();
+        let clients = HashMap::from([(previous_url.clone(), logged_in_client(&previous_url))]);
+
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        cmd_tx
+            .send(VerdantCmd::UpdateServer {
+                previous_url: Some(previous_url.clone()),
+                discovery: Box::new(discovery),
+            })
+            .unwrap();
+        cmd_tx
+            .send(VerdantCmd::RefreshToken { url: url.clone() })
+            .unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Disconnected { url }) if url == previous_url
+        ));
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Error(e)) if e.message.contains("rejected")
+        ));
+        // neither announced nor kept as a client
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Error(e)) if e.message.contains("unknown server")
+        ));
+        assert!(ui_rx.recv().await.is_none());
+    }
+
+    #[cfg(feature = "mdns")]
+    #[tokio::test]
+    async fn expired_discovery_is_rediscovered_when_seen_again() {
+        use crate::api::{KeyType, PubKeyResponse};
+
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let (url, _) = mock_server(vec![(200, serde_json::to_string(&pubkey).unwrap())]).await;
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let discovery = test_discovery(port, pubkey.key_hash().unwrap());
+
+        let (cmd_tx, internal_tx, mut ui_rx, service) =
+            spawn_test_service(HashMap::new(), Arc::new(Vec::new()), true);
+
+        cmd_tx
+            .send(VerdantCmd::ServerDiscovered(Box::new(discovery.clone())))
+            .unwrap();
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ServerDiscovered(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        internal_tx
+            .send(InternalCmd::ExpireDiscoveries {
+                ttl: Duration::ZERO,
+            })
+            .unwrap();
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ServerLost(_))
+        ));
+
+        // the beacon is unchanged, the discovery task only reports it as seen
+        internal_tx
+            .send(InternalCmd::ServerSeen(discovery))
+            .unwrap();
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ServerDiscovered(seen)) if seen.server_url() == Some(url)
+        ));
+        internal_tx.send(InternalCmd::Shutdown).unwrap();
+        service.await.unwrap();
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn service_loop_is_traced() {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (_, internal_rx) = mpsc::unbounded_channel();
+        let (ui_tx, _ui_rx) = ui_channel();
+        let room_id = Uuid::new_v4();
+        cmd_tx
+            .send(VerdantCmd::SubscribeRoomEvents {
+                url: "http://unknown".to_string(),
+                room_id,
+            })
+            .unwrap();
+        drop(cmd_tx);
+        verdant_service(
+            cmd_rx,
+            internal_rx,
+            ui_tx,
+            HashMap::new(),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Vec::new()),
+            true,
+            DiscoveryCache::<Discovery>::DEFAULT_MAX_SIZE,
+        )
+        .await;
+
+        assert!(logs_contain("verdant_service{failover_on_5xx=true}"));
+        assert!(logs_contain("subscribing to room events"));
+        assert!(logs_contain(&format!("room_id={room_id}")));
+    }
+
+    #[tokio::test]
+    async fn participants_are_listed() {
+        let (url, _) = mock_server(vec![(
+            200,
+            r#"[{"identity":"alice","name":null,"joined_at":0,"is_publisher":false,"tracks":[]}]"#
+                .to_string(),
+        )])
+        .await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 3600)));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        VerdantService::list_participants(&cmd_tx, &url, Uuid::new_v4()).unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ParticipantList(participants))
+                if participants.len() == 1 && participants[0].identity == "alice"
+        ));
+    }
+
+    #[tokio::test]
+    async fn token_is_refreshed_on_request() {
+        let fresh = jwt(unix_now() + 7200);
+        let (url, requests) = mock_server(vec![
+            (
+                200,
+                serde_json::to_string(&LoginResult::Success(fresh.clone())).unwrap(),
+            ),
+            (401, String::new()),
+        ])
+        .await;
+        let mut clients = HashMap::new();
+        // far from expiry, so only an explicit refresh touches it
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 3600)));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        VerdantService::refresh_token(&cmd_tx, &url).unwrap();
+        VerdantService::refresh_token(&cmd_tx, &url).unwrap();
+        VerdantService::refresh_token(&cmd_tx, "http://unknown.invalid").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::LoginResult(LoginResult::Success(token))) if token == fresh
+        ));
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Error(_))));
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Error(_))));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unreachable_after_two_failed_health_checks() {
+        let (server, url) = spawn_test_server().await;
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), server.client());
+        let (cmd_tx, internal_tx, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        VerdantService::health_check(&cmd_tx, &url).unwrap();
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::HealthStatus(server, status))
+                if server == url && status.authenticated_users == Some(0)
+        ));
+        server.set_unavailable(true);
+        internal_tx.send(InternalCmd::HealthCheckAll).unwrap();
+        internal_tx.send(InternalCmd::HealthCheckAll).unwrap();
+        drop(cmd_tx);
+        drop(internal_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::ServerUnreachable(server)) if server == url
+        ));
+        assert!(ui_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn hanging_health_check_does_not_block_commands() {
+        // accepts connections but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("http://{}", silent.local_addr().unwrap());
+        let (server, url) = spawn_test_server().await;
+        let mut clients = HashMap::new();
+        clients.insert(
+            silent_url.clone(),
+            client_with_token(&silent_url, jwt(unix_now() + 3600)),
+        );
+        clients.insert(url.clone(), server.client());
+        let (cmd_tx, internal_tx, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        internal_tx.send(InternalCmd::HealthCheckAll).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        VerdantService::ping(&cmd_tx, &url).unwrap();
+
+        let pong = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ui_rx.recv().await {
+                    Some(VerdantUiCmd::Pong(pinged, _)) => break pinged,
+                    Some(_) => continue,
+                    None => panic!("service stopped"),
+                }
+            }
+        })
+        .await
+        .expect("ping waited for the health check");
+        assert_eq!(pong, url);
+        service.abort();
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn removed_server_is_forgotten() {
+        let url = "http://127.0.0.1:9".to_string();
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client_with_token(&url, jwt(unix_now() + 3600)));
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), true);
+
+        cmd_tx
+            .send(VerdantCmd::RemoveServer { url: url.clone() })
+            .unwrap();
+        VerdantService::list_participants(&cmd_tx, &url, Uuid::new_v4()).unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::Error(e)) if e.message.contains("unknown server")
+        ));
+    }
+
+    #[tokio::test]
+    async fn added_server_is_reported() {
+        let pubkey = serde_json::to_string(&crate::api::PubKeyResponse::encode_pubkey(
+            crate::api::KeyType::Ed25519,
+            &[42u8; 32],
+        ))
+        .unwrap();
+        let (url, requests) = mock_server(vec![(200, pubkey)]).await;
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(HashMap::new(), Arc::new(Vec::new()), false);
+        VerdantService::add_server(&cmd_tx, &url).unwrap();
+        // already known, answered without contacting the server again
+        VerdantService::add_server(&cmd_tx, &url).unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        for _ in 0..2 {
+            assert!(matches!(
+                ui_rx.recv().await,
+                Some(VerdantUiCmd::ServerAdded { url: added }) if added == url
+            ));
+        }
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["GET /pubkey HTTP/1.1".to_string()]
+        );
+    }
+
+    #[test]
+    fn saved_discoveries_are_verified_on_startup() {
+        use crate::api::{KeyType, PubKeyResponse};
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &[42u8; 32]);
+        let (url, _) = runtime.block_on(mock_server(vec![(
+            200,
+            serde_json::to_string(&pubkey).unwrap(),
+        )]));
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let path = std::env::temp_dir().join(format!("verdant-{}.jsonl", Uuid::new_v4()));
+        let store = DiscoveryStore::new(&path);
+        runtime.block_on(async {
+            store
+                .append(&test_discovery(port, pubkey.key_hash().unwrap()))
+                .await
+                .unwrap();
+            // nothing listens there, so it can't be verified
+            store
+                .append(&test_discovery(1, pubkey.key_hash().unwrap()))
+                .await
+                .unwrap();
+        });
+
+        let mut service = VerdantService::with_config(
+            &runtime,
+            VerdantServiceConfig {
+                discovery: false,
+                persist_path: Some(path.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let events: Vec<_> = (0..2)
+            .map(|_| service.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert!(events.iter().any(|event| matches!(
+            event,
+            VerdantUiCmd::ServerDiscovered(discovery) if discovery.server_url().as_ref() == Some(&url)
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            VerdantUiCmd::Error(e) if e.message.contains("rejected")
+        )));
+        assert_eq!(service.discoveries().urls(), vec![url.clone()]);
+
+        // the verified server has a client, it just isn't logged in
+        VerdantService::refresh_token(service.tx(), &url).unwrap();
+        assert!(matches!(
+            service.recv_timeout(Duration::from_secs(5)),
+            Some(VerdantUiCmd::Error(e)) if !e.message.contains("unknown server")
+        ));
+        service.shutdown().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn shutdown_stops_the_service_task() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let service = VerdantService::with_config(
+            &runtime,
+            VerdantServiceConfig {
+                discovery: false,
+                health_check_interval: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let cmd_tx = service.tx().clone();
+
+        service.shutdown().unwrap();
+        // the service task dropped its receiver
+        assert!(VerdantService::ping(&cmd_tx, "https://a.example").is_err());
+    }
+
+    #[test]
+    fn no_callbacks_after_shutdown() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        service
+            .set_event_callback(Some(Box::new(move |event| {
+                let _ = tx.send(event);
+            })))
+            .unwrap();
+        for _ in 0..10 {
+            VerdantService::ping(service.tx(), "https://unknown.example").unwrap();
+        }
+
+        service.shutdown().unwrap();
+        let delivered = rx.try_iter().count();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.try_iter().count(), 0);
+        assert!(delivered <= 10);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn events_are_awaited_and_streamed() {
+        use tokio_stream::StreamExt;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        for _ in 0..2 {
+            VerdantService::ping(service.tx(), "https://unknown.example").unwrap();
+        }
+
+        runtime.block_on(async {
+            assert!(matches!(
+                service.wait_for_event().await,
+                Some(VerdantUiCmd::Error(_))
+            ));
+            assert!(matches!(
+                service.event_stream().next().await,
+                Some(VerdantUiCmd::Error(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn recv_timeout_waits_for_events() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        assert!(service.recv_timeout(Duration::from_millis(10)).is_none());
+
+        VerdantService::ping(service.tx(), "https://unknown.example").unwrap();
+        assert!(matches!(
+            service.recv_timeout(Duration::from_secs(5)),
+            Some(VerdantUiCmd::Error(_))
+        ));
+    }
+
+    #[test]
+    fn events_are_delivered_to_the_callback() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        service
+            .set_event_callback(Some(Box::new(move |event| {
+                let thread = std::thread::current().name().map(str::to_string);
+                let _ = tx.send((thread, event));
+            })))
+            .unwrap();
+        VerdantService::ping(service.tx(), "https://unknown.example").unwrap();
+
+        let (thread, event) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(thread.as_deref(), Some("verdant-events"));
+        assert!(matches!(event, VerdantUiCmd::Error(_)));
+        assert!(service.try_recv().is_none());
+    }
+
+    #[test]
+    fn verdant_err_reports_its_message() {
+        assert!(VerdantErr::noop().is_noop());
+        let err = VerdantErr::new(-1, "error: unknown server: https://a.example");
+        assert!(!err.is_noop());
+        assert_eq!(err.errorcode(), -1);
+        assert_eq!(
+            err.to_string(),
+            "error: unknown server: https://a.example (code -1)"
+        );
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert!(boxed.source().is_none());
+    }
+
+    #[test]
+    fn state_follows_logins() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        assert_eq!(service.state(), &ConnectionState::Idle);
+
+        service
+            .login_sync("https://a.example", "user", "password")
+            .unwrap();
+        assert_eq!(service.state(), &ConnectionState::Authenticating);
+        service.observe(&VerdantUiCmd::LoginResult(LoginResult::Success(
+            "token".to_string(),
+        )));
+        let connected = ConnectionState::Connected {
+            url: "https://a.example".to_string(),
+        };
+        assert_eq!(service.state(), &connected);
+
+        // refreshes don't change the state
+        service.observe(&VerdantUiCmd::LoginResult(LoginResult::Success(
+            "refreshed".to_string(),
+        )));
+        assert_eq!(service.state(), &connected);
+        service.observe(&VerdantUiCmd::Disconnected {
+            url: "https://a.example".to_string(),
+        });
+        assert_eq!(service.state(), &ConnectionState::Idle);
+
+        service
+            .login_sync("https://a.example", "user", "wrong")
+            .unwrap();
+        service.observe(&VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+            UnauthorizedReason::InvalidCredentials,
+        )));
+        assert!(matches!(service.state(), ConnectionState::Error(_)));
+    }
+
+    #[test]
+    fn servers_are_added_and_removed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut service = VerdantService::new(&runtime, false).unwrap();
+        assert_eq!(service.server_count(), 0);
+
+        service.add_server_sync("https://a.example".to_string());
+        service.add_server_sync("https://b.example".to_string());
+        service.add_server_sync("https://a.example".to_string());
+        assert_eq!(service.server_count(), 2);
+
+        service.remove_server_sync("https://a.example");
+        assert_eq!(service.server_count(), 1);
+        service.remove_server_sync("https://unknown.example");
+        assert_eq!(service.server_count(), 1);
+        assert!(service.discoveries_snapshot().is_empty());
+    }
+
+    fn failover_client(primary: &str, fallback: &str) -> APIClient {
+        APIClient::builder(
+            primary,
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        )
+        .fallback_url(fallback)
+        .skip_compatibility_check(true)
+        .build()
+        .unwrap()
+    }
+
+    async fn login_once(client: APIClient, url: &str, failover_on_5xx: bool) -> Vec<VerdantUiCmd> {
+        let mut clients = HashMap::new();
+        clients.insert(url.to_string(), client);
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), failover_on_5xx);
+        VerdantService::login(&cmd_tx, url, "alice", "password").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        let mut events = Vec::new();
+        while let Some(event) = ui_rx.recv().await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn logout_and_ping_are_answered() {
+        let (server, url) = spawn_test_server().await;
+        server.register_user("alice", "correct horse");
+        let mut client = server.client();
+        client.login("alice", "correct horse").await.unwrap();
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), client);
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), false);
+        VerdantService::ping(&cmd_tx, &url).unwrap();
+        VerdantService::logout(&cmd_tx, &url).unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Pong(pinged, _)) if pinged == url));
+        assert!(matches!(
+            ui_rx.recv().await,
+            Some(VerdantUiCmd::LoginResult(LoginResult::Unauthorized(
+                UnauthorizedReason::LoggedOut
+            )))
+        ));
+        assert_eq!(
+            server.requests()[server.requests().len() - 2..],
+            [
+                "GET /.well-known/verdant HTTP/1.1".to_string(),
+                "POST /auth/api/logout HTTP/1.1".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn logout_from_unknown_server_reports_error() {
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(HashMap::new(), Arc::new(Vec::new()), false);
+        VerdantService::logout(&cmd_tx, "http://unknown").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        match ui_rx.recv().await {
+            Some(VerdantUiCmd::Error(err)) => assert!(err.message.contains("unknown server")),
+            other => panic!("expected an error, got {other:?}"),
+        }
+        assert!(ui_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_registration_is_reported() {
+        // the username is taken
+        let (server, url) = spawn_test_server().await;
+        server.register_user("heidi", "password");
+        let mut clients = HashMap::new();
+        clients.insert(url.clone(), server.client());
+        let (cmd_tx, _, mut ui_rx, service) =
+            spawn_test_service(clients, Arc::new(Vec::new()), false);
+        let request = RegistrationRequest {
+            first_name: "Heidi".to_string(),
+            last_name: "Example".to_string(),
+            username: "heidi".to_string(),
+            email: "heidi@example.com".to_string(),
+            gender: None,
+            nonce: None,
+        };
+        VerdantService::register(&cmd_tx, &url, request, "password").unwrap();
+        drop(cmd_tx);
+        service.await.unwrap();
+
+        assert!(matches!(ui_rx.recv().await, Some(VerdantUiCmd::Error(_))));
+        assert_eq!(
+            server.requests(),
+            vec!["POST /auth/api/register/challenge HTTP/1.1".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn login_fails_over_on_server_error() {
+        let (primary, primary_requests) = mock_server(vec![(503, String::new())]).await;
+        let pubkey = serde_json::to_string(&crate::api::PubKeyResponse::encode_pubkey(
+            crate::api::KeyType::Ed25519,
+            &[42u8; 32],
+        ))
+        .unwrap();
+        let (fallback, fallback_requests) = mock_server(vec![
+            (200, pubkey),
+            // no compatibility endpoint
+            (404, String::new()),
+            (
+                200,
+                serde_json::to_string(&crate::server::auth::LoginResponse::AccessDenied).unwrap(),
+            ),
+        ])
+        .await;
+
+        let events = login_once(failover_client(&primary, &fallback), &primary, true).await;
+
+        assert_eq!(primary_requests.lock().unwrap().len(), 1);
+        assert_eq!(fallback_requests.lock().unwrap().len(), 3);
+        assert!(matches!(
+            &events[..],
+            [
+                VerdantUiCmd::ServerFailover { url, fallback_url },
+                VerdantUiCmd::LoginResult(LoginResult::Unauthorized(UnauthorizedReason::InvalidCredentials)),
+            ] if *url == primary && *fallback_url == fallback
+        ));
+    }
+
+    #[tokio::test]
+    async fn failover_can_be_disabled() {
+        let (primary, _) = mock_server(vec![(503, String::new())]).await;
+        let (fallback, fallback_requests) = mock_server(Vec::new()).await;
+
+        let events = login_once(failover_client(&primary, &fallback), &primary, false).await;
+
+        assert!(fallback_requests.lock().unwrap().is_empty());
+        assert!(matches!(
+            &events[..],
+            [VerdantUiCmd::LoginResult(LoginResult::Unauthorized(_))]
+        ));
+    }
+}
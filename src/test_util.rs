@@ -0,0 +1,570 @@
+//! Helpers shared by the unit tests.
+//!
+//! [`spawn_test_server`] is also available to other crates with the `test-utils` feature.
+
+use crate::api::{
+    APIClient, HealthStatus, KeyType, PubKeyResponse, ServerInfo, VerdantClaims, unverified_claims,
+};
+use crate::auth::challenge::{LoginCompletion, LoginUpload, Transcript};
+use crate::auth::registration::{
+    RegistrationChallenge, RegistrationFinish, RegistrationRequest, RegistrationStart,
+    RegistrationStartResponse,
+};
+use crate::auth::{DefaultCipherSuite, LoginResult, UnauthorizedReason};
+use crate::client::auth::{Client, LoginRequest, decode_credential_request};
+use crate::livekit::TokenResponse;
+use crate::server::auth::{LoginResponse, Server, ServerLogin, ServerRegistration, ServerSetup};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use opaque_ke::errors::ProtocolError;
+use rand::rngs::OsRng;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+#[cfg(test)]
+use {
+    crate::discovery::DiscoveryCache,
+    crate::plugin::{Plugins, UiSender},
+    crate::services::{InternalCmd, VerdantCmd, VerdantUiCmd, verdant_service},
+    keycast::discovery::Discovery,
+    tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    tokio::task::JoinHandle,
+};
+
+/// Runs the service loop over `clients` on its own task, the way [`VerdantService`] does
+/// without discovery.
+///
+/// Returns the command and internal command senders, the UI events and the task. The task
+/// ends once both senders are dropped.
+///
+/// [`VerdantService`]: crate::services::VerdantService
+#[cfg(test)]
+pub(crate) fn spawn_test_service(
+    clients: HashMap<String, APIClient>,
+    plugins: Plugins,
+    failover_on_5xx: bool,
+) -> (
+    UnboundedSender<VerdantCmd>,
+    UnboundedSender<InternalCmd>,
+    UnboundedReceiver<VerdantUiCmd>,
+    JoinHandle<()>,
+) {
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let (internal_tx, internal_rx) = mpsc::unbounded_channel();
+    let (tx, ui_rx) = mpsc::unbounded_channel();
+    let ui_tx = UiSender::new(tx, plugins.clone());
+    let service = tokio::spawn(verdant_service(
+        cmd_rx,
+        internal_rx,
+        ui_tx,
+        clients,
+        Arc::new(Mutex::new(None)),
+        plugins,
+        failover_on_5xx,
+        DiscoveryCache::<Discovery>::DEFAULT_MAX_SIZE,
+    ));
+    (cmd_tx, internal_tx, ui_rx, service)
+}
+
+/// A beacon of a server on `127.0.0.1:port` advertising the Ed25519 key with the
+/// hash `hash`, see
+/// [`PubKeyResponse::key_hash`](crate::api::PubKeyResponse::key_hash).
+#[cfg(test)]
+pub(crate) fn test_discovery(port: u16, hash: String) -> Discovery {
+    use keycast::crypto::{Encoding, HashAlg, KeyAlg, KeyHash};
+    use keycast::discovery::WebProtocol;
+
+    Discovery {
+        version: "1".to_string(),
+        addrs: vec!["127.0.0.1".parse().unwrap()],
+        protocol: WebProtocol::Http,
+        port,
+        name: String::new(),
+        host: String::new(),
+        pubkey_hash: KeyHash {
+            key_encoding: Encoding::Base64Der,
+            key_alg: KeyAlg::Ed25519,
+            hash_alg: HashAlg::Sha256,
+            hash,
+        },
+    }
+}
+
+/// Serves `responses` in order, one per connection, recording each request line.
+#[cfg(test)]
+pub(crate) async fn mock_server(
+    responses: Vec<(u16, String)>,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let responses = responses
+        .into_iter()
+        .map(|(status, body)| MockResponse {
+            status,
+            headers: vec![("content-type", "application/json".to_string())],
+            body: body.into_bytes(),
+        })
+        .collect();
+    mock_server_raw(responses).await
+}
+
+#[cfg(test)]
+pub(crate) struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Like [`mock_server`] with full control over headers and the raw body.
+#[cfg(test)]
+pub(crate) async fn mock_server_raw(
+    responses: Vec<MockResponse>,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 8192];
+            let n = socket.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]);
+            seen.lock()
+                .unwrap()
+                .push(request.lines().next().unwrap_or_default().to_string());
+            let mut head = format!("HTTP/1.1 {} OK\r\n", response.status);
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str(&format!(
+                "content-length: {}\r\nconnection: close\r\n\r\n",
+                response.body.len()
+            ));
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&response.body).await.unwrap();
+        }
+    });
+    (format!("http://{}", addr), requests)
+}
+
+/// Reads a full request, returning its request line, headers and body.
+async fn read_request(socket: &mut TcpStream) -> (String, String, Vec<u8>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break buf.len();
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < header_end + content_length {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let line = head.lines().next().unwrap_or_default().to_string();
+    (line, head, buf[header_end..].to_vec())
+}
+
+/// An in-process verdant server implementing the key, compatibility, health, registration,
+/// login, refresh, logout and token endpoints, for testing [`APIClient`] against the real
+/// protocol.
+///
+/// Users are kept in memory, see [`TestServer::register_user`]. Access tokens are signed
+/// with a fresh Ed25519 key served at `/pubkey`.
+pub struct TestServer {
+    url: String,
+    state: Arc<Mutex<TestServerState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+struct TestServerState {
+    server: Server,
+    /// signs the access tokens, its public half is `pubkey`.
+    signing_key: EncodingKey,
+    pubkey: [u8; 32],
+    started: Instant,
+    users: HashMap<String, ServerRegistration>,
+    /// registration challenges, with the username once the OPAQUE exchange started.
+    registrations: HashMap<Uuid, Option<String>>,
+    /// logins waiting for their finalization, keyed by session id.
+    pending: HashMap<Uuid, (ServerLogin, LoginRequest, LoginResponse)>,
+    /// access tokens that haven't been replaced by a refresh.
+    tokens: HashSet<String>,
+    logins: usize,
+    /// if set, finalizations are answered with this (unsigned) rejection.
+    rejection: Option<UnauthorizedReason>,
+    /// if set, completions are signed for this transcript nonce instead of the upload's.
+    completion_nonce: Option<[u8; 16]>,
+    /// if set, `/health` answers 503.
+    unavailable: bool,
+    /// request lines received so far.
+    requests: Vec<String>,
+}
+
+impl TestServer {
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// The Ed25519 key access tokens are signed with, as served at `/pubkey`.
+    pub fn pubkey(&self) -> PubKeyResponse {
+        PubKeyResponse::encode_pubkey(KeyType::Ed25519, &self.state.lock().unwrap().pubkey)
+    }
+
+    /// An [`APIClient`] for this server.
+    pub fn client(&self) -> APIClient {
+        let pubkey = self.state.lock().unwrap().pubkey;
+        APIClient::new(
+            self.url(),
+            DecodingKey::from_ed_der(&pubkey),
+            Validation::new(Algorithm::EdDSA),
+        )
+    }
+
+    pub fn register_user(&self, username: &str, password: &str) {
+        let mut state = self.state.lock().unwrap();
+        let registration = crate::auth::register_user(&state.server, username, password)
+            .expect("registration failed");
+        state.users.insert(username.to_string(), registration);
+    }
+
+    /// Replaces the password of `username` with [`Client::change_password`], failing
+    /// unless `old_password` is the current one.
+    pub fn change_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), ProtocolError> {
+        let mut state = self.state.lock().unwrap();
+        let stored = state
+            .users
+            .get(username)
+            .cloned()
+            .ok_or(ProtocolError::InvalidLoginError)?;
+        let changed = Client::new(old_password).change_password(
+            new_password,
+            &state.server,
+            stored,
+            username,
+        )?;
+        state.users.insert(username.to_string(), changed);
+        Ok(())
+    }
+
+    /// number of completed logins.
+    pub fn login_count(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    /// Rejects the following login finalizations with `reason`, like a server refusing
+    /// an account after checking its credentials.
+    pub fn reject_logins(&self, reason: UnauthorizedReason) {
+        self.state.lock().unwrap().rejection = Some(reason);
+    }
+
+    /// Signs the following login completions for the transcript nonce `nonce`, like a
+    /// completion replayed from another session.
+    pub fn replay_completions(&self, nonce: [u8; 16]) {
+        self.state.lock().unwrap().completion_nonce = Some(nonce);
+    }
+
+    /// Makes `/health` answer 503 (or 200 again), like a server going down.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+
+    /// Request lines received so far, e.g. `"POST /auth/api/logout HTTP/1.1"`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Stops accepting connections.
+    pub fn shutdown(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Starts a [`TestServer`] on `127.0.0.1`, returning it along with its url.
+pub async fn spawn_test_server() -> (TestServer, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (signing_key, verifying_key) = crate::crypto::generate_ed25519_pair();
+    let (private_pem, _) = crate::crypto::ed25519_pair_to_pem(&signing_key);
+    let state = Arc::new(Mutex::new(TestServerState {
+        server: Server::new(ServerSetup::new(&mut OsRng)),
+        signing_key: EncodingKey::from_ed_pem(private_pem.as_bytes()).unwrap(),
+        pubkey: verifying_key.to_bytes(),
+        started: Instant::now(),
+        users: HashMap::new(),
+        registrations: HashMap::new(),
+        pending: HashMap::new(),
+        tokens: HashSet::new(),
+        logins: 0,
+        rejection: None,
+        completion_nonce: None,
+        unavailable: false,
+        requests: Vec::new(),
+    }));
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let served = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let mut socket = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((socket, _)) => socket,
+                    Err(_) => break,
+                },
+                _ = &mut shutdown_rx => break,
+            };
+            let (line, head, body) = read_request(&mut socket).await;
+            let (status, body) = handle(&mut served.lock().unwrap(), &line, &head, &body);
+            let head = format!(
+                "HTTP/1.1 {} OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(body.as_bytes()).await;
+        }
+    });
+    let server = TestServer {
+        url: url.clone(),
+        state,
+        shutdown: Some(shutdown_tx),
+    };
+    (server, url)
+}
+
+/// Answers one request to the [`TestServer`].
+fn handle(state: &mut TestServerState, line: &str, head: &str, body: &[u8]) -> (u16, String) {
+    state.requests.push(line.to_string());
+    let bearer = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .map(str::to_string);
+    let authorized = bearer
+        .as_ref()
+        .is_some_and(|token| state.tokens.contains(token));
+    let path = line.split_whitespace().nth(1).unwrap_or_default();
+    match path {
+        "/pubkey" => {
+            let pubkey = PubKeyResponse::encode_pubkey(KeyType::Ed25519, &state.pubkey);
+            (200, serde_json::to_string(&pubkey).unwrap())
+        }
+        "/.well-known/verdant" => {
+            let info = ServerInfo {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                supported_algorithms: vec!["EdDSA".to_string()],
+                require_registration_invite: false,
+                features: Vec::new(),
+            };
+            (200, serde_json::to_string(&info).unwrap())
+        }
+        "/health" if state.unavailable => (503, String::new()),
+        "/health" => {
+            let status = HealthStatus {
+                status: "ok".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_secs: state.started.elapsed().as_secs(),
+                authenticated_users: Some(state.tokens.len() as u32),
+            };
+            (200, serde_json::to_string(&status).unwrap())
+        }
+        "/auth/api/compatibility" => (200, r#"{"min_client_version":"0.0.0"}"#.to_string()),
+        "/auth/api/register/challenge" => {
+            let Ok(details) = serde_json::from_slice::<RegistrationRequest>(body) else {
+                return (400, String::new());
+            };
+            if state.users.contains_key(&details.username) {
+                return (409, String::new());
+            }
+            let id = Uuid::new_v4();
+            state.registrations.insert(id, None);
+            (
+                200,
+                serde_json::to_string(&RegistrationChallenge { id }).unwrap(),
+            )
+        }
+        "/auth/api/register/start" => {
+            let Ok(start) = serde_json::from_slice::<RegistrationStart>(body) else {
+                return (400, String::new());
+            };
+            let Some(username) = state.registrations.get_mut(&start.id) else {
+                return (404, String::new());
+            };
+            *username = Some(start.username.clone());
+            let request = STANDARD.decode(&start.message).ok().and_then(|message| {
+                opaque_ke::RegistrationRequest::<DefaultCipherSuite>::deserialize(&message).ok()
+            });
+            match request.map(|request| state.server.start_registration(request, start.username)) {
+                Some(Ok(response)) => {
+                    let message = STANDARD.encode(response.serialize());
+                    (
+                        200,
+                        serde_json::to_string(&RegistrationStartResponse { message }).unwrap(),
+                    )
+                }
+                _ => (400, String::new()),
+            }
+        }
+        "/auth/api/register/finish" => {
+            let Ok(finish) = serde_json::from_slice::<RegistrationFinish>(body) else {
+                return (400, String::new());
+            };
+            let Some(Some(username)) = state.registrations.remove(&finish.id) else {
+                return (404, String::new());
+            };
+            let upload = STANDARD.decode(&finish.message).ok().and_then(|message| {
+                opaque_ke::RegistrationUpload::<DefaultCipherSuite>::deserialize(&message).ok()
+            });
+            match upload {
+                Some(upload) => {
+                    let registration = state.server.finish_registration(upload);
+                    state.users.insert(username, registration);
+                    (200, String::new())
+                }
+                None => (400, String::new()),
+            }
+        }
+        "/auth/api/logout" => match bearer.filter(|_| authorized) {
+            Some(token) => {
+                state.tokens.remove(&token);
+                (200, String::new())
+            }
+            None => (401, String::new()),
+        },
+        "/auth/api/login/" => {
+            let Ok(request) = serde_json::from_slice::<LoginRequest>(body) else {
+                return (400, String::new());
+            };
+            let response = match (
+                state.users.get(&request.username),
+                decode_credential_request(&request.credentials),
+            ) {
+                (Some(registration), Ok(credentials)) => {
+                    match state.server.start_login(
+                        registration.clone(),
+                        credentials,
+                        &request.username,
+                    ) {
+                        Ok((login, message)) => {
+                            let id = Uuid::new_v4();
+                            let response = LoginResponse::PAKE((id, message));
+                            state.pending.insert(id, (login, request, response.clone()));
+                            response
+                        }
+                        Err(_) => LoginResponse::AccessDenied,
+                    }
+                }
+                _ => LoginResponse::AccessDenied,
+            };
+            (200, serde_json::to_string(&response).unwrap())
+        }
+        "/auth/api/login/finalize" => {
+            let Ok(upload) = serde_json::from_slice::<LoginUpload>(body) else {
+                return (400, String::new());
+            };
+            let Some((login, request, response)) = state.pending.remove(&upload.id()) else {
+                return (404, String::new());
+            };
+            let completion = match (
+                state.rejection,
+                state.server.finish_login(login, upload.finalization()),
+            ) {
+                (Some(reason), _) => LoginCompletion::unauthorized(reason),
+                (None, Ok(key)) if upload.verify(&key, &request, &response) => {
+                    let token = issue_token(&state.signing_key, &request.username);
+                    state.tokens.insert(token.clone());
+                    state.logins += 1;
+                    let nonce = state.completion_nonce.unwrap_or(upload.nonce());
+                    let transcript =
+                        Transcript::compute_transcript_with_nonce(&request, &response, nonce)
+                            .unwrap();
+                    LoginCompletion::new(LoginResult::Success(token), &key, transcript, None)
+                }
+                _ => LoginCompletion::unauthorized(UnauthorizedReason::InvalidCredentials),
+            };
+            (200, serde_json::to_string(&completion).unwrap())
+        }
+        "/auth/api/refresh" => match bearer.filter(|_| authorized) {
+            Some(old) => {
+                state.tokens.remove(&old);
+                let sub = unverified_claims(&old)
+                    .and_then(|claims| claims.sub)
+                    .unwrap_or_default();
+                let token = issue_token(&state.signing_key, &sub);
+                state.tokens.insert(token.clone());
+                (
+                    200,
+                    serde_json::to_string(&LoginResult::Success(token)).unwrap(),
+                )
+            }
+            None => (
+                200,
+                serde_json::to_string(&LoginResult::Unauthorized(
+                    UnauthorizedReason::SessionExpired,
+                ))
+                .unwrap(),
+            ),
+        },
+        "/rpc/token" if authorized => {
+            let response = TokenResponse {
+                room_id: Uuid::nil(),
+                token: "livekit-token".to_string(),
+                room: "lobby".to_string(),
+                url: "ws://127.0.0.1:7880".to_string(),
+                expires_at: None,
+            };
+            (200, serde_json::to_string(&response).unwrap())
+        }
+        "/rpc/token" => (401, String::new()),
+        _ => (404, String::new()),
+    }
+}
+
+/// A token for `username` signed with `key`, valid for an hour. Each call yields a
+/// different token.
+fn issue_token(key: &EncodingKey, username: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let claims = VerdantClaims {
+        sub: Some(username.to_string()),
+        exp: Some(now.as_secs() + 3600),
+        iat: Some(now.as_secs()),
+        ..Default::default()
+    };
+    // the nanoseconds keep tokens issued within the same second apart
+    let header = Header {
+        kid: Some(now.subsec_nanos().to_string()),
+        ..Header::new(Algorithm::EdDSA)
+    };
+    jsonwebtoken::encode(&header, &claims, key).unwrap()
+}